@@ -3,6 +3,21 @@ use std::error::Error;
 fn main() -> Result<(), Box<dyn Error>> {
     let disk_path = std::env::args_os().nth(1).unwrap();
     let disk = vhdx::Vhdx::load(disk_path)?;
-    dbg!(disk);
+
+    println!("Creator: {}", disk.creator());
+    println!("Format version: {}", disk.format_version());
+    println!("Virtual disk id: {}", disk.virtual_disk_id());
+    println!("Virtual size: {} bytes", disk.virtual_size());
+    println!("Block size: {} bytes", disk.block_size());
+    println!(
+        "Sector size: {} bytes logical, {} bytes physical",
+        disk.logical_sector_size(),
+        disk.physical_sector_size()
+    );
+    println!("Has parent: {}", disk.has_parent());
+    println!("Leave blocks allocated: {}", disk.leave_block_allocated());
+    println!("File write guid: {}", disk.file_write_guid());
+    println!("Data write guid: {}", disk.data_write_guid());
+
     Ok(())
 }
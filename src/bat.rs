@@ -1,18 +1,60 @@
-use std::{fs::File, io::Read};
+//! The block allocation table (BAT), which records where each block of the
+//! virtual disk is stored in the file.
+//!
+//! The BAT has an entry for every payload block of the virtual disk, and after
+//! every chunk of payload blocks, an entry for the sector bitmap block that
+//! records which sectors of the chunk are present in a differencing disk.
 
-use crate::Error;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::{file_io, lru::Lru, mmap::Mapping, Error, KB};
+
+/// The bits of an entry that hold the state of the block.
+const STATE_MASK: u64 = 0b111;
+/// The bits of an entry that hold the offset of the block in the file, in
+/// units of 1 MB.
+const FILE_OFFSET_MASK: u64 = 0xFFFFFFFFFFF00000;
+
+/// The state of a payload block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PayloadBatEntryState {
+    /// The block is not stored in the file. It reads as zeros, or from the
+    /// parent of a differencing disk.
     NotPresent,
+    /// The contents of the block are undefined, which this crate reads in the
+    /// same way as a block that is not present.
     Undefined,
+    /// The block reads as zeros.
     Zero,
+    /// The block has been discarded, and reads as zeros.
     Unmapped,
+    /// The whole block is stored in the file.
     FullyPresent,
+    /// Some sectors of the block of a differencing disk are stored in the
+    /// file, as recorded by the sector bitmap block of its chunk.
     PartiallyPresent,
+    /// A state that is reserved by the specification, which is only found in
+    /// entries read from a file. The block is read in the same way as a block
+    /// that is not present.
+    Reserved(ReservedState),
 }
 
 impl PayloadBatEntryState {
+    /// Whether the block has data stored in the file, as opposed to reading
+    /// as zeros (or from the parent disk).
+    pub fn is_allocated(self) -> bool {
+        matches!(
+            self,
+            PayloadBatEntryState::FullyPresent | PayloadBatEntryState::PartiallyPresent
+        )
+    }
+
     fn from_bits(value: u8) -> Self {
         match value {
             0 => PayloadBatEntryState::NotPresent,
@@ -21,69 +63,497 @@ impl PayloadBatEntryState {
             3 => PayloadBatEntryState::Unmapped,
             6 => PayloadBatEntryState::FullyPresent,
             7 => PayloadBatEntryState::PartiallyPresent,
-            _ => panic!("unknown state: {value:b}"),
+            value => PayloadBatEntryState::Reserved(ReservedState(value)),
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            PayloadBatEntryState::NotPresent => 0,
+            PayloadBatEntryState::Undefined => 1,
+            PayloadBatEntryState::Zero => 2,
+            PayloadBatEntryState::Unmapped => 3,
+            PayloadBatEntryState::FullyPresent => 6,
+            PayloadBatEntryState::PartiallyPresent => 7,
+            PayloadBatEntryState::Reserved(state) => state.0,
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for PayloadBatEntryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadBatEntryState::NotPresent => write!(f, "not present"),
+            PayloadBatEntryState::Undefined => write!(f, "undefined"),
+            PayloadBatEntryState::Zero => write!(f, "zero"),
+            PayloadBatEntryState::Unmapped => write!(f, "unmapped"),
+            PayloadBatEntryState::FullyPresent => write!(f, "fully present"),
+            PayloadBatEntryState::PartiallyPresent => write!(f, "partially present"),
+            PayloadBatEntryState::Reserved(state) => write!(f, "reserved ({state})"),
+        }
+    }
+}
+
+/// The state of a sector bitmap block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SectorBitmapBatEntryState {
+    /// The sector bitmap block is not stored in the file, so none of the
+    /// sectors of the chunk are present.
+    NotPresent,
+    /// The sector bitmap block is stored in the file.
+    Present,
+    /// A state that is reserved by the specification.
+    Reserved(ReservedState),
+}
+
+impl SectorBitmapBatEntryState {
+    fn from_bits(value: u8) -> Self {
+        match value {
+            0 => SectorBitmapBatEntryState::NotPresent,
+            6 => SectorBitmapBatEntryState::Present,
+            value => SectorBitmapBatEntryState::Reserved(ReservedState(value)),
+        }
+    }
+}
+
+impl fmt::Display for SectorBitmapBatEntryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectorBitmapBatEntryState::NotPresent => write!(f, "not present"),
+            SectorBitmapBatEntryState::Present => write!(f, "present"),
+            SectorBitmapBatEntryState::Reserved(state) => write!(f, "reserved ({state})"),
+        }
+    }
+}
+
+/// The value of a state that is reserved by the specification, as read from
+/// an entry of the BAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReservedState(u8);
+
+impl ReservedState {
+    /// The value of the state, from the low three bits of the entry.
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for ReservedState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The block that an entry of the BAT describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Block {
+    /// The payload block with the given index, which holds the data of the
+    /// virtual disk from `index * block_size`.
+    Payload(u64),
+    /// The sector bitmap block of the chunk with the given index.
+    SectorBitmap(u64),
+}
+
+impl Block {
+    /// The block described by the BAT entry at `index`, where every chunk of
+    /// `chunk_ratio` payload blocks is followed by its sector bitmap block.
+    pub(crate) fn at(index: u64, chunk_ratio: u64) -> Self {
+        let chunk_index = index / (chunk_ratio + 1);
+        if (index + 1).is_multiple_of(chunk_ratio + 1) {
+            Block::SectorBitmap(chunk_index)
+        } else {
+            Block::Payload(index - chunk_index)
+        }
+    }
+}
+
+/// An entry of the BAT.
+///
+/// The same entry is used for payload blocks and sector bitmap blocks, which
+/// interpret the state differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BatEntry {
     state: PayloadBatEntryState,
     file_offset: u64,
 }
 
 impl BatEntry {
-    fn read(file: &mut File) -> Result<Self, Error> {
-        let mut buffer = vec![0; 8];
-        file.read_exact(&mut buffer)?;
-
-        let value = u64::from_le_bytes(buffer.try_into().expect("infallible"));
-        let state = PayloadBatEntryState::from_bits(value as u8 & 0b111);
+    /// An entry of a payload block with the given state and offset in the
+    /// file.
+    ///
+    /// The file offset must be a multiple of 1 MB, which is the unit that
+    /// offsets are stored in, or [`Error::UnalignedFileOffset`] is returned.
+    pub fn try_new(state: PayloadBatEntryState, file_offset: u64) -> Result<Self, Error> {
+        if file_offset & !FILE_OFFSET_MASK != 0 {
+            return Err(Error::UnalignedFileOffset(file_offset));
+        }
+        Ok(Self { state, file_offset })
+    }
 
-        let mask = 0xFFFFFFFFFFF00000;
-        let file_offset = value & mask;
+    /// An entry with no offset in the file, for a block that is not stored in
+    /// the file.
+    pub(crate) fn new(state: PayloadBatEntryState) -> Self {
+        Self {
+            state,
+            file_offset: 0,
+        }
+    }
 
-        Ok(Self { state, file_offset })
+    /// Parse an entry as it is stored in the file. The reserved bits between
+    /// the state and the file offset are ignored.
+    pub fn from_bits(value: u64) -> Self {
+        Self {
+            state: PayloadBatEntryState::from_bits((value & STATE_MASK) as u8),
+            file_offset: value & FILE_OFFSET_MASK,
+        }
     }
 
+    /// The offset of the block in the file, which is only meaningful for
+    /// blocks that are stored in the file.
     pub fn file_offset(&self) -> u64 {
         self.file_offset
     }
 
+    /// The state of the entry of a payload block.
     pub fn state(&self) -> PayloadBatEntryState {
         self.state
     }
+
+    /// The state of the entry of a sector bitmap block.
+    pub fn sector_bitmap_state(&self) -> SectorBitmapBatEntryState {
+        SectorBitmapBatEntryState::from_bits(self.state.to_bits())
+    }
+
+    /// The same entry, transitioned to a different state.
+    pub(crate) fn with_state(self, state: PayloadBatEntryState) -> Self {
+        Self { state, ..self }
+    }
+
+    /// The entry as it is stored in the file.
+    pub fn to_bits(self) -> u64 {
+        self.file_offset | self.state.to_bits() as u64
+    }
 }
 
+/// The number of entries in each page of the BAT, which is loaded from the
+/// file as a whole. A 64 KB page covers 8 GB of a disk with 1 MB blocks.
+const ENTRIES_PER_PAGE: u64 = 8 * KB as u64;
+/// The number of unmodified pages that are kept in memory, 16 MB of entries.
+const CACHED_PAGES: u64 = 256;
+
+/// Where the entries of the BAT are loaded from.
 #[derive(Debug)]
-pub struct Bat {
+enum Source {
+    /// The BAT region of a file, which starts at the given offset.
+    File(File, u64),
+    /// The BAT region, already read into memory.
+    Memory(Vec<u8>),
+    /// The BAT region of a memory-mapped file, which starts at the given
+    /// offset.
+    Mapped(Mapping, u64),
+}
+
+/// The pages of the BAT that are in memory, with each entry packed as it is
+/// stored in the file.
+#[derive(Debug)]
+struct Pages {
+    /// Pages that have been modified, which are kept until they have been
+    /// written to the file.
+    dirty: HashMap<u64, Box<[u64]>>,
+    clean: Lru<u64, Box<[u64]>>,
+}
+
+/// The BAT of a file, which is loaded a page at a time as it is used, so that
+/// opening a very large disk doesn't read the whole BAT.
+#[derive(Debug)]
+pub(crate) struct Bat {
     block_size: u64,
     chunk_ratio: u64,
-    entries: Vec<BatEntry>,
+    payload_blocks_count: u64,
+    entries_count: u64,
+    /// The number of entries that can be loaded from the source. Entries added
+    /// by growing the disk are not present until they are written.
+    stored_entries_count: u64,
+    source: Source,
+    pages: Mutex<Pages>,
 }
 
 impl Bat {
-    pub(crate) fn read(file: &mut File, metadata: &crate::Metadata) -> Result<Self, Error> {
+    /// Prepare to load the BAT from the region of the file at `offset`.
+    pub(crate) fn open(
+        file: &File,
+        offset: u64,
+        metadata: &crate::Metadata,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(Source::File(file.try_clone()?, offset), metadata))
+    }
+
+    /// Load the BAT from the region of a memory-mapped file at `offset`.
+    pub(crate) fn mapped(mapping: Mapping, offset: u64, metadata: &crate::Metadata) -> Self {
+        Self::new(Source::Mapped(mapping, offset), metadata)
+    }
+
+    /// Use a BAT region that has already been read into memory.
+    pub(crate) fn from_bytes(bytes: Vec<u8>, metadata: &crate::Metadata) -> Self {
+        Self::new(Source::Memory(bytes), metadata)
+    }
+
+    fn new(source: Source, metadata: &crate::Metadata) -> Self {
         let virt_disk_size = metadata.virtual_disk_size.virtual_disk_size();
         let logical_sector_size = metadata.logical_sector_size.logical_sector_size();
         let block_size = metadata.file_parameters.block_size() as u64;
-        let chunk_ratio = (2 << 23) * logical_sector_size as u64 / block_size;
+        let chunk_ratio = (1 << 23) * logical_sector_size as u64 / block_size;
         let payload_blocks_count = div_ceil(virt_disk_size, block_size);
-        let total_bat_entries = payload_blocks_count + (payload_blocks_count - 1) / chunk_ratio;
+        let entries_count = entries_for(
+            payload_blocks_count,
+            chunk_ratio,
+            metadata.file_parameters.has_parent(),
+        );
 
-        if total_bat_entries - payload_blocks_count != 0 {
-            unimplemented!("sector bitmap blocks");
+        Self {
+            block_size,
+            chunk_ratio,
+            payload_blocks_count,
+            entries_count,
+            stored_entries_count: entries_count,
+            source,
+            pages: Mutex::new(Pages {
+                dirty: HashMap::new(),
+                clean: Lru::new(CACHED_PAGES),
+            }),
         }
+    }
 
-        let entries = (0..total_bat_entries)
-            .map(|_| BatEntry::read(file))
-            .collect::<Result<_, _>>()?;
+    /// Read a page of entries from the source. Entries beyond those that are
+    /// stored are not present.
+    fn load_page(&self, page_index: u64) -> io::Result<Box<[u64]>> {
+        let first = page_index * ENTRIES_PER_PAGE;
+        let count = ENTRIES_PER_PAGE.min(self.stored_entries_count.saturating_sub(first));
+        let mut bytes = vec![0; count as usize * 8];
+        match &self.source {
+            Source::File(file, offset) => {
+                file_io::read_exact_at(file, &mut bytes, offset + first * 8)?
+            }
+            Source::Memory(source) => {
+                let start = (first * 8) as usize;
+                let source = source.get(start..start + bytes.len()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "BAT region is too small")
+                })?;
+                bytes.copy_from_slice(source);
+            }
+            Source::Mapped(mapping, offset) => {
+                mapping.read_exact_at(offset + first * 8, &mut bytes)?
+            }
+        }
 
-        Ok(Self {
-            block_size,
-            chunk_ratio,
-            entries,
-        })
+        let mut page = vec![0; ENTRIES_PER_PAGE as usize].into_boxed_slice();
+        for (entry, bytes) in page.iter_mut().zip(bytes.chunks_exact(8)) {
+            *entry = u64::from_le_bytes(bytes.try_into().expect("infallible"));
+        }
+        Ok(page)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Pages> {
+        self.pages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run a function on a page, loading it if it isn't in memory.
+    fn with_page<T>(&self, page_index: u64, f: impl FnOnce(&[u64]) -> T) -> io::Result<T> {
+        let mut pages = self.lock();
+        if let Some(page) = pages.dirty.get(&page_index) {
+            return Ok(f(page));
+        }
+        if let Some(page) = pages.clean.get(&page_index) {
+            return Ok(f(page));
+        }
+        let page = self.load_page(page_index)?;
+        let result = f(&page);
+        pages.clean.insert(page_index, page, 1);
+        Ok(result)
+    }
+
+    /// Get a page to modify, keeping it in memory until it has been written.
+    fn page_mut(&mut self, page_index: u64) -> io::Result<&mut [u64]> {
+        if !self.pages_mut().dirty.contains_key(&page_index) {
+            let page = match self.pages_mut().clean.remove(&page_index) {
+                Some(page) => page,
+                None => self.load_page(page_index)?,
+            };
+            self.pages_mut().dirty.insert(page_index, page);
+        }
+        Ok(self
+            .pages_mut()
+            .dirty
+            .get_mut(&page_index)
+            .expect("page is dirty"))
+    }
+
+    fn pages_mut(&mut self) -> &mut Pages {
+        self.pages.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Mark the modified pages as written to the file, after which they may
+    /// be evicted.
+    pub(crate) fn mark_written(&mut self) {
+        let pages = self.pages_mut();
+        for (page_index, page) in pages.dirty.drain() {
+            pages.clean.insert(page_index, page, 1);
+        }
+        self.stored_entries_count = self.entries_count;
+    }
+
+    fn entry(&self, bat_index: u64) -> io::Result<BatEntry> {
+        let offset = (bat_index % ENTRIES_PER_PAGE) as usize;
+        let value = self.with_page(bat_index / ENTRIES_PER_PAGE, |page| page[offset])?;
+        Ok(BatEntry::from_bits(value))
+    }
+
+    /// The number of payload blocks that make up the virtual disk.
+    pub(crate) fn payload_blocks_count(&self) -> u64 {
+        self.payload_blocks_count
+    }
+
+    /// The total number of entries, including sector bitmap entries.
+    pub(crate) fn entries_count(&self) -> u64 {
+        self.entries_count
+    }
+
+    /// Add or remove entries to match a new virtual disk size. Any new entries
+    /// are not present.
+    pub(crate) fn resize(&mut self, virtual_disk_size: u64) {
+        let payload_blocks_count = div_ceil(virtual_disk_size, self.block_size);
+        let entries_count = total_entries(payload_blocks_count, self.chunk_ratio);
+
+        // Entries beyond the smaller of the two sizes are not present, in
+        // the source or in memory
+        let kept = entries_count.min(self.entries_count);
+        self.stored_entries_count = self.stored_entries_count.min(kept);
+        let pages = self.pages_mut();
+        pages.clean.clear();
+        for (&page_index, page) in pages.dirty.iter_mut() {
+            let first = page_index * ENTRIES_PER_PAGE;
+            let kept_in_page = kept.saturating_sub(first).min(ENTRIES_PER_PAGE) as usize;
+            page[kept_in_page..].fill(0);
+        }
+
+        self.entries_count = entries_count;
+        self.payload_blocks_count = payload_blocks_count;
+    }
+
+    /// Get the entry for a payload block, skipping over any sector bitmap
+    /// entries.
+    pub(crate) fn payload_entry(&self, payload_block_index: u64) -> io::Result<Option<BatEntry>> {
+        if payload_block_index >= self.payload_blocks_count {
+            return Ok(None);
+        }
+        self.entry(self.payload_bat_index(payload_block_index))
+            .map(Some)
+    }
+
+    /// Replace the entry for a payload block.
+    pub(crate) fn set_payload_entry(
+        &mut self,
+        payload_block_index: u64,
+        entry: BatEntry,
+    ) -> io::Result<()> {
+        let bat_index = self.payload_bat_index(payload_block_index);
+        let page = self.page_mut(bat_index / ENTRIES_PER_PAGE)?;
+        page[(bat_index % ENTRIES_PER_PAGE) as usize] = entry.to_bits();
+        Ok(())
+    }
+
+    /// The index of the 4 KB sector within the BAT region that contains the
+    /// entry for a payload block.
+    pub(crate) fn payload_entry_sector(&self, payload_block_index: u64) -> u64 {
+        self.payload_bat_index(payload_block_index) * 8 / (4 * KB as u64)
+    }
+
+    /// Serialise a 4 KB sector of the BAT region.
+    pub(crate) fn sector(&self, sector_index: u64) -> io::Result<Box<[u8; 4 * KB]>> {
+        let mut sector = Box::new([0; 4 * KB]);
+        let entries_per_sector = 4 * KB as u64 / 8;
+        let first = sector_index * entries_per_sector;
+        let count = entries_per_sector.min(self.entries_count.saturating_sub(first)) as usize;
+        if count == 0 {
+            return Ok(sector);
+        }
+
+        let offset = (first % ENTRIES_PER_PAGE) as usize;
+        self.with_page(first / ENTRIES_PER_PAGE, |page| {
+            for (bytes, entry) in sector
+                .chunks_exact_mut(8)
+                .zip(&page[offset..offset + count])
+            {
+                bytes.copy_from_slice(&entry.to_le_bytes());
+            }
+        })?;
+        Ok(sector)
+    }
+
+    /// Get the entry for the sector bitmap block of a chunk, which records
+    /// which sectors of a differencing disk are present in the file.
+    pub(crate) fn sector_bitmap_entry(&self, chunk_index: u64) -> io::Result<Option<BatEntry>> {
+        let bat_index = chunk_index * (self.chunk_ratio + 1) + self.chunk_ratio;
+        if bat_index >= self.entries_count {
+            return Ok(None);
+        }
+        self.entry(bat_index).map(Some)
+    }
+
+    /// The number of payload blocks that share each sector bitmap block.
+    pub(crate) fn chunk_ratio(&self) -> u64 {
+        self.chunk_ratio
+    }
+
+    fn payload_bat_index(&self, payload_block_index: u64) -> u64 {
+        let sector_bitmap_blocks = payload_block_index / self.chunk_ratio;
+        payload_block_index + sector_bitmap_blocks
+    }
+
+    /// All of the entries, in the order they are stored, with the block that
+    /// each describes. The entries that pad the last chunk of a differencing
+    /// disk, beyond the end of the virtual disk, are skipped.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = io::Result<(Block, BatEntry)>> + '_ {
+        let chunk_ratio = self.chunk_ratio;
+        let pages_count = self.entries_count.div_ceil(ENTRIES_PER_PAGE);
+        (0..pages_count)
+            .flat_map(move |page_index| {
+                let first = page_index * ENTRIES_PER_PAGE;
+                let count = ENTRIES_PER_PAGE.min(self.entries_count - first) as usize;
+                match self.with_page(page_index, |page| page[..count].to_vec()) {
+                    Ok(page) => page
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, value)| Ok((first + i as u64, BatEntry::from_bits(value))))
+                        .collect(),
+                    Err(error) => vec![Err(error)],
+                }
+            })
+            .map(move |result| result.map(|(index, entry)| (Block::at(index, chunk_ratio), entry)))
+            .filter(|result| {
+                !matches!(
+                    result,
+                    Ok((Block::Payload(index), _)) if *index >= self.payload_blocks_count
+                )
+            })
+    }
+
+    /// Find the first payload block at or after `payload_block_index` whose
+    /// state matches the predicate.
+    pub(crate) fn find_payload_block(
+        &self,
+        payload_block_index: u64,
+        predicate: impl Fn(PayloadBatEntryState) -> bool,
+    ) -> io::Result<Option<u64>> {
+        for index in payload_block_index..self.payload_blocks_count {
+            let entry = self
+                .payload_entry(index)?
+                .expect("block is within the disk");
+            if predicate(entry.state()) {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
     }
 
     /// Get the associated entry for a given disk offset.
@@ -93,17 +563,37 @@ impl Bat {
     ///
     /// Returns none if the offset is outside of the range based on the entries
     /// in the bat table.
-    pub fn offset_to_entry(&self, offset: u64) -> Option<(&BatEntry, u64)> {
+    pub(crate) fn offset_to_entry(&self, offset: u64) -> io::Result<Option<(BatEntry, u64)>> {
         let payload_block_index = offset / self.block_size;
-        let sector_bitmap_blocks = payload_block_index / self.chunk_ratio;
-        let bat_index = payload_block_index + sector_bitmap_blocks;
-        let entry = self.entries.get(bat_index as usize)?;
+        let Some(entry) = self.payload_entry(payload_block_index)? else {
+            return Ok(None);
+        };
         let base_address = payload_block_index * self.block_size;
-        Some((entry, offset - base_address))
+        Ok(Some((entry, offset - base_address)))
     }
 }
 
-const fn div_ceil(dividend: u64, divisor: u64) -> u64 {
+/// The number of BAT entries needed for a number of payload blocks, where a
+/// sector bitmap entry follows every `chunk_ratio` payload entries.
+const fn total_entries(payload_blocks_count: u64, chunk_ratio: u64) -> u64 {
+    payload_blocks_count + payload_blocks_count.saturating_sub(1) / chunk_ratio
+}
+
+/// The number of BAT entries in a file. Differencing disks have entries for
+/// the whole of the last chunk, so that its sector bitmap entry is present.
+pub(crate) const fn entries_for(
+    payload_blocks_count: u64,
+    chunk_ratio: u64,
+    has_parent: bool,
+) -> u64 {
+    if has_parent {
+        div_ceil(payload_blocks_count, chunk_ratio) * (chunk_ratio + 1)
+    } else {
+        total_entries(payload_blocks_count, chunk_ratio)
+    }
+}
+
+pub(crate) const fn div_ceil(dividend: u64, divisor: u64) -> u64 {
     let d = dividend / divisor;
     let r = dividend % divisor;
     if r > 0 && divisor > 0 {
@@ -112,3 +602,88 @@ const fn div_ceil(dividend: u64, divisor: u64) -> u64 {
         d
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A BAT of a disk with 1 MB blocks and 512 byte sectors, with every
+    /// payload block fully present at an offset that identifies it.
+    fn memory_bat(payload_blocks_count: u64) -> Bat {
+        let chunk_ratio = 4096;
+        let entries_count = total_entries(payload_blocks_count, chunk_ratio);
+        let bytes = (0..entries_count)
+            .flat_map(|index| ((index + 1) << 20 | 6).to_le_bytes())
+            .collect();
+        Bat {
+            block_size: 1 << 20,
+            chunk_ratio,
+            payload_blocks_count,
+            entries_count,
+            stored_entries_count: entries_count,
+            source: Source::Memory(bytes),
+            pages: Mutex::new(Pages {
+                dirty: HashMap::new(),
+                clean: Lru::new(CACHED_PAGES),
+            }),
+        }
+    }
+
+    #[test]
+    fn modified_pages_are_kept() {
+        let payload_blocks_count = (CACHED_PAGES + 4) * ENTRIES_PER_PAGE;
+        let mut bat = memory_bat(payload_blocks_count);
+        let entry = bat.payload_entry(5000).unwrap().unwrap();
+        // The sector bitmap entry of the first chunk comes before it
+        assert_eq!(entry.file_offset(), 5002 << 20);
+
+        let zero = BatEntry::new(PayloadBatEntryState::Zero);
+        bat.set_payload_entry(5000, zero).unwrap();
+        // Reading every page evicts all of the unmodified pages
+        assert_eq!(
+            bat.entries().count() as u64,
+            total_entries(payload_blocks_count, 4096)
+        );
+        assert_eq!(bat.payload_entry(5000).unwrap(), Some(zero));
+        let sector = bat.sector(bat.payload_entry_sector(5000)).unwrap();
+        assert_eq!(sector[5001 % 512 * 8], 2);
+
+        // Growing the disk adds entries that are not present
+        bat.resize((payload_blocks_count + 10) << 20);
+        let last = bat.payload_entry(payload_blocks_count + 9).unwrap();
+        assert_eq!(last.unwrap().state(), PayloadBatEntryState::NotPresent);
+    }
+
+    #[test]
+    fn reserved_states_round_trip() {
+        for state in 0..8 {
+            let value = 0x300000 | state;
+            let entry = BatEntry::from_bits(value);
+            assert_eq!(entry.to_bits(), value);
+            assert_eq!(entry.file_offset(), 0x300000);
+        }
+        let entry = BatEntry::from_bits(4);
+        let PayloadBatEntryState::Reserved(state) = entry.state() else {
+            panic!("unexpected state {}", entry.state());
+        };
+        assert_eq!(state.value(), 4);
+        assert_eq!(
+            entry.sector_bitmap_state(),
+            SectorBitmapBatEntryState::Reserved(state)
+        );
+        let entry = BatEntry::from_bits(6);
+        assert_eq!(entry.state(), PayloadBatEntryState::FullyPresent);
+        assert_eq!(
+            entry.sector_bitmap_state(),
+            SectorBitmapBatEntryState::Present
+        );
+    }
+
+    #[test]
+    fn unaligned_file_offset() {
+        let error = BatEntry::try_new(PayloadBatEntryState::FullyPresent, 0x300000 | 512);
+        assert!(matches!(error, Err(Error::UnalignedFileOffset(0x300200))));
+        let entry = BatEntry::try_new(PayloadBatEntryState::FullyPresent, 0x300000).unwrap();
+        assert_eq!(entry.file_offset(), 0x300000);
+    }
+}
@@ -0,0 +1,688 @@
+//! Command line tool for inspecting, creating and converting VHDX files.
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use vhdx::{
+    bat::{Block, SectorBitmapBatEntryState},
+    check,
+    convert::{self, Progress},
+    metadata::ParentLocator,
+    qcow2::{self, Qcow2},
+    repair::RepairOptions,
+    vhd::{DiskType, Vhd},
+    vmdk::{Subformat, Vmdk},
+    Builder, Vhdx,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Inspect, create and convert VHDX virtual hard disks")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the headers, regions, metadata and allocation of a VHDX file
+    Info {
+        path: PathBuf,
+        /// Print the information as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the consistency of a VHDX file, exiting with a failure if there
+    /// are any errors
+    Check {
+        path: PathBuf,
+        /// Print the findings as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Repair common corruptions of a VHDX file, only printing the repairs
+    /// that would be made unless --write is given
+    Repair {
+        path: PathBuf,
+        /// Write the repairs to the file
+        #[arg(long)]
+        write: bool,
+    },
+    /// Create a new VHDX file
+    Create {
+        path: PathBuf,
+        /// The size of the virtual disk, such as 64G, which defaults to the
+        /// size of the parent
+        #[arg(long, value_parser = parse_size)]
+        size: Option<u64>,
+        #[command(flatten)]
+        layout: Layout,
+        /// Create a differencing disk with this parent
+        #[arg(long, conflicts_with = "fixed")]
+        parent: Option<PathBuf>,
+    },
+    /// Convert between VHDX and raw, VHD, QCOW2 or VMDK images
+    ///
+    /// One of the input or output must be a VHDX file. Formats are detected
+    /// from the file extensions unless given explicitly.
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        /// The format of the input
+        #[arg(long, short = 'f')]
+        from: Option<Format>,
+        /// The format of the output
+        #[arg(long, short = 'O')]
+        to: Option<Format>,
+        #[command(flatten)]
+        layout: Layout,
+        /// The layout of a VMDK output
+        #[arg(long, value_enum, default_value_t = VmdkSubformat::MonolithicSparse)]
+        subformat: VmdkSubformat,
+        /// Compress the clusters of a QCOW2 output
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Write the contents of the virtual disk to standard output
+    Cat { path: PathBuf },
+    /// Change the size of the virtual disk
+    Resize {
+        path: PathBuf,
+        /// The new size, such as 64G
+        #[arg(value_parser = parse_size)]
+        size: u64,
+    },
+    /// Reduce the size of the file by removing unused blocks
+    Compact { path: PathBuf },
+}
+
+/// Options for the layout of a new VHDX or VHD file.
+#[derive(Debug, clap::Args)]
+struct Layout {
+    /// Allocate the whole disk up front, for a fixed VHDX or VHD
+    #[arg(long)]
+    fixed: bool,
+    /// The size of each payload block of a VHDX, such as 32M
+    #[arg(long, value_parser = parse_size)]
+    block_size: Option<u64>,
+    /// The logical sector size of a VHDX, 512 or 4096
+    #[arg(long)]
+    logical_sector_size: Option<u32>,
+    /// The physical sector size of a VHDX, 512 or 4096
+    #[arg(long)]
+    physical_sector_size: Option<u32>,
+}
+
+impl Layout {
+    fn apply(&self, builder: &mut Builder) -> Result<(), Box<dyn Error>> {
+        builder.fixed(self.fixed);
+        if let Some(block_size) = self.block_size {
+            builder.block_size(u32::try_from(block_size).map_err(|_| "block size is too large")?);
+        }
+        if let Some(logical_sector_size) = self.logical_sector_size {
+            builder.logical_sector_size(logical_sector_size);
+        }
+        if let Some(physical_sector_size) = self.physical_sector_size {
+            builder.physical_sector_size(physical_sector_size);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Raw,
+    Vhd,
+    Vhdx,
+    Qcow2,
+    Vmdk,
+}
+
+impl Format {
+    fn detect(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        Ok(match extension.as_deref() {
+            Some("vhdx") => Format::Vhdx,
+            Some("vhd") => Format::Vhd,
+            Some("qcow2") => Format::Qcow2,
+            Some("vmdk") => Format::Vmdk,
+            Some("raw" | "img" | "bin") => Format::Raw,
+            _ => {
+                return Err(format!(
+                    "can't detect the format of {}, set it with --from or --to",
+                    path.display()
+                )
+                .into())
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VmdkSubformat {
+    MonolithicSparse,
+    MonolithicFlat,
+    StreamOptimized,
+}
+
+impl From<VmdkSubformat> for Subformat {
+    fn from(value: VmdkSubformat) -> Self {
+        match value {
+            VmdkSubformat::MonolithicSparse => Subformat::MonolithicSparse,
+            VmdkSubformat::MonolithicFlat => Subformat::MonolithicFlat,
+            VmdkSubformat::StreamOptimized => Subformat::StreamOptimized,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<ExitCode, Box<dyn Error>> {
+    match command {
+        Command::Info { path, json } => info(&path, json)?,
+        Command::Check { path, json } => return check(&path, json),
+        Command::Repair { path, write } => repair(&path, write)?,
+        Command::Create {
+            path,
+            size,
+            layout,
+            parent,
+        } => {
+            let mut builder = Builder::new(path);
+            layout.apply(&mut builder)?;
+            if let Some(size) = size {
+                builder.virtual_size(size);
+            }
+            if let Some(parent) = parent {
+                builder.parent(parent);
+            } else if size.is_none() {
+                return Err("--size is required unless creating a differencing disk".into());
+            }
+            builder.create()?;
+        }
+        Command::Convert {
+            input,
+            output,
+            from,
+            to,
+            layout,
+            subformat,
+            compress,
+        } => {
+            let from = from.map_or_else(|| Format::detect(&input), Ok)?;
+            let to = to.map_or_else(|| Format::detect(&output), Ok)?;
+            convert(&input, from, &output, to, &layout, subformat, compress)?;
+        }
+        Command::Cat { path } => {
+            let mut disk = Vhdx::options().read_only(true).open(path)?;
+            cat(&mut disk)?;
+        }
+        Command::Resize { path, size } => Vhdx::load(path)?.resize(size)?,
+        Command::Compact { path } => {
+            let reclaimed = Vhdx::load(path)?.compact()?;
+            println!("Reclaimed {}", format_size(reclaimed));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn info(path: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let mut disk = Vhdx::options().read_only(true).open(path)?;
+    let file_size = std::fs::metadata(path)?.len();
+    let parent_locator = disk.get_metadata::<ParentLocator>()?;
+    let data_size = data_size(&mut disk)?;
+    let block_count = disk.virtual_size().div_ceil(disk.block_size() as u64);
+    let block_states = block_states(&disk)?;
+
+    let info = json!({
+        "path": path,
+        "file_size": file_size,
+        "creator": disk.creator(),
+        "header": {
+            "format_version": disk.format_version(),
+            "sequence_number": disk.sequence_number(),
+            "file_write_guid": disk.file_write_guid().to_string(),
+            "data_write_guid": disk.data_write_guid().to_string(),
+            "log_guid": disk.log_guid().to_string(),
+        },
+        "regions": disk.region_entries().iter().map(|region| json!({
+            "guid": region.guid().to_string(),
+            "file_offset": region.file_offset(),
+            "length": region.length(),
+            "required": region.is_required(),
+        })).collect::<Vec<_>>(),
+        "metadata": {
+            "virtual_size": disk.virtual_size(),
+            "block_size": disk.block_size(),
+            "logical_sector_size": disk.logical_sector_size(),
+            "physical_sector_size": disk.physical_sector_size(),
+            "virtual_disk_id": disk.virtual_disk_id().to_string(),
+            "leave_block_allocated": disk.leave_block_allocated(),
+            "has_parent": disk.has_parent(),
+            "parent_locator": parent_locator.as_ref().map(|locator| {
+                locator
+                    .entries()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), json!(value)))
+                    .collect::<serde_json::Map<_, _>>()
+            }),
+            "items": disk.metadata_entries().iter().map(|entry| json!({
+                "item_id": entry.item_id().to_string(),
+                "length": entry.length(),
+                "user": entry.is_user(),
+                "virtual_disk": entry.is_virtual_disk(),
+                "required": entry.is_required(),
+            })).collect::<Vec<_>>(),
+        },
+        "bat": {
+            "payload_blocks": block_count,
+            "block_states": block_states
+                .iter()
+                .map(|(state, count)| (state.clone(), json!(count)))
+                .collect::<serde_json::Map<_, _>>(),
+            "data_size": data_size,
+        },
+    });
+
+    if json {
+        let mut out = serde_json::to_string_pretty(&info)?;
+        out.push('\n');
+        return write_stdout(out.as_bytes());
+    }
+
+    let mut out = String::new();
+    writeln!(out, "Path: {}", path.display())?;
+    writeln!(out, "File size: {}", format_size(file_size))?;
+    writeln!(out, "Creator: {}", disk.creator())?;
+    writeln!(out)?;
+    writeln!(out, "Header")?;
+    writeln!(out, "  Format version: {}", disk.format_version())?;
+    writeln!(out, "  Sequence number: {}", disk.sequence_number())?;
+    writeln!(out, "  File write GUID: {}", disk.file_write_guid())?;
+    writeln!(out, "  Data write GUID: {}", disk.data_write_guid())?;
+    writeln!(out, "  Log GUID: {}", disk.log_guid())?;
+    writeln!(out)?;
+    writeln!(out, "Regions")?;
+    for region in disk.region_entries() {
+        writeln!(
+            out,
+            "  {} at {:#x}, {}{}",
+            region.guid(),
+            region.file_offset(),
+            format_size(region.length() as u64),
+            if region.is_required() {
+                ", required"
+            } else {
+                ""
+            }
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(out, "Metadata")?;
+    writeln!(out, "  Virtual size: {}", format_size(disk.virtual_size()))?;
+    writeln!(
+        out,
+        "  Block size: {}",
+        format_size(disk.block_size() as u64)
+    )?;
+    writeln!(
+        out,
+        "  Sector size: {} bytes logical, {} bytes physical",
+        disk.logical_sector_size(),
+        disk.physical_sector_size()
+    )?;
+    writeln!(out, "  Virtual disk ID: {}", disk.virtual_disk_id())?;
+    writeln!(
+        out,
+        "  Leave blocks allocated: {}",
+        disk.leave_block_allocated()
+    )?;
+    writeln!(out, "  Has parent: {}", disk.has_parent())?;
+    if let Some(locator) = &parent_locator {
+        for (key, value) in locator.entries() {
+            writeln!(out, "    {key}: {value}")?;
+        }
+    }
+    writeln!(out, "  Items:")?;
+    for entry in disk.metadata_entries() {
+        writeln!(
+            out,
+            "    {}, {} bytes{}{}{}",
+            entry.item_id(),
+            entry.length(),
+            if entry.is_user() { ", user" } else { "" },
+            if entry.is_virtual_disk() {
+                ", virtual disk"
+            } else {
+                ""
+            },
+            if entry.is_required() {
+                ", required"
+            } else {
+                ""
+            }
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(out, "BAT")?;
+    writeln!(out, "  Payload blocks: {block_count}")?;
+    for (state, count) in &block_states {
+        writeln!(out, "    {state}: {count}")?;
+    }
+    writeln!(out, "  Data: {}", format_size(data_size))?;
+    write_stdout(out.as_bytes())
+}
+
+/// The number of payload blocks in each state, and of sector bitmap blocks
+/// that are present, in the order they first appear in the BAT.
+fn block_states(disk: &Vhdx) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let mut counts: Vec<(String, u64)> = Vec::new();
+    let mut add = |name: String| match counts.iter_mut().find(|(other, _)| *other == name) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((name, 1)),
+    };
+    for result in disk.bat_entries() {
+        let (block, entry) = result?;
+        match block {
+            Block::Payload(_) => add(entry.state().to_string()),
+            Block::SectorBitmap(_) => match entry.sector_bitmap_state() {
+                SectorBitmapBatEntryState::NotPresent => {}
+                state => add(format!("sector bitmap {state}")),
+            },
+        }
+    }
+    counts.sort_by_key(|(name, _)| name.starts_with("sector bitmap"));
+    Ok(counts)
+}
+
+/// The number of bytes of the virtual disk that contain data, in the disk or
+/// any of its parents.
+fn data_size(disk: &mut Vhdx) -> Result<u64, Box<dyn Error>> {
+    let disk_size = disk.virtual_size();
+    let mut reader = disk.reader();
+    let mut size = 0;
+    let mut offset = 0;
+    while offset < disk_size {
+        let start = match reader.seek_data(offset) {
+            Ok(start) => start,
+            Err(error) if error.kind() == io::ErrorKind::InvalidInput => break,
+            Err(error) => return Err(error.into()),
+        };
+        let end = reader.seek_hole(start)?;
+        size += end - start;
+        offset = end;
+    }
+    Ok(size)
+}
+
+fn check(path: &Path, json: bool) -> Result<ExitCode, Box<dyn Error>> {
+    let report = check::check_file(path)?;
+    // Opening the file also finds the parent of a differencing disk
+    let open_error = if report.is_ok() {
+        Vhdx::options()
+            .read_only(true)
+            .open(path)
+            .err()
+            .map(|error| error.to_string())
+    } else {
+        None
+    };
+    let ok = report.is_ok() && open_error.is_none();
+
+    let mut out = String::new();
+    if json {
+        let findings = report
+            .findings()
+            .iter()
+            .map(|finding| {
+                json!({
+                    "severity": finding.severity().to_string(),
+                    "location": finding.location().to_string(),
+                    "message": finding.message(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let result = json!({
+            "path": path,
+            "ok": ok,
+            "findings": findings,
+            "open_error": open_error,
+        });
+        out = serde_json::to_string_pretty(&result)?;
+        out.push('\n');
+    } else {
+        for finding in report.findings() {
+            writeln!(out, "{}: {finding}", path.display())?;
+        }
+        if let Some(error) = &open_error {
+            writeln!(out, "{}: error: failed to open: {error}", path.display())?;
+        }
+        if ok {
+            writeln!(out, "{}: ok", path.display())?;
+        }
+    }
+    write_stdout(out.as_bytes())?;
+
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn repair(path: &Path, write: bool) -> Result<(), Box<dyn Error>> {
+    let report = Vhdx::repair(path, RepairOptions::new().dry_run(!write))?;
+    let mut out = String::new();
+    for repair in report.repairs() {
+        writeln!(out, "{}: {repair}", path.display())?;
+    }
+    if report.repairs().is_empty() {
+        writeln!(out, "{}: nothing to repair", path.display())?;
+    } else if report.is_dry_run() {
+        writeln!(
+            out,
+            "{}: run with --write to make these repairs",
+            path.display()
+        )?;
+    }
+    write_stdout(out.as_bytes())?;
+    Ok(())
+}
+
+fn convert(
+    input: &Path,
+    from: Format,
+    output: &Path,
+    to: Format,
+    layout: &Layout,
+    subformat: VmdkSubformat,
+    compress: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut progress = progress_reporter();
+
+    if to == Format::Vhdx {
+        let mut builder = Builder::new(output);
+        layout.apply(&mut builder)?;
+        match from {
+            Format::Raw => {
+                let mut file = File::open(input)?;
+                convert::from_raw_with_progress(&mut file, &builder, &mut progress)?;
+            }
+            Format::Vhd => {
+                let mut vhd = Vhd::open(input)?;
+                convert::from_vhd_with_progress(&mut vhd, &builder, &mut progress)?;
+            }
+            Format::Vhdx => {
+                let mut disk = Vhdx::options().read_only(true).open(input)?;
+                if layout.logical_sector_size.is_none() {
+                    builder.logical_sector_size(disk.logical_sector_size());
+                }
+                if layout.physical_sector_size.is_none() {
+                    builder.physical_sector_size(disk.physical_sector_size());
+                }
+                builder.virtual_size(disk.virtual_size());
+                convert::from_raw_with_progress(&mut disk.reader(), &builder, &mut progress)?;
+            }
+            Format::Qcow2 => {
+                let mut qcow2 = Qcow2::open(input)?;
+                convert::from_qcow2_with_progress(&mut qcow2, &builder, &mut progress)?;
+            }
+            Format::Vmdk => {
+                let mut vmdk = Vmdk::open(input)?;
+                convert::from_vmdk_with_progress(&mut vmdk, &builder, &mut progress)?;
+            }
+        }
+        finish_progress();
+        return Ok(());
+    }
+
+    if from != Format::Vhdx {
+        return Err("one of the input or output must be a VHDX file".into());
+    }
+    let mut disk = Vhdx::options().read_only(true).open(input)?;
+    match to {
+        Format::Raw => {
+            let mut file = File::create_new(output)?;
+            convert::to_raw_with_progress(&mut disk, &mut file, &mut progress)?;
+        }
+        Format::Vhd => {
+            let disk_type = if layout.fixed {
+                DiskType::Fixed
+            } else {
+                DiskType::Dynamic
+            };
+            let mut file = File::create_new(output)?;
+            convert::to_vhd_with_progress(&mut disk, &mut file, disk_type, &mut progress)?;
+        }
+        Format::Qcow2 => {
+            let mut options = qcow2::WriteOptions::new();
+            options.compress(compress);
+            let mut file = File::create_new(output)?;
+            convert::to_qcow2_with_progress(&mut disk, &mut file, &options, &mut progress)?;
+        }
+        Format::Vmdk => {
+            convert::to_vmdk_with_progress(&mut disk, output, subformat.into(), &mut progress)?;
+        }
+        Format::Vhdx => unreachable!("handled above"),
+    }
+    finish_progress();
+    Ok(())
+}
+
+/// Report the progress of a conversion on standard error, when it is a
+/// terminal.
+fn progress_reporter() -> impl FnMut(Progress) {
+    let enabled = std::io::stderr().is_terminal();
+    let mut last_percent = None;
+    move |progress: Progress| {
+        if !enabled || progress.bytes_total == 0 {
+            return;
+        }
+        let percent = progress.bytes_processed * 100 / progress.bytes_total;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            eprint!("\r{percent:3}%");
+        }
+    }
+}
+
+fn finish_progress() {
+    if std::io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+fn cat(disk: &mut Vhdx) -> Result<(), Box<dyn Error>> {
+    let mut reader = disk.reader();
+    let mut stdout = std::io::stdout().lock();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let length = reader.read(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        if ignore_broken_pipe(stdout.write_all(&buffer[..length]))?.is_none() {
+            return Ok(());
+        }
+    }
+    ignore_broken_pipe(stdout.flush())?;
+    Ok(())
+}
+
+fn write_stdout(data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut stdout = std::io::stdout().lock();
+    if ignore_broken_pipe(stdout.write_all(data))?.is_some() {
+        ignore_broken_pipe(stdout.flush())?;
+    }
+    Ok(())
+}
+
+/// Treat the reader of the output, such as `head`, stopping early as success,
+/// returning none if it has.
+fn ignore_broken_pipe(result: std::io::Result<()>) -> std::io::Result<Option<()>> {
+    match result {
+        Ok(()) => Ok(Some(())),
+        Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Parse a size in bytes, with an optional binary suffix such as `K`, `M`,
+/// `G` or `T`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {value}"))?;
+    let shift = match suffix.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" | "KI" => 10,
+        "M" | "MI" => 20,
+        "G" | "GI" => 30,
+        "T" | "TI" => 40,
+        _ => return Err(format!("invalid size suffix: {suffix}")),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size is too large: {value}"))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KB", "MB", "GB", "TB"];
+    let mut unit = 0;
+    while unit + 1 < UNITS.len() && bytes >= 1 << (10 * (unit + 1)) {
+        unit += 1;
+    }
+    if unit == 0 || bytes.is_multiple_of(1 << (10 * unit)) {
+        format!("{} {}", bytes >> (10 * unit), UNITS[unit])
+    } else {
+        format!(
+            "{:.1} {}",
+            bytes as f64 / (1u64 << (10 * unit)) as f64,
+            UNITS[unit]
+        )
+    }
+}
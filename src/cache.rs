@@ -0,0 +1,162 @@
+use crate::{lru::Lru, KB};
+
+/// The size of the parts of the virtual disk that are cached. This is
+/// smaller than any block, so that reading a single sector doesn't read a
+/// whole block, and divides every block size, so that no chunk spans two
+/// blocks.
+pub(crate) const CHUNK_SIZE: u64 = 64 * KB as u64;
+
+/// How reads of the virtual disk have been served by the block cache, from
+/// [`crate::Vhdx::cache_stats`].
+///
+/// Reads of whole chunks of the disk bypass the cache, so that a sequential
+/// scan doesn't evict the rest of the cache, and aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheStats {
+    /// The number of reads served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of reads that had to read a chunk of the disk into the
+    /// cache.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+/// A cache of chunks of the virtual disk, as they are read through a
+/// [`crate::Vhdx`], including data from the parent of a differencing disk.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    chunks: Lru<u64, Box<[u8]>>,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    /// A cache that holds up to `budget` bytes of the virtual disk.
+    pub(crate) fn new(budget: u64) -> Self {
+        Self {
+            chunks: Lru::new(budget),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Copy from the cached chunk containing `offset`, stopping at the end of
+    /// the chunk. Returns none if the chunk isn't cached.
+    pub(crate) fn read(&mut self, offset: u64, buf: &mut [u8]) -> Option<usize> {
+        let Some(chunk) = self.chunks.get(&(offset / CHUNK_SIZE)) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        let chunk = chunk
+            .get((offset % CHUNK_SIZE) as usize..)
+            .unwrap_or_default();
+        let length = buf.len().min(chunk.len());
+        buf[..length].copy_from_slice(&chunk[..length]);
+        Some(length)
+    }
+
+    /// Add the chunk that starts at `offset`.
+    pub(crate) fn insert(&mut self, offset: u64, chunk: Box<[u8]>) {
+        let weight = chunk.len() as u64;
+        self.chunks.insert(offset / CHUNK_SIZE, chunk, weight);
+    }
+
+    /// Drop the chunks that overlap the range of the virtual disk from
+    /// `start` to `end`, after it has been modified.
+    pub(crate) fn invalidate(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let chunks = start / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE;
+        self.chunks
+            .retain(|chunk_index| !chunks.contains(chunk_index));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.chunks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::{
+        testing::{disk_with_data, pattern, TempPath},
+        Vhdx, MB,
+    };
+
+    fn read_sector(disk: &mut Vhdx, offset: u64) -> [u8; 512] {
+        let mut sector = [0; 512];
+        let mut reader = disk.reader();
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        reader.read_exact(&mut sector).unwrap();
+        sector
+    }
+
+    #[test]
+    fn invalidated_by_discard() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 8 * MB as u64, &data));
+
+        let mut disk = Vhdx::options().cache_size(MB as u64).open(&path).unwrap();
+        for _ in 0..3 {
+            assert_eq!(read_sector(&mut disk, 4096)[..], data[4096..][..512]);
+        }
+        assert_eq!(disk.cache_stats().misses(), 1);
+        assert_eq!(disk.cache_stats().hits(), 2);
+
+        disk.discard(0, MB as u64).unwrap();
+        assert_eq!(read_sector(&mut disk, 4096), [0; 512]);
+    }
+
+    #[test]
+    fn invalidated_by_write_zeroes() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 8 * MB as u64, &data));
+
+        let mut disk = Vhdx::options().cache_size(MB as u64).open(&path).unwrap();
+        assert_eq!(read_sector(&mut disk, 4096)[..], data[4096..][..512]);
+        assert_eq!(read_sector(&mut disk, 8192)[..], data[8192..][..512]);
+
+        // Only part of the block is zeroed, so the zeros are written to the
+        // file and the rest of the cached chunk is unchanged
+        disk.write_zeroes(4096, 4096).unwrap();
+        assert_eq!(read_sector(&mut disk, 4096), [0; 512]);
+        assert_eq!(read_sector(&mut disk, 8192)[..], data[8192..][..512]);
+    }
+
+    #[test]
+    fn invalidated_by_resize() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 2 * MB as u64, &data));
+
+        let mut disk = Vhdx::options().cache_size(MB as u64).open(&path).unwrap();
+        let offset = MB as u64 + 4096;
+        assert_eq!(
+            read_sector(&mut disk, offset)[..],
+            data[offset as usize..][..512]
+        );
+
+        // Shrinking zeroes the rest of the last block, which doesn't come back
+        // when the disk is grown again
+        disk.resize(MB as u64 + 512).unwrap();
+        disk.resize(2 * MB as u64).unwrap();
+        assert_eq!(read_sector(&mut disk, offset), [0; 512]);
+    }
+}
@@ -0,0 +1,1020 @@
+//! Checking the consistency of a VHDX file.
+//!
+//! The checker reads the structures of the file directly, rather than relying
+//! on what was parsed when the file was opened, so [`check_file`] also works
+//! on files that are too damaged to open with [`Vhdx::load`].
+
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    bat::{entries_for, BatEntry, Block, PayloadBatEntryState, SectorBitmapBatEntryState},
+    metadata::{FileParameters, LogicalSectorSize, MetadataItem, ParentLocator, VirtualDiskSize},
+    resize::MAX_VIRTUAL_DISK_SIZE,
+    sparse, Error, Guid, Header, RegionTable, RegionTableEntry, Vhdx, FILE_SIGNATURE, KB, MB,
+    METADATA_TABLE_SIGNATURE, REGION_GUID_BAT, REGION_GUID_METADATA,
+};
+
+/// The maximum number of entries in the region and metadata tables.
+const MAX_TABLE_ENTRIES: u32 = 2047;
+/// The bits of a BAT entry that are reserved between the state and offset.
+pub(crate) const BAT_RESERVED_MASK: u64 = 0xF_FFF8;
+
+/// How serious a finding is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Something that is unusual but allowed by the specification.
+    Info,
+    /// Something that does not affect the data of the disk now, but wastes
+    /// space or may cause problems for other implementations.
+    Warning,
+    /// A corruption or violation of the specification, which may lose data or
+    /// prevent the file from being opened.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// The part of the file that a finding is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// The file type identifier at the start of the file.
+    FileTypeIdentifier,
+    /// The first megabyte of the file, which holds the file type identifier,
+    /// headers and region tables.
+    HeaderSection,
+    /// One of the two headers, numbered 1 or 2.
+    Header(u8),
+    /// One of the two region tables, numbered 1 or 2.
+    RegionTable(u8),
+    /// The region with the given GUID.
+    Region(Guid),
+    /// The log region.
+    Log,
+    /// The table at the start of the metadata region.
+    MetadataTable,
+    /// The metadata item with the given GUID.
+    MetadataItem(Guid),
+    /// The payload block with the given index.
+    PayloadBlock(u64),
+    /// The sector bitmap block of the chunk with the given index.
+    SectorBitmapBlock(u64),
+    /// A range of the file that isn't part of any structure.
+    File { offset: u64, length: u64 },
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::FileTypeIdentifier => write!(f, "file type identifier"),
+            Location::HeaderSection => write!(f, "header section"),
+            Location::Header(number) => write!(f, "header {number}"),
+            Location::RegionTable(number) => write!(f, "region table {number}"),
+            Location::Region(guid) if *guid == REGION_GUID_BAT => write!(f, "BAT region"),
+            Location::Region(guid) if *guid == REGION_GUID_METADATA => {
+                write!(f, "metadata region")
+            }
+            Location::Region(guid) => write!(f, "region {guid}"),
+            Location::Log => write!(f, "log"),
+            Location::MetadataTable => write!(f, "metadata table"),
+            Location::MetadataItem(guid) => write!(f, "metadata item {guid}"),
+            Location::PayloadBlock(index) => write!(f, "payload block {index}"),
+            Location::SectorBitmapBlock(index) => write!(f, "sector bitmap block {index}"),
+            Location::File { offset, length } => {
+                write!(f, "file range {offset:#x}..{:#x}", offset + length)
+            }
+        }
+    }
+}
+
+impl Location {
+    fn is_block(&self) -> bool {
+        matches!(
+            self,
+            Location::PayloadBlock(_) | Location::SectorBitmapBlock(_)
+        )
+    }
+}
+
+/// A single problem found by [`Vhdx::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    severity: Severity,
+    location: Location,
+    message: String,
+}
+
+impl Finding {
+    /// How serious the problem is.
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Where in the file the problem is.
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// A description of the problem.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.location, self.message)
+    }
+}
+
+/// The result of checking a file, created by [`Vhdx::check`] or
+/// [`check_file`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    /// Everything that was found, in the order the file was checked.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// The severity of the most serious finding, or none if nothing was
+    /// found.
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.findings.iter().map(Finding::severity).max()
+    }
+
+    /// Whether there are no findings with [`Severity::Error`].
+    pub fn is_ok(&self) -> bool {
+        self.max_severity() < Some(Severity::Error)
+    }
+
+    /// Whether there are no findings at all.
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "{finding}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Vhdx {
+    /// Check the consistency of the file.
+    ///
+    /// This covers the headers, region tables, metadata and BAT, including
+    /// whether any of them overlap, and whether there is allocated space in
+    /// the file that nothing refers to. The file is read as it is on disk, so
+    /// a log that has not been replayed is reported rather than applied. The
+    /// parent of a differencing disk is not checked.
+    ///
+    /// An error is only returned if the file can't be read. Problems with the
+    /// file are returned as findings in the report.
+    pub fn check(&self) -> Result<CheckReport, Error> {
+        Checker::new(&self.file)?.run()
+    }
+}
+
+/// Check the consistency of the VHDX file at the given path, without opening
+/// it as a [`Vhdx`].
+///
+/// See [`Vhdx::check`] for details.
+pub fn check_file(path: impl AsRef<Path>) -> Result<CheckReport, Error> {
+    let file = File::open(path)?;
+    Checker::new(&file)?.run()
+}
+
+/// The parameters of the disk from the metadata region that are needed to
+/// check the BAT.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Layout {
+    pub(crate) block_size: u64,
+    pub(crate) logical_sector_size: u64,
+    pub(crate) virtual_size: u64,
+    pub(crate) has_parent: bool,
+}
+
+impl Layout {
+    pub(crate) fn chunk_ratio(&self) -> u64 {
+        (1 << 23) * self.logical_sector_size / self.block_size
+    }
+}
+
+/// The structures of a file that is damaged, as found by the checker.
+#[derive(Debug, Clone)]
+pub(crate) struct Structure {
+    /// The current header, if either header is valid
+    pub(crate) header: Option<Header>,
+    /// The regions of the first valid region table
+    pub(crate) regions: Option<Vec<RegionTableEntry>>,
+    /// The layout of the disk, if the metadata region is valid
+    pub(crate) layout: Option<Layout>,
+}
+
+/// Read the headers, region table and metadata of a file without reporting
+/// any problems with them.
+pub(crate) fn read_structure(file: &File) -> Result<Structure, Error> {
+    let mut checker = Checker::new(file)?;
+    let header = checker.check_headers()?;
+    let regions = checker.check_region_tables()?;
+    let metadata_region = regions
+        .iter()
+        .flatten()
+        .find(|region| region.guid == REGION_GUID_METADATA);
+    let layout = match metadata_region {
+        Some(region) => checker.check_metadata(&region.clone())?,
+        None => None,
+    };
+    Ok(Structure {
+        header,
+        regions,
+        layout,
+    })
+}
+
+/// Read part of a file, with anything beyond the end of the file read as
+/// zeros.
+pub(crate) fn read_at(mut file: &File, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < length {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(buffer)
+}
+
+/// Walk the first `entries_count` entries of the BAT region at `offset`, as
+/// they are stored in the file, yielding the index and value of each entry
+/// along with the block it describes. The region is read 1 MB at a time.
+pub(crate) fn raw_bat_entries(
+    file: &File,
+    offset: u64,
+    entries_count: u64,
+    chunk_ratio: u64,
+) -> impl Iterator<Item = Result<(u64, u64, Block), Error>> + '_ {
+    let entries_per_read = MB as u64 / 8;
+    (0..entries_count)
+        .step_by(entries_per_read as usize)
+        .flat_map(move |first| {
+            let count = entries_per_read.min(entries_count - first);
+            match read_at(file, offset + first * 8, count as usize * 8) {
+                Ok(buffer) => buffer
+                    .chunks_exact(8)
+                    .enumerate()
+                    .map(|(i, bytes)| {
+                        let index = first + i as u64;
+                        let value = u64::from_le_bytes(bytes.try_into().expect("infallible"));
+                        Ok((index, value, Block::at(index, chunk_ratio)))
+                    })
+                    .collect(),
+                Err(error) => vec![Err(error)],
+            }
+        })
+}
+
+struct Checker<'a> {
+    file: &'a File,
+    file_size: u64,
+    findings: Vec<Finding>,
+    /// The ranges of the file that are used by structures and allocated
+    /// blocks, which must not overlap.
+    used: Vec<(u64, u64, Location)>,
+    /// The ranges of the file kept by blocks that read as zero, which aren't
+    /// orphaned, but may be reused.
+    retained: Vec<(u64, u64)>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(file: &'a File) -> Result<Self, Error> {
+        let file_size = file.metadata()?.len();
+        Ok(Self {
+            file,
+            file_size,
+            findings: Vec::new(),
+            used: Vec::new(),
+            retained: Vec::new(),
+        })
+    }
+
+    fn report(&mut self, severity: Severity, location: Location, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity,
+            location,
+            message: message.into(),
+        });
+    }
+
+    fn read_at(&self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        read_at(self.file, offset, length)
+    }
+
+    /// Record that a range of the file is used, reporting if it extends past
+    /// the end of the file.
+    fn use_range(&mut self, offset: u64, length: u64, location: Location) {
+        if offset.saturating_add(length) > self.file_size {
+            self.report(
+                Severity::Error,
+                location,
+                format!(
+                    "extends past the end of the file, to {:#x} of {:#x} bytes",
+                    offset.saturating_add(length),
+                    self.file_size
+                ),
+            );
+        }
+        self.used
+            .push((offset, offset.saturating_add(length), location));
+    }
+
+    fn run(mut self) -> Result<CheckReport, Error> {
+        if self.file_size < MB as u64 {
+            self.report(
+                Severity::Error,
+                Location::HeaderSection,
+                "the file is smaller than the header section",
+            );
+        }
+        self.use_range(0, MB as u64, Location::HeaderSection);
+
+        let identifier = self.read_at(0, 8)?;
+        if identifier != FILE_SIGNATURE.as_bytes() {
+            self.report(
+                Severity::Error,
+                Location::FileTypeIdentifier,
+                "invalid signature",
+            );
+        }
+
+        if let Some(header) = self.check_headers()? {
+            self.check_log(&header);
+        }
+        if let Some(regions) = self.check_region_tables()? {
+            self.check_regions(&regions);
+            let metadata_region = regions.iter().find(|r| r.guid == REGION_GUID_METADATA);
+            let bat_region = regions.iter().find(|r| r.guid == REGION_GUID_BAT);
+            if let (Some(metadata_region), Some(bat_region)) = (metadata_region, bat_region) {
+                if let Some(layout) = self.check_metadata(metadata_region)? {
+                    self.check_bat(bat_region, layout)?;
+                }
+            }
+        }
+
+        self.check_overlaps();
+        self.check_orphaned()?;
+        Ok(CheckReport {
+            findings: self.findings,
+        })
+    }
+
+    /// Check both headers, returning the one that is current.
+    fn check_headers(&mut self) -> Result<Option<Header>, Error> {
+        let mut valid = Vec::new();
+        for number in 1..=2 {
+            let buffer = self.read_at(64 * KB as u64 * number as u64, 4 * KB)?;
+            match Header::read(&mut buffer.as_slice()) {
+                Ok(header) => valid.push(header),
+                Err(error) => {
+                    self.report(Severity::Error, Location::Header(number), error.to_string())
+                }
+            }
+        }
+
+        if valid.is_empty() {
+            self.report(
+                Severity::Error,
+                Location::HeaderSection,
+                "neither header is valid",
+            );
+        }
+        if let [header_1, header_2] = valid.as_slice() {
+            if header_1.sequence_number == header_2.sequence_number {
+                self.report(
+                    Severity::Warning,
+                    Location::HeaderSection,
+                    "both headers have the same sequence number",
+                );
+            }
+        }
+        Ok(valid
+            .into_iter()
+            .max_by_key(|header| header.sequence_number))
+    }
+
+    fn check_log(&mut self, header: &Header) {
+        if header.log_guid != Guid::ZERO {
+            self.report(
+                Severity::Warning,
+                Location::Log,
+                "the log has entries that have not been replayed",
+            );
+        }
+        if header.log_length == 0 {
+            if header.log_guid != Guid::ZERO {
+                self.report(
+                    Severity::Error,
+                    Location::Log,
+                    "the log is empty but active",
+                );
+            }
+            return;
+        }
+        if !header.log_offset.is_multiple_of(MB as u64)
+            || !header.log_length.is_multiple_of(MB as u32)
+        {
+            self.report(
+                Severity::Error,
+                Location::Log,
+                format!(
+                    "misaligned at offset {:#x} with length {:#x}",
+                    header.log_offset, header.log_length
+                ),
+            );
+        }
+        self.use_range(header.log_offset, header.log_length as u64, Location::Log);
+    }
+
+    /// Check both region tables, returning the entries of the one to use.
+    fn check_region_tables(&mut self) -> Result<Option<Vec<RegionTableEntry>>, Error> {
+        let mut tables = Vec::new();
+        for number in 1..=2 {
+            let offset = (128 + 64 * number as u64) * KB as u64;
+            let buffer = self.read_at(offset, 64 * KB)?;
+            match RegionTable::read(&mut buffer.as_slice()) {
+                Ok(table) => tables.push(table.entries),
+                Err(error) => self.report(
+                    Severity::Error,
+                    Location::RegionTable(number),
+                    error.to_string(),
+                ),
+            }
+        }
+
+        if let [table_1, table_2] = tables.as_slice() {
+            if table_1 != table_2 {
+                self.report(
+                    Severity::Warning,
+                    Location::RegionTable(2),
+                    "differs from region table 1",
+                );
+            }
+        }
+        if tables.is_empty() {
+            self.report(
+                Severity::Error,
+                Location::HeaderSection,
+                "neither region table is valid",
+            );
+        }
+        Ok(tables.into_iter().next())
+    }
+
+    fn check_regions(&mut self, regions: &[RegionTableEntry]) {
+        for (i, region) in regions.iter().enumerate() {
+            let location = Location::Region(region.guid);
+            if regions[..i].iter().any(|other| other.guid == region.guid) {
+                self.report(Severity::Error, location, "appears more than once");
+                continue;
+            }
+            if !region.file_offset.is_multiple_of(MB as u64)
+                || !region.length.is_multiple_of(MB as u32)
+                || region.length == 0
+            {
+                self.report(
+                    Severity::Error,
+                    location,
+                    format!(
+                        "misaligned at offset {:#x} with length {:#x}",
+                        region.file_offset, region.length
+                    ),
+                );
+            }
+            self.use_range(region.file_offset, region.length as u64, location);
+        }
+
+        for (guid, name) in [(REGION_GUID_BAT, "BAT"), (REGION_GUID_METADATA, "metadata")] {
+            if !regions.iter().any(|region| region.guid == guid) {
+                self.report(
+                    Severity::Error,
+                    Location::RegionTable(1),
+                    format!("missing the {name} region"),
+                );
+            }
+        }
+    }
+
+    /// Check the metadata table and items, returning the layout of the disk
+    /// if the system items are valid.
+    fn check_metadata(&mut self, region: &RegionTableEntry) -> Result<Option<Layout>, Error> {
+        let table = self.read_at(region.file_offset, 64 * KB)?;
+        if &table[0..8] != METADATA_TABLE_SIGNATURE.as_bytes() {
+            self.report(
+                Severity::Error,
+                Location::MetadataTable,
+                "invalid signature",
+            );
+            return Ok(None);
+        }
+        let entry_count = u16::from_le_bytes([table[10], table[11]]) as u32;
+        if entry_count > MAX_TABLE_ENTRIES {
+            self.report(
+                Severity::Error,
+                Location::MetadataTable,
+                format!("too many entries ({entry_count})"),
+            );
+            return Ok(None);
+        }
+
+        // The item ID, offset and length of each item with data
+        let mut items: Vec<(Guid, u64, u64)> = Vec::new();
+        let mut ids = Vec::new();
+        for entry in table[32..].chunks_exact(32).take(entry_count as usize) {
+            let item_id = Guid::from_bytes(entry[0..16].try_into().expect("infallible"));
+            let offset = u32::from_le_bytes(entry[16..20].try_into().expect("infallible")) as u64;
+            let length = u32::from_le_bytes(entry[20..24].try_into().expect("infallible")) as u64;
+            let location = Location::MetadataItem(item_id);
+
+            if ids.contains(&item_id) {
+                self.report(Severity::Error, location, "appears more than once");
+                continue;
+            }
+            ids.push(item_id);
+
+            if length == 0 {
+                if offset != 0 {
+                    self.report(
+                        Severity::Warning,
+                        location,
+                        "empty item with a non-zero offset",
+                    );
+                }
+                continue;
+            }
+            if length > MB as u64 {
+                self.report(
+                    Severity::Error,
+                    location,
+                    format!("length of {length} bytes is larger than 1 MB"),
+                );
+            }
+            if offset < 64 * KB as u64 {
+                self.report(Severity::Error, location, "overlaps the metadata table");
+            } else if offset + length > region.length as u64 {
+                self.report(
+                    Severity::Error,
+                    location,
+                    "extends past the end of the metadata region",
+                );
+            }
+            items.push((item_id, offset, length));
+        }
+
+        items.sort_by_key(|&(_, offset, _)| offset);
+        for pair in items.windows(2) {
+            let (first, first_offset, first_length) = pair[0];
+            let (second, second_offset, _) = pair[1];
+            if second_offset < first_offset + first_length {
+                self.report(
+                    Severity::Error,
+                    Location::MetadataItem(second),
+                    format!("overlaps metadata item {first}"),
+                );
+            }
+        }
+
+        let read_item =
+            |checker: &mut Self, item_id: Guid, name: &str| {
+                let item = items.iter().find(|&&(id, _, _)| id == item_id).filter(
+                    |&&(_, offset, length)| {
+                        offset >= 64 * KB as u64
+                            && length <= MB as u64
+                            && offset + length <= region.length as u64
+                    },
+                );
+                match item {
+                    Some(&(_, offset, length)) => checker
+                        .read_at(region.file_offset + offset, length as usize)
+                        .map(Some),
+                    None => {
+                        checker.report(
+                            Severity::Error,
+                            Location::MetadataTable,
+                            format!("missing the {name} item"),
+                        );
+                        Ok(None)
+                    }
+                }
+            };
+        let file_parameters = read_item(&mut *self, FileParameters::GUID, "file parameters")?;
+        let virtual_size = read_item(&mut *self, VirtualDiskSize::GUID, "virtual disk size")?;
+        let logical_sector_size =
+            read_item(&mut *self, LogicalSectorSize::GUID, "logical sector size")?;
+        let (Some(file_parameters), Some(virtual_size), Some(logical_sector_size)) =
+            (file_parameters, virtual_size, logical_sector_size)
+        else {
+            return Ok(None);
+        };
+
+        let mut valid = true;
+        let mut invalid = |checker: &mut Self, item_id: Guid, message: String| {
+            checker.report(Severity::Error, Location::MetadataItem(item_id), message);
+            valid = false;
+        };
+        let block_size = file_parameters.get(0..4).map_or(0, |bytes| {
+            u32::from_le_bytes(bytes.try_into().expect("infallible"))
+        }) as u64;
+        let has_parent = file_parameters.get(4).is_some_and(|flags| flags & 2 != 0);
+        if !block_size.is_power_of_two() || !(MB as u64..=256 * MB as u64).contains(&block_size) {
+            invalid(
+                &mut *self,
+                FileParameters::GUID,
+                format!("invalid block size of {block_size} bytes"),
+            );
+        }
+        let logical_sector_size = logical_sector_size.get(0..4).map_or(0, |bytes| {
+            u32::from_le_bytes(bytes.try_into().expect("infallible"))
+        }) as u64;
+        if ![512, 4096].contains(&logical_sector_size) {
+            invalid(
+                &mut *self,
+                LogicalSectorSize::GUID,
+                format!("invalid logical sector size of {logical_sector_size} bytes"),
+            );
+        }
+        let virtual_size = virtual_size.get(0..8).map_or(0, |bytes| {
+            u64::from_le_bytes(bytes.try_into().expect("infallible"))
+        });
+        if virtual_size == 0
+            || virtual_size > MAX_VIRTUAL_DISK_SIZE
+            || !virtual_size.is_multiple_of(logical_sector_size.max(1))
+        {
+            invalid(
+                &mut *self,
+                VirtualDiskSize::GUID,
+                format!("invalid virtual disk size of {virtual_size} bytes"),
+            );
+        }
+        if has_parent && !items.iter().any(|&(id, _, _)| id == ParentLocator::GUID) {
+            self.report(
+                Severity::Error,
+                Location::MetadataTable,
+                "differencing disk is missing the parent locator item",
+            );
+        }
+
+        Ok(valid.then_some(Layout {
+            block_size,
+            logical_sector_size,
+            virtual_size,
+            has_parent,
+        }))
+    }
+
+    fn check_bat(&mut self, region: &RegionTableEntry, layout: Layout) -> Result<(), Error> {
+        let chunk_ratio = layout.chunk_ratio();
+        let payload_blocks_count = layout.virtual_size.div_ceil(layout.block_size);
+        let total_entries = entries_for(payload_blocks_count, chunk_ratio, layout.has_parent);
+
+        let available_entries = region.length as u64 / 8;
+        if total_entries > available_entries {
+            self.report(
+                Severity::Error,
+                Location::Region(REGION_GUID_BAT),
+                format!(
+                    "has space for {available_entries} entries, but {total_entries} are needed"
+                ),
+            );
+        }
+        let entries_to_check = total_entries.min(available_entries);
+
+        let mut partially_present_chunks = Vec::new();
+        let mut present_bitmaps = Vec::new();
+        let file = self.file;
+        for result in raw_bat_entries(file, region.file_offset, entries_to_check, chunk_ratio) {
+            let (index, value, block) = result?;
+            let entry = BatEntry::from_bits(value);
+            let offset = entry.file_offset();
+
+            let chunk_index = index / (chunk_ratio + 1);
+            let location = match block {
+                Block::SectorBitmap(chunk_index) => Location::SectorBitmapBlock(chunk_index),
+                Block::Payload(block) => Location::PayloadBlock(block),
+            };
+
+            if value & BAT_RESERVED_MASK != 0 {
+                self.report(
+                    Severity::Warning,
+                    location,
+                    format!("reserved bits are set in entry {value:#018x}"),
+                );
+            }
+
+            if let Block::SectorBitmap(_) = block {
+                match entry.sector_bitmap_state() {
+                    SectorBitmapBatEntryState::NotPresent => {}
+                    SectorBitmapBatEntryState::Present => {
+                        if !layout.has_parent {
+                            self.report(
+                                Severity::Warning,
+                                location,
+                                "present in a disk without a parent",
+                            );
+                        }
+                        present_bitmaps.push(chunk_index);
+                        self.use_range(offset, MB as u64, location);
+                    }
+                    SectorBitmapBatEntryState::Reserved(state) => {
+                        self.report(Severity::Error, location, format!("invalid state {state}"));
+                    }
+                }
+                continue;
+            }
+
+            match entry.state() {
+                PayloadBatEntryState::NotPresent | PayloadBatEntryState::Undefined => {}
+                PayloadBatEntryState::Zero | PayloadBatEntryState::Unmapped => {
+                    if offset != 0 {
+                        self.retained.push((offset, offset + layout.block_size));
+                    }
+                }
+                PayloadBatEntryState::FullyPresent => {
+                    self.use_range(offset, layout.block_size, location);
+                }
+                PayloadBatEntryState::PartiallyPresent => {
+                    if layout.has_parent {
+                        partially_present_chunks.push(chunk_index);
+                    } else {
+                        self.report(
+                            Severity::Error,
+                            location,
+                            "partially present in a disk without a parent",
+                        );
+                    }
+                    self.use_range(offset, layout.block_size, location);
+                }
+                PayloadBatEntryState::Reserved(state) => {
+                    self.report(Severity::Error, location, format!("invalid state {state}"));
+                }
+            }
+        }
+
+        partially_present_chunks.dedup();
+        for chunk_index in partially_present_chunks {
+            if !present_bitmaps.contains(&chunk_index) {
+                self.report(
+                    Severity::Error,
+                    Location::SectorBitmapBlock(chunk_index),
+                    "not present, but has partially present payload blocks",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Report ranges of the file that are used by more than one structure or
+    /// block.
+    fn check_overlaps(&mut self) {
+        let mut used = std::mem::take(&mut self.used);
+        used.sort_by_key(|&(start, end, _)| (start, end));
+
+        // The range that extends furthest so far
+        let mut furthest: Option<(u64, Location)> = None;
+        for &(start, end, location) in &used {
+            if start == end {
+                continue;
+            }
+            if let Some((furthest_end, other)) = furthest {
+                if start < furthest_end {
+                    let (location, other) = if other.is_block() && !location.is_block() {
+                        (other, location)
+                    } else {
+                        (location, other)
+                    };
+                    let message = if location.is_block() && other.is_block() {
+                        format!("shares file space with {other}")
+                    } else {
+                        format!("overlaps the {other}")
+                    };
+                    self.report(Severity::Error, location, message);
+                }
+            }
+            if furthest.is_none_or(|(furthest_end, _)| end > furthest_end) {
+                furthest = Some((end, location));
+            }
+        }
+        self.used = used;
+    }
+
+    /// Report ranges of the file that contain data, but are not used by any
+    /// structure or block.
+    fn check_orphaned(&mut self) -> Result<(), Error> {
+        let mut covered = self
+            .used
+            .iter()
+            .map(|&(start, end, _)| (start, end))
+            .chain(self.retained.iter().copied())
+            .collect::<Vec<_>>();
+        covered.sort();
+
+        let mut gaps = Vec::new();
+        let mut position = 0;
+        for (start, end) in covered {
+            if start > position {
+                gaps.push((position, start.min(self.file_size)));
+            }
+            position = position.max(end);
+            if position >= self.file_size {
+                break;
+            }
+        }
+        if position < self.file_size {
+            gaps.push((position, self.file_size));
+        }
+
+        for (start, end) in gaps {
+            if start >= end {
+                continue;
+            }
+            // Holes in sparse files don't take up any space
+            if sparse::next_data(self.file, start)?.is_none_or(|data| data >= end) {
+                continue;
+            }
+            self.report(
+                Severity::Warning,
+                Location::File {
+                    offset: start,
+                    length: end - start,
+                },
+                "allocated space that is not used by any structure or block",
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::testing::{disk_with_data, TempPath};
+
+    #[test]
+    fn shared_block_offset() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        assert!(disk.check().unwrap().is_clean());
+
+        // Point the second block at the first
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset;
+        let first = disk.bat.payload_entry(0).unwrap().unwrap().to_bits();
+        drop(disk);
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(bat_offset + 8)).unwrap();
+        file.write_all(&first.to_le_bytes()).unwrap();
+        drop(file);
+
+        let report = check_file(&path).unwrap();
+        assert!(!report.is_ok());
+        let shared = report
+            .findings()
+            .iter()
+            .find(|finding| finding.location() == Location::PayloadBlock(1))
+            .unwrap();
+        assert_eq!(shared.severity(), Severity::Error);
+        assert_eq!(shared.message(), "shares file space with payload block 0");
+        // The space of the second block is no longer referenced
+        assert!(report
+            .findings()
+            .iter()
+            .any(|finding| matches!(finding.location(), Location::File { .. })));
+    }
+
+    /// Point the BAT entry of the second block at `offset`, returning the
+    /// messages of the errors that the checker finds for the block.
+    fn move_second_block(offset: u64) -> Vec<String> {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset;
+        let second = disk.bat.payload_entry(1).unwrap().unwrap();
+        drop(disk);
+        let moved = BatEntry::from_bits(second.to_bits() & !second.file_offset() | offset);
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(bat_offset + 8)).unwrap();
+        file.write_all(&moved.to_bits().to_le_bytes()).unwrap();
+        drop(file);
+
+        check_file(&path)
+            .unwrap()
+            .findings()
+            .iter()
+            .filter(|finding| finding.location() == Location::PayloadBlock(1))
+            .inspect(|finding| assert_eq!(finding.severity(), Severity::Error))
+            .map(|finding| finding.message().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn block_past_end_of_file() {
+        let messages = move_second_block(1024 * MB as u64);
+        assert!(messages[0].starts_with("extends past the end of the file"));
+    }
+
+    #[test]
+    fn block_in_header_section() {
+        let messages = move_second_block(0);
+        // An offset of zero isn't valid for an allocated block either
+        assert!(messages
+            .iter()
+            .any(|message| message == "overlaps the header section"));
+    }
+
+    #[test]
+    fn block_in_metadata_region() {
+        let path = TempPath::new("vhdx");
+        let metadata_offset = disk_with_data(&path, 8 * MB as u64, &[])
+            .region(REGION_GUID_METADATA)
+            .unwrap()
+            .file_offset;
+        let messages = move_second_block(metadata_offset);
+        let metadata = Location::Region(REGION_GUID_METADATA);
+        assert_eq!(messages, [format!("overlaps the {metadata}")]);
+    }
+
+    #[test]
+    fn overlapping_regions() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        let mut region_table = disk.header_section.region_table().clone();
+        let metadata_offset = disk.region(REGION_GUID_METADATA).unwrap().file_offset;
+        for entry in &mut region_table.entries {
+            if entry.guid == REGION_GUID_BAT {
+                entry.file_offset = metadata_offset;
+            }
+        }
+        drop(disk);
+        let mut file = File::options().write(true).open(&path).unwrap();
+        for offset in [192 * KB as u64, 256 * KB as u64] {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&region_table.to_bytes()).unwrap();
+        }
+        drop(file);
+
+        // Either region may be reported as overlapping the other
+        let report = check_file(&path).unwrap();
+        let bat = Location::Region(REGION_GUID_BAT);
+        let metadata = Location::Region(REGION_GUID_METADATA);
+        assert!(report.findings().iter().any(|finding| {
+            (finding.location(), finding.message()) == (metadata, &format!("overlaps the {bat}"))
+                || (finding.location(), finding.message())
+                    == (bat, &format!("overlaps the {metadata}"))
+        }));
+    }
+
+    #[test]
+    fn dirty_log() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        assert!(disk.check().unwrap().is_clean());
+        // Start a log without marking it as empty, as after a crash
+        disk.begin_modification(false).unwrap();
+        disk.log_writer = None;
+        drop(disk);
+
+        let report = check_file(&path).unwrap();
+        let log = report
+            .findings()
+            .iter()
+            .find(|finding| finding.location() == Location::Log)
+            .unwrap();
+        assert_eq!(log.severity(), Severity::Warning);
+        assert_eq!(
+            log.message(),
+            "the log has entries that have not been replayed"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{
+    bat::{BatEntry, PayloadBatEntryState},
+    log::next_multiple_of,
+    sparse, Error, Vhdx, MB,
+};
+
+impl Vhdx {
+    /// Reduce the size of the file by removing the space used by blocks that
+    /// do not contain any data.
+    ///
+    /// Allocated blocks that only contain zeros are dropped, and any space
+    /// still reserved for zero or unmapped blocks is released. The remaining
+    /// payload blocks are then moved towards the start of the file to fill the
+    /// gaps, and the file is truncated.
+    ///
+    /// Returns the number of bytes that the file shrunk by.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        self.check_writable()?;
+        if self.metadata.file_parameters.has_parent() {
+            return Err(Error::Unsupported("compacting differencing disks"));
+        }
+        if self.metadata.file_parameters.leave_block_allocated() {
+            return Err(Error::Unsupported(
+                "compacting disks with blocks left allocated",
+            ));
+        }
+
+        let original_size = self.file.seek(SeekFrom::End(0))?;
+
+        self.drop_empty_blocks()?;
+        self.relocate_blocks()?;
+
+        // Each log entry records the size of the file when it was written, so
+        // the log has to be emptied before the file can be truncated
+        self.clear_log()?;
+        let new_size = self
+            .used_extents()?
+            .iter()
+            .map(|&(_, end)| end)
+            .max()
+            .unwrap_or(0);
+        if new_size >= original_size {
+            return Ok(0);
+        }
+        self.file.set_len(new_size)?;
+        self.file.sync_all()?;
+
+        Ok(original_size - new_size)
+    }
+
+    /// Remove blocks that are allocated but only contain zeros, and release
+    /// any space that unallocated blocks still have reserved.
+    fn drop_empty_blocks(&mut self) -> Result<(), Error> {
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+
+        let mut bat_updates = Vec::new();
+        for block_index in 0..self.bat.payload_blocks_count() {
+            let entry = self
+                .bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+            match entry.state() {
+                PayloadBatEntryState::FullyPresent
+                    if self.is_file_range_zero(entry.file_offset(), block_size)? =>
+                {
+                    let entry = BatEntry::new(PayloadBatEntryState::NotPresent);
+                    bat_updates.push((block_index, entry));
+                }
+                state if !state.is_allocated() && entry.file_offset() != 0 => {
+                    bat_updates.push((block_index, BatEntry::new(state)));
+                }
+                _ => {}
+            }
+        }
+
+        if bat_updates.is_empty() {
+            return Ok(());
+        }
+
+        // Dropping blocks does not change what is read from the virtual disk
+        self.begin_modification(false)?;
+        let writes = self.bat_writes(bat_updates)?;
+        self.write_logged(&writes)
+    }
+
+    /// Move payload blocks into the lowest free space in the file.
+    ///
+    /// Each block is copied to its new location before the BAT is updated
+    /// through the log, so the block is never unreachable.
+    fn relocate_blocks(&mut self) -> Result<(), Error> {
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+
+        let mut used = self.used_extents()?;
+        let mut blocks = Vec::new();
+        for block_index in 0..self.bat.payload_blocks_count() {
+            let entry = self
+                .bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+            if entry.state().is_allocated() {
+                blocks.push((block_index, entry));
+            }
+        }
+        blocks.sort_by_key(|(_, entry)| entry.file_offset());
+
+        for (block_index, entry) in blocks {
+            let old_offset = entry.file_offset();
+            let Some(new_offset) = find_free_space(&used, block_size, MB as u64, old_offset) else {
+                continue;
+            };
+
+            self.copy_file_range(old_offset, new_offset, block_size)?;
+            self.file.sync_data()?;
+
+            self.begin_modification(false)?;
+            let moved = BatEntry::try_new(entry.state(), new_offset)?;
+            let writes = self.bat_writes([(block_index, moved)])?;
+            self.write_logged(&writes)?;
+            sparse::punch_hole(&self.file, old_offset, block_size)?;
+
+            used.retain(|&(start, _)| start != old_offset);
+            used.push((new_offset, new_offset + block_size));
+            used.sort_unstable();
+        }
+
+        Ok(())
+    }
+
+    /// The sorted ranges of the file that are in use by the header section,
+    /// regions and payload blocks.
+    fn used_extents(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let header = self.current_header();
+
+        let mut used = vec![
+            (0, MB as u64),
+            (
+                header.log_offset,
+                header.log_offset + header.log_length as u64,
+            ),
+        ];
+        used.extend(
+            self.header_section
+                .region_table()
+                .entries
+                .iter()
+                .map(|entry| (entry.file_offset, entry.file_offset + entry.length as u64)),
+        );
+        for block_index in 0..self.bat.payload_blocks_count() {
+            let entry = self
+                .bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+            if entry.file_offset() != 0 {
+                used.push((entry.file_offset(), entry.file_offset() + block_size));
+            }
+        }
+        used.sort_unstable();
+        Ok(used)
+    }
+
+    fn is_file_range_zero(&mut self, offset: u64, length: u64) -> Result<bool, Error> {
+        let mut buffer = vec![0; MB.min(length as usize)];
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let mut remaining = length;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(MB as u64) as usize];
+            self.file.read_exact(chunk)?;
+            if chunk.iter().any(|&byte| byte != 0) {
+                return Ok(false);
+            }
+            remaining -= chunk.len() as u64;
+        }
+
+        Ok(true)
+    }
+
+    fn copy_file_range(&mut self, from: u64, to: u64, length: u64) -> Result<(), Error> {
+        let mut buffer = vec![0; MB.min(length as usize)];
+
+        let mut copied = 0;
+        while copied < length {
+            let chunk = &mut buffer[..(length - copied).min(MB as u64) as usize];
+            self.file.seek(SeekFrom::Start(from + copied))?;
+            self.file.read_exact(chunk)?;
+            self.file.seek(SeekFrom::Start(to + copied))?;
+            self.file.write_all(chunk)?;
+            copied += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the lowest range of `length` bytes, starting at a multiple of
+/// `alignment`, that does not overlap any of the sorted `used` ranges, and
+/// ends before `limit`.
+///
+/// This is shared by moving payload blocks within the file and placing
+/// metadata items within the metadata region.
+pub(crate) fn find_free_space(
+    used: &[(u64, u64)],
+    length: u64,
+    alignment: u64,
+    limit: u64,
+) -> Option<u64> {
+    let mut candidate = 0;
+    for &(start, end) in used {
+        if candidate + length <= start {
+            break;
+        }
+        candidate = candidate.max(next_multiple_of(end, alignment));
+    }
+    (candidate + length <= limit).then_some(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disk_with_data, pattern, TempPath};
+
+    #[test]
+    fn free_space_search() {
+        let mb = MB as u64;
+        let used = [(0, mb), (mb, 4 * mb), (6 * mb, 8 * mb), (10 * mb, 12 * mb)];
+
+        assert_eq!(find_free_space(&used, 2 * mb, mb, 10 * mb), Some(4 * mb));
+        assert_eq!(find_free_space(&used, 2 * mb, mb, 5 * mb), None);
+        assert_eq!(find_free_space(&used, 3 * mb, mb, 10 * mb), None);
+        assert_eq!(find_free_space(&used, 3 * mb, mb, 20 * mb), Some(12 * mb));
+
+        // Unaligned ranges fit directly after the used ranges
+        let used = [(0, 100), (150, 200)];
+        assert_eq!(find_free_space(&used, 50, 1, 300), Some(100));
+        assert_eq!(find_free_space(&used, 60, 1, 300), Some(200));
+        assert_eq!(find_free_space(&used, 60, 1, 250), None);
+    }
+
+    #[test]
+    fn compact_reclaims_empty_blocks() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(4 * MB);
+        let mut disk = disk_with_data(&path, 4 * MB as u64, &data);
+        let original_size = disk.file.metadata().unwrap().len();
+
+        // The second block is discarded, and the third is zeroed in two
+        // parts so that it stays allocated
+        let mb = MB as u64;
+        disk.discard(mb, mb).unwrap();
+        disk.write_zeroes(2 * mb, mb / 2).unwrap();
+        disk.write_zeroes(2 * mb + mb / 2, mb / 2).unwrap();
+        data[MB..3 * MB].fill(0);
+        assert_eq!(
+            disk.block_state(2).unwrap(),
+            PayloadBatEntryState::FullyPresent
+        );
+
+        let reclaimed = disk.compact().unwrap();
+        assert_eq!(reclaimed, 2 * mb);
+        let new_size = disk.file.metadata().unwrap().len();
+        assert_eq!(new_size, original_size - reclaimed);
+        assert_eq!(
+            disk.block_state(2).unwrap(),
+            PayloadBatEntryState::NotPresent
+        );
+        drop(disk);
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        let mut contents = Vec::new();
+        disk.reader().read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+        assert_eq!(disk.compact().unwrap(), 0);
+    }
+}
@@ -0,0 +1,855 @@
+//! Conversion between VHDX files and other disk image formats.
+
+use std::{
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    bat::{BatEntry, PayloadBatEntryState},
+    log::next_multiple_of,
+    qcow2::{self, Qcow2},
+    sparse,
+    vhd::{self, DiskType, DynamicHeader, Footer, Vhd},
+    vmdk::{self, Vmdk},
+    Builder, Error, Reader, Vhdx, MB,
+};
+
+/// The progress of a conversion, passed to the progress callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of bytes of the virtual disk that have been processed.
+    pub bytes_processed: u64,
+    /// The size of the virtual disk in bytes.
+    pub bytes_total: u64,
+}
+
+/// Write the contents of the virtual disk to a raw image.
+///
+/// See [`to_raw_with_progress`] for details.
+pub fn to_raw<W: Write + Seek>(vhdx: &mut Vhdx, writer: &mut W) -> Result<(), Error> {
+    to_raw_with_progress(vhdx, writer, |_| {})
+}
+
+/// Write the contents of the virtual disk to a raw image, reporting progress
+/// after each part of the disk is processed.
+///
+/// Only the parts of the disk that contain data, in the disk or any of its
+/// parents, are written. Unallocated and zero blocks, as well as any zeros
+/// within allocated blocks, are skipped over by seeking, so the output should
+/// be a new or empty file, which is left sparse on filesystems that support
+/// it. The output is extended to the size of the virtual disk and flushed.
+pub fn to_raw_with_progress<W: Write + Seek>(
+    vhdx: &mut Vhdx,
+    writer: &mut W,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let disk_size = vhdx.virtual_size();
+    let mut ranges = Vec::new();
+    vhdx.data_ranges(0, disk_size, &mut ranges)?;
+
+    let mut buffer = vec![0; MB];
+    let mut written_end = 0;
+    let mut reader = vhdx.reader();
+    for (start, end) in ranges {
+        reader.seek(SeekFrom::Start(start))?;
+
+        let mut offset = start;
+        while offset < end {
+            let chunk = &mut buffer[..(end - offset).min(MB as u64) as usize];
+            reader.read_exact(chunk)?;
+
+            if chunk.iter().any(|&byte| byte != 0) {
+                writer.seek(SeekFrom::Start(offset))?;
+                writer.write_all(chunk)?;
+                written_end = offset + chunk.len() as u64;
+            }
+            offset += chunk.len() as u64;
+
+            progress(Progress {
+                bytes_processed: offset,
+                bytes_total: disk_size,
+            });
+        }
+    }
+
+    // A trailing hole doesn't extend the output on its own
+    if written_end < disk_size {
+        writer.seek(SeekFrom::Start(disk_size - 1))?;
+        writer.write_all(&[0])?;
+    }
+    writer.flush()?;
+
+    progress(Progress {
+        bytes_processed: disk_size,
+        bytes_total: disk_size,
+    });
+    Ok(())
+}
+
+/// Write the contents of the virtual disk to a VHD file.
+///
+/// See [`to_vhd_with_progress`] for details.
+pub fn to_vhd<W: Write + Seek>(
+    vhdx: &mut Vhdx,
+    writer: &mut W,
+    disk_type: DiskType,
+) -> Result<(), Error> {
+    to_vhd_with_progress(vhdx, writer, disk_type, |_| {})
+}
+
+/// Write the contents of the virtual disk to a VHD file, reporting progress
+/// after each part of the disk is processed.
+///
+/// Either a fixed or a dynamic VHD can be written, where the data of a
+/// differencing VHDX and its parents is combined. The VHD has the same size
+/// as the virtual disk, and the CHS geometry is calculated from it. Sizes
+/// above [`vhd::MAX_VIRTUAL_SIZE`] are rejected with [`Error::VhdTooLarge`].
+///
+/// As with [`to_raw`], the output should be a new or empty file. Zero data
+/// is skipped over in fixed disks, and dynamic disks only have blocks
+/// allocated for non-zero data.
+pub fn to_vhd_with_progress<W: Write + Seek>(
+    vhdx: &mut Vhdx,
+    writer: &mut W,
+    disk_type: DiskType,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let disk_size = vhdx.virtual_size();
+    if disk_size > vhd::MAX_VIRTUAL_SIZE {
+        return Err(Error::VhdTooLarge(disk_size));
+    }
+
+    match disk_type {
+        DiskType::Fixed => {
+            to_raw_with_progress(vhdx, writer, progress)?;
+            let footer = Footer::new(DiskType::Fixed, disk_size, u64::MAX);
+            writer.seek(SeekFrom::Start(disk_size))?;
+            writer.write_all(&footer.to_bytes())?;
+            writer.flush()?;
+            return Ok(());
+        }
+        DiskType::Dynamic => {}
+        DiskType::Differencing => return Err(Error::Unsupported("exporting differencing VHDs")),
+    }
+
+    let block_size = vhd::DEFAULT_BLOCK_SIZE as u64;
+    let blocks_count = disk_size.div_ceil(block_size);
+    let table_offset = (vhd::FOOTER_SIZE + vhd::DYNAMIC_HEADER_SIZE) as u64;
+    let table_length = next_multiple_of(blocks_count * 4, vhd::SECTOR_SIZE);
+    let dynamic_header = DynamicHeader::new(table_offset, blocks_count as u32, block_size as u32);
+
+    // Every sector of a block is marked as present, as the data of the whole
+    // block is written
+    let bitmap = vec![0xFF; dynamic_header.bitmap_size() as usize];
+    let mut buffer = vec![0; block_size as usize];
+    let mut bat = vec![vhd::UNUSED_ENTRY; blocks_count as usize];
+    let mut ranges = Vec::new();
+    let mut next_block_offset = table_offset + table_length;
+    for block_index in 0..blocks_count {
+        let start = block_index * block_size;
+        let end = (start + block_size).min(disk_size);
+        ranges.clear();
+        vhdx.data_ranges(start, end, &mut ranges)?;
+
+        if !ranges.is_empty() {
+            let mut reader = vhdx.reader();
+            reader.seek(SeekFrom::Start(start))?;
+            let length = (end - start) as usize;
+            reader.read_exact(&mut buffer[..length])?;
+            buffer[length..].fill(0);
+
+            if buffer.iter().any(|&byte| byte != 0) {
+                bat[block_index as usize] = (next_block_offset / vhd::SECTOR_SIZE) as u32;
+                writer.seek(SeekFrom::Start(next_block_offset))?;
+                writer.write_all(&bitmap)?;
+                writer.write_all(&buffer)?;
+                next_block_offset += bitmap.len() as u64 + block_size;
+            }
+        }
+
+        progress(Progress {
+            bytes_processed: end,
+            bytes_total: disk_size,
+        });
+    }
+
+    let footer = Footer::new(DiskType::Dynamic, disk_size, vhd::FOOTER_SIZE as u64).to_bytes();
+    writer.seek(SeekFrom::Start(next_block_offset))?;
+    writer.write_all(&footer)?;
+
+    let mut table = vec![0xFF; table_length as usize];
+    for (bytes, entry) in table.chunks_exact_mut(4).zip(bat) {
+        bytes.copy_from_slice(&entry.to_be_bytes());
+    }
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(&footer)?;
+    writer.write_all(&dynamic_header.to_bytes())?;
+    writer.write_all(&table)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Write the contents of the virtual disk to a QCOW2 image.
+///
+/// See [`to_qcow2_with_progress`] for details.
+pub fn to_qcow2<W: Write + Seek>(
+    vhdx: &mut Vhdx,
+    writer: &mut W,
+    options: &qcow2::WriteOptions,
+) -> Result<(), Error> {
+    to_qcow2_with_progress(vhdx, writer, options, |_| {})
+}
+
+/// Write the contents of the virtual disk to a QCOW2 image, reporting
+/// progress after each cluster is processed.
+///
+/// Only clusters with non-zero data are allocated. The output should be a new
+/// or empty file, and must support seeking past its end, as the header and L1
+/// table are only written once every cluster has been written after them.
+///
+/// By default, the data of a differencing disk and its parents is combined.
+/// With [`qcow2::WriteOptions::backing_file`], only the blocks stored in the
+/// differencing disk itself are written, and the rest is read from the
+/// backing file. Blocks that are zero in the differencing disk are marked as
+/// zero clusters, so that they hide the data in the backing file.
+pub fn to_qcow2_with_progress<W: Write + Seek>(
+    vhdx: &mut Vhdx,
+    writer: &mut W,
+    options: &qcow2::WriteOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let disk_size = vhdx.virtual_size();
+    let cluster_size = options.get_cluster_size() as u64;
+    let backing = options.has_backing_file();
+    let mut writer = qcow2::Writer::new(writer, disk_size, options)?;
+    if backing && !vhdx.has_parent() {
+        return Err(Error::Unsupported(
+            "a backing file for a disk without a parent",
+        ));
+    }
+    if backing && cluster_size > vhdx.block_size() as u64 {
+        return Err(Error::Unsupported(
+            "QCOW2 clusters larger than the block size with a backing file",
+        ));
+    }
+
+    let mut buffer = vec![0; cluster_size as usize];
+    let mut ranges = Vec::new();
+    for cluster_index in 0..disk_size.div_ceil(cluster_size) {
+        let start = cluster_index * cluster_size;
+        let end = (start + cluster_size).min(disk_size);
+
+        let has_data = if backing {
+            let (entry, _) = vhdx
+                .bat
+                .offset_to_entry(start)?
+                .expect("offset is within the virtual disk");
+            match entry.state() {
+                PayloadBatEntryState::NotPresent
+                | PayloadBatEntryState::Undefined
+                | PayloadBatEntryState::Reserved(_) => false,
+                PayloadBatEntryState::Zero | PayloadBatEntryState::Unmapped => {
+                    writer.zero_cluster(cluster_index)?;
+                    false
+                }
+                PayloadBatEntryState::FullyPresent | PayloadBatEntryState::PartiallyPresent => true,
+            }
+        } else {
+            ranges.clear();
+            vhdx.data_ranges(start, end, &mut ranges)?;
+            !ranges.is_empty()
+        };
+
+        if has_data {
+            let length = (end - start) as usize;
+            let mut reader = vhdx.reader();
+            reader.seek(SeekFrom::Start(start))?;
+            reader.read_exact(&mut buffer[..length])?;
+            buffer[length..].fill(0);
+
+            if buffer.iter().any(|&byte| byte != 0) {
+                writer.write_cluster(cluster_index, &buffer)?;
+            } else if backing {
+                writer.zero_cluster(cluster_index)?;
+            }
+        }
+
+        progress(Progress {
+            bytes_processed: end,
+            bytes_total: disk_size,
+        });
+    }
+
+    writer.finish()
+}
+
+/// Write the contents of the virtual disk to a VMDK disk at the given path.
+///
+/// See [`to_vmdk_with_progress`] for details.
+pub fn to_vmdk(
+    vhdx: &mut Vhdx,
+    path: impl AsRef<Path>,
+    subformat: vmdk::Subformat,
+) -> Result<(), Error> {
+    to_vmdk_with_progress(vhdx, path, subformat, |_| {})
+}
+
+/// Write the contents of the virtual disk to a VMDK disk at the given path,
+/// reporting progress after each grain is processed.
+///
+/// The data of a differencing disk and its parents is combined, and the
+/// files are created new, failing if they already exist. Sparse formats only
+/// allocate grains with non-zero data, and sizes above
+/// [`vmdk::MAX_SPARSE_SIZE`] are rejected with [`Error::VmdkTooLarge`]. For
+/// [`vmdk::Subformat::MonolithicFlat`], the path is the descriptor, and the
+/// data is written as a raw image next to it with `-flat` added to its name.
+pub fn to_vmdk_with_progress(
+    vhdx: &mut Vhdx,
+    path: impl AsRef<Path>,
+    subformat: vmdk::Subformat,
+    mut progress: impl FnMut(Progress),
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let disk_size = vhdx.virtual_size();
+    let file_name = |path: &Path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_owned)
+            .ok_or(Error::Unsupported("VMDK file names that are not UTF-8"))
+    };
+
+    if subformat == vmdk::Subformat::MonolithicFlat {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let flat_path = path.with_file_name(format!("{stem}-flat.vmdk"));
+        let descriptor = vmdk::descriptor(subformat, disk_size, &file_name(&flat_path)?);
+
+        let mut descriptor_file = File::create_new(path)?;
+        let mut flat_file = File::create_new(&flat_path)?;
+        to_raw_with_progress(vhdx, &mut flat_file, progress)?;
+        descriptor_file.write_all(descriptor.as_bytes())?;
+        descriptor_file.flush()?;
+        return Ok(());
+    }
+
+    if disk_size > vmdk::MAX_SPARSE_SIZE {
+        return Err(Error::VmdkTooLarge(disk_size));
+    }
+    let descriptor = vmdk::descriptor(subformat, disk_size, &file_name(path)?);
+    let mut file = File::create_new(path)?;
+    let mut writer = vmdk::SparseWriter::new(&mut file, disk_size, subformat, &descriptor)?;
+    let grain_size = writer.grain_bytes();
+
+    let mut buffer = vec![0; grain_size as usize];
+    let mut ranges = Vec::new();
+    for grain_index in 0..disk_size.div_ceil(grain_size) {
+        let start = grain_index * grain_size;
+        let end = (start + grain_size).min(disk_size);
+
+        ranges.clear();
+        vhdx.data_ranges(start, end, &mut ranges)?;
+        if !ranges.is_empty() {
+            let length = (end - start) as usize;
+            let mut reader = vhdx.reader();
+            reader.seek(SeekFrom::Start(start))?;
+            reader.read_exact(&mut buffer[..length])?;
+            buffer[length..].fill(0);
+
+            if buffer.iter().any(|&byte| byte != 0) {
+                writer.write_grain(grain_index, &buffer)?;
+            }
+        }
+
+        progress(Progress {
+            bytes_processed: end,
+            bytes_total: disk_size,
+        });
+    }
+
+    writer.finish()
+}
+
+/// Create a new VHDX file from the contents of a QCOW2 image.
+///
+/// See [`from_qcow2_with_progress`] for details.
+pub fn from_qcow2(qcow2: &mut Qcow2, builder: &Builder) -> Result<Vhdx, Error> {
+    from_qcow2_with_progress(qcow2, builder, |_| {})
+}
+
+/// Create a new VHDX file from the contents of a QCOW2 image, reporting
+/// progress after each payload block is processed.
+///
+/// By default, the data of the image and its backing files is combined, and
+/// only blocks with non-zero data are allocated. If the builder creates a
+/// differencing disk, only the blocks that have clusters in the image itself
+/// are stored, and the rest is read from the parent. The parent should have
+/// the same contents as the backing file, such as a VHDX converted from it.
+pub fn from_qcow2_with_progress(
+    qcow2: &mut Qcow2,
+    builder: &Builder,
+    progress: impl FnMut(Progress),
+) -> Result<Vhdx, Error> {
+    if builder.parent.is_some() {
+        import(&mut qcow2.overlay_reader(), builder, progress)
+    } else {
+        import(&mut qcow2.reader(), builder, progress)
+    }
+}
+
+/// A source of a raw disk image, which may be able to report where its data
+/// is to avoid reading holes.
+pub trait RawSource: Read + Seek {
+    /// Find the next range at or after `offset` that may contain data,
+    /// returning its start and end.
+    ///
+    /// Returns none if there is no data at or after `offset`. By default, the
+    /// whole source is treated as data.
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let length = self.seek(SeekFrom::End(0))?;
+        Ok((offset < length).then_some((offset, length)))
+    }
+}
+
+/// Files use `SEEK_DATA` and `SEEK_HOLE` where they are supported.
+impl RawSource for File {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let Some(start) = sparse::next_data(self, offset)? else {
+            return Ok(None);
+        };
+        let end = sparse::next_hole(self, start)?;
+        Ok((start < end).then_some((start, end)))
+    }
+}
+
+impl<T: AsRef<[u8]>> RawSource for Cursor<T> {}
+
+/// The unallocated parts of a virtual disk are skipped, so that one VHDX file
+/// can be imported into another.
+impl RawSource for Reader<'_> {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let start = match self.seek_data(offset) {
+            Ok(start) => start,
+            Err(error) if error.kind() == std::io::ErrorKind::InvalidInput => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let end = self.seek_hole(start)?;
+        Ok(Some((start, end)))
+    }
+}
+
+impl<S: RawSource + ?Sized> RawSource for &mut S {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        (**self).next_data_range(offset)
+    }
+}
+
+/// Create a new VHDX file from the contents of a raw image.
+///
+/// See [`from_raw_with_progress`] for details.
+pub fn from_raw<R: RawSource>(reader: &mut R, builder: &Builder) -> Result<Vhdx, Error> {
+    from_raw_with_progress(reader, builder, |_| {})
+}
+
+/// Create a new VHDX file from the contents of a raw image, reporting
+/// progress after each payload block is processed.
+///
+/// If the builder does not set a virtual size, the size of the image is used,
+/// rounded up to a whole logical sector. Only the blocks of the image that
+/// contain non-zero data are allocated, and the holes in sparse files are
+/// skipped without being read.
+pub fn from_raw_with_progress<R: RawSource>(
+    reader: &mut R,
+    builder: &Builder,
+    progress: impl FnMut(Progress),
+) -> Result<Vhdx, Error> {
+    if builder.parent.is_some() {
+        return Err(Error::Unsupported("importing into a differencing disk"));
+    }
+    import(reader, builder, progress)
+}
+
+/// Create a new VHDX file and copy the blocks that overlap the data ranges of
+/// the source into it.
+///
+/// For a differencing disk, the source has to read as the full contents of
+/// the virtual disk, and the rest of the disk is read from the parent.
+fn import<R: RawSource>(
+    reader: &mut R,
+    builder: &Builder,
+    mut progress: impl FnMut(Progress),
+) -> Result<Vhdx, Error> {
+    let source_size = reader.seek(SeekFrom::End(0))?;
+    let mut builder = builder.clone();
+    match builder.virtual_size {
+        Some(virtual_size) if source_size > virtual_size => return Err(Error::OutOfBounds),
+        Some(_) => {}
+        None => {
+            let sector_size = builder.logical_sector_size.unwrap_or(512) as u64;
+            builder.virtual_size(next_multiple_of(source_size, sector_size));
+        }
+    }
+    let mut vhdx = builder.create()?;
+    let disk_size = vhdx.virtual_size();
+
+    vhdx.begin_modification(true)?;
+    let block_size = vhdx.block_size() as u64;
+    let blocks_count = vhdx.bat.payload_blocks_count();
+    let mut buffer = vec![0; block_size as usize];
+    let mut bat_updates = Vec::new();
+
+    let mut block_index = 0;
+    while block_index < blocks_count {
+        let block_start = block_index * block_size;
+        let Some((data_start, _)) = reader.next_data_range(block_start)? else {
+            break;
+        };
+        if data_start >= block_start + block_size {
+            block_index = data_start / block_size;
+            continue;
+        }
+
+        reader.seek(SeekFrom::Start(block_start))?;
+        let length = read_up_to(reader, &mut buffer)?;
+        buffer[length..].fill(0);
+
+        if buffer.iter().all(|&byte| byte == 0) {
+            // Zeros in a differencing disk have to hide the data of the parent
+            if vhdx.has_parent() {
+                let entry = BatEntry::new(PayloadBatEntryState::Zero);
+                bat_updates.push((block_index, entry));
+            }
+        } else {
+            let entry = vhdx
+                .bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+            // The blocks of a fixed disk are already allocated. New blocks
+            // are not referenced by the BAT until all of the data is written
+            let file_offset = if entry.state().is_allocated() {
+                entry.file_offset()
+            } else {
+                let file_offset = vhdx.allocate(block_size)?;
+                let entry = BatEntry::try_new(PayloadBatEntryState::FullyPresent, file_offset)?;
+                bat_updates.push((block_index, entry));
+                file_offset
+            };
+            vhdx.file.seek(SeekFrom::Start(file_offset))?;
+            vhdx.file.write_all(&buffer)?;
+        }
+
+        block_index += 1;
+        progress(Progress {
+            bytes_processed: (block_index * block_size).min(disk_size),
+            bytes_total: disk_size,
+        });
+    }
+
+    vhdx.file.sync_data()?;
+    let writes = vhdx.bat_writes(bat_updates)?;
+    vhdx.write_logged(&writes)?;
+    vhdx.clear_log()?;
+
+    progress(Progress {
+        bytes_processed: disk_size,
+        bytes_total: disk_size,
+    });
+    Ok(vhdx)
+}
+
+/// Create a new VHDX file from the contents of a VHD file.
+///
+/// See [`from_vhd_with_progress`] for details.
+pub fn from_vhd(vhd: &mut Vhd, builder: &Builder) -> Result<Vhdx, Error> {
+    from_vhd_with_progress(vhd, builder, |_| {})
+}
+
+/// Create a new VHDX file from the contents of a VHD file, reporting progress
+/// after each payload block is processed.
+///
+/// Unless set on the builder, the virtual disk keeps the size of the VHD and
+/// its 512 byte sectors, so that it appears the same to the guest. VHDX does
+/// not store a CHS geometry, as the size of the disk is reported directly
+/// instead, which matches how the VHD size is presented on modern systems.
+///
+/// A differencing VHD is converted to a standalone VHDX that contains the
+/// data from all of its parents. As with [`from_raw`], only blocks with
+/// non-zero data are allocated.
+pub fn from_vhd_with_progress(
+    vhd: &mut Vhd,
+    builder: &Builder,
+    progress: impl FnMut(Progress),
+) -> Result<Vhdx, Error> {
+    let mut builder = builder.clone();
+    if builder.virtual_size.is_none() {
+        builder.virtual_size(vhd.virtual_size());
+    }
+    if builder.logical_sector_size.is_none() {
+        builder.logical_sector_size(512);
+    }
+    if builder.physical_sector_size.is_none() {
+        builder.physical_sector_size(512);
+    }
+    from_raw_with_progress(&mut vhd.reader(), &builder, progress)
+}
+
+/// Create a new VHDX file from the contents of a VMDK disk.
+///
+/// See [`from_vmdk_with_progress`] for details.
+pub fn from_vmdk(vmdk: &mut Vmdk, builder: &Builder) -> Result<Vhdx, Error> {
+    from_vmdk_with_progress(vmdk, builder, |_| {})
+}
+
+/// Create a new VHDX file from the contents of a VMDK disk, reporting
+/// progress after each payload block is processed.
+///
+/// Unallocated grains and zero extents are skipped without being read, and
+/// as with [`from_raw`], only blocks with non-zero data are allocated.
+pub fn from_vmdk_with_progress(
+    vmdk: &mut Vmdk,
+    builder: &Builder,
+    progress: impl FnMut(Progress),
+) -> Result<Vhdx, Error> {
+    from_raw_with_progress(&mut vmdk.reader(), builder, progress)
+}
+
+/// Read into the buffer until it is full or the end of the reader is
+/// reached, returning the number of bytes read.
+fn read_up_to(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut length = 0;
+    while length < buffer.len() {
+        match reader.read(&mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disk_with_data, pattern, TempPath};
+
+    #[test]
+    fn raw_output_is_sparse() {
+        let path = TempPath::new("vhdx");
+        let raw_path = TempPath::new("raw");
+        // The second block is all zeros, so it isn't allocated
+        let mut data = pattern(3 * MB);
+        data[MB..2 * MB].fill(0);
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &data);
+
+        let mut raw = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&raw_path)
+            .unwrap();
+        to_raw(&mut disk, &mut raw).unwrap();
+        assert_eq!(raw.metadata().unwrap().len(), 8 * MB as u64);
+        let mut contents = Vec::new();
+        raw.seek(SeekFrom::Start(0)).unwrap();
+        raw.read_to_end(&mut contents).unwrap();
+        assert!(contents[..3 * MB] == data);
+        assert!(contents[3 * MB..].iter().all(|&byte| byte == 0));
+
+        // Only the blocks with data are written
+        #[cfg(target_os = "linux")]
+        {
+            let mb = MB as u64;
+            assert_eq!(sparse::next_hole(&raw, 0).unwrap(), mb);
+            assert_eq!(sparse::next_data(&raw, mb).unwrap(), Some(2 * mb));
+            assert_eq!(sparse::next_hole(&raw, 2 * mb).unwrap(), 3 * mb);
+            // Apart from the last byte, which extends the output
+            assert!(sparse::next_data(&raw, 3 * mb).unwrap().unwrap() > 7 * mb);
+        }
+    }
+
+    #[test]
+    fn raw_input_allocates_blocks_with_data() {
+        let raw_path = TempPath::new("raw");
+        let mb = MB as u64;
+        let mut data = vec![0; 8 * MB];
+        data[..MB + 100].copy_from_slice(&pattern(MB + 100));
+        data[6 * MB + 4096..6 * MB + 8192].fill(1);
+
+        // The raw file has a hole over the third block, and explicit zeros
+        // in the fourth, which are data to SEEK_DATA but aren't allocated
+        let mut raw = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&raw_path)
+            .unwrap();
+        raw.set_len(8 * mb).unwrap();
+        for range in [0..2 * MB, 3 * MB..4 * MB, 6 * MB..7 * MB] {
+            raw.seek(SeekFrom::Start(range.start as u64)).unwrap();
+            raw.write_all(&data[range]).unwrap();
+        }
+        #[cfg(target_os = "linux")]
+        assert_eq!(sparse::next_data(&raw, 2 * mb).unwrap(), Some(3 * mb));
+
+        use PayloadBatEntryState::*;
+        let expected_states = [
+            FullyPresent,
+            FullyPresent,
+            NotPresent,
+            NotPresent,
+            NotPresent,
+            NotPresent,
+            FullyPresent,
+            NotPresent,
+        ];
+        let builder = |path: &Path| {
+            let mut builder = Builder::new(path);
+            builder.block_size(MB as u32);
+            builder
+        };
+        let paths = [TempPath::new("vhdx"), TempPath::new("vhdx")];
+        let from_file = from_raw(&mut raw, &builder(&paths[0])).unwrap();
+        let from_cursor = from_raw(&mut Cursor::new(&data), &builder(&paths[1])).unwrap();
+        for mut disk in [from_file, from_cursor] {
+            assert_eq!(disk.virtual_size(), 8 * mb);
+            let states = (0..8)
+                .map(|block_index| disk.block_state(block_index).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(states, expected_states);
+            let mut contents = Vec::new();
+            disk.reader().read_to_end(&mut contents).unwrap();
+            assert!(contents == data);
+        }
+    }
+
+    #[test]
+    fn vhd_round_trip() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(5 * MB);
+        data[2 * MB..4 * MB].fill(0);
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &data);
+        let mut expected = Vec::new();
+        disk.reader().read_to_end(&mut expected).unwrap();
+
+        for disk_type in [DiskType::Fixed, DiskType::Dynamic] {
+            let vhd_path = TempPath::new("vhd");
+            let mut file = File::options()
+                .write(true)
+                .create_new(true)
+                .open(&vhd_path)
+                .unwrap();
+            to_vhd(&mut disk, &mut file, disk_type).unwrap();
+            drop(file);
+
+            let mut vhd = Vhd::open(&vhd_path).unwrap();
+            assert_eq!(vhd.disk_type(), disk_type);
+            assert_eq!(vhd.virtual_size(), 8 * MB as u64);
+            let imported_path = TempPath::new("vhdx");
+            let mut builder = Builder::new(&*imported_path);
+            builder.block_size(MB as u32);
+            let mut imported = from_vhd(&mut vhd, &builder).unwrap();
+            assert_eq!(imported.logical_sector_size(), 512);
+            let mut contents = Vec::new();
+            imported.reader().read_to_end(&mut contents).unwrap();
+            assert!(contents == expected);
+            // The zero blocks aren't allocated
+            for block_index in 2..4 {
+                assert_eq!(
+                    imported.block_state(block_index).unwrap(),
+                    PayloadBatEntryState::NotPresent
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vhd_size_limit() {
+        let path = TempPath::new("vhdx");
+        let disk_size = vhd::MAX_VIRTUAL_SIZE + MB as u64;
+        let mut disk = disk_with_data(&path, disk_size, &[]);
+        for disk_type in [DiskType::Fixed, DiskType::Dynamic] {
+            let error = to_vhd(&mut disk, &mut Cursor::new(Vec::new()), disk_type).unwrap_err();
+            assert!(matches!(error, Error::VhdTooLarge(size) if size == disk_size));
+        }
+    }
+
+    #[test]
+    fn vmdk_size_limit() {
+        let path = TempPath::new("vhdx");
+        let disk_size = vmdk::MAX_SPARSE_SIZE + MB as u64;
+        let mut disk = disk_with_data(&path, disk_size, &[]);
+        for subformat in [
+            vmdk::Subformat::MonolithicSparse,
+            vmdk::Subformat::StreamOptimized,
+        ] {
+            let vmdk_path = TempPath::new("vmdk");
+            let error = to_vmdk(&mut disk, &vmdk_path, subformat).unwrap_err();
+            assert!(matches!(error, Error::VmdkTooLarge(size) if size == disk_size));
+            assert!(!vmdk_path.exists());
+        }
+
+        // The largest disk fits, with every grain table within 2 TB
+        let mut file = Cursor::new(Vec::new());
+        let descriptor = vmdk::descriptor(
+            vmdk::Subformat::MonolithicSparse,
+            vmdk::MAX_SPARSE_SIZE,
+            "disk.vmdk",
+        );
+        let writer = vmdk::SparseWriter::new(
+            &mut file,
+            vmdk::MAX_SPARSE_SIZE,
+            vmdk::Subformat::MonolithicSparse,
+            &descriptor,
+        );
+        assert!(writer.is_ok());
+    }
+
+    #[test]
+    fn vmdk_round_trip() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(5 * MB);
+        data[2 * MB..4 * MB].fill(0);
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &data);
+        let mut expected = Vec::new();
+        disk.reader().read_to_end(&mut expected).unwrap();
+
+        for (subformat, create_type) in [
+            (vmdk::Subformat::MonolithicSparse, "monolithicSparse"),
+            (vmdk::Subformat::MonolithicFlat, "monolithicFlat"),
+            (vmdk::Subformat::StreamOptimized, "streamOptimized"),
+        ] {
+            let vmdk_path = TempPath::new("vmdk");
+            to_vmdk(&mut disk, &vmdk_path, subformat).unwrap();
+            let flat_path = vmdk_path.with_file_name(format!(
+                "{}-flat.vmdk",
+                vmdk_path.file_stem().unwrap().to_str().unwrap()
+            ));
+            assert_eq!(
+                flat_path.exists(),
+                subformat == vmdk::Subformat::MonolithicFlat
+            );
+
+            let mut vmdk = Vmdk::open(&vmdk_path).unwrap();
+            assert_eq!(vmdk.create_type(), create_type);
+            assert_eq!(vmdk.virtual_size(), 8 * MB as u64);
+            let imported_path = TempPath::new("vhdx");
+            let mut builder = Builder::new(&*imported_path);
+            builder.block_size(MB as u32);
+            let mut imported = from_vmdk(&mut vmdk, &builder).unwrap();
+            let mut contents = Vec::new();
+            imported.reader().read_to_end(&mut contents).unwrap();
+            assert!(contents == expected);
+            // The zero blocks aren't allocated
+            for block_index in 2..4 {
+                assert_eq!(
+                    imported.block_state(block_index).unwrap(),
+                    PayloadBatEntryState::NotPresent
+                );
+            }
+            drop(vmdk);
+            let _ = std::fs::remove_file(&flat_path);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    bat::{div_ceil, entries_for, BatEntry, PayloadBatEntryState},
+    log::next_multiple_of,
+    metadata::{
+        FileParameters, LogicalSectorSize, MetadataItem, ParentLocator, PhysicalSectorSize,
+        VirtualDiskId, VirtualDiskSize,
+    },
+    resize::MAX_VIRTUAL_DISK_SIZE,
+    Error, FileTypeIdentifier, Guid, Header, MetadataTable, MetadataTableEntry, OpenOptions,
+    RegionTable, RegionTableEntry, Vhdx, FILE_SIGNATURE, HEADER_SIGNATURE, KB, MB,
+    METADATA_TABLE_SIGNATURE, REGION_GUID_BAT, REGION_GUID_METADATA, REGION_TABLE_SIGNATURE,
+};
+
+/// The default block size for dynamic and fixed disks.
+const DEFAULT_BLOCK_SIZE: u32 = 32 * MB as u32;
+/// The default block size for differencing disks, which is smaller so that
+/// small writes copy less data from the parent.
+const DEFAULT_DIFFERENCING_BLOCK_SIZE: u32 = 2 * MB as u32;
+
+const LOG_OFFSET: u64 = MB as u64;
+const LOG_LENGTH: u32 = MB as u32;
+const METADATA_OFFSET: u64 = 2 * MB as u64;
+const METADATA_LENGTH: u32 = MB as u32;
+const BAT_OFFSET: u64 = 3 * MB as u64;
+
+/// Creates new VHDX files.
+///
+/// By default a dynamic disk is created, where payload blocks are only
+/// allocated when they are written to. The virtual size must be set, except
+/// for differencing disks, which default to the size of their parent.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    path: PathBuf,
+    pub(crate) virtual_size: Option<u64>,
+    block_size: Option<u32>,
+    pub(crate) logical_sector_size: Option<u32>,
+    pub(crate) physical_sector_size: Option<u32>,
+    fixed: bool,
+    pub(crate) parent: Option<PathBuf>,
+}
+
+impl Builder {
+    /// Create a builder for a new file at `path`, which must not exist.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            virtual_size: None,
+            block_size: None,
+            logical_sector_size: None,
+            physical_sector_size: None,
+            fixed: false,
+            parent: None,
+        }
+    }
+
+    /// The size of the virtual disk in bytes, which must be a multiple of
+    /// the logical sector size.
+    pub fn virtual_size(&mut self, virtual_size: u64) -> &mut Self {
+        self.virtual_size = Some(virtual_size);
+        self
+    }
+
+    /// The size of each payload block, which must be a power of two between
+    /// 1 MB and 256 MB.
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// The sector size presented by the virtual disk, either 512 (the
+    /// default) or 4096 bytes.
+    pub fn logical_sector_size(&mut self, logical_sector_size: u32) -> &mut Self {
+        self.logical_sector_size = Some(logical_sector_size);
+        self
+    }
+
+    /// The physical sector size reported by the virtual disk, either 512 or
+    /// 4096 (the default) bytes.
+    pub fn physical_sector_size(&mut self, physical_sector_size: u32) -> &mut Self {
+        self.physical_sector_size = Some(physical_sector_size);
+        self
+    }
+
+    /// Create a fixed disk, where every payload block is allocated up front.
+    pub fn fixed(&mut self, fixed: bool) -> &mut Self {
+        self.fixed = fixed;
+        self
+    }
+
+    /// Create a differencing disk on top of the parent at `parent`. Parts of
+    /// the disk that have not been written are read from the parent.
+    pub fn parent(&mut self, parent: impl Into<PathBuf>) -> &mut Self {
+        self.parent = Some(parent.into());
+        self
+    }
+
+    /// The path that the file will be created at.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Create the file and open it.
+    pub fn create(&self) -> Result<Vhdx, Error> {
+        if self.fixed && self.parent.is_some() {
+            return Err(Error::Unsupported("fixed differencing disks"));
+        }
+
+        let parent = self
+            .parent
+            .as_ref()
+            .map(|path| {
+                let parent = OpenOptions::new().read_only(true).open(path)?;
+                Ok::<_, Error>((path, parent))
+            })
+            .transpose()?;
+
+        let logical_sector_size = self
+            .logical_sector_size
+            .or(parent
+                .as_ref()
+                .map(|(_, parent)| parent.logical_sector_size()))
+            .unwrap_or(512);
+        let physical_sector_size = self
+            .physical_sector_size
+            .or(parent
+                .as_ref()
+                .map(|(_, parent)| parent.physical_sector_size()))
+            .unwrap_or(4096);
+        let block_size = self.block_size.unwrap_or(if parent.is_some() {
+            DEFAULT_DIFFERENCING_BLOCK_SIZE
+        } else {
+            DEFAULT_BLOCK_SIZE
+        });
+        let virtual_size = self
+            .virtual_size
+            .or(parent.as_ref().map(|(_, parent)| parent.virtual_size()))
+            .ok_or(Error::InvalidVirtualDiskSize(0))?;
+
+        if !block_size.is_power_of_two() || !(MB as u32..=256 * MB as u32).contains(&block_size) {
+            return Err(Error::InvalidMetadataItem(
+                "block size must be a power of two between 1 MB and 256 MB",
+            ));
+        }
+        if virtual_size == 0
+            || !virtual_size.is_multiple_of(logical_sector_size as u64)
+            || virtual_size > MAX_VIRTUAL_DISK_SIZE
+        {
+            return Err(Error::InvalidVirtualDiskSize(virtual_size));
+        }
+        if let Some((_, parent)) = &parent {
+            if parent.logical_sector_size() != logical_sector_size {
+                return Err(Error::InvalidMetadataItem(
+                    "logical sector size must match the parent",
+                ));
+            }
+        }
+
+        let mut items = Vec::new();
+        push_item(
+            &mut items,
+            &FileParameters::new(block_size, self.fixed, parent.is_some()),
+            false,
+        )?;
+        push_item(&mut items, &VirtualDiskSize::new(virtual_size), true)?;
+        push_item(&mut items, &VirtualDiskId::new(Guid::new_random()), true)?;
+        push_item(
+            &mut items,
+            &LogicalSectorSize::new(logical_sector_size)?,
+            true,
+        )?;
+        push_item(
+            &mut items,
+            &PhysicalSectorSize::new(physical_sector_size)?,
+            true,
+        )?;
+        if let Some((parent_path, parent)) = &parent {
+            let locator = self.parent_locator(parent_path, parent)?;
+            push_item(&mut items, &locator, false)?;
+        }
+
+        let chunk_ratio = (1 << 23) * logical_sector_size as u64 / block_size as u64;
+        let payload_blocks_count = div_ceil(virtual_size, block_size as u64);
+        let bat_entries = entries_for(payload_blocks_count, chunk_ratio, parent.is_some());
+        let bat_length = next_multiple_of(bat_entries * 8, MB as u64);
+        let payload_offset = BAT_OFFSET + bat_length;
+
+        let mut bat = vec![0; bat_length as usize];
+        if self.fixed {
+            for block_index in 0..payload_blocks_count {
+                let bat_index = block_index + block_index / chunk_ratio;
+                let entry = BatEntry::try_new(
+                    PayloadBatEntryState::FullyPresent,
+                    payload_offset + block_index * block_size as u64,
+                )?;
+                bat[bat_index as usize * 8..][..8].copy_from_slice(&entry.to_bits().to_le_bytes());
+            }
+        }
+        let file_length = if self.fixed {
+            payload_offset + payload_blocks_count * block_size as u64
+        } else {
+            payload_offset
+        };
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&self.path)?;
+        file.write_all(&header_area(bat_length as u32))?;
+        file.seek(SeekFrom::Start(METADATA_OFFSET))?;
+        file.write_all(&metadata_region(items))?;
+        file.seek(SeekFrom::Start(BAT_OFFSET))?;
+        file.write_all(&bat)?;
+        file.set_len(file_length)?;
+        file.sync_all()?;
+        drop(file);
+
+        Vhdx::load(&self.path)
+    }
+
+    /// Describe the location of the parent, relative to the new file and as an
+    /// absolute path.
+    fn parent_locator(&self, parent_path: &Path, parent: &Vhdx) -> Result<ParentLocator, Error> {
+        let parent_path = std::fs::canonicalize(parent_path)?;
+        let child_dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => std::fs::canonicalize(dir)?,
+            _ => std::env::current_dir()?,
+        };
+
+        let mut entries = vec![(
+            "parent_linkage".to_owned(),
+            format!("{{{}}}", parent.data_write_guid()),
+        )];
+        if let Some(relative_path) = relative_path(&child_dir, &parent_path) {
+            entries.push(("relative_path".to_owned(), relative_path));
+        }
+        let absolute_path = parent_path.to_string_lossy();
+        let absolute_path = absolute_path
+            .strip_prefix(r"\\?\")
+            .unwrap_or(&absolute_path);
+        entries.push(("absolute_win32_path".to_owned(), absolute_path.to_owned()));
+
+        Ok(ParentLocator::new(entries))
+    }
+}
+
+/// Serialise a metadata item and add it to the list of items.
+fn push_item<T: MetadataItem>(
+    items: &mut Vec<(MetadataTableEntry, Vec<u8>)>,
+    item: &T,
+    is_virtual_disk: bool,
+) -> Result<(), Error> {
+    let mut data = Vec::new();
+    item.write(&mut data)?;
+    let entry = MetadataTableEntry {
+        item_id: T::GUID,
+        offset: 0,
+        length: data.len() as u32,
+        is_user: false,
+        is_virtual_disk,
+        is_required: true,
+        is_empty: data.is_empty(),
+    };
+    items.push((entry, data));
+    Ok(())
+}
+
+/// The first 1 MB of the file, with the file type identifier, both headers
+/// and both region tables.
+fn header_area(bat_length: u32) -> Vec<u8> {
+    let mut buffer = vec![0; MB];
+
+    FileTypeIdentifier {
+        signature: FILE_SIGNATURE.to_owned(),
+        creator: concat!("vhdx ", env!("CARGO_PKG_VERSION")).to_owned(),
+    }
+    .write_to(&mut buffer);
+
+    let file_write_guid = Guid::new_random();
+    let data_write_guid = Guid::new_random();
+    for (sequence_number, offset) in [(0, 64 * KB), (1, 128 * KB)] {
+        let header = Header {
+            signature: HEADER_SIGNATURE.to_owned(),
+            checksum: [0; 4],
+            sequence_number,
+            file_write_guid,
+            data_write_guid,
+            log_guid: Guid::ZERO,
+            log_version: 0,
+            version: 1,
+            log_length: LOG_LENGTH,
+            log_offset: LOG_OFFSET,
+        };
+        header
+            .write(&mut &mut buffer[offset..][..4 * KB])
+            .expect("buffer is large enough");
+    }
+
+    let region_table = RegionTable {
+        signature: REGION_TABLE_SIGNATURE.to_owned(),
+        checksum: [0; 4],
+        entries: vec![
+            RegionTableEntry {
+                guid: REGION_GUID_BAT,
+                file_offset: BAT_OFFSET,
+                length: bat_length,
+                required: 1,
+            },
+            RegionTableEntry {
+                guid: REGION_GUID_METADATA,
+                file_offset: METADATA_OFFSET,
+                length: METADATA_LENGTH,
+                required: 1,
+            },
+        ],
+    }
+    .to_bytes();
+    buffer[192 * KB..][..64 * KB].copy_from_slice(&region_table);
+    buffer[256 * KB..][..64 * KB].copy_from_slice(&region_table);
+
+    buffer
+}
+
+/// The metadata region, with the table followed by the data of each item.
+fn metadata_region(items: Vec<(MetadataTableEntry, Vec<u8>)>) -> Vec<u8> {
+    let mut buffer = vec![0; METADATA_LENGTH as usize];
+    let mut entries = Vec::with_capacity(items.len());
+    let mut offset = 64 * KB;
+    for (mut entry, data) in items {
+        if !data.is_empty() {
+            entry.offset = offset as u32;
+            buffer[offset..][..data.len()].copy_from_slice(&data);
+            offset += data.len();
+        }
+        entries.push(entry);
+    }
+
+    let table = MetadataTable {
+        signature: METADATA_TABLE_SIGNATURE.to_owned(),
+        entries,
+    };
+    buffer[..64 * KB].copy_from_slice(&table.to_bytes());
+    buffer
+}
+
+/// The path of `target` relative to the directory `base`, with Windows
+/// separators as used in parent locators.
+///
+/// Returns none if there is no relative path, such as on different drives.
+fn relative_path(base: &Path, target: &Path) -> Option<String> {
+    let base = base.components().collect::<Vec<_>>();
+    let target = target.components().collect::<Vec<_>>();
+    if base.first() != target.first() {
+        return None;
+    }
+
+    let common = base
+        .iter()
+        .zip(&target)
+        .take_while(|(base, target)| base == target)
+        .count();
+    let mut parts = vec![".".to_owned()];
+    parts.extend((common..base.len()).map(|_| "..".to_owned()));
+    for component in &target[common..] {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            _ => return None,
+        }
+    }
+    Some(parts.join("\\"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("/images/child"), Path::new("/images/base.vhdx")),
+            Some(r".\..\base.vhdx".to_owned())
+        );
+        assert_eq!(
+            relative_path(Path::new("/images"), Path::new("/images/base/base.vhdx")),
+            Some(r".\base\base.vhdx".to_owned())
+        );
+    }
+}
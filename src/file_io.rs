@@ -0,0 +1,41 @@
+use std::{fs::File, io};
+
+/// Read from a position in the file, without depending on the position of
+/// the handle, which may be shared with the [`crate::Vhdx`] and other threads.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Read from a position in the file, without depending on the position of
+/// the handle. The position is moved, but the [`crate::Vhdx`] always seeks
+/// before reading or writing.
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(num_read) => {
+                buf = &mut buf[num_read..];
+                offset += num_read as u64;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Read from a position in the file. The position of the handle is moved, but
+/// the [`crate::Vhdx`] always seeks before reading or writing. The position is
+/// shared with every clone of the handle, so this can't be used from another
+/// thread.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
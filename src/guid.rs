@@ -1,4 +1,9 @@
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// A GUID, as used to identify regions, metadata items and disks.
+///
+/// GUIDs are displayed in the usual uppercase form, such as
+/// `2DC27766-F623-4200-9D64-115E9BFD4A08`, and stored on disk with the first
+/// three fields in little-endian order.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Guid {
     data_1: u32,
     data_2: u16,
@@ -30,10 +35,68 @@ impl Guid {
         }
     }
 
-    pub const fn from_str(value: &str) -> Self {
-        let value = value.as_bytes();
+    /// Convert to the on-disk representation, where the first three fields
+    /// are little-endian.
+    pub const fn to_bytes(self) -> [u8; 16] {
+        let data_1 = self.data_1.to_le_bytes();
+        let data_2 = self.data_2.to_le_bytes();
+        let data_3 = self.data_3.to_le_bytes();
+        [
+            data_1[0],
+            data_1[1],
+            data_1[2],
+            data_1[3],
+            data_2[0],
+            data_2[1],
+            data_3[0],
+            data_3[1],
+            self.data_4[0],
+            self.data_4[1],
+            self.data_4[2],
+            self.data_4[3],
+            self.data_4[4],
+            self.data_4[5],
+            self.data_4[6],
+            self.data_4[7],
+        ]
+    }
+
+    /// Generate a new random (version 4) GUID.
+    pub fn new_random() -> Self {
+        let mut bytes = [0; 16];
+        for chunk in bytes.chunks_exact_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_le_bytes());
+        }
+
+        let mut guid = Self::from_bytes(bytes);
+        guid.data_3 = (guid.data_3 & 0x0FFF) | 0x4000;
+        guid.data_4[0] = (guid.data_4[0] & 0x3F) | 0x80;
+        guid
+    }
+
+    /// Parse a GUID in a constant context, such as for the well-known GUIDs
+    /// in the specification.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is not a valid GUID. Use [`str::parse`] to parse
+    /// GUIDs that are not known in advance.
+    pub const fn parse_const(value: &str) -> Self {
+        match Self::try_parse(value) {
+            Ok(guid) => guid,
+            Err(_) => panic!("invalid GUID"),
+        }
+    }
+
+    /// Parse a GUID in the form `2DC27766-F623-4200-9D64-115E9BFD4A08`,
+    /// optionally surrounded by braces, and in either case.
+    pub const fn try_parse(value: &str) -> Result<Self, ParseGuidError> {
+        let mut value = value.as_bytes();
+        if let [b'{', inner @ .., b'}'] = value {
+            value = inner;
+        }
         if value.len() != 36 {
-            panic!("uuid has incorrect length");
+            return Err(ParseGuidError::InvalidLength);
         }
 
         let mut bytes = [0; 16];
@@ -43,13 +106,19 @@ impl Guid {
         while i < value.len() {
             let character = value[i];
 
-            if character == b'-' {
+            if matches!(i, 8 | 13 | 18 | 23) {
+                if character != b'-' {
+                    return Err(ParseGuidError::InvalidCharacter(i));
+                }
                 i += 1;
                 skipped_chars += 1;
                 continue;
             }
 
-            let nibble = hex_digit_to_nibble(character);
+            let nibble = match hex_digit_to_nibble(character) {
+                Some(nibble) => nibble,
+                None => return Err(ParseGuidError::InvalidCharacter(i)),
+            };
 
             let nibble_index = i - skipped_chars;
             let buffer_idx = nibble_index / 2;
@@ -64,7 +133,7 @@ impl Guid {
             i += 1;
         }
 
-        Self {
+        Ok(Self {
             data_1: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
             data_2: u16::from_be_bytes([bytes[4], bytes[5]]),
             data_3: u16::from_be_bytes([bytes[6], bytes[7]]),
@@ -72,6 +141,91 @@ impl Guid {
                 bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14],
                 bytes[15],
             ],
+        })
+    }
+}
+
+/// An error from parsing a [`Guid`] from a string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseGuidError {
+    #[error("GUID has incorrect length")]
+    InvalidLength,
+    #[error("invalid character in GUID at position {0}")]
+    InvalidCharacter(usize),
+}
+
+impl std::str::FromStr for Guid {
+    type Err = ParseGuidError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::try_parse(value)
+    }
+}
+
+impl TryFrom<&str> for Guid {
+    type Error = ParseGuidError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::try_parse(value)
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<uuid::Uuid> for Guid {
+    fn from(value: uuid::Uuid) -> Self {
+        let (data_1, data_2, data_3, data_4) = value.as_fields();
+        Self::new(data_1, data_2, data_3, *data_4)
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<Guid> for uuid::Uuid {
+    fn from(value: Guid) -> Self {
+        uuid::Uuid::from_fields(value.data_1, value.data_2, value.data_3, &value.data_4)
+    }
+}
+
+/// GUIDs are serialised as strings in human readable formats, and as the 16
+/// bytes of their on-disk representation otherwise.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Guid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Guid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Guid;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a GUID string or 16 bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Guid, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, value: &[u8]) -> Result<Guid, E> {
+                let bytes = value
+                    .try_into()
+                    .map_err(|_| E::invalid_length(value.len(), &self))?;
+                Ok(Guid::from_bytes(bytes))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(Visitor)
+        } else {
+            deserializer.deserialize_bytes(Visitor)
         }
     }
 }
@@ -102,12 +256,24 @@ impl std::fmt::Debug for Guid {
     }
 }
 
-const fn hex_digit_to_nibble(input: u8) -> u8 {
+/// Produce a random value using the randomly seeded keys of the standard
+/// library's hasher, mixed with the current time.
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    if let Ok(elapsed) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish()
+}
+
+const fn hex_digit_to_nibble(input: u8) -> Option<u8> {
     match input {
-        b'0'..=b'9' => input - b'0',
-        b'a'..=b'f' => input - b'a' + 10,
-        b'A'..=b'F' => input - b'A' + 10,
-        _ => panic!("unknown char"),
+        b'0'..=b'9' => Some(input - b'0'),
+        b'a'..=b'f' => Some(input - b'a' + 10),
+        b'A'..=b'F' => Some(input - b'A' + 10),
+        _ => None,
     }
 }
 
@@ -126,6 +292,51 @@ mod tests {
 
         let string = "2DC27766-F623-4200-9D64-115E9BFD4A08";
 
-        assert_eq!(expected, Guid::from_str(string));
+        assert_eq!(expected, Guid::parse_const(string));
+        assert_eq!(Ok(expected), string.to_lowercase().parse());
+        assert_eq!(
+            Ok(expected),
+            Guid::try_from(format!("{{{string}}}").as_str())
+        );
+        assert_eq!(
+            Err(ParseGuidError::InvalidCharacter(8)),
+            "2DC27766 F623-4200-9D64-115E9BFD4A08".parse::<Guid>()
+        );
+        assert_eq!(
+            Err(ParseGuidError::InvalidLength),
+            "{2DC27766}".parse::<Guid>()
+        );
+    }
+
+    #[test]
+    fn guid_bytes_round_trip() {
+        let guid = Guid::parse_const("2DC27766-F623-4200-9D64-115E9BFD4A08");
+        assert_eq!(guid, Guid::from_bytes(guid.to_bytes()));
+    }
+
+    #[test]
+    fn guid_random() {
+        let guid = Guid::new_random();
+        assert_ne!(guid, Guid::new_random());
+        assert_eq!(guid.data_3 >> 12, 4);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn guid_serde() {
+        let guid = Guid::parse_const("2DC27766-F623-4200-9D64-115E9BFD4A08");
+        let json = serde_json::to_string(&guid).unwrap();
+        assert_eq!(json, "\"2DC27766-F623-4200-9D64-115E9BFD4A08\"");
+        assert_eq!(guid, serde_json::from_str(&json).unwrap());
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn guid_uuid() {
+        let guid = Guid::parse_const("2DC27766-F623-4200-9D64-115E9BFD4A08");
+        let uuid = uuid::Uuid::from(guid);
+        assert_eq!(uuid.to_string().to_uppercase(), guid.to_string());
+        assert_eq!(uuid.to_bytes_le(), guid.to_bytes());
+        assert_eq!(guid, Guid::from(uuid));
     }
 }
@@ -1,5 +1,8 @@
 #![doc = include_str!("../README.md")]
-#![forbid(unsafe_code)]
+// Memory-mapping a file is the only unsafe operation, which is confined to
+// the `mmap` module
+#![cfg_attr(not(feature = "mmap"), forbid(unsafe_code))]
+#![cfg_attr(feature = "mmap", deny(unsafe_code))]
 #![allow(dead_code)]
 
 use std::{
@@ -12,21 +15,50 @@ use std::{
 use thiserror::Error;
 
 use metadata::MetadataItem;
-
-use crate::guid::Guid;
-
-mod bat;
+use mmap::Mapping;
+use read_ahead::ReadAhead;
+
+use crate::bat::{PayloadBatEntryState, SectorBitmapBatEntryState};
+
+pub mod bat;
+mod cache;
+pub mod check;
+mod checksum;
+mod compact;
+pub mod convert;
+mod create;
+mod file_io;
 mod guid;
 mod log;
-mod metadata;
+mod lru;
+pub mod metadata;
+mod mmap;
+mod open;
+pub mod qcow2;
+mod read_ahead;
+pub mod repair;
+mod resize;
+mod sparse;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod user_metadata;
+pub mod vhd;
+pub mod vmdk;
+
+pub use cache::CacheStats;
+pub use create::Builder;
+pub use guid::{Guid, ParseGuidError};
+pub use open::OpenOptions;
+pub use stream::{StreamOptions, StreamReader};
 
 static FILE_SIGNATURE: &str = "vhdxfile";
 static HEADER_SIGNATURE: &str = "head";
 static REGION_TABLE_SIGNATURE: &str = "regi";
 static METADATA_TABLE_SIGNATURE: &str = "metadata";
 
-static REGION_GUID_BAT: Guid = Guid::from_str("2DC27766-F623-4200-9D64-115E9BFD4A08");
-static REGION_GUID_METADATA: Guid = Guid::from_str("8B7CA206-4790-4B9A-B8FE-575F050F886E");
+static REGION_GUID_BAT: Guid = Guid::parse_const("2DC27766-F623-4200-9D64-115E9BFD4A08");
+static REGION_GUID_METADATA: Guid = Guid::parse_const("8B7CA206-4790-4B9A-B8FE-575F050F886E");
 
 const KB: usize = 1024;
 const MB: usize = KB * KB;
@@ -39,6 +71,8 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("invalid signature")]
     InvalidSignature,
+    #[error("invalid checksum")]
+    InvalidChecksum,
     #[error("invalid UTF-8: {0}")]
     InvalidUtf8(#[from] Utf8Error),
     #[error("invalid UTF-16: {0}")]
@@ -47,6 +81,56 @@ pub enum Error {
     MissingRequiredMetadata(&'static str),
     #[error("missing required region: {0}")]
     MissingRequiredRegion(&'static str),
+    #[error("range is outside of the virtual disk")]
+    OutOfBounds,
+    #[error("unsupported: {0}")]
+    Unsupported(&'static str),
+    #[error("invalid virtual disk size: {0}")]
+    InvalidVirtualDiskSize(u64),
+    #[error("blocks beyond the new size of the virtual disk are allocated")]
+    AllocatedBeyondSize,
+    #[error("invalid metadata item: {0}")]
+    InvalidMetadataItem(&'static str),
+    #[error("unknown required regions: {}", format_guids(.0))]
+    UnknownRequiredRegions(Vec<Guid>),
+    #[error("unknown required metadata items: {}", format_guids(.0))]
+    UnknownRequiredMetadata(Vec<Guid>),
+    #[error("the file is opened read-only")]
+    ReadOnly,
+    #[error("the parent of the differencing disk could not be found")]
+    ParentNotFound,
+    #[error("the parent of the differencing disk has been modified")]
+    ParentModified,
+    #[error("out of order payload data exceeds the spill limit of {0} bytes")]
+    SpillLimitExceeded(u64),
+    #[error("virtual disk size of {0} bytes is larger than the VHD limit of 2040 GB")]
+    VhdTooLarge(u64),
+    #[error("virtual disk size of {0} bytes is too large for a VMDK sparse extent")]
+    VmdkTooLarge(u64),
+    #[error("the log has entries that must be replayed by opening the file for writing")]
+    LogReplayRequired,
+    #[error("the file has been truncated since the log was written")]
+    LogTruncated,
+    #[error("invalid log entry: {0}")]
+    InvalidLogEntry(&'static str),
+    #[error("invalid header: {0}")]
+    InvalidHeader(&'static str),
+    #[error("invalid region table: {0}")]
+    InvalidRegionTable(&'static str),
+    #[error("BAT entry file offset {0} is not 1 MB aligned")]
+    UnalignedFileOffset(u64),
+    #[error("invalid image: {0}")]
+    InvalidImage(&'static str),
+    #[error("the file cannot be repaired: {0}")]
+    Unrepairable(&'static str),
+}
+
+fn format_guids(guids: &[Guid]) -> String {
+    guids
+        .iter()
+        .map(Guid::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl From<std::string::FromUtf8Error> for Error {
@@ -64,11 +148,13 @@ struct FileTypeIdentifier {
 impl FileTypeIdentifier {
     /// Read a file type identifier from the current position in the file,
     /// advancing the file to beyond the file type identifier.
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(file: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; KB];
         file.read_exact(&mut buffer)?;
-        let signature = String::from_utf8(buffer[..8].to_vec())?;
-        assert_eq!(signature, FILE_SIGNATURE);
+        if &buffer[..8] != FILE_SIGNATURE.as_bytes() {
+            return Err(Error::InvalidSignature);
+        }
+        let signature = FILE_SIGNATURE.to_owned();
 
         let creator_iter = buffer[8..(8 + 512)]
             .chunks_exact(2)
@@ -78,9 +164,23 @@ impl FileTypeIdentifier {
 
         Ok(Self { signature, creator })
     }
+
+    /// Serialise the file type identifier into the start of a buffer, with
+    /// the creator truncated to fit.
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[..8].copy_from_slice(FILE_SIGNATURE.as_bytes());
+        let creator = self
+            .creator
+            .encode_utf16()
+            .take(255)
+            .flat_map(u16::to_le_bytes);
+        for (byte, value) in buffer[8..8 + 512].iter_mut().zip(creator) {
+            *byte = value;
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Header {
     signature: String,
     checksum: [u8; 4],
@@ -95,14 +195,24 @@ struct Header {
 }
 
 impl Header {
-    /// Read a header from the current position in the file, advancing the
-    /// file to beyond the header.
-    fn read(file: &mut File) -> Result<Self, Error> {
-        let mut buffer = vec![0; 128];
+    /// Read a 4 KB header from the current position in the file, advancing
+    /// the file to beyond the header.
+    ///
+    /// The location of the log isn't validated, so that the checker can
+    /// report on it.
+    fn read(file: &mut impl Read) -> Result<Self, Error> {
+        let mut buffer = vec![0; 4 * KB];
         file.read_exact(&mut buffer)?;
 
+        if &buffer[0..4] != HEADER_SIGNATURE.as_bytes() {
+            return Err(Error::InvalidSignature);
+        }
         let signature = String::from_utf8(buffer[0..4].to_vec())?;
-        let checksum = buffer[4..8].try_into().expect("infallible");
+        let checksum: [u8; 4] = buffer[4..8].try_into().expect("infallible");
+        buffer[4..8].fill(0);
+        if checksum::crc32c(&buffer) != u32::from_le_bytes(checksum) {
+            return Err(Error::InvalidChecksum);
+        }
         let sequence_number = u64::from_le_bytes(buffer[8..16].try_into().expect("infallible"));
 
         let file_write_guid = Guid::from_bytes(buffer[16..32].try_into().expect("infallible"));
@@ -114,11 +224,12 @@ impl Header {
         let log_length = u32::from_le_bytes(buffer[68..72].try_into().expect("infallible"));
         let log_offset = u64::from_le_bytes(buffer[72..80].try_into().expect("infallible"));
 
-        assert_eq!(signature, HEADER_SIGNATURE);
-        assert_eq!(log_version, 0);
-        assert_eq!(version, 1);
-        assert_eq!(log_length % MB as u32, 0);
-        assert_eq!(log_offset % MB as u64, 0);
+        if version != 1 {
+            return Err(Error::InvalidHeader("unsupported version"));
+        }
+        if log_version != 0 {
+            return Err(Error::InvalidHeader("unsupported log version"));
+        }
 
         Ok(Self {
             signature,
@@ -133,10 +244,32 @@ impl Header {
             log_offset,
         })
     }
+
+    /// Write the header to the current position in the file, calculating its
+    /// checksum.
+    fn write(&self, file: &mut impl Write) -> Result<(), Error> {
+        let mut buffer = vec![0; 4 * KB];
+        buffer[0..4].copy_from_slice(HEADER_SIGNATURE.as_bytes());
+        buffer[8..16].copy_from_slice(&self.sequence_number.to_le_bytes());
+        buffer[16..32].copy_from_slice(&self.file_write_guid.to_bytes());
+        buffer[32..48].copy_from_slice(&self.data_write_guid.to_bytes());
+        buffer[48..64].copy_from_slice(&self.log_guid.to_bytes());
+        buffer[64..66].copy_from_slice(&self.log_version.to_le_bytes());
+        buffer[66..68].copy_from_slice(&self.version.to_le_bytes());
+        buffer[68..72].copy_from_slice(&self.log_length.to_le_bytes());
+        buffer[72..80].copy_from_slice(&self.log_offset.to_le_bytes());
+
+        let checksum = checksum::crc32c(&buffer);
+        buffer[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        file.write_all(&buffer)?;
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
-struct RegionTableEntry {
+/// An entry in the region table, describing a region of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionTableEntry {
     guid: Guid,
     file_offset: u64,
     length: u32,
@@ -144,7 +277,7 @@ struct RegionTableEntry {
 }
 
 impl RegionTableEntry {
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(file: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 32];
         file.read_exact(&mut buffer)?;
 
@@ -153,11 +286,6 @@ impl RegionTableEntry {
         let length = u32::from_le_bytes(buffer[24..28].try_into().expect("infallible"));
         let required = u32::from_le_bytes(buffer[28..32].try_into().expect("infallible"));
 
-        assert_eq!(file_offset % MB as u64, 0);
-        assert!(file_offset > MB as u64);
-        assert_eq!(length % MB as u32, 0);
-        assert!(required == 0 || [REGION_GUID_BAT, REGION_GUID_METADATA].contains(&guid));
-
         Ok(Self {
             guid,
             file_offset,
@@ -165,9 +293,36 @@ impl RegionTableEntry {
             required,
         })
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..16].copy_from_slice(&self.guid.to_bytes());
+        buffer[16..24].copy_from_slice(&self.file_offset.to_le_bytes());
+        buffer[24..28].copy_from_slice(&self.length.to_le_bytes());
+        buffer[28..32].copy_from_slice(&self.required.to_le_bytes());
+    }
+
+    /// The GUID that identifies the region, such as the BAT or metadata.
+    pub fn guid(&self) -> Guid {
+        self.guid
+    }
+
+    /// The offset of the region in the file in bytes.
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset
+    }
+
+    /// The length of the region in bytes.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Whether an implementation must understand the region to open the file.
+    pub fn is_required(&self) -> bool {
+        self.required & 1 == 1
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RegionTable {
     signature: String,
     checksum: [u8; 4],
@@ -175,22 +330,33 @@ struct RegionTable {
 }
 
 impl RegionTable {
-    /// Read a region table from the current position in the file, advancing
-    /// the file to beyond the region table.
-    fn read(file: &mut File) -> Result<Self, Error> {
-        let mut buffer = vec![0; 16];
+    /// Read a 64 KB region table from the current position in the file,
+    /// advancing the file to beyond the region table.
+    ///
+    /// The locations of the regions aren't validated, so that the checker
+    /// can report on them.
+    fn read(file: &mut impl Read) -> Result<Self, Error> {
+        let mut buffer = vec![0; 64 * KB];
         file.read_exact(&mut buffer)?;
 
+        if &buffer[0..4] != REGION_TABLE_SIGNATURE.as_bytes() {
+            return Err(Error::InvalidSignature);
+        }
         let signature = String::from_utf8(buffer[0..4].to_vec())?;
-        let checksum = buffer[4..8].try_into().expect("infallible");
+        let checksum: [u8; 4] = buffer[4..8].try_into().expect("infallible");
+        buffer[4..8].fill(0);
+        if checksum::crc32c(&buffer) != u32::from_le_bytes(checksum) {
+            return Err(Error::InvalidChecksum);
+        }
         let entry_count = u32::from_le_bytes(buffer[8..12].try_into().expect("infallible"));
-
-        assert_eq!(signature, REGION_TABLE_SIGNATURE);
-        assert!(entry_count <= 2047);
+        if entry_count > 2047 {
+            return Err(Error::InvalidRegionTable("too many entries"));
+        }
 
         let mut entries = Vec::with_capacity(entry_count as usize);
+        let mut entry_bytes = &buffer[16..];
         for _ in 0..entry_count {
-            entries.push(RegionTableEntry::read(file)?);
+            entries.push(RegionTableEntry::read(&mut entry_bytes)?);
         }
 
         Ok(Self {
@@ -199,10 +365,25 @@ impl RegionTable {
             entries,
         })
     }
+
+    /// Serialise the full 64 KB region table, calculating its checksum.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![0; 64 * KB];
+        buffer[0..4].copy_from_slice(REGION_TABLE_SIGNATURE.as_bytes());
+        buffer[8..12].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for (i, entry) in self.entries.iter().enumerate() {
+            entry.write_to(&mut buffer[16 + 32 * i..][..32]);
+        }
+
+        let checksum = checksum::crc32c(&buffer);
+        buffer[4..8].copy_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
 }
 
-#[derive(Debug)]
-struct MetadataTableEntry {
+/// An entry in the metadata table, describing a metadata item.
+#[derive(Debug, Clone)]
+pub struct MetadataTableEntry {
     item_id: Guid,
     offset: u32,
     length: u32,
@@ -213,7 +394,9 @@ struct MetadataTableEntry {
 }
 
 impl MetadataTableEntry {
-    fn read(file: &mut File) -> Result<Self, Error> {
+    /// Read an entry of the table of a metadata region of `region_length`
+    /// bytes.
+    fn read(file: &mut impl Read, region_length: u32) -> Result<Self, Error> {
         let mut buffer = vec![0; 32];
         file.read_exact(&mut buffer)?;
 
@@ -224,13 +407,24 @@ impl MetadataTableEntry {
         let is_virtual_disk = buffer[24] >> 1 & 1 == 1;
         let is_required = buffer[24] >> 2 & 1 == 1;
 
-        assert!(offset >= 64 * KB as u32);
-        assert!(length <= MB as u32);
-
-        if length == 0 {
-            assert_eq!(offset, 0);
+        if length > MB as u32 {
+            return Err(Error::InvalidMetadataItem("item is larger than 1 MB"));
         }
+
         let is_empty = length == 0;
+        if is_empty {
+            if offset != 0 {
+                return Err(Error::InvalidMetadataItem("empty item has an offset"));
+            }
+        } else if offset < 64 * KB as u32 {
+            return Err(Error::InvalidMetadataItem(
+                "item overlaps the metadata table",
+            ));
+        } else if offset as u64 + length as u64 > region_length as u64 {
+            return Err(Error::InvalidMetadataItem(
+                "item extends beyond the metadata region",
+            ));
+        }
 
         Ok(Self {
             item_id,
@@ -242,41 +436,97 @@ impl MetadataTableEntry {
             is_empty,
         })
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..16].copy_from_slice(&self.item_id.to_bytes());
+        buffer[16..20].copy_from_slice(&self.offset.to_le_bytes());
+        buffer[20..24].copy_from_slice(&self.length.to_le_bytes());
+        buffer[24] =
+            self.is_user as u8 | (self.is_virtual_disk as u8) << 1 | (self.is_required as u8) << 2;
+    }
+
+    /// The GUID that identifies the item.
+    pub fn item_id(&self) -> Guid {
+        self.item_id
+    }
+
+    /// The length of the item's data in bytes.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Whether this is a user-defined item, rather than a system item.
+    pub fn is_user(&self) -> bool {
+        self.is_user
+    }
+
+    /// Whether the item describes the virtual disk, rather than the file, and
+    /// should be kept when the virtual disk is converted to another format.
+    pub fn is_virtual_disk(&self) -> bool {
+        self.is_virtual_disk
+    }
+
+    /// Whether an implementation must understand the item to open the file.
+    pub fn is_required(&self) -> bool {
+        self.is_required
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MetadataTable {
     signature: String,
     entries: Vec<MetadataTableEntry>,
 }
 
 impl MetadataTable {
-    fn read(file: &mut File) -> Result<Self, Error> {
+    /// Read the table at the start of a metadata region of `region_length`
+    /// bytes.
+    fn read(file: &mut impl Read, region_length: u32) -> Result<Self, Error> {
         let mut buffer = vec![0; 32];
         file.read_exact(&mut buffer)?;
 
+        if &buffer[0..8] != METADATA_TABLE_SIGNATURE.as_bytes() {
+            return Err(Error::InvalidImage("invalid metadata table signature"));
+        }
         let signature = String::from_utf8(buffer[0..8].to_vec())?;
         let entry_count = u16::from_le_bytes(buffer[10..12].try_into().expect("infallible"));
-
-        assert_eq!(signature, METADATA_TABLE_SIGNATURE);
-        assert!(entry_count <= 2047);
+        if entry_count > 2047 {
+            return Err(Error::InvalidImage("too many metadata table entries"));
+        }
 
         let mut entries = Vec::with_capacity(entry_count as usize);
         for _ in 0..entry_count {
-            entries.push(MetadataTableEntry::read(file)?);
+            entries.push(MetadataTableEntry::read(file, region_length)?);
         }
 
         Ok(Self { signature, entries })
     }
 
-    fn get<T: MetadataItem>(&self, file: &mut File, offset: u64) -> Result<Option<T>, Error> {
+    /// Serialise the full 64 KB metadata table.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![0; 64 * KB];
+        buffer[0..8].copy_from_slice(METADATA_TABLE_SIGNATURE.as_bytes());
+        buffer[10..12].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        for (i, entry) in self.entries.iter().enumerate() {
+            entry.write_to(&mut buffer[32 + 32 * i..][..32]);
+        }
+        buffer
+    }
+
+    fn get<T: MetadataItem>(
+        &self,
+        file: &mut (impl Read + Seek),
+        offset: u64,
+    ) -> Result<Option<T>, Error> {
         self.entries
             .iter()
             .find(|e| e.item_id == T::GUID)
             .filter(|e| !e.is_empty)
             .map(|e| {
+                let mut data = vec![0; e.length as usize];
                 file.seek(SeekFrom::Start(offset + e.offset as u64))?;
-                T::read(file)
+                file.read_exact(&mut data)?;
+                T::read(&mut data.as_slice())
             })
             .transpose()
     }
@@ -287,29 +537,95 @@ struct HeaderSection {
     file_type_identifier: FileTypeIdentifier,
     header_1: Header,
     header_2: Header,
+    /// Whether header 1 is the current header, rather than header 2
+    is_header_1_current: bool,
+    /// The region table in use, which is a copy of region table 2 when region
+    /// table 1 is invalid
     region_table_1: RegionTable,
     region_table_2: RegionTable,
 }
 
 impl HeaderSection {
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(file: &mut (impl Read + Seek)) -> Result<Self, Error> {
         let file_type_identifier = FileTypeIdentifier::read(file)?;
         file.seek(SeekFrom::Start(64 * KB as u64))?;
-        let header_1 = Header::read(file)?;
+        let header_1 = Header::read(file);
         file.seek(SeekFrom::Start(128 * KB as u64))?;
-        let header_2 = Header::read(file)?;
-        file.seek(SeekFrom::Start(192 * KB as u64))?;
-        let region_table_1 = RegionTable::read(file)?;
-        file.seek(SeekFrom::Start(256 * KB as u64))?;
-        let region_table_2 = RegionTable::read(file)?;
+        let header_2 = Header::read(file);
+
+        // The current header is the valid one with the highest sequence
+        // number. If one header is invalid, as after a torn write, the other
+        // is used in its place, so that the invalid one is written over next.
+        let (header_1, header_2, is_header_1_current) = match (header_1, header_2) {
+            (Ok(header_1), Ok(header_2)) => {
+                let is_header_1_current = header_1.sequence_number > header_2.sequence_number;
+                (header_1, header_2, is_header_1_current)
+            }
+            (Ok(header), Err(_)) => (header.clone(), header, true),
+            (Err(_), Ok(header)) => (header.clone(), header, false),
+            (Err(error), Err(_)) => return Err(error),
+        };
 
-        Ok(Self {
+        let (region_table_1, region_table_2) = Self::read_region_tables(file)?;
+
+        let header_section = Self {
             file_type_identifier,
             header_1,
             header_2,
+            is_header_1_current,
             region_table_1,
             region_table_2,
-        })
+        };
+        let header = header_section.current_header();
+        if !header.log_offset.is_multiple_of(MB as u64)
+            || !header.log_length.is_multiple_of(MB as u32)
+        {
+            return Err(Error::InvalidHeader("the log is not aligned to 1 MB"));
+        }
+        for entry in &header_section.region_table().entries {
+            if !entry.file_offset.is_multiple_of(MB as u64)
+                || !entry.length.is_multiple_of(MB as u32)
+            {
+                return Err(Error::InvalidRegionTable("region is not aligned to 1 MB"));
+            }
+            if entry.file_offset < MB as u64 {
+                return Err(Error::InvalidRegionTable(
+                    "region overlaps the header section",
+                ));
+            }
+        }
+        Ok(header_section)
+    }
+
+    /// Read both region tables. Region table 1 is used unless it is invalid,
+    /// in which case region table 2 is used in its place.
+    fn read_region_tables(
+        file: &mut (impl Read + Seek),
+    ) -> Result<(RegionTable, RegionTable), Error> {
+        file.seek(SeekFrom::Start(192 * KB as u64))?;
+        let region_table_1 = RegionTable::read(file);
+        file.seek(SeekFrom::Start(256 * KB as u64))?;
+        let region_table_2 = RegionTable::read(file);
+        match (region_table_1, region_table_2) {
+            (Ok(region_table_1), Ok(region_table_2)) => Ok((region_table_1, region_table_2)),
+            (Ok(region_table), Err(_)) | (Err(_), Ok(region_table)) => {
+                Ok((region_table.clone(), region_table))
+            }
+            (Err(error), Err(_)) => Err(error),
+        }
+    }
+
+    /// The region table in use.
+    fn region_table(&self) -> &RegionTable {
+        &self.region_table_1
+    }
+
+    fn current_header(&self) -> &Header {
+        if self.is_header_1_current {
+            &self.header_1
+        } else {
+            &self.header_2
+        }
     }
 }
 
@@ -325,7 +641,7 @@ struct Metadata {
 }
 impl Metadata {
     fn from_table(
-        file: &mut File,
+        file: &mut (impl Read + Seek),
         metadata_table: &MetadataTable,
         offset: u64,
     ) -> Result<Self, Error> {
@@ -365,6 +681,18 @@ pub struct Vhdx {
     metadata_table: MetadataTable,
     metadata: Metadata,
     bat: bat::Bat,
+    /// The parent of a differencing disk, opened read-only
+    parent: Option<Box<Vhdx>>,
+    /// Present once the file has been modified, after the headers have been
+    /// updated with a new log GUID
+    log_writer: Option<log::LogWriter>,
+    data_write_guid_updated: bool,
+    /// Set when opened read-only or in best effort mode
+    read_only: bool,
+    /// Present when the file is read through a memory mapping
+    mapping: Option<Mapping>,
+    /// Present when opened with a cache size
+    cache: Option<cache::BlockCache>,
 }
 
 impl Vhdx {
@@ -373,66 +701,441 @@ impl Vhdx {
     /// Through opening the file, if there is a log to be replayed it will be
     /// applied during this function.
     pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let mut file = File::options().read(true).write(true).open(path)?;
-        let header_section = HeaderSection::read(&mut file)?;
+        Self::options().open(path)
+    }
+
+    /// Options for how to open a VHDX file, such as opening files with
+    /// unknown required metadata for recovery.
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// Whether the file was opened read-only, in which case any modification
+    /// fails with [`Error::ReadOnly`].
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
 
-        // TODO: how do we choose between region table 1 and region table 2?
+    /// Read the metadata and BAT regions that are pointed to by the region
+    /// table, through the mapping if the file is memory-mapped.
+    fn read_regions(
+        file: &mut File,
+        mapping: Option<&Mapping>,
+        header_section: &HeaderSection,
+    ) -> Result<(MetadataTable, Metadata, bat::Bat), Error> {
         // Find the metadata table
         let metadata_table_section = header_section
-            .region_table_1
+            .region_table()
             .entries
             .iter()
             .find(|entry| entry.guid == REGION_GUID_METADATA)
             .ok_or(Error::MissingRequiredRegion("metadata"))?;
 
-        file.seek(SeekFrom::Start(metadata_table_section.file_offset))?;
-        let metadata_table = MetadataTable::read(&mut file)?;
-        let metadata = Metadata::from_table(
-            &mut file,
-            &metadata_table,
-            metadata_table_section.file_offset,
-        )?;
+        let (metadata_table, metadata) = match mapping {
+            Some(mapping) => Self::read_metadata(&mut mapping.cursor(), metadata_table_section)?,
+            None => Self::read_metadata(file, metadata_table_section)?,
+        };
 
         // Find the BAT table
         let bat_table_section = header_section
-            .region_table_1
+            .region_table()
             .entries
             .iter()
             .find(|entry| entry.guid == REGION_GUID_BAT)
             .ok_or(Error::MissingRequiredRegion("bat"))?;
-        file.seek(SeekFrom::Start(bat_table_section.file_offset))?;
-        let bat = bat::Bat::read(&mut file, &metadata)?;
-
-        let mut disk = Vhdx {
-            file,
-            header_section,
-            metadata_table,
-            metadata,
-            bat,
+        let bat = match mapping {
+            Some(mapping) => {
+                bat::Bat::mapped(mapping.clone(), bat_table_section.file_offset, &metadata)
+            }
+            None => bat::Bat::open(file, bat_table_section.file_offset, &metadata)?,
         };
-        disk.try_replay_log()?;
 
-        Ok(disk)
+        Ok((metadata_table, metadata, bat))
+    }
+
+    /// Read the table of the metadata region, and the system metadata items.
+    fn read_metadata(
+        file: &mut (impl Read + Seek),
+        region: &RegionTableEntry,
+    ) -> Result<(MetadataTable, Metadata), Error> {
+        file.seek(SeekFrom::Start(region.file_offset))?;
+        let metadata_table = MetadataTable::read(file, region.length)?;
+        let metadata = Metadata::from_table(file, &metadata_table, region.file_offset)?;
+        Ok((metadata_table, metadata))
+    }
+
+    /// Re-read the region tables, and the regions they point to, after they
+    /// have been modified.
+    fn reload_regions(&mut self) -> Result<(), Error> {
+        (
+            self.header_section.region_table_1,
+            self.header_section.region_table_2,
+        ) = HeaderSection::read_region_tables(&mut self.file)?;
+
+        let (metadata_table, metadata, bat) =
+            Self::read_regions(&mut self.file, self.mapping.as_ref(), &self.header_section)?;
+        self.metadata_table = metadata_table;
+        self.metadata = metadata;
+        self.bat = bat;
+        Ok(())
+    }
+
+    /// Discard a range of the virtual disk, as for a TRIM or UNMAP command.
+    ///
+    /// Blocks that are entirely within the range are marked as unmapped. Unless
+    /// the file requires blocks to be left allocated, their space in the file
+    /// is released, by punching a hole where the platform supports it. Parts
+    /// of blocks within the range are overwritten with zeros.
+    pub fn discard(&mut self, offset: u64, length: u64) -> Result<(), Error> {
+        self.zero_range(offset, length, PayloadBatEntryState::Unmapped)
+    }
+
+    /// Write zeros to a range of the virtual disk, without writing the zeros
+    /// to the file where possible.
+    ///
+    /// Blocks that are entirely within the range are marked as zero, and their
+    /// space in the file is released in the same way as for
+    /// [`Vhdx::discard`]. Only the parts of allocated blocks at the start and
+    /// end of the range are overwritten with zeros.
+    pub fn write_zeroes(&mut self, offset: u64, length: u64) -> Result<(), Error> {
+        self.zero_range(offset, length, PayloadBatEntryState::Zero)
+    }
+
+    /// The size of the virtual disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.metadata.virtual_disk_size.virtual_disk_size()
+    }
+
+    /// The size of each payload block in bytes.
+    pub fn block_size(&self) -> u32 {
+        self.metadata.file_parameters.block_size()
+    }
+
+    /// The sector size presented by the virtual disk, either 512 or 4096 bytes.
+    pub fn logical_sector_size(&self) -> u32 {
+        self.metadata.logical_sector_size.logical_sector_size()
+    }
+
+    /// The physical sector size reported by the virtual disk, either 512 or
+    /// 4096 bytes.
+    pub fn physical_sector_size(&self) -> u32 {
+        self.metadata.physical_sector_size.physical_sector_size()
+    }
+
+    /// The identifier of the virtual disk, which stays the same for the
+    /// lifetime of the disk.
+    pub fn virtual_disk_id(&self) -> Guid {
+        self.metadata.virtual_disk_id.virtual_disk_id()
+    }
+
+    /// Whether this is a differencing disk with a parent.
+    pub fn has_parent(&self) -> bool {
+        self.metadata.file_parameters.has_parent()
+    }
+
+    /// The parent of a differencing disk, which is opened along with the disk.
+    ///
+    /// This is none for disks without a parent, and for differencing disks
+    /// opened in best effort mode whose parent could not be opened.
+    pub fn parent(&self) -> Option<&Vhdx> {
+        self.parent.as_deref()
+    }
+
+    /// Whether blocks must be left allocated in the file, as for a fixed
+    /// disk.
+    pub fn leave_block_allocated(&self) -> bool {
+        self.metadata.file_parameters.leave_block_allocated()
+    }
+
+    /// The application that created the file.
+    pub fn creator(&self) -> &str {
+        &self.header_section.file_type_identifier.creator
+    }
+
+    /// The identifier that changes each time the file is opened for
+    /// modification.
+    pub fn file_write_guid(&self) -> Guid {
+        self.current_header().file_write_guid
+    }
+
+    /// The identifier that changes each time the contents of the virtual disk
+    /// are modified.
+    pub fn data_write_guid(&self) -> Guid {
+        self.current_header().data_write_guid
+    }
+
+    /// The version of the file format.
+    pub fn format_version(&self) -> u16 {
+        self.current_header().version
+    }
+
+    /// The sequence number of the current header, which increases each time
+    /// the headers are updated.
+    pub fn sequence_number(&self) -> u64 {
+        self.current_header().sequence_number
+    }
+
+    /// The identifier of the active log, which is nil when the log is empty.
+    pub fn log_guid(&self) -> Guid {
+        self.current_header().log_guid
+    }
+
+    /// The entries in the region table.
+    pub fn region_entries(&self) -> &[RegionTableEntry] {
+        &self.header_section.region_table().entries
+    }
+
+    /// The entries of the block allocation table, in the order they are
+    /// stored, with the payload or sector bitmap block that each describes.
+    ///
+    /// The BAT is read from the file as it is iterated.
+    pub fn bat_entries(
+        &self,
+    ) -> impl Iterator<Item = Result<(bat::Block, bat::BatEntry), Error>> + '_ {
+        self.bat.entries().map(|result| result.map_err(Error::from))
+    }
+
+    /// The state of a payload block, which covers the virtual disk from
+    /// `block_index * block_size`.
+    pub fn block_state(&self, block_index: u64) -> Result<PayloadBatEntryState, Error> {
+        self.bat
+            .payload_entry(block_index)?
+            .map(|entry| entry.state())
+            .ok_or(Error::OutOfBounds)
     }
 
     /// Use the disk as a [`Reader`] that implements [`std::io::Read`] and [`std::io::Seek`].
-    pub fn reader(&mut self) -> Reader {
+    pub fn reader(&mut self) -> Reader<'_> {
         Reader {
             disk: self,
             offset: 0,
+            read_ahead: ReadAhead::default(),
+        }
+    }
+
+    /// How reads have been served by the block cache, which is enabled with
+    /// [`OpenOptions::cache_size`]. The counters are zero without a cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(cache::BlockCache::stats)
+            .unwrap_or_default()
+    }
+
+    /// Read from the virtual disk at `offset`, stopping at the end of the
+    /// block or of a run of sectors that are stored in the same place.
+    ///
+    /// Returns zero at the end of the disk.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(cache) = &mut self.cache else {
+            return self.read_uncached(offset, buf);
+        };
+        if offset.is_multiple_of(cache::CHUNK_SIZE) && buf.len() as u64 >= cache::CHUNK_SIZE {
+            return self.read_uncached(offset, buf);
+        }
+        if let Some(num_read) = cache.read(offset, buf) {
+            return Ok(num_read);
+        }
+
+        let chunk_start = offset - offset % cache::CHUNK_SIZE;
+        let disk_size = self.metadata.virtual_disk_size.virtual_disk_size();
+        if chunk_start >= disk_size {
+            // eof
+            return Ok(0);
+        }
+        let mut chunk = vec![0; cache::CHUNK_SIZE.min(disk_size - chunk_start) as usize];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match self.read_uncached(chunk_start + filled as u64, &mut chunk[filled..])? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                num_read => filled += num_read,
+            }
+        }
+
+        let rest = chunk
+            .get((offset - chunk_start) as usize..)
+            .unwrap_or_default();
+        let num_read = buf.len().min(rest.len());
+        buf[..num_read].copy_from_slice(&rest[..num_read]);
+        let cache = self.cache.as_mut().expect("cache is enabled");
+        cache.insert(chunk_start, chunk.into_boxed_slice());
+        Ok(num_read)
+    }
+
+    /// Read from the virtual disk at `offset` without the block cache, as
+    /// for [`Vhdx::read_at`].
+    fn read_uncached(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((entry, offset_in_block)) = self.bat.offset_to_entry(offset)? else {
+            // eof
+            return Ok(0);
+        };
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let disk_size = self.metadata.virtual_disk_size.virtual_disk_size();
+        // The last block may extend past the end of the virtual disk
+        let bytes_remaining_in_block =
+            (block_size - offset_in_block).min(disk_size.saturating_sub(offset));
+        let num_to_read = buf.len().min(bytes_remaining_in_block as usize);
+        let dest_slice = &mut buf[..num_to_read];
+
+        use bat::PayloadBatEntryState::*;
+        match entry.state() {
+            Zero | Unmapped => {
+                dest_slice.fill(0);
+                Ok(num_to_read)
+            }
+            NotPresent | Undefined | Reserved(_) => self.read_parent(offset, dest_slice),
+            FullyPresent => self.read_file(entry.file_offset() + offset_in_block, dest_slice),
+            PartiallyPresent => {
+                let (present, length) = self.sector_run(offset, dest_slice.len())?;
+                let dest_slice = &mut dest_slice[..length];
+                if present {
+                    self.read_file(entry.file_offset() + offset_in_block, dest_slice)
+                } else {
+                    self.read_parent(offset, dest_slice)
+                }
+            }
+        }
+    }
+
+    /// Read from the file at `file_offset`, through the mapping if the file
+    /// is memory-mapped.
+    fn read_file(&mut self, file_offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(mapping) = &self.mapping {
+            return Ok(mapping.read_at(file_offset, buf));
+        }
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        self.file.read(buf)
+    }
+
+    /// Read data that is not stored in this file, which is either from the
+    /// parent of a differencing disk or zero.
+    fn read_parent(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.metadata.file_parameters.has_parent() {
+            buf.fill(0);
+            return Ok(buf.len());
+        }
+        let Some(parent) = &mut self.parent else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                Error::ParentNotFound,
+            ));
+        };
+
+        // Beyond the end of a smaller parent is read as zeros
+        match parent.read_at(offset, buf)? {
+            0 => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            num_read => Ok(num_read),
         }
     }
 
+    /// Find whether the sector at `offset` in a partially present block is
+    /// stored in this file, and the length of the run of sectors from
+    /// `offset` (up to `max_length`) that are stored in the same place.
+    fn sector_run(&mut self, offset: u64, max_length: usize) -> std::io::Result<(bool, usize)> {
+        let sector_size = self.metadata.logical_sector_size.logical_sector_size() as u64;
+        let sectors_per_chunk = 1 << 23;
+
+        let sector = offset / sector_size;
+        let chunk_index = sector / sectors_per_chunk;
+        let Some(bitmap) = self
+            .bat
+            .sector_bitmap_entry(chunk_index)?
+            .filter(|entry| entry.sector_bitmap_state() == SectorBitmapBatEntryState::Present)
+        else {
+            return Ok((false, max_length));
+        };
+
+        // Read just the bits covering the requested range
+        let first_bit = sector % sectors_per_chunk;
+        let last_bit = (offset + max_length as u64).div_ceil(sector_size)
+            - 1
+            - chunk_index * sectors_per_chunk;
+        let last_bit = last_bit.min(sectors_per_chunk - 1);
+        let mut bits = vec![0; (last_bit / 8 - first_bit / 8 + 1) as usize];
+        let bits_offset = bitmap.file_offset() + first_bit / 8;
+        match &self.mapping {
+            Some(mapping) => mapping.read_exact_at(bits_offset, &mut bits)?,
+            None => {
+                self.file.seek(SeekFrom::Start(bits_offset))?;
+                self.file.read_exact(&mut bits)?;
+            }
+        }
+
+        let is_present = |bit: u64| {
+            let bit = bit - first_bit / 8 * 8;
+            bits[(bit / 8) as usize] >> (bit % 8) & 1 == 1
+        };
+        let present = is_present(first_bit);
+        let run_end = (first_bit..=last_bit)
+            .find(|&bit| is_present(bit) != present)
+            .map_or(u64::MAX, |bit| {
+                (chunk_index * sectors_per_chunk + bit) * sector_size
+            });
+
+        let length = (run_end - offset).min(max_length as u64) as usize;
+        Ok((present, length))
+    }
+
+    /// The ranges of the virtual disk within `start..end` that contain data
+    /// in this disk or any of its parents, merged into `ranges`.
+    fn data_ranges(
+        &self,
+        start: u64,
+        end: u64,
+        ranges: &mut Vec<(u64, u64)>,
+    ) -> std::io::Result<()> {
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let end = end.min(self.metadata.virtual_disk_size.virtual_disk_size());
+
+        let mut offset = start;
+        while offset < end {
+            let block_index = offset / block_size;
+            let block_end = ((block_index + 1) * block_size).min(end);
+            let state = self
+                .bat
+                .payload_entry(block_index)?
+                .expect("offset is within the virtual disk")
+                .state();
+
+            match state {
+                PayloadBatEntryState::FullyPresent | PayloadBatEntryState::PartiallyPresent => {
+                    match ranges.last_mut() {
+                        Some((_, last_end)) if *last_end == offset => *last_end = block_end,
+                        _ => ranges.push((offset, block_end)),
+                    }
+                }
+                PayloadBatEntryState::NotPresent
+                | PayloadBatEntryState::Undefined
+                | PayloadBatEntryState::Reserved(_) => {
+                    if let Some(parent) = &self.parent {
+                        parent.data_ranges(offset, block_end, ranges)?;
+                    }
+                }
+                PayloadBatEntryState::Zero | PayloadBatEntryState::Unmapped => {}
+            }
+            offset = block_end;
+        }
+        Ok(())
+    }
+
     /// Find the active sequence of the log.
     ///
-    /// This function does not care if the log is empty or has no valid entries,
-    /// and may not return valid entries if it is called in this state.
-    fn find_log(&mut self) -> Result<LogSequence, Error> {
-        let current_header = self.current_header();
+    /// Returns none if there are no valid sequences in the log, in which case
+    /// there is nothing to replay.
+    fn find_log(file: &mut File, current_header: &Header) -> Result<Option<LogSequence>, Error> {
         let log_guid = current_header.log_guid;
         let log_offset = current_header.log_offset;
         let log_length = current_header.log_length;
-        println!("log length {} at offset 0x{:X}", log_length, log_offset);
 
         // From 2.3.3 Log Replay
         // Tail is earlier on in the file, head is later
@@ -453,13 +1156,12 @@ impl Vhdx {
                 entries: Vec::new(),
             };
             let mut head_value = current_tail;
-            self.file.seek(SeekFrom::Start(current_tail))?;
+            file.seek(SeekFrom::Start(current_tail))?;
 
             // Step 3
             loop {
-                let entry_offset = self.file.stream_position()?;
-                //println!("Attempting to read entry at offset {}", entry_offset);
-                match log::Entry::read(&mut self.file) {
+                let entry_offset = file.stream_position()?;
+                match log::Entry::read(file, log_length) {
                     Ok(entry) => {
                         // Check if the entry matches the guid in the file header
                         if entry.header().log_guid() != log_guid {
@@ -469,7 +1171,7 @@ impl Vhdx {
                             // Extend the current sequence to include the log entry
                             current.sequence_number = entry.header().sequence_number;
                             current.entries.push((entry_offset - log_offset, entry));
-                            head_value = entry_offset;
+                            head_value = file.stream_position()?;
                         } else if entry.header().sequence_number
                             == current
                                 .head()
@@ -480,10 +1182,15 @@ impl Vhdx {
                         {
                             // Extend the current sequence to include the log entry
                             current.entries.push((entry_offset - log_offset, entry));
-                            head_value = entry_offset;
+                            head_value = file.stream_position()?;
                         }
                     }
-                    Err(Error::InvalidSignature) => {
+                    Err(
+                        Error::InvalidSignature
+                        | Error::InvalidChecksum
+                        | Error::InvalidUtf8(_)
+                        | Error::InvalidLogEntry(_),
+                    ) => {
                         // Not a valid entry, stop searching
                         break;
                     }
@@ -501,7 +1208,6 @@ impl Vhdx {
             let is_current_sequence_empty = current.is_empty();
             if is_current_sequence_valid && current.sequence_number > candidate.sequence_number {
                 candidate = current;
-                //println!("new candidate sequence: {}", candidate.sequence_number);
             }
 
             // Step 6
@@ -525,131 +1231,371 @@ impl Vhdx {
         }
 
         if candidate.is_empty() {
-            panic!("no valid log sequences, file is corrupt");
+            return Ok(None);
         }
 
-        // Check if the file has been truncated since the log was written
-        let file_size = self.file.seek(SeekFrom::End(0))?;
-        if file_size
-            < candidate
-                .head()
-                .expect("candidate is not empty")
-                .header()
-                .flushed_file_offset
-        {
-            panic!("file has been truncated, cannot open");
-        }
-
-        let active_sequence = candidate;
-        println!(
-            "Found active sequence with {} entries ({} -> {})",
-            active_sequence.entries.len(),
-            active_sequence
-                .tail()
-                .expect("active sequence is not empty")
-                .header()
-                .sequence_number,
-            active_sequence
-                .head()
-                .expect("active sequence is not empty")
-                .header()
-                .sequence_number,
-        );
-
-        Ok(active_sequence)
+        Ok(Some(candidate))
     }
 
-    fn try_replay_log(&mut self) -> Result<(), Error> {
+    /// Replay the active sequence of the log, if there is one.
+    ///
+    /// Returns whether any entries were replayed.
+    fn try_replay_log(file: &mut File, current_header: &Header) -> Result<bool, Error> {
         // Check if we should replay the log
-        let current_header = self.current_header();
         if current_header.log_guid == Guid::ZERO {
-            return Ok(());
+            return Ok(false);
         }
 
-        println!("replaying log");
-        let sequence = self.find_log()?;
+        let Some(sequence) = Self::find_log(file, current_header)? else {
+            return Ok(false);
+        };
+
+        // Check if the file has been truncated since the log was written
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if file_size < sequence.flushed_file_offset() {
+            return Err(Error::LogTruncated);
+        }
 
         // Replay the log
         for entry in sequence.iter() {
-            let mut data_sector_offset = 0;
-            for desc in entry.descriptors() {
-                match desc {
-                    log::Descriptor::Zero(desc) => {
-                        if desc.sequence_number() != entry.header().sequence_number {
-                            panic!("descriptor does not have the correct sequence number");
-                        }
+            entry.apply(file)?;
+        }
+        file.sync_data()?;
 
-                        // TODO: Do we need to expand the file?
-                        let file_length = self.file.seek(SeekFrom::End(0))?;
-                        if desc.file_offset() >= file_length {
-                            panic!("zeros write start is greater than file length");
-                        }
-                        if desc.file_offset() + desc.zero_length() >= file_length {
-                            panic!("zeros write start is greater than file length");
-                        }
+        Ok(true)
+    }
 
-                        self.file.seek(SeekFrom::Start(desc.file_offset()))?;
-                        let num_sectors = desc.zero_length() / (4 * KB as u64);
-                        for _ in 0..num_sectors {
-                            self.file.write_all(&ZEROS)?;
-                        }
-                    }
-                    log::Descriptor::Data(desc) => {
-                        if desc.sequence_number() != entry.header().sequence_number {
-                            panic!("descriptor does not have the correct sequence number");
-                        }
+    fn current_header(&self) -> &Header {
+        self.header_section.current_header()
+    }
 
-                        let data_sector = &entry.data_sectors()[data_sector_offset];
+    fn region(&self, guid: Guid) -> Option<&RegionTableEntry> {
+        self.header_section
+            .region_table()
+            .entries
+            .iter()
+            .find(|entry| entry.guid == guid)
+    }
 
-                        // TODO: Do we need to expand the file?
-                        let file_length = self.file.seek(SeekFrom::End(0))?;
-                        if desc.file_offset() >= file_length {
-                            panic!("data write start is greater than file length");
-                        }
-                        if desc.file_offset() + 4 * KB as u64 >= file_length {
-                            panic!("data write end is greater than file length");
-                        }
-                        self.file.seek(SeekFrom::Start(desc.file_offset()))?;
-                        self.file.write_all(&desc.leading_bytes())?;
-                        self.file.write_all(data_sector.data())?;
-                        self.file.write_all(&desc.trailing_bytes())?;
+    /// The offset in the file of a metadata item's data, if it is present.
+    fn metadata_item_offset(&self, item_id: Guid) -> Option<u64> {
+        let region_offset = self.region(REGION_GUID_METADATA)?.file_offset;
+        self.metadata_table
+            .entries
+            .iter()
+            .find(|entry| entry.item_id == item_id && !entry.is_empty)
+            .map(|entry| region_offset + entry.offset as u64)
+    }
 
-                        data_sector_offset += 1;
-                    }
+    /// Write a modified copy of the current header with the next sequence
+    /// number over the non-current header, making it the current header.
+    fn update_header(&mut self, update: impl FnOnce(&mut Header)) -> Result<(), Error> {
+        let mut header = self.current_header().clone();
+        header.sequence_number += 1;
+        update(&mut header);
+
+        let (offset, slot) = if self.header_section.is_header_1_current {
+            (128 * KB as u64, &mut self.header_section.header_2)
+        } else {
+            (64 * KB as u64, &mut self.header_section.header_1)
+        };
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        header.write(&mut self.file)?;
+        self.file.sync_data()?;
+        *slot = header;
+        self.header_section.is_header_1_current = !self.header_section.is_header_1_current;
+
+        Ok(())
+    }
+
+    /// Prepare the file to be modified.
+    ///
+    /// Before the first modification the file write GUID is changed and a new
+    /// log is started, and before the first modification that is visible
+    /// through the virtual disk the data write GUID is changed.
+    fn begin_modification(&mut self, modifies_data: bool) -> Result<(), Error> {
+        self.check_writable()?;
+        let update_data_write_guid = modifies_data && !self.data_write_guid_updated;
+
+        if self.log_writer.is_none() {
+            let log_guid = Guid::new_random();
+            self.update_header(|header| {
+                header.file_write_guid = Guid::new_random();
+                header.log_guid = log_guid;
+                if update_data_write_guid {
+                    header.data_write_guid = Guid::new_random();
                 }
-            }
+            })?;
+
+            let header = self.current_header();
+            self.log_writer = Some(log::LogWriter::new(
+                log_guid,
+                header.log_offset,
+                header.log_length,
+            ));
+        } else if update_data_write_guid {
+            self.update_header(|header| header.data_write_guid = Guid::new_random())?;
         }
 
+        self.data_write_guid_updated |= modifies_data;
         Ok(())
     }
 
-    fn debug_log_sectors(&mut self, log_offset: u64, log_length: u32) -> Result<(), Error> {
-        let mut entry_offset = log_offset;
-        let stride = 4 * KB as u64;
-        while entry_offset - log_offset < log_length as u64 {
-            self.file.seek(SeekFrom::Start(entry_offset))?;
-            let mut buffer = vec![0; 64];
-            self.file.read_exact(&mut buffer)?;
-            let signature = String::from_utf8(buffer[0..4].to_vec())?;
-            if !buffer.iter().all(|c| *c == 0) {
-                println!(
-                    "Entry {}: '{}' (offset {})",
-                    (entry_offset - log_offset) / stride,
-                    signature,
-                    entry_offset,
+    /// Make a set of changes to the file through the log.
+    fn write_logged(&mut self, writes: &[log::LogWrite]) -> Result<(), Error> {
+        let log_writer = self
+            .log_writer
+            .as_mut()
+            .expect("modification has been started");
+        log_writer.write(&mut self.file, writes)?;
+        // The changes have been applied, so the BAT can be loaded from the
+        // file again
+        self.bat.mark_written();
+        Ok(())
+    }
+
+    /// Mark the log as empty once all of its entries have been applied.
+    fn clear_log(&mut self) -> Result<(), Error> {
+        if self.log_writer.take().is_some() {
+            self.update_header(|header| header.log_guid = Guid::ZERO)?;
+        }
+        Ok(())
+    }
+
+    /// Build the log writes that update the sectors of the BAT containing
+    /// the given payload blocks, after updating the in-memory BAT.
+    fn bat_writes(
+        &mut self,
+        updates: impl IntoIterator<Item = (u64, bat::BatEntry)>,
+    ) -> Result<Vec<log::LogWrite>, Error> {
+        let bat_offset = self
+            .region(REGION_GUID_BAT)
+            .ok_or(Error::MissingRequiredRegion("bat"))?
+            .file_offset;
+
+        let mut sectors = std::collections::BTreeSet::new();
+        for (payload_block_index, entry) in updates {
+            self.bat.set_payload_entry(payload_block_index, entry)?;
+            sectors.insert(self.bat.payload_entry_sector(payload_block_index));
+        }
+
+        sectors
+            .into_iter()
+            .map(|sector_index| {
+                Ok(log::LogWrite::Data {
+                    file_offset: bat_offset + sector_index * 4 * KB as u64,
+                    sector: self.bat.sector(sector_index)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Build the log writes that zero a range of the file.
+    ///
+    /// Whole 4 KB sectors are zeroed directly, and sectors that are only
+    /// partially within the range are rewritten with the range zeroed.
+    fn zero_writes(&mut self, start: u64, end: u64) -> Result<Vec<log::LogWrite>, Error> {
+        let sector_size = 4 * KB as u64;
+        let aligned_start = log::next_multiple_of(start, sector_size);
+        let aligned_end = end - end % sector_size;
+        let mut writes = Vec::new();
+
+        if !start.is_multiple_of(sector_size) {
+            let sector_start = start - start % sector_size;
+            let mut sector = self.read_sector(sector_start)?;
+            let zero_end = end.min(sector_start + sector_size);
+            sector[(start - sector_start) as usize..(zero_end - sector_start) as usize].fill(0);
+            writes.push(log::LogWrite::Data {
+                file_offset: sector_start,
+                sector,
+            });
+        }
+        if aligned_start < aligned_end {
+            writes.push(log::LogWrite::Zero {
+                file_offset: aligned_start,
+                length: aligned_end - aligned_start,
+            });
+        }
+        if !end.is_multiple_of(sector_size) && aligned_end >= aligned_start {
+            let mut sector = self.read_sector(aligned_end)?;
+            sector[..(end - aligned_end) as usize].fill(0);
+            writes.push(log::LogWrite::Data {
+                file_offset: aligned_end,
+                sector,
+            });
+        }
+
+        Ok(writes)
+    }
+
+    /// Build the log writes that replace a range of the file with `data`,
+    /// preserving the rest of any 4 KB sectors that are partially covered.
+    fn range_writes(&mut self, file_offset: u64, data: &[u8]) -> Result<Vec<log::LogWrite>, Error> {
+        let sector_size = 4 * KB as u64;
+        let end = file_offset + data.len() as u64;
+
+        let mut writes = Vec::new();
+        let mut sector_start = file_offset - file_offset % sector_size;
+        while sector_start < end {
+            let mut sector = if sector_start >= file_offset && sector_start + sector_size <= end {
+                Box::new([0; 4 * KB])
+            } else {
+                self.read_sector(sector_start)?
+            };
+
+            let copy_start = file_offset.max(sector_start);
+            let copy_end = end.min(sector_start + sector_size);
+            sector[(copy_start - sector_start) as usize..(copy_end - sector_start) as usize]
+                .copy_from_slice(
+                    &data[(copy_start - file_offset) as usize..(copy_end - file_offset) as usize],
                 );
+            writes.push(log::LogWrite::Data {
+                file_offset: sector_start,
+                sector,
+            });
+
+            sector_start += sector_size;
+        }
+
+        Ok(writes)
+    }
+
+    /// Build the log writes that update both copies of the region table to
+    /// point a region at a new location.
+    fn region_table_writes(
+        &mut self,
+        guid: Guid,
+        file_offset: u64,
+        length: u32,
+    ) -> Result<Vec<log::LogWrite>, Error> {
+        let mut region_table = self.header_section.region_table().clone();
+        let region = region_table
+            .entries
+            .iter_mut()
+            .find(|entry| entry.guid == guid)
+            .ok_or(Error::MissingRequiredRegion("relocated region"))?;
+        region.file_offset = file_offset;
+        region.length = length;
+
+        let region_table = region_table.to_bytes();
+        let mut writes = self.range_writes(192 * KB as u64, &region_table)?;
+        writes.extend(self.range_writes(256 * KB as u64, &region_table)?);
+        Ok(writes)
+    }
+
+    /// Reserve space for new data at the end of the file, returning its
+    /// offset.
+    fn allocate(&mut self, length: u64) -> Result<u64, Error> {
+        let file_size = self.file.seek(SeekFrom::End(0))?;
+        let offset = log::next_multiple_of(file_size, MB as u64);
+        self.file.set_len(offset + length)?;
+        Ok(offset)
+    }
+
+    fn read_sector(&mut self, file_offset: u64) -> Result<Box<[u8; 4 * KB]>, Error> {
+        let mut sector = Box::new([0; 4 * KB]);
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        self.file.read_exact(sector.as_mut())?;
+        Ok(sector)
+    }
+
+    /// Make a range of the virtual disk read as zeros.
+    ///
+    /// Allocated blocks that are entirely within the range are transitioned
+    /// to `block_state`, and allocated blocks that are partially within the
+    /// range have that part zeroed. Blocks that are not allocated already read
+    /// as zeros, and are only transitioned if `block_state` guarantees zeros
+    /// where their current state does not.
+    fn zero_range(
+        &mut self,
+        offset: u64,
+        length: u64,
+        block_state: PayloadBatEntryState,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        let disk_size = self.metadata.virtual_disk_size.virtual_disk_size();
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let leave_block_allocated = self.metadata.file_parameters.leave_block_allocated();
+
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= disk_size)
+            .ok_or(Error::OutOfBounds)?;
+        if length == 0 {
+            return Ok(());
+        }
+        if self.metadata.file_parameters.has_parent() {
+            return Err(Error::Unsupported("modifying differencing disks"));
+        }
+
+        let mut bat_updates = Vec::new();
+        let mut freed_blocks = Vec::new();
+        let mut zero_writes = Vec::new();
+        for block_index in offset / block_size..=(end - 1) / block_size {
+            let entry = self
+                .bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+
+            let block_start = block_index * block_size;
+            let block_end = (block_start + block_size).min(disk_size);
+            let start = offset.max(block_start);
+            let stop = end.min(block_end);
+            let is_whole_block = start == block_start && stop == block_end;
+
+            match (entry.state(), is_whole_block) {
+                (state, true) if state.is_allocated() => {
+                    if leave_block_allocated {
+                        bat_updates.push((block_index, entry.with_state(block_state)));
+                    } else {
+                        bat_updates.push((block_index, bat::BatEntry::new(block_state)));
+                        freed_blocks.push(entry.file_offset());
+                    }
+                }
+                (state, false) if state.is_allocated() => {
+                    let file_start = entry.file_offset() + (start - block_start);
+                    let file_end = entry.file_offset() + (stop - block_start);
+                    zero_writes.extend(self.zero_writes(file_start, file_end)?);
+                }
+                // The contents of unmapped and undefined blocks are not
+                // guaranteed to be zero, so they are made explicitly zero
+                (PayloadBatEntryState::Unmapped | PayloadBatEntryState::Undefined, true)
+                    if block_state == PayloadBatEntryState::Zero =>
+                {
+                    bat_updates.push((block_index, entry.with_state(block_state)));
+                }
+                _ => {}
             }
-            entry_offset += stride;
         }
+
+        if bat_updates.is_empty() && zero_writes.is_empty() {
+            return Ok(());
+        }
+
+        self.begin_modification(true)?;
+        let mut writes = self.bat_writes(bat_updates)?;
+        writes.append(&mut zero_writes);
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(offset, end);
+        }
+        self.write_logged(&writes)?;
+
+        // The blocks are no longer referenced by the BAT, so their space can
+        // be released
+        for file_offset in freed_blocks {
+            sparse::punch_hole(&self.file, file_offset, block_size)?;
+        }
+
         Ok(())
     }
+}
 
-    fn current_header(&self) -> &Header {
-        std::cmp::max_by_key(
-            &self.header_section.header_1,
-            &self.header_section.header_2,
-            |header| header.sequence_number,
-        )
+impl Drop for Vhdx {
+    fn drop(&mut self) {
+        // Every log entry is applied as soon as it is written, so the log can
+        // always be marked as empty when the file is closed
+        let _ = self.clear_log();
     }
 }
 
@@ -688,6 +1634,13 @@ impl LogSequence {
         self.entries.is_empty()
     }
 
+    /// The size of the file when the head entry was written, which it must be
+    /// at least for the log to be replayed.
+    fn flushed_file_offset(&self) -> u64 {
+        self.head()
+            .map_or(0, |entry| entry.header().flushed_file_offset)
+    }
+
     /// Iterate over the sequence in order from tail to head.
     fn iter(&self) -> impl Iterator<Item = &log::Entry> {
         self.entries.iter().map(|(_, entry)| entry)
@@ -700,40 +1653,116 @@ impl LogSequence {
 pub struct Reader<'a> {
     disk: &'a mut Vhdx,
     offset: u64,
+    read_ahead: ReadAhead,
 }
 
-impl Read for Reader<'_> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // Read at most to the end of this block
-        let Some((entry, offset)) = self.disk.bat.offset_to_entry(self.offset) else {
-            // eof
-            return Ok(0);
-        };
-        let block_size = self.disk.metadata.file_parameters.block_size() as usize;
-        let bytes_remaining_in_block = block_size as u64 - offset;
-        let num_to_read = buf.len().min(bytes_remaining_in_block as usize);
-        let dest_slice = &mut buf[..num_to_read];
+impl Reader<'_> {
+    /// Once reads are sequential, read up to `window` bytes ahead of the
+    /// position, in large reads of the blocks that are stored in the file.
+    /// Blocks whose data isn't stored in the file, such as unallocated
+    /// blocks, are skipped, and are read as usual when they are reached.
+    ///
+    /// A window of zero disables read-ahead, which is the default. A window
+    /// of a few blocks suits scanning the whole disk.
+    pub fn read_ahead(&mut self, window: u64) -> &mut Self {
+        self.read_ahead.set_window(window);
+        self
+    }
 
-        self.offset += num_to_read as u64;
+    /// Read ahead on a background thread, so that reading from the file
+    /// overlaps with processing the data that has already been read. This has
+    /// no effect without a read-ahead window.
+    ///
+    /// Without a background thread, reading ahead only replaces many small
+    /// reads of the file with fewer large ones, which helps little where the
+    /// operating system already reads ahead of sequential reads.
+    pub fn read_ahead_in_background(&mut self, background: bool) -> &mut Self {
+        self.read_ahead.set_background(background);
+        self
+    }
 
-        use bat::PayloadBatEntryState::*;
-        let num_actually_read = match entry.state() {
-            NotPresent | Undefined | Zero | Unmapped => {
-                // Return zeros
-                dest_slice.fill(0);
-                num_to_read
+    /// Move to the start of the next allocated region of the disk at or after
+    /// `offset`, returning the new position.
+    ///
+    /// This mirrors `lseek(SEEK_DATA)`: if `offset` is within an allocated
+    /// block then the position is `offset` itself. If there is no data at or
+    /// after `offset`, an error of kind [`std::io::ErrorKind::InvalidInput`]
+    /// is returned (the equivalent of `ENXIO`) and the position is unchanged.
+    pub fn seek_data(&mut self, offset: u64) -> std::io::Result<u64> {
+        let disk_size = self.disk.metadata.virtual_disk_size.virtual_disk_size();
+        let block_size = self.disk.metadata.file_parameters.block_size() as u64;
+
+        // Search one block at a time, as the data of a differencing disk may
+        // come from its parents
+        let mut ranges = Vec::new();
+        let mut position = offset;
+        while position < disk_size {
+            let block_end = (position / block_size + 1) * block_size;
+            self.disk.data_ranges(position, block_end, &mut ranges)?;
+            if let Some(&(start, _)) = ranges.first() {
+                self.offset = start;
+                return Ok(self.offset);
             }
-            FullyPresent => {
-                // Read from file
-                self.disk
-                    .file
-                    .seek(SeekFrom::Start(entry.file_offset() + offset))?;
-                self.disk.file.read(dest_slice)?
+            position = block_end;
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no data at or after offset",
+        ))
+    }
+
+    /// Move to the start of the next unallocated region of the disk at or
+    /// after `offset`, returning the new position.
+    ///
+    /// This mirrors `lseek(SEEK_HOLE)`: blocks that are not present (in this
+    /// disk or any of its parents), zero or unmapped are holes, and there is
+    /// always an implicit hole at the end of the disk. If `offset` is beyond
+    /// the end of the disk, an error of kind
+    /// [`std::io::ErrorKind::InvalidInput`] is returned (the equivalent of
+    /// `ENXIO`) and the position is unchanged.
+    pub fn seek_hole(&mut self, offset: u64) -> std::io::Result<u64> {
+        let disk_size = self.disk.metadata.virtual_disk_size.virtual_disk_size();
+        let block_size = self.disk.metadata.file_parameters.block_size() as u64;
+
+        if offset >= disk_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offset is beyond the end of the disk",
+            ));
+        }
+
+        let mut ranges = Vec::new();
+        let mut position = offset;
+        while position < disk_size {
+            let block_end = ((position / block_size + 1) * block_size).min(disk_size);
+            ranges.clear();
+            self.disk.data_ranges(position, block_end, &mut ranges)?;
+            match ranges.first() {
+                Some(&(start, end)) if start == position && end == block_end => {}
+                Some(&(start, end)) if start == position => {
+                    position = end;
+                    break;
+                }
+                _ => break,
             }
-            PartiallyPresent => unimplemented!("differential disks"),
-        };
+            position = block_end;
+        }
 
-        Ok(num_actually_read)
+        self.offset = position.min(disk_size);
+        Ok(self.offset)
+    }
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = if self.read_ahead.is_enabled() {
+            self.read_ahead.read(self.disk, self.offset, buf)?
+        } else {
+            self.disk.read_at(self.offset, buf)?
+        };
+        self.offset += num_read as u64;
+        Ok(num_read)
     }
 }
 
@@ -767,3 +1796,229 @@ impl Write for Reader<'_> {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read};
+
+    use super::*;
+    use crate::testing::{disk_with_data, pattern, TempPath};
+
+    #[test]
+    fn seek_data_and_holes() {
+        let path = TempPath::new("vhdx");
+        // The second block is all zeros, so it isn't allocated
+        let mut data = pattern(6 * MB);
+        data[MB..2 * MB].fill(0);
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &data);
+        disk.write_zeroes(3 * MB as u64, MB as u64).unwrap();
+        disk.discard(4 * MB as u64, MB as u64).unwrap();
+        let states = (0..8)
+            .map(|block_index| disk.block_state(block_index).unwrap())
+            .collect::<Vec<_>>();
+        use PayloadBatEntryState::*;
+        assert_eq!(
+            states,
+            [
+                FullyPresent,
+                NotPresent,
+                FullyPresent,
+                Zero,
+                Unmapped,
+                FullyPresent,
+                NotPresent,
+                NotPresent
+            ]
+        );
+
+        let mb = MB as u64;
+        let mut reader = disk.reader();
+        assert_eq!(reader.seek_data(0).unwrap(), 0);
+        assert_eq!(reader.seek_hole(0).unwrap(), mb);
+        assert_eq!(reader.seek_data(mb).unwrap(), 2 * mb);
+        assert_eq!(reader.seek_data(2 * mb + 100).unwrap(), 2 * mb + 100);
+        assert_eq!(reader.seek_hole(2 * mb).unwrap(), 3 * mb);
+        // Zero and unmapped blocks are holes
+        assert_eq!(reader.seek_data(3 * mb).unwrap(), 5 * mb);
+        assert_eq!(reader.seek_hole(5 * mb + 100).unwrap(), 6 * mb);
+        assert_eq!(reader.seek_hole(7 * mb).unwrap(), 7 * mb);
+
+        // The position is unchanged when there is no data after the offset
+        let error = reader.seek_data(6 * mb).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(reader.stream_position().unwrap(), 7 * mb);
+        assert_eq!(reader.seek_hole(8 * mb - 1).unwrap(), 8 * mb - 1);
+        let error = reader.seek_hole(8 * mb).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn contents(disk: &mut Vhdx) -> Vec<u8> {
+        let mut contents = Vec::new();
+        disk.reader().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn discard_unmaps_whole_blocks() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(4 * MB);
+        let mut disk = disk_with_data(&path, 4 * MB as u64, &data);
+        #[cfg(target_os = "linux")]
+        let freed_offset = disk.bat.payload_entry(1).unwrap().unwrap().file_offset();
+
+        // Only the middle block is whole, so the ends of the blocks either
+        // side of it are zeroed in place
+        disk.discard(MB as u64 / 2, 2 * MB as u64).unwrap();
+        data[MB / 2..5 * MB / 2].fill(0);
+        let states = (0..4)
+            .map(|block_index| disk.block_state(block_index).unwrap())
+            .collect::<Vec<_>>();
+        use PayloadBatEntryState::*;
+        assert_eq!(states, [FullyPresent, Unmapped, FullyPresent, FullyPresent]);
+        assert!(contents(&mut disk) == data);
+
+        // The space of the unmapped block is released
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            sparse::next_hole(&disk.file, freed_offset).unwrap(),
+            freed_offset
+        );
+
+        drop(disk);
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.block_state(1).unwrap(), Unmapped);
+        assert!(contents(&mut disk) == data);
+    }
+
+    #[test]
+    fn write_zeroes_to_whole_and_partial_blocks() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(4 * MB);
+        let mut disk = disk_with_data(&path, 4 * MB as u64, &data);
+
+        // The range covers the tail of the first block, the whole of the
+        // second and third blocks and the head of the fourth block
+        let start = MB as u64 - 4 * KB as u64;
+        let end = 3 * MB as u64 + 12 * KB as u64;
+        disk.write_zeroes(start, end - start).unwrap();
+        data[start as usize..end as usize].fill(0);
+        let states = (0..4)
+            .map(|block_index| disk.block_state(block_index).unwrap())
+            .collect::<Vec<_>>();
+        use PayloadBatEntryState::*;
+        assert_eq!(states, [FullyPresent, Zero, Zero, FullyPresent]);
+        assert!(contents(&mut disk) == data);
+
+        // Unmapped blocks are made explicitly zero
+        disk.discard(0, MB as u64).unwrap();
+        assert_eq!(disk.block_state(0).unwrap(), Unmapped);
+        disk.write_zeroes(0, MB as u64).unwrap();
+        assert_eq!(disk.block_state(0).unwrap(), Zero);
+        data[..MB].fill(0);
+
+        drop(disk);
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert!(contents(&mut disk) == data);
+    }
+
+    #[test]
+    fn one_invalid_header() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 2 * MB as u64, &data));
+        let is_header_1_current = Vhdx::load(&path)
+            .unwrap()
+            .header_section
+            .is_header_1_current;
+
+        // Damage the current header, so that the older one is used
+        let current_offset = if is_header_1_current { 64 } else { 128 } * KB as u64;
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(current_offset + 100)).unwrap();
+        file.write_all(&[0xFF; 4]).unwrap();
+        drop(file);
+
+        // Opening for writing replaces the damaged header
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.header_section.is_header_1_current, is_header_1_current);
+        assert!(contents(&mut disk) == data);
+        drop(disk);
+        assert!(check::check_file(&path).unwrap().is_clean());
+    }
+
+    #[test]
+    fn log_is_replayed_when_not_cleared() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        let mut disk = disk_with_data(&path, 2 * MB as u64, &data);
+        let block_offset = disk.bat.payload_entry(0).unwrap().unwrap().file_offset();
+
+        // Rewriting every sector of a block doesn't fit in one entry of the
+        // 1 MB log, so the changes are split over two entries, which are
+        // applied in order
+        let sector_size = 4 * KB as u64;
+        let writes = (0..MB as u64 / sector_size)
+            .map(|sector_index| log::LogWrite::Data {
+                file_offset: block_offset + sector_index * sector_size,
+                sector: Box::new([(sector_index % 200) as u8 + 1; 4 * KB]),
+            })
+            .chain([log::LogWrite::Data {
+                file_offset: block_offset,
+                sector: Box::new([0xff; 4 * KB]),
+            }])
+            .collect::<Vec<_>>();
+        disk.begin_modification(true).unwrap();
+        disk.write_logged(&writes).unwrap();
+        let mut expected = data.clone();
+        for (sector_index, sector) in expected[..MB].chunks_mut(4 * KB).enumerate() {
+            sector.fill((sector_index % 200) as u8 + 1);
+        }
+        expected[..4 * KB].fill(0xff);
+        assert!(contents(&mut disk) == expected);
+
+        let header = disk.current_header().clone();
+        let sequence = Vhdx::find_log(&mut disk.file, &header).unwrap().unwrap();
+        assert_eq!(sequence.head().unwrap().header().sequence_number, 2);
+
+        // Stop without marking the log as empty, as after a crash, and undo
+        // the last sector written so that replaying it can be seen
+        disk.log_writer = None;
+        disk.file
+            .seek(SeekFrom::Start(block_offset + MB as u64 - sector_size))
+            .unwrap();
+        disk.file.write_all(&[0; 4 * KB]).unwrap();
+        drop(disk);
+        let error = Vhdx::options().read_only(true).open(&path).unwrap_err();
+        assert!(matches!(error, Error::LogReplayRequired));
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.current_header().log_guid, Guid::ZERO);
+        assert!(contents(&mut disk) == expected);
+        drop(disk);
+
+        // The log has been marked as empty, so the file can now be opened
+        // read-only
+        let mut disk = Vhdx::options().read_only(true).open(&path).unwrap();
+        assert!(contents(&mut disk) == expected);
+    }
+
+    #[test]
+    fn log_entry_beyond_end_of_file() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 2 * MB as u64, &pattern(2 * MB));
+        let file_size = disk.file.metadata().unwrap().len();
+        disk.begin_modification(true).unwrap();
+        let writes = [log::LogWrite::Data {
+            file_offset: file_size,
+            sector: Box::new([1; 4 * KB]),
+        }];
+        let error = disk.write_logged(&writes).unwrap_err();
+        assert!(matches!(error, Error::InvalidLogEntry(_)));
+
+        // The entry is still in the log, and fails again when it is replayed
+        disk.log_writer = None;
+        drop(disk);
+        let error = Vhdx::load(&path).unwrap_err();
+        assert!(matches!(error, Error::InvalidLogEntry(_)));
+    }
+}
@@ -1,9 +1,9 @@
 use std::{
     fs::File,
-    io::{Read, Seek, SeekFrom},
+    io::{Read, Seek, SeekFrom, Write},
 };
 
-use crate::{guid::Guid, Error, KB, MB};
+use crate::{checksum::crc32c, guid::Guid, Error, KB, MB, ZEROS};
 
 const LOG_ENTRY_SIGNATURE: &str = "loge";
 const ZERO_DESCRIPTOR_SIGNATURE: &str = "zero";
@@ -20,7 +20,7 @@ pub struct LogEntryHeader {
     descriptor_count: u32,
     log_guid: Guid,
     pub flushed_file_offset: u64,
-    last_file_offset: u64,
+    pub last_file_offset: u64,
 }
 
 impl LogEntryHeader {
@@ -42,11 +42,17 @@ impl LogEntryHeader {
         if signature != LOG_ENTRY_SIGNATURE {
             return Err(Error::InvalidSignature);
         }
-        assert_eq!(entry_length % (4 * KB as u32), 0);
-        assert_eq!(tail % (4 * KB as u32), 0);
-        assert!(sequence_number > 0);
-        assert_eq!(flushed_file_offset % MB as u64, 0);
-        assert_eq!(last_file_offset % MB as u64, 0);
+        if !entry_length.is_multiple_of(4 * KB as u32) || !tail.is_multiple_of(4 * KB as u32) {
+            return Err(Error::InvalidLogEntry("entry is not aligned to 4 KB"));
+        }
+        if sequence_number == 0 {
+            return Err(Error::InvalidLogEntry("sequence number is zero"));
+        }
+        if !flushed_file_offset.is_multiple_of(MB as u64)
+            || !last_file_offset.is_multiple_of(MB as u64)
+        {
+            return Err(Error::InvalidLogEntry("file offset is not aligned to 1 MB"));
+        }
 
         Ok(Self {
             signature,
@@ -64,6 +70,18 @@ impl LogEntryHeader {
     pub fn log_guid(&self) -> Guid {
         self.log_guid
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(LOG_ENTRY_SIGNATURE.as_bytes());
+        buffer[4..8].copy_from_slice(&self.checksum);
+        buffer[8..12].copy_from_slice(&self.entry_length.to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.tail.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.sequence_number.to_le_bytes());
+        buffer[24..28].copy_from_slice(&self.descriptor_count.to_le_bytes());
+        buffer[32..48].copy_from_slice(&self.log_guid.to_bytes());
+        buffer[48..56].copy_from_slice(&self.flushed_file_offset.to_le_bytes());
+        buffer[56..64].copy_from_slice(&self.last_file_offset.to_le_bytes());
+    }
 }
 
 #[derive(Debug)]
@@ -90,9 +108,15 @@ impl ZeroDescriptor {
         let file_offset = u64::from_le_bytes(buffer[16..24].try_into().expect("infallible"));
         let sequence_number = u64::from_le_bytes(buffer[24..32].try_into().expect("infallible"));
 
-        assert_eq!(signature, ZERO_DESCRIPTOR_SIGNATURE);
-        assert_eq!(zero_length % (4 * KB as u64), 0);
-        assert_eq!(file_offset % (4 * KB as u64), 0);
+        if signature != ZERO_DESCRIPTOR_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if !zero_length.is_multiple_of(4 * KB as u64) || !file_offset.is_multiple_of(4 * KB as u64)
+        {
+            return Err(Error::InvalidLogEntry(
+                "zero descriptor is not aligned to 4 KB",
+            ));
+        }
 
         Ok(Self {
             signature,
@@ -113,6 +137,13 @@ impl ZeroDescriptor {
     pub fn sequence_number(&self) -> u64 {
         self.sequence_number
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(ZERO_DESCRIPTOR_SIGNATURE.as_bytes());
+        buffer[8..16].copy_from_slice(&self.zero_length.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.file_offset.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.sequence_number.to_le_bytes());
+    }
 }
 
 #[derive(Debug)]
@@ -135,8 +166,14 @@ impl DataDescriptor {
         let file_offset = u64::from_le_bytes(buffer[16..24].try_into().expect("infallible"));
         let sequence_number = u64::from_le_bytes(buffer[24..32].try_into().expect("infallible"));
 
-        assert_eq!(signature, DATA_DESCRIPTOR_SIGNATURE);
-        assert_eq!(file_offset % (4 * KB as u64), 0);
+        if signature != DATA_DESCRIPTOR_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if !file_offset.is_multiple_of(4 * KB as u64) {
+            return Err(Error::InvalidLogEntry(
+                "data descriptor is not aligned to 4 KB",
+            ));
+        }
 
         Ok(Self {
             signature,
@@ -162,6 +199,14 @@ impl DataDescriptor {
     pub fn leading_bytes(&self) -> [u8; 8] {
         self.leading_bytes
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(DATA_DESCRIPTOR_SIGNATURE.as_bytes());
+        buffer[4..8].copy_from_slice(&self.trailing_bytes);
+        buffer[8..16].copy_from_slice(&self.leading_bytes);
+        buffer[16..24].copy_from_slice(&self.file_offset.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.sequence_number.to_le_bytes());
+    }
 }
 
 pub struct DataSector {
@@ -193,7 +238,9 @@ impl DataSector {
             .expect("infallible");
         let sequence_low = u32::from_le_bytes(buffer[4092..4096].try_into().expect("infallible"));
 
-        assert_eq!(signature, DATA_SECTOR_SIGNATURE);
+        if signature != DATA_SECTOR_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
 
         Ok(Self {
             signature,
@@ -214,6 +261,13 @@ impl DataSector {
     pub fn sequence_low(&self) -> u32 {
         self.sequence_low
     }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(DATA_SECTOR_SIGNATURE.as_bytes());
+        buffer[4..8].copy_from_slice(&self.sequence_high.to_le_bytes());
+        buffer[8..4092].copy_from_slice(self.data.as_ref());
+        buffer[4092..4096].copy_from_slice(&self.sequence_low.to_le_bytes());
+    }
 }
 
 #[derive(Debug)]
@@ -224,11 +278,34 @@ pub struct Entry {
 }
 
 impl Entry {
+    /// Read an entry from a log of `log_length` bytes.
+    ///
     /// File cursor will be at the end of the entry after this function
-    pub fn read(file: &mut File) -> Result<Self, Error> {
+    pub fn read(file: &mut File, log_length: u32) -> Result<Self, Error> {
         let original_position = file.stream_position()?;
 
         let header = LogEntryHeader::read(file)?;
+        if header.entry_length > log_length {
+            return Err(Error::InvalidLogEntry("entry is longer than the log"));
+        }
+        // The descriptors must fit in the entry before anything is allocated
+        // for them
+        if descriptors_length(header.descriptor_count as usize) as u64 > header.entry_length as u64
+        {
+            return Err(Error::InvalidLogEntry(
+                "descriptors are longer than the entry",
+            ));
+        }
+
+        // Verify the checksum over the whole entry before trusting any of it
+        let mut buffer = vec![0; header.entry_length as usize];
+        file.seek(SeekFrom::Start(original_position))?;
+        file.read_exact(&mut buffer)?;
+        buffer[4..8].fill(0);
+        if crc32c(&buffer) != u32::from_le_bytes(header.checksum) {
+            return Err(Error::InvalidChecksum);
+        }
+        file.seek(SeekFrom::Start(original_position + 64))?;
         let mut descriptors = Vec::with_capacity(header.descriptor_count as usize);
         let mut data_sectors = Vec::with_capacity(header.descriptor_count as usize);
 
@@ -251,6 +328,15 @@ impl Entry {
                 _ => Err(Error::InvalidSignature)?,
             };
 
+            let sequence_number = match &descriptor {
+                Descriptor::Zero(desc) => desc.sequence_number(),
+                Descriptor::Data(desc) => desc.sequence_number(),
+            };
+            if sequence_number != header.sequence_number {
+                return Err(Error::InvalidLogEntry(
+                    "descriptor sequence number doesn't match the entry",
+                ));
+            }
             descriptors.push(descriptor);
         }
 
@@ -265,23 +351,27 @@ impl Entry {
             .iter()
             .filter(|desc| matches!(desc, Descriptor::Data(_)))
             .count();
-        // println!(
-        //     "entry had {} descriptors, with {} data sectors",
-        //     descriptors.len(),
-        //     num_data_sectors
-        // );
 
         // Read all the data sectors, in order
         for _ in 0..num_data_sectors {
-            data_sectors.push(DataSector::read(file)?);
+            let data_sector = DataSector::read(file)?;
+            let sequence_number =
+                ((data_sector.sequence_high() as u64) << 32) | data_sector.sequence_low() as u64;
+            if sequence_number != header.sequence_number {
+                return Err(Error::InvalidLogEntry(
+                    "data sector sequence number doesn't match the entry",
+                ));
+            }
+            data_sectors.push(data_sector);
         }
 
         // After reading the data sectors, the file position should be after the end of the entry
         let current_position = file.stream_position()?;
-        assert_eq!(
-            current_position,
-            original_position + header.entry_length as u64
-        );
+        if current_position != original_position + header.entry_length as u64 {
+            return Err(Error::InvalidLogEntry(
+                "entry length doesn't match its descriptors",
+            ));
+        }
 
         Ok(Self {
             header,
@@ -301,6 +391,248 @@ impl Entry {
     pub fn data_sectors(&self) -> &[DataSector] {
         self.data_sectors.as_ref()
     }
+
+    /// Build a log entry out of a set of writes.
+    fn new(
+        log_guid: Guid,
+        sequence_number: u64,
+        tail: u32,
+        file_size: u64,
+        writes: &[LogWrite],
+    ) -> Self {
+        let mut descriptors = Vec::with_capacity(writes.len());
+        let mut data_sectors = Vec::new();
+        for write in writes {
+            match write {
+                LogWrite::Data {
+                    file_offset,
+                    sector,
+                } => {
+                    descriptors.push(Descriptor::Data(DataDescriptor {
+                        signature: DATA_DESCRIPTOR_SIGNATURE.to_owned(),
+                        trailing_bytes: sector[4092..4096].try_into().expect("infallible"),
+                        leading_bytes: sector[0..8].try_into().expect("infallible"),
+                        file_offset: *file_offset,
+                        sequence_number,
+                    }));
+                    data_sectors.push(DataSector {
+                        signature: DATA_SECTOR_SIGNATURE.to_owned(),
+                        sequence_high: (sequence_number >> 32) as u32,
+                        data: Box::<[u8]>::from(&sector[8..4092])
+                            .try_into()
+                            .expect("infallible"),
+                        sequence_low: sequence_number as u32,
+                    });
+                }
+                LogWrite::Zero {
+                    file_offset,
+                    length,
+                } => descriptors.push(Descriptor::Zero(ZeroDescriptor {
+                    signature: ZERO_DESCRIPTOR_SIGNATURE.to_owned(),
+                    zero_length: *length,
+                    file_offset: *file_offset,
+                    sequence_number,
+                })),
+            }
+        }
+
+        // The log records the file size so that truncation can be detected
+        let file_size = file_size - file_size % MB as u64;
+        let header = LogEntryHeader {
+            signature: LOG_ENTRY_SIGNATURE.to_owned(),
+            checksum: [0; 4],
+            entry_length: entry_length(descriptors.len(), data_sectors.len()),
+            tail,
+            sequence_number,
+            descriptor_count: descriptors.len() as u32,
+            log_guid,
+            flushed_file_offset: file_size,
+            last_file_offset: file_size,
+        };
+
+        Self {
+            header,
+            descriptors,
+            data_sectors,
+        }
+    }
+
+    /// Write the entry to the current position in the file, calculating its
+    /// checksum.
+    fn write(&self, file: &mut File) -> Result<(), Error> {
+        let mut buffer = vec![0; self.header.entry_length as usize];
+        self.header.write_to(&mut buffer[0..64]);
+        for (i, descriptor) in self.descriptors.iter().enumerate() {
+            let descriptor_buffer = &mut buffer[64 + 32 * i..][..32];
+            match descriptor {
+                Descriptor::Zero(desc) => desc.write_to(descriptor_buffer),
+                Descriptor::Data(desc) => desc.write_to(descriptor_buffer),
+            }
+        }
+        let data_start = descriptors_length(self.descriptors.len());
+        for (i, data_sector) in self.data_sectors.iter().enumerate() {
+            data_sector.write_to(&mut buffer[data_start + 4 * KB * i..][..4 * KB]);
+        }
+
+        let checksum = crc32c(&buffer);
+        buffer[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+
+    /// Apply the entry's descriptors to their final locations in the file.
+    ///
+    /// The file must already extend over every write, as it is not extended
+    /// here.
+    pub fn apply(&self, file: &mut File) -> Result<(), Error> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let mut data_sectors = self.data_sectors.iter();
+        for desc in self.descriptors() {
+            match desc {
+                Descriptor::Zero(desc) => {
+                    let is_within_file = desc
+                        .file_offset()
+                        .checked_add(desc.zero_length())
+                        .is_some_and(|end| end <= file_length);
+                    if !is_within_file {
+                        return Err(Error::InvalidLogEntry(
+                            "zeros are written beyond the end of the file",
+                        ));
+                    }
+
+                    file.seek(SeekFrom::Start(desc.file_offset()))?;
+                    let num_sectors = desc.zero_length() / (4 * KB as u64);
+                    for _ in 0..num_sectors {
+                        file.write_all(&ZEROS)?;
+                    }
+                }
+                Descriptor::Data(desc) => {
+                    let data_sector = data_sectors
+                        .next()
+                        .ok_or(Error::InvalidLogEntry("data descriptor has no data sector"))?;
+
+                    let is_within_file = desc
+                        .file_offset()
+                        .checked_add(4 * KB as u64)
+                        .is_some_and(|end| end <= file_length);
+                    if !is_within_file {
+                        return Err(Error::InvalidLogEntry(
+                            "data is written beyond the end of the file",
+                        ));
+                    }
+                    file.seek(SeekFrom::Start(desc.file_offset()))?;
+                    file.write_all(&desc.leading_bytes())?;
+                    file.write_all(data_sector.data())?;
+                    file.write_all(&desc.trailing_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A single change to the file that is made through the log.
+#[derive(Debug)]
+pub enum LogWrite {
+    /// Replace a 4 KB aligned sector of the file.
+    Data {
+        file_offset: u64,
+        sector: Box<[u8; 4 * KB]>,
+    },
+    /// Zero a 4 KB aligned range of the file.
+    Zero { file_offset: u64, length: u64 },
+}
+
+/// Writes entries into the circular log of a file, and applies them.
+///
+/// Each entry is applied to the file as soon as it has been written, so
+/// every entry forms its own sequence with its tail pointing to itself.
+#[derive(Debug)]
+pub struct LogWriter {
+    log_guid: Guid,
+    log_offset: u64,
+    log_length: u64,
+    /// Offset of the next entry, relative to the start of the log
+    head: u64,
+    sequence_number: u64,
+}
+
+impl LogWriter {
+    pub fn new(log_guid: Guid, log_offset: u64, log_length: u32) -> Self {
+        Self {
+            log_guid,
+            log_offset,
+            log_length: log_length as u64,
+            head: 0,
+            sequence_number: 1,
+        }
+    }
+
+    /// Write a set of changes to the log, then apply them to the file.
+    ///
+    /// The changes are split over as many entries as required to fit within
+    /// the log. Each entry is flushed to disk before it is applied, and the
+    /// applied changes are flushed before the next entry is written.
+    ///
+    /// Only the changes within one entry are atomic. The entries are applied
+    /// in the order of `writes`, so if writing stops part way through, as
+    /// after a crash, the file is left with a prefix of the changes applied.
+    /// Callers must order the changes so that every prefix leaves the file
+    /// consistent, such as by writing the virtual disk size after the BAT.
+    pub fn write(&mut self, file: &mut File, writes: &[LogWrite]) -> Result<(), Error> {
+        let mut remaining = writes;
+        while !remaining.is_empty() {
+            let mut num_writes = 0;
+            let mut num_data_sectors = 0;
+            for write in remaining {
+                let data_sectors =
+                    num_data_sectors + matches!(write, LogWrite::Data { .. }) as usize;
+                if entry_length(num_writes + 1, data_sectors) as u64 > self.log_length {
+                    break;
+                }
+                num_writes += 1;
+                num_data_sectors = data_sectors;
+            }
+            let (batch, rest) = remaining.split_at(num_writes);
+            remaining = rest;
+
+            let file_size = file.seek(SeekFrom::End(0))?;
+            let entry_length = entry_length(num_writes, num_data_sectors) as u64;
+            if self.head + entry_length > self.log_length {
+                self.head = 0;
+            }
+            let entry = Entry::new(
+                self.log_guid,
+                self.sequence_number,
+                self.head as u32,
+                file_size,
+                batch,
+            );
+
+            file.seek(SeekFrom::Start(self.log_offset + self.head))?;
+            entry.write(file)?;
+            file.sync_data()?;
+
+            entry.apply(file)?;
+            file.sync_data()?;
+
+            self.head += entry_length;
+            self.sequence_number += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// The size of the entry header and descriptors, padded to a 4 KB boundary.
+fn descriptors_length(descriptor_count: usize) -> usize {
+    next_multiple_of(64 + 32 * descriptor_count as u64, 4 * KB as u64) as usize
+}
+
+fn entry_length(descriptor_count: usize, data_sector_count: usize) -> u32 {
+    (descriptors_length(descriptor_count) + 4 * KB * data_sector_count) as u32
 }
 
 pub const fn next_multiple_of(value: u64, rhs: u64) -> u64 {
@@ -0,0 +1,143 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+};
+
+/// A cache that evicts the least recently used entries once the total weight
+/// of its entries exceeds a budget.
+pub(crate) struct Lru<K, V> {
+    budget: u64,
+    weight: u64,
+    /// Incremented on every access, to order the entries by recency
+    tick: u64,
+    entries: HashMap<K, LruEntry<V>>,
+    /// The key of each entry by the tick it was last used
+    order: BTreeMap<u64, K>,
+}
+
+struct LruEntry<V> {
+    value: V,
+    weight: u64,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub(crate) fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            weight: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Get an entry, marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(&entry.value)
+    }
+
+    /// Insert an entry as the most recently used, evicting others until the
+    /// total weight is within the budget. An entry that is heavier than the
+    /// whole budget is not kept.
+    pub(crate) fn insert(&mut self, key: K, value: V, weight: u64) {
+        self.remove(&key);
+        if weight > self.budget {
+            return;
+        }
+        while self.weight + weight > self.budget {
+            let (_, oldest) = self.order.pop_first().expect("weight is of entries");
+            let entry = self.entries.remove(&oldest).expect("entry is ordered");
+            self.weight -= entry.weight;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                weight,
+                tick: self.tick,
+            },
+        );
+        self.weight += weight;
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    /// Remove the entries whose keys don't match the predicate.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        let removed = self
+            .entries
+            .keys()
+            .filter(|key| !keep(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.weight = 0;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The total weight of the entries.
+    pub(crate) fn weight(&self) -> u64 {
+        self.weight
+    }
+}
+
+impl<K, V> fmt::Debug for Lru<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lru")
+            .field("budget", &self.budget)
+            .field("weight", &self.weight)
+            .field("len", &self.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(3);
+        lru.insert(1, 'a', 1);
+        lru.insert(2, 'b', 1);
+        lru.insert(3, 'c', 1);
+        assert_eq!(lru.get(&1), Some(&'a'));
+
+        // 2 is now the least recently used
+        lru.insert(4, 'd', 2);
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&3), None);
+        assert_eq!(lru.get(&1), Some(&'a'));
+        assert_eq!(lru.get(&4), Some(&'d'));
+        assert_eq!(lru.weight(), 3);
+
+        lru.insert(5, 'e', 4);
+        assert_eq!(lru.get(&5), None);
+        assert_eq!(lru.len(), 2);
+    }
+}
@@ -1,17 +1,62 @@
-use std::{fs::File, io::Read};
+//! Items stored in the metadata region of a VHDX file.
+//!
+//! Each item is identified by a GUID, and is read and written through the
+//! [`MetadataItem`] trait. The system items defined by the specification are
+//! provided here, and other crates can implement [`MetadataItem`] for their
+//! own user items to use them with [`Vhdx::get_metadata`] and
+//! [`Vhdx::set_metadata`].
+//!
+//! [`Vhdx::get_metadata`]: crate::Vhdx::get_metadata
+//! [`Vhdx::set_metadata`]: crate::Vhdx::set_metadata
+
+use std::io::{Read, Write};
+
+use crate::{guid::Guid, Error, MB};
+
+static PARENT_LOCATOR_TYPE: Guid = Guid::parse_const("B04AEFB7-D19E-4A81-B789-25B8E9445913");
+
+/// The GUIDs of the system metadata items defined by the specification.
+pub(crate) static SYSTEM_ITEMS: [Guid; 6] = [
+    FileParameters::GUID,
+    VirtualDiskSize::GUID,
+    VirtualDiskId::GUID,
+    LogicalSectorSize::GUID,
+    PhysicalSectorSize::GUID,
+    ParentLocator::GUID,
+];
+
+/// The system items that define the layout of the file, which cannot be
+/// changed by replacing the item.
+pub(crate) static LAYOUT_ITEMS: [Guid; 3] = [
+    FileParameters::GUID,
+    VirtualDiskSize::GUID,
+    LogicalSectorSize::GUID,
+];
+
+/// A metadata item with a known GUID and format.
+pub trait MetadataItem: Sized {
+    /// The GUID that identifies the item in the metadata table.
+    const GUID: Guid;
 
-use crate::{guid::Guid, Error};
+    /// Whether the item describes the virtual disk, rather than the file.
+    ///
+    /// This is only used when a user item is first added to a file.
+    const IS_VIRTUAL_DISK: bool = false;
 
-static PARENT_LOCATOR_TYPE: Guid = Guid::from_str("B04AEFB7-D19E-4A81-B789-25B8E9445913");
+    /// Whether an implementation must understand the item to open the file.
+    ///
+    /// This is only used when a user item is added to a file.
+    const IS_REQUIRED: bool = false;
 
-pub trait MetadataItem {
-    const GUID: Guid;
+    /// Read the item from a reader that contains exactly the item's data.
+    fn read(reader: &mut impl Read) -> Result<Self, Error>;
 
-    fn read(file: &mut File) -> Result<Self, Error>
-    where
-        Self: Sized;
+    /// Write the item's data.
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error>;
 }
 
+/// The block size of the file and whether it is a fixed or differencing
+/// disk.
 #[derive(Debug)]
 pub struct FileParameters {
     block_size: u32,
@@ -20,19 +65,40 @@ pub struct FileParameters {
 }
 
 impl FileParameters {
+    pub(crate) fn new(block_size: u32, leave_block_allocated: bool, has_parent: bool) -> Self {
+        Self {
+            block_size,
+            leave_block_allocated,
+            has_parent,
+        }
+    }
+
     pub fn block_size(&self) -> u32 {
         self.block_size
     }
+
+    pub fn leave_block_allocated(&self) -> bool {
+        self.leave_block_allocated
+    }
+
+    pub fn has_parent(&self) -> bool {
+        self.has_parent
+    }
 }
 
 impl MetadataItem for FileParameters {
-    const GUID: Guid = Guid::from_str("CAA16737-FA36-4D43-B3B6-33F0AA44E76B");
+    const GUID: Guid = Guid::parse_const("CAA16737-FA36-4D43-B3B6-33F0AA44E76B");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 8];
-        file.read_exact(&mut buffer)?;
+        reader.read_exact(&mut buffer)?;
 
         let block_size = u32::from_le_bytes(buffer[0..4].try_into().expect("infallible"));
+        if !block_size.is_power_of_two() || !(MB as u32..=256 * MB as u32).contains(&block_size) {
+            return Err(Error::InvalidMetadataItem(
+                "block size must be a power of two between 1 MB and 256 MB",
+            ));
+        }
         let leave_block_allocated = buffer[4] & 1 == 1;
         let has_parent = buffer[4] >> 1 & 1 == 1;
 
@@ -42,132 +108,336 @@ impl MetadataItem for FileParameters {
             has_parent,
         })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let flags = self.leave_block_allocated as u32 | (self.has_parent as u32) << 1;
+        writer.write_all(&self.block_size.to_le_bytes())?;
+        writer.write_all(&flags.to_le_bytes())?;
+        Ok(())
+    }
 }
 
+/// The size of the virtual disk in bytes.
 #[derive(Debug)]
 pub struct VirtualDiskSize {
     virtual_disk_size: u64,
 }
 
 impl VirtualDiskSize {
+    pub(crate) fn new(virtual_disk_size: u64) -> Self {
+        Self { virtual_disk_size }
+    }
+
     pub fn virtual_disk_size(&self) -> u64 {
         self.virtual_disk_size
     }
 }
 
 impl MetadataItem for VirtualDiskSize {
-    const GUID: Guid = Guid::from_str("2FA54224-CD1B-4876-B211-5DBED83BF4B8");
+    const GUID: Guid = Guid::parse_const("2FA54224-CD1B-4876-B211-5DBED83BF4B8");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 8];
-        file.read_exact(&mut buffer)?;
+        reader.read_exact(&mut buffer)?;
 
         let virtual_disk_size = u64::from_le_bytes(buffer[0..8].try_into().expect("infallible"));
 
         Ok(Self { virtual_disk_size })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.virtual_disk_size.to_le_bytes())?;
+        Ok(())
+    }
 }
 
+/// The identifier of the virtual disk.
 #[derive(Debug)]
 pub struct VirtualDiskId {
     virtual_disk_id: Guid,
 }
 
 impl VirtualDiskId {
+    pub fn new(virtual_disk_id: Guid) -> Self {
+        Self { virtual_disk_id }
+    }
+
     pub fn virtual_disk_id(&self) -> Guid {
         self.virtual_disk_id
     }
 }
 
 impl MetadataItem for VirtualDiskId {
-    const GUID: Guid = Guid::from_str("BECA12AB-B2E6-4523-93EF-C309E000C746");
+    const GUID: Guid = Guid::parse_const("BECA12AB-B2E6-4523-93EF-C309E000C746");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 16];
-        file.read_exact(&mut buffer)?;
+        reader.read_exact(&mut buffer)?;
 
         let virtual_disk_id = Guid::from_bytes(buffer[0..16].try_into().expect("infallible"));
 
         Ok(Self { virtual_disk_id })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.virtual_disk_id.to_bytes())?;
+        Ok(())
+    }
 }
 
+/// The sector size presented by the virtual disk.
 #[derive(Debug)]
 pub struct LogicalSectorSize {
     logical_sector_size: u32,
 }
 
 impl LogicalSectorSize {
+    /// Create a logical sector size item, which must be either 512 or 4096
+    /// bytes.
+    pub(crate) fn new(logical_sector_size: u32) -> Result<Self, Error> {
+        if ![512, 4096].contains(&logical_sector_size) {
+            return Err(Error::InvalidMetadataItem(
+                "logical sector size must be 512 or 4096",
+            ));
+        }
+        Ok(Self {
+            logical_sector_size,
+        })
+    }
+
     pub fn logical_sector_size(&self) -> u32 {
         self.logical_sector_size
     }
 }
 
 impl MetadataItem for LogicalSectorSize {
-    const GUID: Guid = Guid::from_str("8141BF1D-A96F-4709-BA47-F233A8FAAB5F");
+    const GUID: Guid = Guid::parse_const("8141BF1D-A96F-4709-BA47-F233A8FAAB5F");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 4];
-        file.read_exact(&mut buffer)?;
+        reader.read_exact(&mut buffer)?;
 
         let logical_sector_size = u32::from_le_bytes(buffer[0..4].try_into().expect("infallible"));
-        assert!([512, 4096].contains(&logical_sector_size));
+        if ![512, 4096].contains(&logical_sector_size) {
+            return Err(Error::InvalidMetadataItem(
+                "logical sector size must be 512 or 4096",
+            ));
+        }
 
         Ok(Self {
             logical_sector_size,
         })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.logical_sector_size.to_le_bytes())?;
+        Ok(())
+    }
 }
 
+/// The physical sector size reported by the virtual disk.
 #[derive(Debug)]
 pub struct PhysicalSectorSize {
     physical_sector_size: u32,
 }
 
 impl PhysicalSectorSize {
+    /// Create a physical sector size item, which must be either 512 or 4096
+    /// bytes.
+    pub fn new(physical_sector_size: u32) -> Result<Self, Error> {
+        if ![512, 4096].contains(&physical_sector_size) {
+            return Err(Error::InvalidMetadataItem(
+                "physical sector size must be 512 or 4096",
+            ));
+        }
+        Ok(Self {
+            physical_sector_size,
+        })
+    }
+
     pub fn physical_sector_size(&self) -> u32 {
         self.physical_sector_size
     }
 }
 
 impl MetadataItem for PhysicalSectorSize {
-    const GUID: Guid = Guid::from_str("CDA348C7-445D-4471-9CC9-E9885251C556");
+    const GUID: Guid = Guid::parse_const("CDA348C7-445D-4471-9CC9-E9885251C556");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
         let mut buffer = vec![0; 4];
-        file.read_exact(&mut buffer)?;
+        reader.read_exact(&mut buffer)?;
 
         let physical_sector_size = u32::from_le_bytes(buffer[0..4].try_into().expect("infallible"));
-        assert!([512, 4096].contains(&physical_sector_size));
+        if ![512, 4096].contains(&physical_sector_size) {
+            return Err(Error::InvalidMetadataItem(
+                "physical sector size must be 512 or 4096",
+            ));
+        }
 
         Ok(Self {
             physical_sector_size,
         })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(&self.physical_sector_size.to_le_bytes())?;
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+/// The location of the parent of a differencing disk, as a set of key-value
+/// pairs.
+#[derive(Debug, Clone)]
 pub struct ParentLocator {
     locator_type: Guid,
-    key_value_count: u16,
+    entries: Vec<(String, String)>,
+}
+
+impl ParentLocator {
+    /// Create a VHDX parent locator from its key-value pairs, such as
+    /// `parent_linkage` and `relative_path`.
+    pub fn new(entries: Vec<(String, String)>) -> Self {
+        Self {
+            locator_type: PARENT_LOCATOR_TYPE,
+            entries,
+        }
+    }
+
+    /// The key-value pairs of the locator, in the order they are stored.
+    pub fn entries(&self) -> &[(String, String)] {
+        &self.entries
+    }
+
+    /// Get the value for a key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 impl MetadataItem for ParentLocator {
-    const GUID: Guid = Guid::from_str("A8D35F2D-B30B-454D-ABF7-D3D84834AB0C");
+    const GUID: Guid = Guid::parse_const("A8D35F2D-B30B-454D-ABF7-D3D84834AB0C");
 
-    fn read(file: &mut File) -> Result<Self, Error> {
-        let mut buffer = vec![0; 20];
-        file.read_exact(&mut buffer)?;
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        if buffer.len() < 20 {
+            return Err(Error::InvalidMetadataItem("parent locator is truncated"));
+        }
 
         let locator_type = Guid::from_bytes(buffer[0..16].try_into().expect("infallible"));
         let key_value_count = u16::from_le_bytes(buffer[18..20].try_into().expect("infallible"));
-        // TODO: Read the key-value data to find the parent
 
-        assert_eq!(locator_type, PARENT_LOCATOR_TYPE);
+        if locator_type != PARENT_LOCATOR_TYPE {
+            return Err(Error::Unsupported("parent locator type"));
+        }
+
+        let read_string = |offset: u32, length: u16| {
+            let bytes = buffer
+                .get(offset as usize..offset as usize + length as usize)
+                .ok_or(Error::InvalidMetadataItem(
+                    "parent locator entry is truncated",
+                ))?;
+            let units = bytes
+                .chunks_exact(2)
+                .map(|bytes| u16::from_le_bytes(bytes.try_into().expect("infallible")));
+            Ok::<_, Error>(char::decode_utf16(units).collect::<Result<String, _>>()?)
+        };
+
+        let mut entries = Vec::with_capacity(key_value_count as usize);
+        for i in 0..key_value_count as usize {
+            let entry =
+                buffer
+                    .get(20 + 12 * i..20 + 12 * (i + 1))
+                    .ok_or(Error::InvalidMetadataItem(
+                        "parent locator entry is truncated",
+                    ))?;
+            let key_offset = u32::from_le_bytes(entry[0..4].try_into().expect("infallible"));
+            let value_offset = u32::from_le_bytes(entry[4..8].try_into().expect("infallible"));
+            let key_length = u16::from_le_bytes(entry[8..10].try_into().expect("infallible"));
+            let value_length = u16::from_le_bytes(entry[10..12].try_into().expect("infallible"));
+
+            entries.push((
+                read_string(key_offset, key_length)?,
+                read_string(value_offset, value_length)?,
+            ));
+        }
 
         Ok(Self {
             locator_type,
-            key_value_count,
+            entries,
         })
     }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let encode = |string: &str| {
+            string
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>()
+        };
+
+        let mut header = Vec::with_capacity(20 + 12 * self.entries.len());
+        header.extend_from_slice(&self.locator_type.to_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+
+        let mut strings = Vec::new();
+        let strings_offset = 20 + 12 * self.entries.len();
+        for (key, value) in &self.entries {
+            let key = encode(key);
+            let value = encode(value);
+            let key_offset = strings_offset + strings.len();
+            strings.extend_from_slice(&key);
+            let value_offset = strings_offset + strings.len();
+            strings.extend_from_slice(&value);
+
+            header.extend_from_slice(&(key_offset as u32).to_le_bytes());
+            header.extend_from_slice(&(value_offset as u32).to_le_bytes());
+            header.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            header.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+
+        writer.write_all(&header)?;
+        writer.write_all(&strings)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_locator_round_trip() {
+        let locator = ParentLocator::new(vec![
+            (
+                "parent_linkage".to_owned(),
+                "{83C1D307-9E71-4ABB-BAF7-40AD3D271807}".to_owned(),
+            ),
+            ("relative_path".to_owned(), ".\\parent.vhdx".to_owned()),
+        ]);
+
+        let mut buffer = Vec::new();
+        locator.write(&mut buffer).unwrap();
+        let read = ParentLocator::read(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(read.entries(), locator.entries());
+        assert_eq!(read.get("relative_path"), Some(".\\parent.vhdx"));
+    }
+
+    #[test]
+    fn invalid_sector_size() {
+        let bytes = 1024u32.to_le_bytes();
+        assert!(matches!(
+            LogicalSectorSize::read(&mut bytes.as_slice()),
+            Err(Error::InvalidMetadataItem(_))
+        ));
+        assert!(matches!(
+            PhysicalSectorSize::read(&mut bytes.as_slice()),
+            Err(Error::InvalidMetadataItem(_))
+        ));
+    }
 }
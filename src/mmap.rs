@@ -0,0 +1,106 @@
+use std::{
+    fs::File,
+    io::{self, Cursor},
+};
+
+/// A read-only memory mapping of a whole VHDX file, which is shared between
+/// the [`crate::Vhdx`] and its BAT.
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone)]
+pub(crate) struct Mapping(std::sync::Arc<memmap2::Mmap>);
+
+/// Without the `mmap` feature, files are never mapped, so this is never
+/// constructed.
+#[cfg(not(feature = "mmap"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Mapping(());
+
+#[cfg(feature = "mmap")]
+impl Mapping {
+    /// Map the whole of a file that has been opened read-only.
+    #[allow(unsafe_code)]
+    pub(crate) fn new(file: &File) -> io::Result<Self> {
+        // SAFETY: the mapping is read-only, and the file is not written to
+        // through this crate while it is mapped. Modifying or truncating the
+        // file from elsewhere while it is mapped is not supported, as
+        // documented on `OpenOptions::mmap`.
+        let mmap = unsafe { memmap2::Mmap::map(file)? };
+        Ok(Self(std::sync::Arc::new(mmap)))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(not(feature = "mmap"))]
+impl Mapping {
+    pub(crate) fn new(_file: &File) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory-mapping requires the mmap feature",
+        ))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &[]
+    }
+}
+
+impl Mapping {
+    /// Copy from the file at `offset`, stopping at the end of the file.
+    /// Returns the number of bytes copied, as for [`std::io::Read::read`].
+    pub(crate) fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let bytes = self.bytes();
+        let start = offset.min(bytes.len() as u64) as usize;
+        let source = &bytes[start..];
+        let length = buf.len().min(source.len());
+        buf[..length].copy_from_slice(&source[..length]);
+        length
+    }
+
+    /// Copy exactly enough of the file at `offset` to fill `buf`.
+    pub(crate) fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.read_at(offset, buf) < buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Read the file through the mapping with [`std::io::Read`] and
+    /// [`std::io::Seek`], as when parsing the headers and metadata.
+    pub(crate) fn cursor(&self) -> Cursor<&[u8]> {
+        Cursor::new(self.bytes())
+    }
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use std::io::Read;
+
+    use crate::{
+        testing::{disk_with_data, pattern, TempPath},
+        Error, Vhdx, MB,
+    };
+
+    #[test]
+    fn read_through_mapping() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(3 * MB);
+        drop(disk_with_data(&path, 8 * MB as u64, &data));
+
+        let writable = Vhdx::options().mmap(true).open(&path);
+        assert!(matches!(writable, Err(Error::Unsupported(_))));
+
+        let mut disk = Vhdx::options()
+            .read_only(true)
+            .mmap(true)
+            .open(&path)
+            .unwrap();
+        let mut contents = Vec::new();
+        disk.reader().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 8 * MB);
+        assert_eq!(contents[..3 * MB], data);
+        assert!(contents[3 * MB..].iter().all(|&byte| byte == 0));
+    }
+}
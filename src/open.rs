@@ -0,0 +1,506 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache::BlockCache,
+    metadata::{ParentLocator, SYSTEM_ITEMS},
+    mmap::Mapping,
+    Error, Guid, HeaderSection, MetadataTable, RegionTable, Vhdx, REGION_GUID_BAT,
+    REGION_GUID_METADATA,
+};
+
+/// Options for opening a VHDX file, created with [`Vhdx::options`].
+///
+/// The specification requires that a file is not opened if it contains a
+/// region or metadata item that is marked as required, but that is not
+/// understood by the implementation. By default, such files are rejected with
+/// [`Error::UnknownRequiredRegions`] or [`Error::UnknownRequiredMetadata`].
+///
+/// The parent of a differencing disk is found through its parent locator and
+/// opened read-only along with the disk.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    best_effort: bool,
+    read_only: bool,
+    mmap: bool,
+    cache_size: u64,
+    known_metadata: Vec<Guid>,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the file even if it contains unknown required regions or metadata
+    /// items, to recover what data is possible.
+    ///
+    /// The file is opened read-only, and the log is not replayed, so the
+    /// contents may not reflect the most recent writes.
+    pub fn best_effort(&mut self, best_effort: bool) -> &mut Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Open the file without write access.
+    ///
+    /// Files with log entries that need to be replayed cannot be opened
+    /// read-only, except in best effort mode, and are rejected with
+    /// [`Error::LogReplayRequired`].
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Read the file through a read-only memory mapping, which avoids a
+    /// system call for each read of the virtual disk. The file must be opened
+    /// read-only.
+    ///
+    /// The file must not be modified or truncated by another process while
+    /// it is open, which may cause reads to return inconsistent data or the
+    /// process to be terminated.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Cache up to `cache_size` bytes of the virtual disk in memory, in
+    /// chunks of 64 KB, evicting the least recently used chunks. This helps
+    /// workloads that read the same small parts of the disk repeatedly, such
+    /// as parsing a filesystem. Reads of whole chunks bypass the cache.
+    ///
+    /// The cache is disabled by default, and with a size of zero. Its
+    /// effectiveness can be seen through [`Vhdx::cache_stats`].
+    pub fn cache_size(&mut self, cache_size: u64) -> &mut Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Mark a required user metadata item as understood by the caller, so
+    /// that it does not prevent the file from being opened.
+    pub fn known_metadata(&mut self, item_id: Guid) -> &mut Self {
+        self.known_metadata.push(item_id);
+        self
+    }
+
+    /// Open the VHDX file at the given path.
+    ///
+    /// Unless opening in best effort mode, if there is a log to be replayed it
+    /// will be applied during this function.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<Vhdx, Error> {
+        self.open_in_chain(path.as_ref(), &mut Vec::new())
+    }
+
+    /// Open the file at `path`, which is a parent of each of the differencing
+    /// disks in `children`, given by their canonical paths.
+    fn open_in_chain(&self, path: &Path, children: &mut Vec<PathBuf>) -> Result<Vhdx, Error> {
+        let read_only = self.read_only || self.best_effort;
+        let mut file = File::options().read(true).write(!read_only).open(path)?;
+        let mapping = if self.mmap {
+            if !read_only {
+                return Err(Error::Unsupported(
+                    "memory-mapping a file that is opened for writing",
+                ));
+            }
+            Some(Mapping::new(&file)?)
+        } else {
+            None
+        };
+        let mut header_section = match &mapping {
+            Some(mapping) => HeaderSection::read(&mut mapping.cursor())?,
+            None => HeaderSection::read(&mut file)?,
+        };
+
+        // Nothing may be written to the file if it has a required region that
+        // is not understood, including through replaying the log
+        let unknown_regions = unknown_required_regions(header_section.region_table());
+        if !unknown_regions.is_empty() && !self.best_effort {
+            return Err(Error::UnknownRequiredRegions(unknown_regions));
+        }
+
+        // The log must be replayed before any of the regions are read, as it
+        // may contain updates to them
+        let needs_replay = header_section.current_header().log_guid != Guid::ZERO;
+        if needs_replay
+            && self.read_only
+            && !self.best_effort
+            && Vhdx::find_log(&mut file, header_section.current_header())?.is_some()
+        {
+            return Err(Error::LogReplayRequired);
+        }
+        if !read_only && Vhdx::try_replay_log(&mut file, header_section.current_header())? {
+            file.seek(SeekFrom::Start(0))?;
+            header_section = HeaderSection::read(&mut file)?;
+        }
+
+        let (metadata_table, metadata, bat) =
+            Vhdx::read_regions(&mut file, mapping.as_ref(), &header_section)?;
+
+        let unknown_metadata = unknown_required_metadata(&metadata_table, &self.known_metadata);
+        if !unknown_metadata.is_empty() && !self.best_effort {
+            return Err(Error::UnknownRequiredMetadata(unknown_metadata));
+        }
+
+        let parent = if metadata.file_parameters.has_parent() {
+            let parent = metadata
+                .parent_locator
+                .as_ref()
+                .ok_or(Error::MissingRequiredMetadata("parent locator"))
+                .and_then(|locator| self.open_parent(path, locator, children));
+            match parent {
+                Ok(parent) => Some(Box::new(parent)),
+                // The parts of the disk that are stored in the file can still
+                // be read without the parent
+                Err(_) if self.best_effort => None,
+                Err(error) => return Err(error),
+            }
+        } else {
+            None
+        };
+
+        let mut vhdx = Vhdx {
+            file,
+            header_section,
+            metadata_table,
+            metadata,
+            bat,
+            parent,
+            log_writer: None,
+            data_write_guid_updated: false,
+            read_only,
+            mapping,
+            cache: (self.cache_size > 0).then(|| BlockCache::new(self.cache_size)),
+        };
+        // Once the log has been replayed it is marked as empty, so that it
+        // isn't replayed again
+        if needs_replay && !read_only {
+            vhdx.update_header(|header| header.log_guid = Guid::ZERO)?;
+        }
+        Ok(vhdx)
+    }
+
+    /// Find and open the parent of a differencing disk, checking that it has
+    /// not been modified since the differencing disk was created.
+    ///
+    /// Each candidate path from the parent locator is tried in turn, skipping
+    /// those that can't be read or that are already part of the chain, as when
+    /// a parent locator points back at one of its children.
+    fn open_parent(
+        &self,
+        child_path: &Path,
+        locator: &ParentLocator,
+        children: &mut Vec<PathBuf>,
+    ) -> Result<Vhdx, Error> {
+        let linkage = [
+            locator.get("parent_linkage"),
+            locator.get("parent_linkage2"),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|linkage| linkage.parse::<Guid>().ok())
+        .collect::<Vec<_>>();
+        if linkage.is_empty() {
+            return Err(Error::InvalidMetadataItem(
+                "parent locator has no parent linkage",
+            ));
+        }
+
+        let child_dir = child_path.parent().unwrap_or(Path::new(""));
+        let candidates = [
+            locator
+                .get("relative_path")
+                .map(|path| child_dir.join(native_path(path))),
+            locator.get("volume_path").map(native_path),
+            locator.get("absolute_win32_path").map(native_path),
+        ];
+
+        let mut options = self.clone();
+        // Reads of the parent are cached along with the child
+        options.read_only(true).cache_size(0);
+
+        children.push(child_path.canonicalize()?);
+        let mut modified = false;
+        let mut open_error = None;
+        for candidate in candidates.into_iter().flatten() {
+            let Ok(canonical_path) = candidate.canonicalize() else {
+                continue;
+            };
+            if !canonical_path.is_file() || children.contains(&canonical_path) {
+                continue;
+            }
+            // Only the headers are read to check the linkage, so that a file
+            // that isn't the parent is never fully opened
+            let Ok(data_write_guid) = read_data_write_guid(&canonical_path) else {
+                continue;
+            };
+            if !linkage.contains(&data_write_guid) {
+                modified = true;
+                continue;
+            }
+            match options.open_in_chain(&canonical_path, children) {
+                Ok(parent) => {
+                    children.pop();
+                    return Ok(parent);
+                }
+                Err(error) => open_error = open_error.or(Some(error)),
+            }
+        }
+        children.pop();
+
+        Err(match open_error {
+            Some(error) => error,
+            None if modified => Error::ParentModified,
+            None => Error::ParentNotFound,
+        })
+    }
+}
+
+/// Read the data write GUID from the headers of a file, without reading the
+/// rest of it.
+fn read_data_write_guid(path: &Path) -> Result<Guid, Error> {
+    let mut file = File::open(path)?;
+    let header_section = HeaderSection::read(&mut file)?;
+    Ok(header_section.current_header().data_write_guid)
+}
+
+/// The GUIDs of regions that are marked as required, but are not understood.
+pub(crate) fn unknown_required_regions(region_table: &RegionTable) -> Vec<Guid> {
+    region_table
+        .entries
+        .iter()
+        .filter(|entry| entry.is_required())
+        .filter(|entry| ![REGION_GUID_BAT, REGION_GUID_METADATA].contains(&entry.guid))
+        .map(|entry| entry.guid)
+        .collect()
+}
+
+/// The GUIDs of metadata items that are marked as required, but are neither
+/// system items nor in the list of known items.
+pub(crate) fn unknown_required_metadata(
+    metadata_table: &MetadataTable,
+    known: &[Guid],
+) -> Vec<Guid> {
+    metadata_table
+        .entries
+        .iter()
+        .filter(|entry| entry.is_required)
+        .filter(|entry| !SYSTEM_ITEMS.contains(&entry.item_id) && !known.contains(&entry.item_id))
+        .map(|entry| entry.item_id)
+        .collect()
+}
+
+/// Convert a path from a parent locator, which uses Windows separators, to a
+/// path for the current platform.
+pub(crate) fn native_path(path: &str) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(path.replace('\\', "/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::{
+        metadata::{FileParameters, MetadataItem},
+        testing::{disk_with_data, pattern, TempPath},
+        Builder, RegionTableEntry, KB, MB,
+    };
+
+    /// Write `region_table` over the region table at `offset`.
+    fn write_region_table(path: &Path, offset: u64, region_table: &RegionTable) {
+        let mut file = File::options().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&region_table.to_bytes()).unwrap();
+    }
+
+    /// Add a required region that is not understood to the region tables at
+    /// `offsets`, returning its GUID.
+    fn add_unknown_region(path: &Path, offsets: &[u64]) -> Guid {
+        let disk = Vhdx::load(path).unwrap();
+        let mut region_table = disk.header_section.region_table().clone();
+        drop(disk);
+        let guid = Guid::new_random();
+        region_table.entries.push(RegionTableEntry {
+            guid,
+            file_offset: 128 * MB as u64,
+            length: MB as u32,
+            required: 1,
+        });
+        for &offset in offsets {
+            write_region_table(path, offset, &region_table);
+        }
+        guid
+    }
+
+    #[test]
+    fn unknown_required_regions() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 2 * MB as u64, &data));
+        let guid = add_unknown_region(&path, &[192 * KB as u64, 256 * KB as u64]);
+
+        let error = Vhdx::load(&path).unwrap_err();
+        assert!(matches!(error, Error::UnknownRequiredRegions(guids) if guids == [guid]));
+        let error = Vhdx::options().read_only(true).open(&path).unwrap_err();
+        assert!(matches!(error, Error::UnknownRequiredRegions(_)));
+
+        let mut disk = Vhdx::options().best_effort(true).open(&path).unwrap();
+        let mut contents = vec![0; data.len()];
+        disk.reader().read_exact(&mut contents).unwrap();
+        assert!(contents == data);
+    }
+
+    #[test]
+    fn region_table_2_is_used_when_region_table_1_is_invalid() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        drop(disk_with_data(&path, 2 * MB as u64, &data));
+
+        // An unknown required region only in region table 2 is ignored while
+        // region table 1 is valid
+        let guid = add_unknown_region(&path, &[256 * KB as u64]);
+        let mut disk = Vhdx::options().read_only(true).open(&path).unwrap();
+        let mut contents = vec![0; data.len()];
+        disk.reader().read_exact(&mut contents).unwrap();
+        assert!(contents == data);
+        drop(disk);
+
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(192 * KB as u64 + 100)).unwrap();
+        file.write_all(&[0xFF; 4]).unwrap();
+        drop(file);
+        let error = Vhdx::options().read_only(true).open(&path).unwrap_err();
+        assert!(matches!(error, Error::UnknownRequiredRegions(guids) if guids == [guid]));
+    }
+
+    #[test]
+    fn invalid_metadata_table_signature() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 2 * MB as u64, &[]);
+        let offset = disk.region(REGION_GUID_METADATA).unwrap().file_offset;
+        drop(disk);
+
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(b"corrupt!").unwrap();
+        drop(file);
+
+        let error = Vhdx::options().read_only(true).open(&path).unwrap_err();
+        assert!(matches!(error, Error::InvalidImage(_)));
+    }
+
+    #[test]
+    fn invalid_block_size() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 2 * MB as u64, &[]);
+        let offset = disk.metadata_item_offset(FileParameters::GUID).unwrap();
+        drop(disk);
+
+        for block_size in [0, 3 * MB as u32, 512 * MB as u32] {
+            let mut file = File::options().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&block_size.to_le_bytes()).unwrap();
+            drop(file);
+
+            let error = Vhdx::options().read_only(true).open(&path).unwrap_err();
+            assert!(matches!(error, Error::InvalidMetadataItem(_)));
+        }
+    }
+
+    #[test]
+    fn read_through_parent() {
+        let parent_path = TempPath::new("vhdx");
+        let child_path = TempPath::new("vhdx");
+        let data = pattern(4 * MB);
+        drop(disk_with_data(&parent_path, 8 * MB as u64, &data));
+        drop(
+            Builder::new(&*child_path)
+                .parent(&*parent_path)
+                .create()
+                .unwrap(),
+        );
+
+        let mut expected = data.clone();
+        expected.resize(8 * MB, 0);
+        let mut child = Vhdx::options().read_only(true).open(&child_path).unwrap();
+        assert!(child.has_parent());
+        let mut contents = Vec::new();
+        child.reader().read_to_end(&mut contents).unwrap();
+        assert!(contents == expected);
+        drop(child);
+
+        // Once the parent has been modified, the child no longer matches it
+        Vhdx::load(&parent_path)
+            .unwrap()
+            .discard(0, MB as u64)
+            .unwrap();
+        let error = Vhdx::options()
+            .read_only(true)
+            .open(&child_path)
+            .unwrap_err();
+        assert!(matches!(error, Error::ParentModified));
+    }
+
+    #[test]
+    fn parent_locator_cycle() {
+        let base_path = TempPath::new("vhdx");
+        let middle_path = TempPath::new("vhdx");
+        let child_path = TempPath::new("vhdx");
+        drop(disk_with_data(&base_path, 8 * MB as u64, &[]));
+        let base_guid = Vhdx::load(&base_path).unwrap().data_write_guid();
+        drop(
+            Builder::new(&*middle_path)
+                .parent(&*base_path)
+                .create()
+                .unwrap(),
+        );
+        drop(
+            Builder::new(&*child_path)
+                .parent(&*middle_path)
+                .create()
+                .unwrap(),
+        );
+
+        // Replace the base with a copy of the child that has the linkage of
+        // the base, so that the parent of the middle disk is the child, whose
+        // parent is the middle disk again
+        let mut child = Vhdx::load(&child_path).unwrap();
+        child
+            .update_header(|header| header.data_write_guid = base_guid)
+            .unwrap();
+        drop(child);
+        std::fs::copy(&child_path, &base_path).unwrap();
+
+        let error = Vhdx::options()
+            .read_only(true)
+            .open(&middle_path)
+            .unwrap_err();
+        assert!(matches!(error, Error::ParentNotFound));
+    }
+
+    #[test]
+    fn empty_log_does_not_need_replay() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        let mut disk = disk_with_data(&path, 2 * MB as u64, &data);
+
+        // Stop after starting a new log, but before writing to it
+        disk.begin_modification(false).unwrap();
+        disk.log_writer = None;
+        drop(disk);
+
+        let mut disk = Vhdx::options().read_only(true).open(&path).unwrap();
+        assert_ne!(disk.log_guid(), Guid::ZERO);
+        let mut contents = vec![0; data.len()];
+        disk.reader().read_exact(&mut contents).unwrap();
+        assert!(contents == data);
+    }
+}
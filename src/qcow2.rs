@@ -0,0 +1,1142 @@
+//! Reading and writing the QCOW2 format used by QEMU.
+//!
+//! The virtual disk is split into clusters, which are mapped to the file
+//! through a two level table: the L1 table points to L2 tables, which point
+//! to the clusters. Clusters can be compressed, or marked as reading as zero,
+//! and clusters that are not allocated are read from the backing file.
+//!
+//! All fields are big-endian.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{convert::RawSource, Error, OpenOptions, Vhdx};
+
+static MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// The length of the version 3 header, without any extensions.
+const HEADER_LENGTH: usize = 104;
+/// The length of the version 2 header.
+const HEADER_LENGTH_V2: usize = 72;
+
+const EXTENSION_END: u32 = 0;
+const EXTENSION_BACKING_FORMAT: u32 = 0xE279_2ACA;
+
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+
+/// The bits of L1 and L2 entries that hold the offset in the file.
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+/// Set in L1 and L2 entries with a reference count of exactly one.
+const ENTRY_COPIED: u64 = 1 << 63;
+const ENTRY_COMPRESSED: u64 = 1 << 62;
+/// Set in the L2 entries of clusters that read as zero.
+const ENTRY_ZERO: u64 = 1 << 0;
+
+/// The largest L1 table that is read, which is the same limit as QEMU's.
+const MAX_L1_TABLE_LENGTH: u64 = 32 * 1024 * 1024;
+/// The longest backing file name, which is the same limit as QEMU's.
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+
+/// The largest distance that compressed data may refer back to.
+const COMPRESSION_WINDOW: usize = 4096;
+
+/// The default cluster size when writing, which is the same as QEMU's.
+pub const DEFAULT_CLUSTER_SIZE: u32 = 64 * 1024;
+
+/// The QCOW2 header, along with the extensions that are understood.
+#[derive(Debug, Clone)]
+struct Header {
+    version: u32,
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    incompatible_features: u64,
+}
+
+impl Header {
+    fn read(file: &mut (impl Read + Seek)) -> Result<Self, Error> {
+        let mut buffer = [0; HEADER_LENGTH];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buffer[..HEADER_LENGTH_V2])?;
+        if &buffer[0..4] != MAGIC {
+            return Err(Error::InvalidSignature);
+        }
+
+        let version = be_u32(&buffer[4..8]);
+        if !(2..=3).contains(&version) {
+            return Err(Error::Unsupported("QCOW2 version"));
+        }
+        let cluster_bits = be_u32(&buffer[20..24]);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(Error::Unsupported("QCOW2 cluster size"));
+        }
+        if be_u32(&buffer[32..36]) != 0 {
+            return Err(Error::Unsupported("encrypted QCOW2 images"));
+        }
+
+        let (incompatible_features, header_length) = if version == 3 {
+            file.read_exact(&mut buffer[HEADER_LENGTH_V2..])?;
+            let refcount_order = be_u32(&buffer[96..100]);
+            if refcount_order > 6 {
+                return Err(Error::Unsupported("QCOW2 refcount order"));
+            }
+            (be_u64(&buffer[72..80]), be_u32(&buffer[100..104]) as u64)
+        } else {
+            (0, HEADER_LENGTH_V2 as u64)
+        };
+        if incompatible_features & INCOMPATIBLE_CORRUPT != 0 {
+            return Err(Error::InvalidImage("QCOW2 image is marked as corrupt"));
+        }
+        // Images that are only dirty can still be read, as only the reference
+        // counts may be out of date
+        if incompatible_features & !INCOMPATIBLE_DIRTY != 0 {
+            return Err(Error::Unsupported("QCOW2 incompatible features"));
+        }
+
+        // The header and its extensions are all within the first cluster
+        let cluster_size = 1 << cluster_bits;
+        let mut backing_format = None;
+        let mut offset = file.seek(SeekFrom::Start(header_length))?;
+        loop {
+            let mut extension = [0; 8];
+            file.read_exact(&mut extension)?;
+            let extension_type = be_u32(&extension[0..4]);
+            let length = be_u32(&extension[4..8]) as usize;
+            if extension_type == EXTENSION_END {
+                break;
+            }
+            offset += 8 + length.next_multiple_of(8) as u64;
+            if offset > cluster_size {
+                return Err(Error::InvalidImage(
+                    "QCOW2 header extensions extend past the first cluster",
+                ));
+            }
+
+            let mut data = vec![0; length.next_multiple_of(8)];
+            file.read_exact(&mut data)?;
+            if extension_type == EXTENSION_BACKING_FORMAT {
+                backing_format = Some(String::from_utf8(data[..length].to_vec())?);
+            }
+        }
+
+        let backing_file_offset = be_u64(&buffer[8..16]);
+        let backing_file_size = be_u32(&buffer[16..20]);
+        if backing_file_size > MAX_BACKING_FILE_NAME {
+            return Err(Error::InvalidImage("QCOW2 backing file name is too long"));
+        }
+        let backing_file = if backing_file_offset != 0 && backing_file_size != 0 {
+            let mut name = vec![0; backing_file_size as usize];
+            file.seek(SeekFrom::Start(backing_file_offset))?;
+            file.read_exact(&mut name)?;
+            Some(String::from_utf8(name)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version,
+            backing_file,
+            backing_format,
+            cluster_bits,
+            size: be_u64(&buffer[24..32]),
+            l1_size: be_u32(&buffer[36..40]),
+            l1_table_offset: be_u64(&buffer[40..48]),
+            incompatible_features,
+        })
+    }
+}
+
+/// Where the data of a cluster is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cluster {
+    /// Read from the backing file, or zero without one.
+    Unallocated,
+    Zero,
+    Data(u64),
+    Compressed {
+        offset: u64,
+        length: u64,
+    },
+}
+
+/// The image that clusters which are not allocated are read from.
+#[derive(Debug)]
+enum Backing {
+    Qcow2(Box<Qcow2>),
+    Vhdx(Box<Vhdx>),
+    Raw(File),
+}
+
+impl Backing {
+    fn open(path: &Path, format: Option<&str>, children: &mut Vec<PathBuf>) -> Result<Self, Error> {
+        let format = match format {
+            Some(format) => format.to_owned(),
+            None => {
+                // Without the format extension, the format is detected from
+                // the start of the file
+                let mut magic = [0; 8];
+                let mut file = File::open(path)?;
+                let length = file.read(&mut magic)?;
+                match &magic[..length] {
+                    [b'Q', b'F', b'I', 0xfb, ..] => "qcow2".to_owned(),
+                    b"vhdxfile" => "vhdx".to_owned(),
+                    _ => "raw".to_owned(),
+                }
+            }
+        };
+
+        match format.as_str() {
+            "qcow2" => Ok(Backing::Qcow2(Box::new(Qcow2::open_in_chain(
+                path, children,
+            )?))),
+            "vhdx" => Ok(Backing::Vhdx(Box::new(
+                OpenOptions::new().read_only(true).open(path)?,
+            ))),
+            "raw" => Ok(Backing::Raw(File::open(path)?)),
+            _ => Err(Error::Unsupported("QCOW2 backing file format")),
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Backing::Qcow2(qcow2) => qcow2.read_at(offset, buf),
+            Backing::Vhdx(vhdx) => vhdx.read_at(offset, buf),
+            Backing::Raw(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read(buf)
+            }
+        }
+    }
+
+    fn has_data(&mut self, start: u64, end: u64) -> std::io::Result<bool> {
+        match self {
+            Backing::Qcow2(qcow2) => qcow2.has_data(start, end, true),
+            Backing::Vhdx(vhdx) => {
+                let mut ranges = Vec::new();
+                vhdx.data_ranges(start, end, &mut ranges)?;
+                Ok(!ranges.is_empty())
+            }
+            Backing::Raw(file) => Ok(start < file.metadata()?.len()),
+        }
+    }
+}
+
+/// A QCOW2 file, opened for reading.
+#[derive(Debug)]
+pub struct Qcow2 {
+    file: File,
+    header: Header,
+    l1_table: Vec<u64>,
+    backing: Option<Backing>,
+    /// The most recently used L2 table, along with its index in the L1 table.
+    l2_table: Option<(u64, Vec<u64>)>,
+    /// The most recently decompressed cluster, along with its offset in the
+    /// file.
+    decompressed: Option<(u64, Vec<u8>)>,
+}
+
+impl Qcow2 {
+    /// Open the QCOW2 file at the given path, along with its backing file.
+    ///
+    /// A relative backing file path is relative to the directory of the
+    /// image. Backing files can be QCOW2, VHDX or raw images.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_in_chain(path.as_ref(), &mut Vec::new())
+    }
+
+    /// Open the file at `path`, which is the backing file of each of the
+    /// images in `children`, given by their canonical paths.
+    fn open_in_chain(path: &Path, children: &mut Vec<PathBuf>) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        let header = Header::read(&mut file)?;
+
+        let l1_table_length = header.l1_size as u64 * 8;
+        if l1_table_length > MAX_L1_TABLE_LENGTH {
+            return Err(Error::InvalidImage("QCOW2 L1 table is too large"));
+        }
+        let mut buffer = vec![0; l1_table_length as usize];
+        file.seek(SeekFrom::Start(header.l1_table_offset))?;
+        file.read_exact(&mut buffer)?;
+        let l1_table = buffer.chunks_exact(8).map(be_u64).collect();
+
+        let backing = match &header.backing_file {
+            Some(backing_file) => {
+                let backing_path = path
+                    .parent()
+                    .unwrap_or(Path::new(""))
+                    .join(PathBuf::from(backing_file));
+                if !backing_path.is_file() {
+                    return Err(Error::ParentNotFound);
+                }
+                // A backing file that refers back to one of its children
+                // would never end
+                let backing_path = backing_path.canonicalize()?;
+                children.push(path.canonicalize()?);
+                let backing = if children.contains(&backing_path) {
+                    Err(Error::ParentNotFound)
+                } else {
+                    Backing::open(&backing_path, header.backing_format.as_deref(), children)
+                };
+                children.pop();
+                Some(backing?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            file,
+            header,
+            l1_table,
+            backing,
+            l2_table: None,
+            decompressed: None,
+        })
+    }
+
+    /// The size of the virtual disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// The size of each cluster in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        1 << self.header.cluster_bits
+    }
+
+    /// The version of the file format, either 2 or 3.
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// The path to the backing file, as stored in the image.
+    pub fn backing_file(&self) -> Option<&str> {
+        self.header.backing_file.as_deref()
+    }
+
+    /// The format of the backing file, if it is stored in the image.
+    pub fn backing_format(&self) -> Option<&str> {
+        self.header.backing_format.as_deref()
+    }
+
+    /// Use the disk as a [`Reader`] that implements [`std::io::Read`] and
+    /// [`std::io::Seek`].
+    ///
+    /// The reader reports the data in the backing file as well as in this
+    /// image through [`RawSource`].
+    pub fn reader(&mut self) -> Reader<'_> {
+        Reader {
+            disk: self,
+            offset: 0,
+            include_backing: true,
+        }
+    }
+
+    /// A reader that only reports the clusters stored in this image through
+    /// [`RawSource`], although it still reads data from the backing file.
+    pub(crate) fn overlay_reader(&mut self) -> Reader<'_> {
+        Reader {
+            disk: self,
+            offset: 0,
+            include_backing: false,
+        }
+    }
+
+    /// Find where the data of a cluster is stored.
+    fn cluster(&mut self, cluster_index: u64) -> std::io::Result<Cluster> {
+        let l2_bits = self.header.cluster_bits - 3;
+        let l1_index = cluster_index >> l2_bits;
+        let l2_index = cluster_index & ((1 << l2_bits) - 1);
+
+        let Some(l1_entry) = self.l1_table.get(l1_index as usize) else {
+            return Ok(Cluster::Unallocated);
+        };
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        if self.l2_table.as_ref().map(|(index, _)| *index) != Some(l1_index) {
+            let mut buffer = vec![0; 1 << self.header.cluster_bits];
+            self.file.seek(SeekFrom::Start(l2_offset))?;
+            self.file.read_exact(&mut buffer)?;
+            let l2_table = buffer.chunks_exact(8).map(be_u64).collect();
+            self.l2_table = Some((l1_index, l2_table));
+        }
+        let (_, l2_table) = self.l2_table.as_ref().expect("table was just read");
+        let entry = l2_table[l2_index as usize];
+
+        if entry & ENTRY_COMPRESSED != 0 {
+            // The offset is followed by the number of additional 512 byte
+            // sectors that the compressed data extends into
+            let offset_bits = 62 - (self.header.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & !(ENTRY_COPIED | ENTRY_COMPRESSED)) >> offset_bits) + 1;
+            let length = sectors * 512 - (offset & 511);
+            return Ok(Cluster::Compressed { offset, length });
+        }
+        if self.header.version >= 3 && entry & ENTRY_ZERO != 0 {
+            return Ok(Cluster::Zero);
+        }
+        match entry & OFFSET_MASK {
+            0 => Ok(Cluster::Unallocated),
+            offset => Ok(Cluster::Data(offset)),
+        }
+    }
+
+    /// Read from the virtual disk at `offset`, stopping at the end of the
+    /// cluster.
+    ///
+    /// Returns zero at the end of the disk.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let cluster_size = 1 << self.header.cluster_bits;
+        let offset_in_cluster = offset % cluster_size;
+        let num_to_read = (buf.len() as u64)
+            .min(self.header.size.saturating_sub(offset))
+            .min(cluster_size - offset_in_cluster) as usize;
+        let buf = &mut buf[..num_to_read];
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.cluster(offset / cluster_size)? {
+            Cluster::Unallocated => {
+                let Some(backing) = &mut self.backing else {
+                    buf.fill(0);
+                    return Ok(buf.len());
+                };
+                // Beyond the end of a smaller backing file is read as zeros
+                match backing.read_at(offset, buf)? {
+                    0 => {
+                        buf.fill(0);
+                        Ok(buf.len())
+                    }
+                    num_read => Ok(num_read),
+                }
+            }
+            Cluster::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Cluster::Data(file_offset) => {
+                self.file
+                    .seek(SeekFrom::Start(file_offset + offset_in_cluster))?;
+                self.file.read(buf)
+            }
+            Cluster::Compressed {
+                offset: file_offset,
+                length,
+            } => {
+                if self.decompressed.as_ref().map(|(offset, _)| *offset) != Some(file_offset) {
+                    let cluster = self.decompress(file_offset, length)?;
+                    self.decompressed = Some((file_offset, cluster));
+                }
+                let (_, cluster) = self.decompressed.as_ref().expect("cluster was just read");
+                buf.copy_from_slice(&cluster[offset_in_cluster as usize..][..buf.len()]);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Read and inflate a compressed cluster.
+    fn decompress(&mut self, file_offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+        // The last compressed cluster may end before the last whole sector
+        let file_size = self.file.seek(SeekFrom::End(0))?;
+        let length = length.min(file_size.saturating_sub(file_offset));
+        let mut compressed = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        self.file.read_exact(&mut compressed)?;
+
+        let cluster_size = 1 << self.header.cluster_bits;
+        let mut cluster =
+            miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, cluster_size).map_err(
+                |_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "invalid compressed cluster",
+                    )
+                },
+            )?;
+        cluster.resize(cluster_size, 0);
+        Ok(cluster)
+    }
+
+    /// Whether any part of `start..end` contains data in this image, or in the
+    /// backing file if `include_backing` is set.
+    ///
+    /// Clusters marked as zero are counted as data, as they hide the data in
+    /// the backing file.
+    fn has_data(&mut self, start: u64, end: u64, include_backing: bool) -> std::io::Result<bool> {
+        let end = end.min(self.header.size);
+        if start >= end {
+            return Ok(false);
+        }
+
+        let cluster_size = 1 << self.header.cluster_bits;
+        let mut unallocated = Vec::new();
+        for cluster_index in start / cluster_size..end.div_ceil(cluster_size) {
+            match self.cluster(cluster_index)? {
+                Cluster::Unallocated => {
+                    let cluster_start = (cluster_index * cluster_size).max(start);
+                    let cluster_end = ((cluster_index + 1) * cluster_size).min(end);
+                    match unallocated.last_mut() {
+                        Some((_, last_end)) if *last_end == cluster_start => {
+                            *last_end = cluster_end
+                        }
+                        _ => unallocated.push((cluster_start, cluster_end)),
+                    }
+                }
+                _ => return Ok(true),
+            }
+        }
+
+        if let (true, Some(backing)) = (include_backing, &mut self.backing) {
+            for (start, end) in unallocated {
+                if backing.has_data(start, end)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// The size of each part of the disk that is checked for data, which
+    /// covers all of the clusters in an L2 table.
+    fn l2_coverage(&self) -> u64 {
+        1 << (2 * self.header.cluster_bits - 3)
+    }
+}
+
+/// A reader over the virtual disk of a [`Qcow2`], created with
+/// [`Qcow2::reader`].
+#[derive(Debug)]
+pub struct Reader<'a> {
+    disk: &'a mut Qcow2,
+    offset: u64,
+    include_backing: bool,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = self.disk.read_at(self.offset, buf)?;
+        self.offset += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+impl Seek for Reader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(from_end) => self.disk.header.size.checked_add_signed(from_end),
+            SeekFrom::Current(from_current) => self.offset.checked_add_signed(from_current),
+        };
+        self.offset = offset.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative offset",
+            )
+        })?;
+        Ok(self.offset)
+    }
+}
+
+/// Clusters that are not allocated are skipped, along with any parts of the
+/// backing file that are not allocated.
+impl RawSource for Reader<'_> {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let disk_size = self.disk.header.size;
+        let cluster_size = self.disk.cluster_size() as u64;
+        let l2_coverage = self.disk.l2_coverage();
+
+        // Skip over whole L2 tables at a time, then find the first cluster
+        let mut start = offset;
+        while start < disk_size {
+            let l2_end = next_boundary(start, l2_coverage);
+            if self.disk.has_data(start, l2_end, self.include_backing)? {
+                break;
+            }
+            start = l2_end;
+        }
+        while start < disk_size {
+            let cluster_end = next_boundary(start, cluster_size);
+            if self
+                .disk
+                .has_data(start, cluster_end, self.include_backing)?
+            {
+                break;
+            }
+            start = cluster_end;
+        }
+        if start >= disk_size {
+            return Ok(None);
+        }
+
+        let mut end = next_boundary(start, cluster_size);
+        while end < disk_size
+            && self
+                .disk
+                .has_data(end, end + cluster_size, self.include_backing)?
+        {
+            end += cluster_size;
+        }
+        Ok(Some((start, end.min(disk_size))))
+    }
+}
+
+/// Options for writing a QCOW2 image with
+/// [`convert::to_qcow2`](crate::convert::to_qcow2).
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    cluster_size: u32,
+    compress: bool,
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            compress: false,
+            backing_file: None,
+            backing_format: None,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// Options for a standalone image with 64 KB clusters and no compression.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The size of each cluster, which must be a power of two between 512
+    /// bytes and 2 MB. Defaults to 64 KB.
+    pub fn cluster_size(&mut self, cluster_size: u32) -> &mut Self {
+        self.cluster_size = cluster_size;
+        self
+    }
+
+    /// Compress each cluster with zlib, where it makes the cluster smaller.
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Write a differencing disk as an image on top of a backing file,
+    /// rather than including the data of its parents.
+    ///
+    /// The path is stored as given, and is relative to the directory of the
+    /// image. The backing file should have the same contents as the parent of
+    /// the differencing disk, such as the parent itself with the `vhdx`
+    /// format, or a QCOW2 image converted from it.
+    pub fn backing_file(&mut self, path: impl Into<String>, format: Option<&str>) -> &mut Self {
+        self.backing_file = Some(path.into());
+        self.backing_format = format.map(str::to_owned);
+        self
+    }
+
+    pub(crate) fn get_cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    pub(crate) fn has_backing_file(&self) -> bool {
+        self.backing_file.is_some()
+    }
+}
+
+/// Writes a QCOW2 image, where clusters are added in order of the virtual
+/// disk, and the metadata is written by [`Writer::finish`].
+///
+/// Clusters are appended to the file as they are written, followed by the L2
+/// table that points to them once all of the clusters it covers have been
+/// written.
+pub(crate) struct Writer<'a, W> {
+    writer: &'a mut W,
+    options: WriteOptions,
+    cluster_bits: u32,
+    size: u64,
+    l1_table: Vec<u64>,
+    /// The L2 table that is currently being filled, along with its index in
+    /// the L1 table.
+    l2_table: Option<(u64, Vec<u64>)>,
+    /// The offset in the file of the end of the written data.
+    end: u64,
+    /// The reference count of each cluster of the file.
+    refcounts: Vec<u16>,
+}
+
+impl<'a, W: Write + Seek> Writer<'a, W> {
+    pub(crate) fn new(writer: &'a mut W, size: u64, options: &WriteOptions) -> Result<Self, Error> {
+        let cluster_size = options.cluster_size as u64;
+        if !cluster_size.is_power_of_two() || !(512..=2 * 1024 * 1024).contains(&cluster_size) {
+            return Err(Error::Unsupported("QCOW2 cluster size"));
+        }
+        let cluster_bits = cluster_size.trailing_zeros();
+
+        let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+        // The header is followed by the L1 table
+        let end = (1 + l1_clusters) * cluster_size;
+        let refcounts = vec![1; 1 + l1_clusters as usize];
+
+        Ok(Self {
+            writer,
+            options: options.clone(),
+            cluster_bits,
+            size,
+            l1_table: vec![0; l1_size as usize],
+            l2_table: None,
+            end,
+            refcounts,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Write the data of a cluster, which must come after any cluster that
+    /// has already been written. The data is zero padded to a whole cluster.
+    pub(crate) fn write_cluster(&mut self, cluster_index: u64, data: &[u8]) -> Result<(), Error> {
+        if self.options.compress {
+            let compressed = deflate(data);
+            if (compressed.len() as u64) < self.cluster_size() {
+                return self.write_compressed(cluster_index, &compressed);
+            }
+        }
+
+        let offset = self.end.next_multiple_of(self.cluster_size());
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(data)?;
+        self.end = offset + self.cluster_size();
+        self.add_references(offset, self.end);
+
+        self.set_l2_entry(cluster_index, offset | ENTRY_COPIED)
+    }
+
+    /// Write compressed data for a cluster, packed directly after the previous
+    /// data in the file.
+    fn write_compressed(&mut self, cluster_index: u64, compressed: &[u8]) -> Result<(), Error> {
+        let offset = self.end;
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(compressed)?;
+        self.end = offset + compressed.len() as u64;
+        self.add_references(offset, self.end);
+
+        let offset_bits = 62 - (self.cluster_bits - 8);
+        let additional_sectors = (self.end - 1) / 512 - offset / 512;
+        let entry = ENTRY_COMPRESSED | additional_sectors << offset_bits | offset;
+        self.set_l2_entry(cluster_index, entry)
+    }
+
+    /// Mark a cluster as reading as zero, without allocating it.
+    pub(crate) fn zero_cluster(&mut self, cluster_index: u64) -> Result<(), Error> {
+        self.set_l2_entry(cluster_index, ENTRY_ZERO)
+    }
+
+    fn set_l2_entry(&mut self, cluster_index: u64, entry: u64) -> Result<(), Error> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = cluster_index >> l2_bits;
+        if self.l2_table.as_ref().map(|(index, _)| *index) != Some(l1_index) {
+            self.flush_l2_table()?;
+            self.l2_table = Some((l1_index, vec![0; 1 << l2_bits]));
+        }
+        let (_, l2_table) = self.l2_table.as_mut().expect("table was just created");
+        l2_table[(cluster_index & ((1 << l2_bits) - 1)) as usize] = entry;
+        Ok(())
+    }
+
+    /// Write the current L2 table to the end of the file.
+    fn flush_l2_table(&mut self) -> Result<(), Error> {
+        let Some((l1_index, l2_table)) = self.l2_table.take() else {
+            return Ok(());
+        };
+
+        let offset = self.end.next_multiple_of(self.cluster_size());
+        let table = l2_table
+            .iter()
+            .flat_map(|entry| entry.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(&table)?;
+        self.end = offset + self.cluster_size();
+        self.add_references(offset, self.end);
+
+        self.l1_table[l1_index as usize] = offset | ENTRY_COPIED;
+        Ok(())
+    }
+
+    /// Count a reference to each cluster that overlaps a range of the file.
+    fn add_references(&mut self, start: u64, end: u64) {
+        let first = start >> self.cluster_bits;
+        let last = (end - 1) >> self.cluster_bits;
+        if self.refcounts.len() <= last as usize {
+            self.refcounts.resize(last as usize + 1, 0);
+        }
+        for refcount in &mut self.refcounts[first as usize..=last as usize] {
+            *refcount += 1;
+        }
+    }
+
+    /// Write the reference counts, L1 table and header.
+    pub(crate) fn finish(mut self) -> Result<(), Error> {
+        self.flush_l2_table()?;
+        let cluster_size = self.cluster_size();
+
+        // The reference count blocks and table are placed after everything
+        // else, and have to count themselves
+        let clusters_count = self.end.div_ceil(cluster_size);
+        let refcounts_per_block = cluster_size / 2;
+        let mut blocks_count = clusters_count.div_ceil(refcounts_per_block);
+        let table_clusters = loop {
+            let table_clusters = (blocks_count * 8).div_ceil(cluster_size);
+            let total = clusters_count + blocks_count + table_clusters;
+            let needed = total.div_ceil(refcounts_per_block);
+            if needed == blocks_count {
+                break table_clusters;
+            }
+            blocks_count = needed;
+        };
+        let blocks_offset = clusters_count * cluster_size;
+        let table_offset = blocks_offset + blocks_count * cluster_size;
+        let table_end = table_offset + table_clusters * cluster_size;
+        self.refcounts.resize(clusters_count as usize, 0);
+        self.add_references(blocks_offset, table_end);
+
+        let blocks = self
+            .refcounts
+            .iter()
+            .flat_map(|refcount| refcount.to_be_bytes())
+            .collect::<Vec<_>>();
+        let mut table = vec![0; (table_clusters * cluster_size) as usize];
+        for (i, entry) in table
+            .chunks_exact_mut(8)
+            .take(blocks_count as usize)
+            .enumerate()
+        {
+            entry.copy_from_slice(&(blocks_offset + i as u64 * cluster_size).to_be_bytes());
+        }
+        self.writer.seek(SeekFrom::Start(blocks_offset))?;
+        self.writer.write_all(&blocks)?;
+        self.writer.seek(SeekFrom::Start(table_offset))?;
+        self.writer.write_all(&table)?;
+
+        let l1_table = self
+            .l1_table
+            .iter()
+            .flat_map(|entry| entry.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.writer.seek(SeekFrom::Start(cluster_size))?;
+        self.writer.write_all(&l1_table)?;
+
+        let header = self.header(table_offset, table_clusters as u32)?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+
+        // Extend the file to the end of the reference count table
+        self.writer.seek(SeekFrom::Start(table_end - 1))?;
+        self.writer.write_all(&[0])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Serialise the version 3 header, followed by its extensions and the
+    /// backing file path.
+    fn header(
+        &self,
+        refcount_table_offset: u64,
+        refcount_table_clusters: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buffer = vec![0; HEADER_LENGTH];
+        buffer[0..4].copy_from_slice(MAGIC);
+        buffer[4..8].copy_from_slice(&3u32.to_be_bytes());
+        buffer[20..24].copy_from_slice(&self.cluster_bits.to_be_bytes());
+        buffer[24..32].copy_from_slice(&self.size.to_be_bytes());
+        buffer[36..40].copy_from_slice(&(self.l1_table.len() as u32).to_be_bytes());
+        buffer[40..48].copy_from_slice(&self.cluster_size().to_be_bytes());
+        buffer[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+        buffer[56..60].copy_from_slice(&refcount_table_clusters.to_be_bytes());
+        // 16 bit reference counts
+        buffer[96..100].copy_from_slice(&4u32.to_be_bytes());
+        buffer[100..104].copy_from_slice(&(HEADER_LENGTH as u32).to_be_bytes());
+
+        if let Some(format) = &self.options.backing_format {
+            buffer.extend_from_slice(&EXTENSION_BACKING_FORMAT.to_be_bytes());
+            buffer.extend_from_slice(&(format.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(format.as_bytes());
+            buffer.resize(buffer.len().next_multiple_of(8), 0);
+        }
+        buffer.extend_from_slice(&EXTENSION_END.to_be_bytes());
+        buffer.extend_from_slice(&0u32.to_be_bytes());
+
+        if let Some(backing_file) = &self.options.backing_file {
+            let offset = buffer.len() as u64;
+            buffer[8..16].copy_from_slice(&offset.to_be_bytes());
+            buffer[16..20].copy_from_slice(&(backing_file.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(backing_file.as_bytes());
+        }
+
+        if buffer.len() as u64 > self.cluster_size() {
+            return Err(Error::Unsupported(
+                "QCOW2 backing file path longer than a cluster",
+            ));
+        }
+        Ok(buffer)
+    }
+}
+
+/// Compress a cluster as raw deflate data.
+///
+/// QEMU inflates clusters with a 4 KB window, so the dictionary is reset
+/// every 4 KB to keep matches within that distance.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    use miniz_oxide::deflate::core::{
+        compress_to_output, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush,
+        TDEFLStatus,
+    };
+
+    let flags = create_comp_flags_from_zip_params(6, 0, 0);
+    let mut compressor = CompressorOxide::new(flags);
+    let mut output = Vec::new();
+    let mut chunks = data.chunks(COMPRESSION_WINDOW).peekable();
+    while let Some(mut chunk) = chunks.next() {
+        let flush = if chunks.peek().is_some() {
+            TDEFLFlush::Full
+        } else {
+            TDEFLFlush::Finish
+        };
+        loop {
+            let (status, bytes_in) = compress_to_output(&mut compressor, chunk, flush, |out| {
+                output.extend_from_slice(out);
+                true
+            });
+            chunk = &chunk[bytes_in..];
+            match status {
+                TDEFLStatus::Done => break,
+                TDEFLStatus::Okay if chunk.is_empty() => break,
+                TDEFLStatus::Okay => {}
+                _ => unreachable!("output is written to a vector"),
+            }
+        }
+    }
+    output
+}
+
+/// The first multiple of `size` after `offset`.
+fn next_boundary(offset: u64, size: u64) -> u64 {
+    (offset / size + 1) * size
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().expect("infallible"))
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("infallible"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        convert::{from_qcow2, to_qcow2},
+        testing::{disk_with_data, pattern, TempPath},
+        Builder, MB,
+    };
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    fn contents(reader: &mut impl Read) -> Vec<u8> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    /// Write an empty image of 1 MB with a backing file.
+    fn image_with_backing(path: &Path, backing_file: &str) {
+        let mut options = WriteOptions::new();
+        options.backing_file(backing_file, Some("qcow2"));
+        let mut file = File::create(path).unwrap();
+        Writer::new(&mut file, MB as u64, &options)
+            .unwrap()
+            .finish()
+            .unwrap();
+    }
+
+    /// Overwrite the header of the image at `path` at `offset`.
+    fn patch_header(path: &Path, offset: u64, bytes: &[u8]) {
+        let mut file = File::options().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let data = (0..64 * 1024u32)
+            .map(|i| ((i % 251) ^ (i / 4096)) as u8)
+            .collect::<Vec<_>>();
+        let compressed = deflate(&data);
+        assert!(compressed.len() < data.len());
+        let decompressed = miniz_oxide::inflate::decompress_to_vec(&compressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut options = WriteOptions::new();
+        options.backing_file("base.qcow2", Some("qcow2"));
+        let mut buffer = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut buffer, 10 * 1024 * 1024, &options).unwrap();
+        writer.write_cluster(3, &[1; 64 * 1024]).unwrap();
+        writer.zero_cluster(4).unwrap();
+        writer.finish().unwrap();
+
+        let header = Header::read(&mut buffer).unwrap();
+        assert_eq!(header.version, 3);
+        assert_eq!(header.cluster_bits, 16);
+        assert_eq!(header.size, 10 * 1024 * 1024);
+        assert_eq!(header.l1_size, 1);
+        assert_eq!(header.backing_file.as_deref(), Some("base.qcow2"));
+        assert_eq!(header.backing_format.as_deref(), Some("qcow2"));
+    }
+
+    #[test]
+    fn image_round_trip() {
+        let base_path = TempPath::new("vhdx");
+        let overlay_path = TempPath::new("qcow2");
+        let child_path = TempPath::new("vhdx");
+        let base_qcow2_path = TempPath::new("qcow2");
+        let child_qcow2_path = TempPath::new("qcow2");
+        let cluster_size = DEFAULT_CLUSTER_SIZE as usize;
+        let clusters_per_block = (MB / cluster_size) as u64;
+
+        let data = pattern(4 * MB);
+        let mut base = disk_with_data(&base_path, 8 * MB as u64, &data);
+
+        // A differencing disk that hides the second block of the base with
+        // zeros and adds a sixth block, imported from an overlay image
+        let mut options = WriteOptions::new();
+        options.backing_file(file_name(&base_path), Some("vhdx"));
+        let mut file = File::create(&overlay_path).unwrap();
+        let mut writer = Writer::new(&mut file, 8 * MB as u64, &options).unwrap();
+        for cluster_index in clusters_per_block..2 * clusters_per_block {
+            writer.zero_cluster(cluster_index).unwrap();
+        }
+        for cluster_index in 5 * clusters_per_block..6 * clusters_per_block {
+            writer
+                .write_cluster(cluster_index, &vec![0xAA; cluster_size])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        drop(file);
+        let mut builder = Builder::new(&*child_path);
+        builder.block_size(MB as u32).parent(&*base_path);
+        let mut overlay = Qcow2::open(&overlay_path).unwrap();
+        let mut child = from_qcow2(&mut overlay, &builder).unwrap();
+        let mut expected = data.clone();
+        expected.resize(8 * MB, 0);
+        expected[MB..2 * MB].fill(0);
+        expected[5 * MB..6 * MB].fill(0xAA);
+        assert!(contents(&mut child.reader()) == expected);
+
+        // Export the base, and the child on top of it
+        let mut options = WriteOptions::new();
+        options.compress(true);
+        let mut file = File::create(&base_qcow2_path).unwrap();
+        to_qcow2(&mut base, &mut file, &options).unwrap();
+        options.backing_file(file_name(&base_qcow2_path), Some("qcow2"));
+        let mut file = File::create(&child_qcow2_path).unwrap();
+        to_qcow2(&mut child, &mut file, &options).unwrap();
+        drop(file);
+
+        let mut child_qcow2 = Qcow2::open(&child_qcow2_path).unwrap();
+        assert_eq!(child_qcow2.cluster(0).unwrap(), Cluster::Unallocated);
+        assert_eq!(
+            child_qcow2.cluster(clusters_per_block).unwrap(),
+            Cluster::Zero
+        );
+        assert!(matches!(
+            child_qcow2.cluster(5 * clusters_per_block).unwrap(),
+            Cluster::Compressed { .. }
+        ));
+        let Some(Backing::Qcow2(base_qcow2)) = &mut child_qcow2.backing else {
+            panic!("the backing file is not a QCOW2 image");
+        };
+        assert!(matches!(
+            base_qcow2.cluster(0).unwrap(),
+            Cluster::Compressed { .. }
+        ));
+        assert!(contents(&mut child_qcow2.reader()) == expected);
+
+        // Import the whole chain as a single disk, and the child alone on top
+        // of the base
+        let imported_path = TempPath::new("vhdx");
+        let mut builder = Builder::new(&*imported_path);
+        builder.block_size(MB as u32);
+        let mut imported = from_qcow2(&mut child_qcow2, &builder).unwrap();
+        assert!(!imported.has_parent());
+        assert!(contents(&mut imported.reader()) == expected);
+
+        let imported_child_path = TempPath::new("vhdx");
+        let mut builder = Builder::new(&*imported_child_path);
+        builder.block_size(MB as u32).parent(&*base_path);
+        let mut imported_child = from_qcow2(&mut child_qcow2, &builder).unwrap();
+        assert!(contents(&mut imported_child.reader()) == expected);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let path = TempPath::new("qcow2");
+        image_with_backing(&path, "base.qcow2");
+
+        let invalid = |offset, bytes: &[u8]| {
+            let mut header = [0; HEADER_LENGTH];
+            let mut file = File::open(&path).unwrap();
+            file.read_exact(&mut header).unwrap();
+            patch_header(&path, offset, bytes);
+            let error = Qcow2::open(&path).unwrap_err();
+            patch_header(&path, 0, &header);
+            match error {
+                Error::InvalidImage(message) => message,
+                error => panic!("unexpected error: {error}"),
+            }
+        };
+        assert_eq!(
+            invalid(72, &INCOMPATIBLE_CORRUPT.to_be_bytes()),
+            "QCOW2 image is marked as corrupt"
+        );
+        assert_eq!(
+            invalid(16, &1024u32.to_be_bytes()),
+            "QCOW2 backing file name is too long"
+        );
+        assert_eq!(
+            invalid(36, &(4 * MB as u32 + 1).to_be_bytes()),
+            "QCOW2 L1 table is too large"
+        );
+        // Otherwise the header is valid, and only the backing file is missing
+        assert!(matches!(Qcow2::open(&path), Err(Error::ParentNotFound)));
+    }
+
+    #[test]
+    fn backing_file_cycle() {
+        let first_path = TempPath::new("qcow2");
+        let second_path = TempPath::new("qcow2");
+        image_with_backing(&first_path, file_name(&second_path));
+        image_with_backing(&second_path, file_name(&first_path));
+
+        let error = Qcow2::open(&first_path).unwrap_err();
+        assert!(matches!(error, Error::ParentNotFound));
+    }
+}
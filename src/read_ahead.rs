@@ -0,0 +1,332 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::JoinHandle,
+};
+
+use crate::{bat::PayloadBatEntryState, Vhdx, MB};
+
+/// The most that is read ahead at once, so that reading ahead starts
+/// returning data soon after it is started, and buffers can be reused.
+const SEGMENT_SIZE: u64 = 4 * MB as u64;
+
+/// A range of the virtual disk that is read ahead from a fully present block.
+#[derive(Debug)]
+struct Segment {
+    start: u64,
+    length: u64,
+    /// Identifies the request to the background thread
+    id: u64,
+    /// None until the background thread has read the data
+    data: Option<io::Result<Vec<u8>>>,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// A request to fill a buffer from the file at `file_offset`.
+struct Request {
+    id: u64,
+    file_offset: u64,
+    buffer: Vec<u8>,
+}
+
+/// A thread that reads ahead through its own handle to the file, and returns
+/// the data in the order that it was requested.
+#[derive(Debug)]
+struct Worker {
+    requests: Option<Sender<Request>>,
+    results: Receiver<(u64, io::Result<Vec<u8>>)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(file: File) -> io::Result<Self> {
+        let (requests, request_receiver) = mpsc::channel::<Request>();
+        let (result_sender, results) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("vhdx-read-ahead".to_owned())
+            .spawn(move || {
+                for mut request in request_receiver {
+                    let result = crate::file_io::read_exact_at(
+                        &file,
+                        &mut request.buffer,
+                        request.file_offset,
+                    )
+                    .map(|()| request.buffer);
+                    if result_sender.send((request.id, result)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            requests: Some(requests),
+            results,
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, request: Request) -> io::Result<()> {
+        self.requests
+            .as_ref()
+            .and_then(|requests| requests.send(request).ok())
+            .ok_or_else(stopped)
+    }
+
+    /// Wait for the result of a request, discarding the results of earlier
+    /// requests whose segments were dropped.
+    fn wait(&self, id: u64) -> io::Result<io::Result<Vec<u8>>> {
+        loop {
+            let (result_id, result) = self.results.recv().map_err(|_| stopped())?;
+            if result_id == id {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once it finishes its current
+        // request
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the read-ahead thread has stopped")
+}
+
+/// Reads ahead of sequential reads through a [`crate::Reader`].
+#[derive(Debug, Default)]
+pub(crate) struct ReadAhead {
+    window: u64,
+    background: bool,
+    worker: Option<Worker>,
+    /// The end of the previous read, to detect sequential reads
+    last_end: Option<u64>,
+    /// The offset of the virtual disk up to which blocks have been read ahead
+    /// or skipped
+    next: u64,
+    segments: VecDeque<Segment>,
+    next_id: u64,
+    /// Buffers of segments that have been read, for reuse
+    spare: Vec<Vec<u8>>,
+}
+
+impl ReadAhead {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.window > 0
+    }
+
+    pub(crate) fn set_window(&mut self, window: u64) {
+        self.window = window;
+        self.reset();
+    }
+
+    pub(crate) fn set_background(&mut self, background: bool) {
+        self.background = background;
+        if !background {
+            self.worker = None;
+        }
+        self.reset();
+    }
+
+    /// Drop everything that has been read ahead, as after a seek.
+    fn reset(&mut self) {
+        self.last_end = None;
+        while let Some(segment) = self.segments.pop_front() {
+            self.recycle(segment);
+        }
+    }
+
+    fn recycle(&mut self, segment: Segment) {
+        if let Some(Ok(buffer)) = segment.data {
+            self.spare.push(buffer);
+        }
+    }
+
+    /// Read from the virtual disk at `offset`, from what has been read ahead
+    /// where possible, as for [`Vhdx::read_at`].
+    pub(crate) fn read(
+        &mut self,
+        disk: &mut Vhdx,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if self.last_end != Some(offset) {
+            self.reset();
+            let num_read = disk.read_at(offset, buf)?;
+            self.last_end = Some(offset + num_read as u64);
+            self.next = offset + num_read as u64;
+            return Ok(num_read);
+        }
+
+        while self
+            .segments
+            .front()
+            .is_some_and(|segment| segment.end() <= offset)
+        {
+            let segment = self.segments.pop_front().expect("segment is present");
+            self.recycle(segment);
+        }
+        self.fill(disk, offset)?;
+
+        let num_read = match self.segments.front_mut() {
+            Some(segment) if segment.start <= offset => {
+                if segment.data.is_none() {
+                    let worker = self
+                        .worker
+                        .as_ref()
+                        .expect("data is read in the background");
+                    segment.data = Some(worker.wait(segment.id)?);
+                }
+                let data = match segment.data.as_ref().expect("data has been read") {
+                    Ok(data) => data,
+                    Err(error) => return Err(io::Error::new(error.kind(), error.to_string())),
+                };
+                let data = &data[(offset - segment.start) as usize..];
+                let num_read = buf.len().min(data.len());
+                buf[..num_read].copy_from_slice(&data[..num_read]);
+                num_read
+            }
+            // Blocks that aren't read ahead are read as usual, up to where the
+            // next segment starts
+            Some(segment) => {
+                let length = buf.len().min((segment.start - offset) as usize);
+                disk.read_at(offset, &mut buf[..length])?
+            }
+            None => disk.read_at(offset, buf)?,
+        };
+        self.last_end = Some(offset + num_read as u64);
+        Ok(num_read)
+    }
+
+    /// Read ahead of `offset` once less than half of the window is buffered,
+    /// until the window is full.
+    fn fill(&mut self, disk: &mut Vhdx, offset: u64) -> io::Result<()> {
+        let mut buffered = self
+            .segments
+            .iter()
+            .map(|segment| segment.end() - segment.start.max(offset))
+            .sum::<u64>();
+        if buffered > self.window / 2 {
+            return Ok(());
+        }
+        // The thread needs to read from the file without moving the position
+        // of the handle that it shares with the disk
+        if self.background && cfg!(any(unix, windows)) && self.worker.is_none() {
+            self.worker = Some(Worker::spawn(disk.file.try_clone()?)?);
+        }
+
+        let block_size = disk.metadata.file_parameters.block_size() as u64;
+        let disk_size = disk.metadata.virtual_disk_size.virtual_disk_size();
+        self.next = self.next.max(offset);
+        while buffered < self.window && self.next < disk_size {
+            let block_index = self.next / block_size;
+            let block_end = ((block_index + 1) * block_size).min(disk_size);
+            let entry = disk
+                .bat
+                .payload_entry(block_index)?
+                .expect("offset is within the virtual disk");
+            if entry.state() != PayloadBatEntryState::FullyPresent {
+                self.next = block_end;
+                continue;
+            }
+
+            let length = (block_end - self.next)
+                .min(self.window - buffered)
+                .min(SEGMENT_SIZE);
+            let mut buffer = self.spare.pop().unwrap_or_default();
+            buffer.resize(length as usize, 0);
+            let file_offset = entry.file_offset() + self.next % block_size;
+            let mut segment = Segment {
+                start: self.next,
+                length,
+                id: self.next_id,
+                data: None,
+            };
+            self.next_id += 1;
+            match &self.worker {
+                Some(worker) => worker.send(Request {
+                    id: segment.id,
+                    file_offset,
+                    buffer,
+                })?,
+                None => {
+                    segment.data =
+                        Some(read_file_exact(disk, file_offset, &mut buffer).map(|()| buffer));
+                }
+            }
+            self.segments.push_back(segment);
+            buffered += length;
+            self.next += length;
+        }
+        Ok(())
+    }
+}
+
+fn read_file_exact(disk: &mut Vhdx, file_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match disk.read_file(file_offset + filled as u64, &mut buf[filled..])? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            num_read => filled += num_read,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::{
+        testing::{disk_with_data, pattern, TempPath},
+        MB,
+    };
+
+    #[test]
+    fn sequential_reads_across_holes() {
+        let path = TempPath::new("vhdx");
+        // The third block is all zeros, so it isn't allocated
+        let mut data = pattern(5 * MB);
+        data[2 * MB..3 * MB].fill(0);
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &data);
+        data.resize(8 * MB, 0);
+
+        for background in [false, true] {
+            let mut reader = disk.reader();
+            reader
+                .read_ahead(3 * MB as u64)
+                .read_ahead_in_background(background);
+            let mut contents = Vec::new();
+            let mut buf = [0; 3000];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    num_read => contents.extend_from_slice(&buf[..num_read]),
+                }
+            }
+            assert!(contents == data);
+
+            // Seeking back starts reading ahead again from the new position
+            let offset = MB as u64 + 12345;
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            contents.clear();
+            reader
+                .take(3 * MB as u64)
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert!(contents == data[offset as usize..][..3 * MB]);
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! Repairing common corruptions of a VHDX file.
+//!
+//! Like the checker, repair reads the structures of the file directly, so it
+//! works on files that are too damaged to be opened as a [`Vhdx`].
+
+use std::{
+    fmt,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+use crate::{
+    bat::{entries_for, BatEntry, Block, PayloadBatEntryState, SectorBitmapBatEntryState},
+    check::{self, Location},
+    checksum::crc32c,
+    Error, Guid, Header, RegionTable, Vhdx, KB, MB, REGION_GUID_BAT,
+};
+
+/// Options for repairing a VHDX file with [`Vhdx::repair`].
+///
+/// By default nothing is written to the file, and the report describes the
+/// repairs that would be made.
+#[derive(Debug, Clone)]
+pub struct RepairOptions {
+    dry_run: bool,
+}
+
+impl Default for RepairOptions {
+    fn default() -> Self {
+        Self { dry_run: true }
+    }
+}
+
+impl RepairOptions {
+    /// Options for a dry run, which reports repairs without making them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only report the repairs that would be made, without writing to the
+    /// file. Enabled by default.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// A single repair made by [`Vhdx::repair`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    location: Location,
+    message: String,
+    data_loss: Option<Range<u64>>,
+}
+
+impl Repair {
+    /// The part of the file that was repaired.
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// A description of the problem and how it was repaired.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The range of the virtual disk whose contents were lost by the repair,
+    /// if any.
+    pub fn data_loss(&self) -> Option<Range<u64>> {
+        self.data_loss.clone()
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        if let Some(range) = &self.data_loss {
+            write!(
+                f,
+                " (lost data at {:#x}..{:#x} of the virtual disk)",
+                range.start, range.end
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of repairing a file, created by [`Vhdx::repair`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    repairs: Vec<Repair>,
+    dry_run: bool,
+}
+
+impl RepairReport {
+    /// Everything that was repaired, in the order it was repaired.
+    pub fn repairs(&self) -> &[Repair] {
+        &self.repairs
+    }
+
+    /// Whether the repairs were only reported, and not written to the file.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Whether any of the repairs lost data from the virtual disk.
+    pub fn loses_data(&self) -> bool {
+        self.repairs.iter().any(|repair| repair.data_loss.is_some())
+    }
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for repair in &self.repairs {
+            writeln!(f, "{repair}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Vhdx {
+    /// Repair common corruptions of the VHDX file at the given path, which
+    /// doesn't need to be possible to open.
+    ///
+    /// - A header or region table that is invalid is restored from the other
+    ///   copy.
+    /// - A log that has no valid sequence is discarded. A valid log is
+    ///   replayed, and if the file has been truncated since the log was
+    ///   written, the file is first extended back to the flushed file offset
+    ///   that the log recorded.
+    /// - BAT entries that point beyond the end of the file or into the header
+    ///   section, log or a region are marked as not present, which is reported
+    ///   as a loss of the data of the block. Partially present blocks lose
+    ///   their data along with their sector bitmap block.
+    ///
+    /// Unless [`RepairOptions::dry_run`] is disabled, nothing is written, and
+    /// the report describes what would be repaired. In that case the BAT is
+    /// examined as it is before the log would be replayed.
+    ///
+    /// Problems that can't be repaired, such as both headers being invalid,
+    /// return [`Error::Unrepairable`], after any earlier repairs have been
+    /// made.
+    pub fn repair(path: impl AsRef<Path>, options: &RepairOptions) -> Result<RepairReport, Error> {
+        let file = File::options()
+            .read(true)
+            .write(!options.dry_run)
+            .open(path)?;
+        Repairer::new(file, options.dry_run)?.run()
+    }
+}
+
+struct Repairer {
+    file: File,
+    file_size: u64,
+    dry_run: bool,
+    repairs: Vec<Repair>,
+    /// The current header, as it is on disk once the repairs are made
+    header: Vec<u8>,
+    /// Which of the two headers is current, numbered 1 or 2
+    header_number: u8,
+}
+
+impl Repairer {
+    fn new(file: File, dry_run: bool) -> Result<Self, Error> {
+        let file_size = file.metadata()?.len();
+        Ok(Self {
+            file,
+            file_size,
+            dry_run,
+            repairs: Vec::new(),
+            header: Vec::new(),
+            header_number: 0,
+        })
+    }
+
+    fn repaired(&mut self, location: Location, message: String, data_loss: Option<Range<u64>>) {
+        self.repairs.push(Repair {
+            location,
+            message,
+            data_loss,
+        });
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if !self.dry_run {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    fn run(mut self) -> Result<RepairReport, Error> {
+        self.repair_headers()?;
+        let data_end = self.repair_log()?;
+        self.repair_region_tables()?;
+        self.repair_bat(data_end)?;
+
+        if !self.repairs.is_empty() {
+            let loses_data = self.repairs.iter().any(|repair| repair.data_loss.is_some());
+            self.update_header(|header| {
+                header[16..32].copy_from_slice(&Guid::new_random().to_bytes());
+                if loses_data {
+                    header[32..48].copy_from_slice(&Guid::new_random().to_bytes());
+                }
+            })?;
+        }
+        if !self.dry_run {
+            self.file.sync_all()?;
+        }
+
+        Ok(RepairReport {
+            repairs: self.repairs,
+            dry_run: self.dry_run,
+        })
+    }
+
+    /// Write a modified copy of the current header with the next sequence
+    /// number over the other header, making it the current header.
+    fn update_header(&mut self, update: impl FnOnce(&mut [u8])) -> Result<(), Error> {
+        let mut header = self.header.clone();
+        let sequence_number = u64::from_le_bytes(header[8..16].try_into().expect("infallible")) + 1;
+        header[8..16].copy_from_slice(&sequence_number.to_le_bytes());
+        update(&mut header);
+        header[4..8].fill(0);
+        let checksum = crc32c(&header);
+        header[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        let number = 3 - self.header_number;
+        self.write_at(64 * KB as u64 * number as u64, &header)?;
+        if !self.dry_run {
+            self.file.sync_data()?;
+        }
+        self.header = header;
+        self.header_number = number;
+        Ok(())
+    }
+
+    fn repair_headers(&mut self) -> Result<(), Error> {
+        let mut valid = Vec::new();
+        let mut invalid = Vec::new();
+        for number in 1..=2 {
+            let buffer = check::read_at(&self.file, 64 * KB as u64 * number as u64, 4 * KB)?;
+            match Header::read(&mut buffer.as_slice()) {
+                Ok(header) => valid.push((number, header.sequence_number, buffer)),
+                Err(error) => invalid.push((number, error)),
+            }
+        }
+
+        let (number, _, buffer) = valid
+            .into_iter()
+            .max_by_key(|&(_, sequence_number, _)| sequence_number)
+            .ok_or(Error::Unrepairable("neither header is valid"))?;
+        self.header = buffer;
+        self.header_number = number;
+
+        // Writing the next header always replaces the invalid one
+        if let Some((number, message)) = invalid.pop() {
+            self.update_header(|_| {})?;
+            self.repaired(
+                Location::Header(number),
+                format!("{message}, restored from header {}", 3 - number),
+                None,
+            );
+        }
+        Ok(())
+    }
+
+    /// Replay or discard the log, returning the end of the data in the file,
+    /// which is before the end of the file if it has been truncated.
+    fn repair_log(&mut self) -> Result<u64, Error> {
+        let header = Header::read(&mut self.header.as_slice())?;
+        if header.log_guid == Guid::ZERO {
+            return Ok(self.file_size);
+        }
+
+        let log_end = header.log_offset.saturating_add(header.log_length as u64);
+        let is_log_usable = header.log_offset.is_multiple_of(MB as u64)
+            && header.log_length.is_multiple_of(MB as u32)
+            && header.log_length > 0
+            && log_end <= self.file_size;
+        // Invalid entries end the search for a sequence, so the only errors
+        // are from reading the file, which must not lose a log that could
+        // have been replayed
+        let sequence = if is_log_usable {
+            Vhdx::find_log(&mut self.file, &header)?
+        } else {
+            None
+        };
+        let Some(sequence) = sequence else {
+            self.update_header(|header| header[48..64].copy_from_slice(&Guid::ZERO.to_bytes()))?;
+            self.repaired(
+                Location::Log,
+                "has no valid sequence, discarded the log".to_owned(),
+                None,
+            );
+            return Ok(self.file_size);
+        };
+
+        let flushed_file_offset = sequence.flushed_file_offset();
+        let last_file_offset = sequence
+            .head()
+            .map_or(0, |entry| entry.header().last_file_offset);
+        if self.file_size < flushed_file_offset {
+            self.repaired(
+                Location::Log,
+                format!(
+                    "the file has been truncated from {flushed_file_offset:#x} to {:#x} bytes, \
+                     extended it to replay the log",
+                    self.file_size
+                ),
+                None,
+            );
+        }
+        if !self.dry_run {
+            let length = self
+                .file_size
+                .max(flushed_file_offset)
+                .max(last_file_offset);
+            self.file.set_len(length)?;
+            for entry in sequence.iter() {
+                entry.apply(&mut self.file)?;
+            }
+            self.file.sync_data()?;
+        }
+        self.update_header(|header| header[48..64].copy_from_slice(&Guid::ZERO.to_bytes()))?;
+        self.repaired(
+            Location::Log,
+            format!("replayed {} log entries", sequence.entries.len()),
+            None,
+        );
+        Ok(self.file_size)
+    }
+
+    fn repair_region_tables(&mut self) -> Result<(), Error> {
+        let mut valid = Vec::new();
+        let mut invalid = Vec::new();
+        for number in 1..=2 {
+            let offset = (128 + 64 * number as u64) * KB as u64;
+            let buffer = check::read_at(&self.file, offset, 64 * KB)?;
+            match RegionTable::read(&mut buffer.as_slice()) {
+                Ok(_) => valid.push((number, buffer)),
+                Err(error) => invalid.push((number, offset, error)),
+            }
+        }
+
+        let (valid_number, buffer) = valid
+            .pop()
+            .ok_or(Error::Unrepairable("neither region table is valid"))?;
+        for (number, offset, message) in invalid {
+            self.write_at(offset, &buffer)?;
+            self.repaired(
+                Location::RegionTable(number),
+                format!("{message}, restored from region table {valid_number}"),
+                None,
+            );
+        }
+        Ok(())
+    }
+
+    fn repair_bat(&mut self, data_end: u64) -> Result<(), Error> {
+        let structure = check::read_structure(&self.file)?;
+        let regions = structure
+            .regions
+            .ok_or(Error::Unrepairable("neither region table is valid"))?;
+        let bat_region = regions
+            .iter()
+            .find(|region| region.guid == REGION_GUID_BAT)
+            .ok_or(Error::MissingRequiredRegion("bat"))?
+            .clone();
+        let layout = structure
+            .layout
+            .ok_or(Error::Unrepairable("the metadata region is invalid"))?;
+
+        // Ranges of the file that blocks must not be in
+        let mut reserved = vec![(0, MB as u64, Location::HeaderSection)];
+        if let Some(header) = structure.header {
+            let log_end = header.log_offset.saturating_add(header.log_length as u64);
+            reserved.push((header.log_offset, log_end, Location::Log));
+        }
+        for region in &regions {
+            let region_end = region.file_offset.saturating_add(region.length as u64);
+            reserved.push((
+                region.file_offset,
+                region_end,
+                Location::Region(region.guid),
+            ));
+        }
+        let problem = |offset: u64, length: u64| {
+            let end = offset.saturating_add(length);
+            if end > data_end {
+                return Some("points beyond the end of the file".to_owned());
+            }
+            reserved
+                .iter()
+                .find(|&&(start, reserved_end, _)| offset < reserved_end && start < end)
+                .map(|(_, _, location)| format!("points into the {location}"))
+        };
+
+        let chunk_ratio = layout.chunk_ratio();
+        let payload_blocks_count = layout.virtual_size.div_ceil(layout.block_size);
+        let total_entries = entries_for(payload_blocks_count, chunk_ratio, layout.has_parent)
+            .min(bat_region.length as u64 / 8);
+        let block_data = |block: u64| {
+            let start = block * layout.block_size;
+            start..(start + layout.block_size).min(layout.virtual_size)
+        };
+
+        // The new value of each BAT entry that is repaired
+        let mut updates = Vec::new();
+        // Partially present payload blocks, with the BAT index and chunk
+        let mut partially_present = Vec::new();
+        let mut present_bitmaps = Vec::new();
+        let file = self.file.try_clone()?;
+        let entries =
+            check::raw_bat_entries(&file, bat_region.file_offset, total_entries, chunk_ratio);
+        for result in entries {
+            let (index, value, block) = result?;
+            let entry = BatEntry::from_bits(value);
+            let offset = entry.file_offset();
+
+            let chunk_index = index / (chunk_ratio + 1);
+            let block = match block {
+                Block::Payload(block) => block,
+                Block::SectorBitmap(_) => {
+                    if entry.sector_bitmap_state() != SectorBitmapBatEntryState::Present {
+                        continue;
+                    }
+                    if let Some(problem) = problem(offset, MB as u64) {
+                        updates.push((index, 0));
+                        self.repaired(
+                            Location::SectorBitmapBlock(chunk_index),
+                            format!("{problem}, marked as not present"),
+                            None,
+                        );
+                    } else {
+                        present_bitmaps.push(chunk_index);
+                    }
+                    continue;
+                }
+            };
+
+            match entry.state() {
+                // Zero and unmapped blocks may keep their space in the file
+                PayloadBatEntryState::Zero | PayloadBatEntryState::Unmapped if offset != 0 => {
+                    if let Some(problem) = problem(offset, layout.block_size) {
+                        updates.push((index, BatEntry::new(entry.state()).to_bits()));
+                        self.repaired(
+                            Location::PayloadBlock(block),
+                            format!("{problem}, cleared the offset"),
+                            None,
+                        );
+                    }
+                }
+                state if state.is_allocated() => {
+                    if let Some(problem) = problem(offset, layout.block_size) {
+                        updates.push((index, 0));
+                        self.repaired(
+                            Location::PayloadBlock(block),
+                            format!("{problem}, marked as not present"),
+                            Some(block_data(block)),
+                        );
+                    } else if state == PayloadBatEntryState::PartiallyPresent {
+                        partially_present.push((index, block, chunk_index));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (index, block, chunk_index) in partially_present {
+            if !present_bitmaps.contains(&chunk_index) {
+                updates.push((index, 0));
+                self.repaired(
+                    Location::PayloadBlock(block),
+                    "partially present without a sector bitmap block, marked as not present"
+                        .to_owned(),
+                    Some(block_data(block)),
+                );
+            }
+        }
+
+        for (index, value) in updates {
+            self.write_at(bat_region.file_offset + index * 8, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::{
+        check::check_file,
+        testing::{disk_with_data, TempPath},
+    };
+
+    #[test]
+    fn corrupt_header_and_block_past_end() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+
+        // Damage the first header, and point the second block past the end
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset;
+        let second = disk.bat.payload_entry(1).unwrap().unwrap().to_bits();
+        drop(disk);
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(64 * KB as u64 + 100)).unwrap();
+        file.write_all(&[0xFF; 4]).unwrap();
+        file.seek(SeekFrom::Start(bat_offset + 8)).unwrap();
+        file.write_all(&(second + (64 * MB as u64)).to_le_bytes())
+            .unwrap();
+        drop(file);
+        let damaged = std::fs::read(&path).unwrap();
+
+        let report = Vhdx::repair(&path, &RepairOptions::new()).unwrap();
+        assert!(report.is_dry_run());
+        assert_eq!(std::fs::read(&path).unwrap(), damaged);
+        let locations = report
+            .repairs()
+            .iter()
+            .map(Repair::location)
+            .collect::<Vec<_>>();
+        assert_eq!(locations, [Location::Header(1), Location::PayloadBlock(1)]);
+        assert_eq!(
+            report.repairs()[1].data_loss(),
+            Some(MB as u64..2 * MB as u64)
+        );
+
+        let report = Vhdx::repair(&path, RepairOptions::new().dry_run(false)).unwrap();
+        assert!(report.loses_data());
+        assert!(check_file(&path).unwrap().is_ok());
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        let mut contents = Vec::new();
+        disk.reader().read_to_end(&mut contents).unwrap();
+        assert!(contents[..MB].iter().all(|&byte| byte == 1));
+        assert!(contents[MB..].iter().all(|&byte| byte == 0));
+    }
+
+    /// Repair the file at `path` in a dry run, checking that the file isn't
+    /// modified, then for real, returning the locations that were repaired.
+    fn repair_locations(path: &Path) -> Vec<Location> {
+        let damaged = std::fs::read(path).unwrap();
+        let dry_run = Vhdx::repair(path, &RepairOptions::new()).unwrap();
+        assert!(std::fs::read(path).unwrap() == damaged);
+
+        let report = Vhdx::repair(path, RepairOptions::new().dry_run(false)).unwrap();
+        assert_eq!(report.repairs(), dry_run.repairs());
+        assert!(!report.loses_data());
+        assert!(check_file(path).unwrap().is_ok());
+        report.repairs().iter().map(Repair::location).collect()
+    }
+
+    #[test]
+    fn empty_log_is_discarded() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        // Start a log, then stop without writing to it or marking it as empty
+        disk.begin_modification(false).unwrap();
+        disk.log_writer = None;
+        drop(disk);
+
+        assert_eq!(repair_locations(&path), [Location::Log]);
+        let disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.current_header().log_guid, Guid::ZERO);
+    }
+
+    #[test]
+    fn log_is_replayed() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]);
+        let block_offset = disk.bat.payload_entry(0).unwrap().unwrap().file_offset();
+        disk.begin_modification(true).unwrap();
+        disk.write_logged(&[crate::log::LogWrite::Data {
+            file_offset: block_offset,
+            sector: Box::new([2; 4 * KB]),
+        }])
+        .unwrap();
+        // Stop without marking the log as empty, and undo the write so that
+        // replaying it can be seen
+        disk.log_writer = None;
+        disk.file.seek(SeekFrom::Start(block_offset)).unwrap();
+        disk.file.write_all(&[1; 4 * KB]).unwrap();
+        drop(disk);
+
+        assert_eq!(repair_locations(&path), [Location::Log]);
+        let mut disk = Vhdx::options().read_only(true).open(&path).unwrap();
+        let mut contents = Vec::new();
+        disk.reader().read_to_end(&mut contents).unwrap();
+        assert!(contents[..4 * KB].iter().all(|&byte| byte == 2));
+        assert!(contents[4 * KB..2 * MB].iter().all(|&byte| byte == 1));
+    }
+
+    #[test]
+    fn region_table_is_restored() {
+        let path = TempPath::new("vhdx");
+        drop(disk_with_data(&path, 8 * MB as u64, &[1; 2 * MB]));
+        let region_table_1 = 192 * KB as u64..256 * KB as u64;
+        let region_table_2 = 256 * KB as u64..320 * KB as u64;
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(region_table_2.start + 20))
+            .unwrap();
+        file.write_all(&[0xFF; 4]).unwrap();
+        drop(file);
+
+        assert_eq!(repair_locations(&path), [Location::RegionTable(2)]);
+        let repaired = std::fs::read(&path).unwrap();
+        assert!(
+            repaired[region_table_1.start as usize..region_table_1.end as usize]
+                == repaired[region_table_2.start as usize..region_table_2.end as usize]
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::{
+    bat::{div_ceil, BatEntry, PayloadBatEntryState},
+    log::next_multiple_of,
+    metadata::{MetadataItem, VirtualDiskSize},
+    sparse, Error, Vhdx, KB, MB, REGION_GUID_BAT,
+};
+
+/// The largest virtual disk size allowed by the specification, 64 TB.
+pub(crate) const MAX_VIRTUAL_DISK_SIZE: u64 = 64 * (MB as u64) * (MB as u64);
+
+impl Vhdx {
+    /// Change the size of the virtual disk.
+    ///
+    /// Growing the disk adds entries to the BAT, moving the BAT region to the
+    /// end of the file if it no longer fits within its current allocation,
+    /// and releasing the space of the old region. If the file requires blocks
+    /// to be left allocated, the new blocks are allocated as well.
+    ///
+    /// Shrinking the disk requires that no blocks beyond the new size are
+    /// allocated, which can be achieved by discarding that part of the disk
+    /// first with [`Vhdx::discard`]. The space in the file that is still held
+    /// by discarded blocks of a file that requires blocks to be left allocated
+    /// is released. The rest of a block that is only partly within the new
+    /// size is zeroed.
+    ///
+    /// The new size of the disk only takes effect once the BAT is in place,
+    /// through the log, so an interrupted resize leaves the disk at its
+    /// original size.
+    pub fn resize(&mut self, new_size: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        let logical_sector_size = self.metadata.logical_sector_size.logical_sector_size() as u64;
+        if new_size == 0
+            || !new_size.is_multiple_of(logical_sector_size)
+            || new_size > MAX_VIRTUAL_DISK_SIZE
+        {
+            return Err(Error::InvalidVirtualDiskSize(new_size));
+        }
+        if self.metadata.file_parameters.has_parent() {
+            return Err(Error::Unsupported("resizing differencing disks"));
+        }
+        if new_size == self.metadata.virtual_disk_size.virtual_disk_size() {
+            return Ok(());
+        }
+
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let old_blocks_count = self.bat.payload_blocks_count();
+        let new_blocks_count = div_ceil(new_size, block_size);
+        for block_index in new_blocks_count..old_blocks_count {
+            let entry = self.bat.payload_entry(block_index)?;
+            if entry.is_some_and(|entry| entry.state().is_allocated()) {
+                return Err(Error::AllocatedBeyondSize);
+            }
+        }
+
+        self.begin_modification(true)?;
+        // The last chunk of the disk changes size, and shrinking removes
+        // chunks altogether
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+
+        // The BAT is changed in memory before the changes are written to the
+        // file, so if anything fails it is read from the file again
+        if let Err(error) = self.resize_bat(new_size) {
+            self.reload_regions()?;
+            return Err(error);
+        }
+        self.reload_regions()
+    }
+
+    /// Resize the BAT and write it to the file, followed by the new size.
+    fn resize_bat(&mut self, new_size: u64) -> Result<(), Error> {
+        let block_size = self.metadata.file_parameters.block_size() as u64;
+        let old_size = self.metadata.virtual_disk_size.virtual_disk_size();
+        let old_blocks_count = self.bat.payload_blocks_count();
+        let new_blocks_count = div_ceil(new_size, block_size);
+
+        // The rest of the last block after a smaller size is zeroed, so that
+        // its old contents don't reappear if the disk is grown again
+        let mut writes = Vec::new();
+        let tail_offset = new_size % block_size;
+        if new_size < old_size && tail_offset != 0 {
+            let entry = self
+                .bat
+                .payload_entry(new_blocks_count - 1)?
+                .expect("block is within the virtual disk");
+            if entry.state().is_allocated() {
+                writes.extend(self.zero_writes(
+                    entry.file_offset() + tail_offset,
+                    entry.file_offset() + block_size,
+                )?);
+            }
+        }
+
+        // Blocks beyond the new size that are not allocated may still have
+        // space in the file, if blocks are left allocated when discarded
+        let mut freed_blocks = Vec::new();
+        for block_index in new_blocks_count..old_blocks_count {
+            let entry = self.bat.payload_entry(block_index)?;
+            if let Some(entry) = entry.filter(|entry| entry.file_offset() != 0) {
+                freed_blocks.push(entry.file_offset());
+            }
+        }
+
+        let old_entries_count = self.bat.entries_count();
+        self.bat.resize(new_size);
+        let new_entries_count = self.bat.entries_count();
+
+        if self.metadata.file_parameters.leave_block_allocated()
+            && new_blocks_count > old_blocks_count
+        {
+            let offset = self.allocate((new_blocks_count - old_blocks_count) * block_size)?;
+            for (i, block_index) in (old_blocks_count..new_blocks_count).enumerate() {
+                let entry = BatEntry::try_new(
+                    PayloadBatEntryState::FullyPresent,
+                    offset + i as u64 * block_size,
+                )?;
+                self.bat.set_payload_entry(block_index, entry)?;
+            }
+        }
+
+        let bat_region = self
+            .region(REGION_GUID_BAT)
+            .ok_or(Error::MissingRequiredRegion("bat"))?
+            .clone();
+        let relocated = new_entries_count * 8 > bat_region.length as u64;
+        if relocated {
+            writes.extend(self.relocate_bat(new_entries_count)?);
+        } else {
+            // Rewrite the sectors containing entries that were added or removed
+            let sector_size = 4 * KB as u64;
+            let first_sector = old_entries_count.min(new_entries_count) * 8 / sector_size;
+            let last_sector = (old_entries_count.max(new_entries_count) * 8 - 1) / sector_size;
+            for sector_index in first_sector..=last_sector {
+                writes.push(crate::log::LogWrite::Data {
+                    file_offset: bat_region.file_offset + sector_index * sector_size,
+                    sector: self.bat.sector(sector_index)?,
+                });
+            }
+        }
+
+        // The size is updated last, so that the BAT is complete before the
+        // new size is visible
+        let size_offset = self
+            .metadata_item_offset(VirtualDiskSize::GUID)
+            .ok_or(Error::MissingRequiredMetadata("virtual disk size"))?;
+        writes.extend(self.range_writes(size_offset, &new_size.to_le_bytes())?);
+        self.write_logged(&writes)?;
+
+        // The old BAT region and the blocks beyond the new size are no longer
+        // referenced, so their space can be released
+        if relocated {
+            sparse::punch_hole(&self.file, bat_region.file_offset, bat_region.length as u64)?;
+        }
+        for file_offset in freed_blocks {
+            sparse::punch_hole(&self.file, file_offset, block_size)?;
+        }
+        Ok(())
+    }
+
+    /// Write the in-memory BAT to a new region at the end of the file, and
+    /// return the log writes that point the region tables at it.
+    fn relocate_bat(&mut self, entries_count: u64) -> Result<Vec<crate::log::LogWrite>, Error> {
+        let sector_size = 4 * KB as u64;
+        let length = next_multiple_of(entries_count * 8, MB as u64);
+        let offset = self.allocate(length)?;
+
+        // The new region is not referenced until the region tables are
+        // updated, so it can be written directly
+        self.file.seek(SeekFrom::Start(offset))?;
+        for sector_index in 0..length / sector_size {
+            self.file
+                .write_all(self.bat.sector(sector_index)?.as_ref())?;
+        }
+        self.file.sync_data()?;
+
+        self.region_table_writes(REGION_GUID_BAT, offset, length as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::*;
+    use crate::{
+        testing::{disk_with_data, pattern, TempPath},
+        Builder,
+    };
+
+    fn read(disk: &mut Vhdx, offset: u64, length: usize) -> Vec<u8> {
+        let mut reader = disk.reader();
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buffer = vec![0; length];
+        reader.read_exact(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn grow_in_place() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        let mut disk = disk_with_data(&path, 2 * MB as u64, &data);
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset;
+
+        disk.resize(8 * MB as u64).unwrap();
+        assert_eq!(
+            disk.region(REGION_GUID_BAT).unwrap().file_offset,
+            bat_offset
+        );
+        drop(disk);
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.virtual_size(), 8 * MB as u64);
+        assert!(read(&mut disk, 0, 2 * MB) == data);
+        assert!(read(&mut disk, 2 * MB as u64, 6 * MB) == vec![0; 6 * MB]);
+    }
+
+    #[test]
+    fn grow_with_relocation() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(2 * MB);
+        let mut disk = disk_with_data(&path, 2 * MB as u64, &data);
+        let old_region = disk.region(REGION_GUID_BAT).unwrap().clone();
+
+        // A 1 MB BAT region holds the entries of 128 GB of 1 MB blocks
+        let new_size = 256 * 1024 * MB as u64;
+        disk.resize(new_size).unwrap();
+        let new_region = disk.region(REGION_GUID_BAT).unwrap().clone();
+        assert!(new_region.file_offset > old_region.file_offset);
+        assert!(new_region.length > old_region.length);
+        // The old region is released
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            sparse::next_hole(&disk.file, old_region.file_offset).unwrap(),
+            old_region.file_offset
+        );
+        drop(disk);
+
+        assert!(crate::check::check_file(&path).unwrap().is_ok());
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.virtual_size(), new_size);
+        assert!(read(&mut disk, 0, 2 * MB) == data);
+        assert!(read(&mut disk, new_size - MB as u64, MB) == vec![0; MB]);
+    }
+
+    #[test]
+    fn shrink_zeroes_rest_of_last_block() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(4 * MB);
+        let mut disk = disk_with_data(&path, 4 * MB as u64, &data);
+
+        // Blocks beyond the new size must not be allocated
+        let new_size = MB as u64 + 4608;
+        let error = disk.resize(new_size).unwrap_err();
+        assert!(matches!(error, Error::AllocatedBeyondSize));
+        assert_eq!(disk.virtual_size(), 4 * MB as u64);
+
+        disk.discard(2 * MB as u64, 2 * MB as u64).unwrap();
+        disk.resize(new_size).unwrap();
+        assert_eq!(disk.virtual_size(), new_size);
+        assert!(read(&mut disk, 0, new_size as usize) == data[..new_size as usize]);
+
+        // Growing again doesn't bring back the data beyond the smaller size
+        disk.resize(4 * MB as u64).unwrap();
+        data[new_size as usize..].fill(0);
+        drop(disk);
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert!(read(&mut disk, 0, 4 * MB) == data);
+    }
+
+    #[test]
+    fn grow_and_shrink_fixed() {
+        let path = TempPath::new("vhdx");
+        let data = pattern(4 * MB);
+        let mut builder = Builder::new(&*path);
+        builder
+            .virtual_size(4 * MB as u64)
+            .block_size(MB as u32)
+            .fixed(true);
+        let mut disk =
+            crate::convert::from_raw(&mut std::io::Cursor::new(&data), &builder).unwrap();
+
+        // Discarded blocks of a fixed disk keep their space, which is
+        // released when they are removed by shrinking the disk
+        disk.discard(2 * MB as u64, 2 * MB as u64).unwrap();
+        let freed = disk.bat.payload_entry(3).unwrap().unwrap().file_offset();
+        assert_ne!(freed, 0);
+        disk.resize(2 * MB as u64).unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(sparse::next_hole(&disk.file, freed).unwrap(), freed);
+        drop(disk);
+        assert!(crate::check::check_file(&path).unwrap().is_ok());
+
+        // Growing the disk allocates the new blocks
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert!(read(&mut disk, 0, 2 * MB) == data[..2 * MB]);
+        disk.resize(6 * MB as u64).unwrap();
+        for block_index in 0..6 {
+            assert_eq!(
+                disk.block_state(block_index).unwrap(),
+                PayloadBatEntryState::FullyPresent
+            );
+        }
+        drop(disk);
+        assert!(crate::check::check_file(&path).unwrap().is_ok());
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.virtual_size(), 6 * MB as u64);
+        assert!(read(&mut disk, 0, 2 * MB) == data[..2 * MB]);
+        assert!(read(&mut disk, 2 * MB as u64, 4 * MB) == vec![0; 4 * MB]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+
+use crate::Error;
+
+/// Release the storage backing a range of the file, where it is supported by
+/// the platform and filesystem. The range reads as zeros afterwards.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, length: u64) -> Result<(), Error> {
+    use rustix::fs::{fallocate, FallocateFlags};
+
+    match fallocate(
+        file,
+        FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE,
+        offset,
+        length,
+    ) {
+        Ok(()) => Ok(()),
+        // Not every filesystem supports punching holes
+        Err(rustix::io::Errno::OPNOTSUPP) => Ok(()),
+        Err(e) => Err(std::io::Error::from(e).into()),
+    }
+}
+
+/// Release the storage backing a range of the file, where it is supported by
+/// the platform and filesystem. The range reads as zeros afterwards.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &File, _offset: u64, _length: u64) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Find the start of the next range of the file at or after `offset` that
+/// may contain data, as with `lseek(SEEK_DATA)`.
+///
+/// Returns none if the rest of the file is a hole. Where this is not
+/// supported, the whole file is treated as data.
+#[cfg(target_os = "linux")]
+pub fn next_data(file: &File, offset: u64) -> std::io::Result<Option<u64>> {
+    use rustix::fs::{seek, SeekFrom};
+
+    match seek(file, SeekFrom::Data(offset)) {
+        Ok(offset) => Ok(Some(offset)),
+        Err(rustix::io::Errno::NXIO) => Ok(None),
+        Err(rustix::io::Errno::INVAL) => Ok(Some(offset)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find the start of the next hole in the file at or after `offset`, as with
+/// `lseek(SEEK_HOLE)`. There is always a hole at the end of the file.
+#[cfg(target_os = "linux")]
+pub fn next_hole(file: &File, offset: u64) -> std::io::Result<u64> {
+    use rustix::fs::{seek, SeekFrom};
+
+    match seek(file, SeekFrom::Hole(offset)) {
+        Ok(offset) => Ok(offset),
+        Err(rustix::io::Errno::INVAL) => Ok(file.metadata()?.len()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find the start of the next range of the file at or after `offset` that
+/// may contain data, as with `lseek(SEEK_DATA)`.
+///
+/// Returns none if the rest of the file is a hole. Where this is not
+/// supported, the whole file is treated as data.
+#[cfg(not(target_os = "linux"))]
+pub fn next_data(file: &File, offset: u64) -> std::io::Result<Option<u64>> {
+    Ok((offset < file.metadata()?.len()).then_some(offset))
+}
+
+/// Find the start of the next hole in the file at or after `offset`, as with
+/// `lseek(SEEK_HOLE)`. There is always a hole at the end of the file.
+#[cfg(not(target_os = "linux"))]
+pub fn next_hole(file: &File, _offset: u64) -> std::io::Result<u64> {
+    Ok(file.metadata()?.len())
+}
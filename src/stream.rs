@@ -0,0 +1,620 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bat::Bat,
+    open::{unknown_required_metadata, unknown_required_regions},
+    Error, Guid, HeaderSection, Metadata, MetadataTable, MB, REGION_GUID_BAT, REGION_GUID_METADATA,
+};
+
+/// The default limit on the data held for out of order payload blocks.
+const DEFAULT_SPILL_LIMIT: u64 = 256 * MB as u64;
+
+/// Options for reading a VHDX file from a stream with a [`StreamReader`], such
+/// as the limit on how much out of order data is held.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    spill_limit: u64,
+    spill_dir: Option<PathBuf>,
+    known_metadata: Vec<Guid>,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            spill_limit: DEFAULT_SPILL_LIMIT,
+            spill_dir: None,
+            known_metadata: Vec::new(),
+        }
+    }
+}
+
+impl StreamOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum number of bytes of payload data that can be held while
+    /// waiting for earlier parts of the virtual disk to arrive. Defaults to
+    /// 256 MB.
+    pub fn spill_limit(&mut self, spill_limit: u64) -> &mut Self {
+        self.spill_limit = spill_limit;
+        self
+    }
+
+    /// Hold out of order payload data in a temporary file in this directory,
+    /// rather than in memory.
+    pub fn spill_dir(&mut self, spill_dir: impl Into<PathBuf>) -> &mut Self {
+        self.spill_dir = Some(spill_dir.into());
+        self
+    }
+
+    /// Mark a required user metadata item as understood by the caller, as for
+    /// [`OpenOptions::known_metadata`](crate::OpenOptions::known_metadata).
+    pub fn known_metadata(&mut self, item_id: Guid) -> &mut Self {
+        self.known_metadata.push(item_id);
+        self
+    }
+
+    /// Read the headers, metadata and BAT from the start of the stream.
+    ///
+    /// Any payload data that appears in the stream before the metadata and
+    /// BAT is held in the spill store until it is needed.
+    pub fn open<R: Read>(&self, reader: R) -> Result<StreamReader<R>, Error> {
+        let mut spill = SpillStore::new(self.spill_limit, self.spill_dir.as_deref())?;
+        let mut stream = Stream {
+            inner: reader,
+            position: 0,
+        };
+
+        let mut header_area = vec![0; MB];
+        stream.read_exact(&mut header_area)?;
+        let header_section = HeaderSection::read(&mut Cursor::new(header_area))?;
+
+        let unknown_regions = unknown_required_regions(header_section.region_table());
+        if !unknown_regions.is_empty() {
+            return Err(Error::UnknownRequiredRegions(unknown_regions));
+        }
+        if header_section.current_header().log_guid != Guid::ZERO {
+            return Err(Error::Unsupported(
+                "streaming a file with a log that needs to be replayed",
+            ));
+        }
+
+        let regions = &header_section.region_table().entries;
+        let metadata_region = regions
+            .iter()
+            .find(|entry| entry.guid == REGION_GUID_METADATA)
+            .ok_or(Error::MissingRequiredRegion("metadata"))?;
+        let bat_region = regions
+            .iter()
+            .find(|entry| entry.guid == REGION_GUID_BAT)
+            .ok_or(Error::MissingRequiredRegion("bat"))?;
+        let log_region = {
+            let header = header_section.current_header();
+            (
+                header.log_offset,
+                header.log_offset + header.log_length as u64,
+            )
+        };
+
+        // Regions and payload blocks are all 1 MB aligned, so the stream can
+        // be consumed in 1 MB chunks until both regions have been seen. Until
+        // then, any chunk that is not part of a region may be payload data.
+        let mut metadata_buffer = vec![0; metadata_region.length as usize];
+        let mut bat_buffer = vec![0; bat_region.length as usize];
+        let regions_end = [metadata_region, bat_region]
+            .iter()
+            .map(|region| region.file_offset + region.length as u64)
+            .max()
+            .expect("there are two regions");
+        let mut chunk = vec![0; MB];
+        while stream.position < regions_end {
+            let offset = stream.position;
+            stream.read_exact(&mut chunk)?;
+
+            if let Some(buffer_offset) = offset.checked_sub(metadata_region.file_offset) {
+                if let Some(data) = metadata_buffer
+                    .get_mut(buffer_offset as usize..)
+                    .filter(|data| !data.is_empty())
+                {
+                    data[..MB].copy_from_slice(&chunk);
+                    continue;
+                }
+            }
+            if let Some(buffer_offset) = offset.checked_sub(bat_region.file_offset) {
+                if let Some(data) = bat_buffer
+                    .get_mut(buffer_offset as usize..)
+                    .filter(|data| !data.is_empty())
+                {
+                    data[..MB].copy_from_slice(&chunk);
+                    continue;
+                }
+            }
+            let in_region = (log_region.0..log_region.1).contains(&offset)
+                || regions.iter().any(|region| {
+                    (region.file_offset..region.file_offset + region.length as u64)
+                        .contains(&offset)
+                });
+            if !in_region {
+                spill.insert(offset, &chunk)?;
+            }
+        }
+
+        let mut metadata_reader = Cursor::new(metadata_buffer);
+        let metadata_table = MetadataTable::read(&mut metadata_reader, metadata_region.length)?;
+        let metadata = Metadata::from_table(&mut metadata_reader, &metadata_table, 0)?;
+        let unknown_metadata = unknown_required_metadata(&metadata_table, &self.known_metadata);
+        if !unknown_metadata.is_empty() {
+            return Err(Error::UnknownRequiredMetadata(unknown_metadata));
+        }
+        if metadata.file_parameters.has_parent() {
+            return Err(Error::Unsupported("streaming differencing disks"));
+        }
+        let bat = Bat::from_bytes(bat_buffer, &metadata);
+
+        let mut blocks = Vec::new();
+        for block_index in 0..bat.payload_blocks_count() {
+            let entry = bat
+                .payload_entry(block_index)?
+                .expect("block is within the virtual disk");
+            if entry.state().is_allocated() {
+                blocks.push((entry.file_offset(), block_index));
+            }
+        }
+        blocks.sort_unstable();
+
+        let mut reader = StreamReader {
+            stream,
+            metadata,
+            bat,
+            blocks,
+            spill,
+            chunk: vec![0; MB].into_boxed_slice(),
+            chunk_offset: None,
+            offset: 0,
+        };
+
+        // Only keep the chunks read so far that turned out to be payload data
+        for offset in reader.spill.offsets() {
+            if !reader.is_needed(offset, 0) {
+                reader.spill.remove(offset);
+            }
+        }
+
+        Ok(reader)
+    }
+}
+
+/// Read the contents of a VHDX virtual disk from a stream that does not
+/// support seeking, such as a pipe from a decompressor.
+///
+/// The headers, metadata and BAT are read when the reader is created, and
+/// then the virtual disk is read in order. Payload blocks that are stored in
+/// the file before the part of the virtual disk currently being read are held
+/// in a bounded spill store, either in memory or in a temporary file.
+///
+/// Differencing disks, and files with a log that needs to be replayed, are
+/// not supported.
+#[derive(Debug)]
+pub struct StreamReader<R> {
+    stream: Stream<R>,
+    metadata: Metadata,
+    bat: Bat,
+    /// The allocated payload blocks as `(file offset, block index)`, sorted
+    /// by file offset
+    blocks: Vec<(u64, u64)>,
+    spill: SpillStore,
+    /// The 1 MB of the virtual disk that is currently being read
+    chunk: Box<[u8]>,
+    chunk_offset: Option<u64>,
+    /// The position in the virtual disk
+    offset: u64,
+}
+
+impl<R: Read> StreamReader<R> {
+    /// Read a VHDX file from a stream with the default options.
+    pub fn new(reader: R) -> Result<Self, Error> {
+        StreamOptions::new().open(reader)
+    }
+
+    /// The size of the virtual disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.metadata.virtual_disk_size.virtual_disk_size()
+    }
+
+    /// The size of each payload block in bytes.
+    pub fn block_size(&self) -> u32 {
+        self.metadata.file_parameters.block_size()
+    }
+
+    /// The sector size presented by the virtual disk, either 512 or 4096 bytes.
+    pub fn logical_sector_size(&self) -> u32 {
+        self.metadata.logical_sector_size.logical_sector_size()
+    }
+
+    /// The physical sector size reported by the virtual disk, either 512 or
+    /// 4096 bytes.
+    pub fn physical_sector_size(&self) -> u32 {
+        self.metadata.physical_sector_size.physical_sector_size()
+    }
+
+    /// The identifier of the virtual disk.
+    pub fn virtual_disk_id(&self) -> Guid {
+        self.metadata.virtual_disk_id.virtual_disk_id()
+    }
+
+    /// The position in the virtual disk that will be read next.
+    pub fn position(&self) -> u64 {
+        self.offset
+    }
+
+    /// Consume the reader, returning the underlying stream.
+    pub fn into_inner(self) -> R {
+        self.stream.inner
+    }
+
+    /// Whether the 1 MB chunk of the file at `file_offset` contains payload
+    /// data for the virtual disk at or after `virtual_offset`.
+    fn is_needed(&self, file_offset: u64, virtual_offset: u64) -> bool {
+        let block_size = self.block_size() as u64;
+        let index = self
+            .blocks
+            .partition_point(|&(block_offset, _)| block_offset <= file_offset);
+        let Some(&(block_offset, block_index)) = index.checked_sub(1).map(|i| &self.blocks[i])
+        else {
+            return false;
+        };
+        if file_offset >= block_offset + block_size {
+            return false;
+        }
+
+        let chunk_virtual_offset = block_index * block_size + (file_offset - block_offset);
+        chunk_virtual_offset >= virtual_offset && chunk_virtual_offset < self.virtual_size()
+    }
+
+    /// Fill the chunk buffer with the 1 MB of the virtual disk that starts at
+    /// `chunk_offset`.
+    fn load_chunk(&mut self, chunk_offset: u64) -> Result<(), Error> {
+        let block_size = self.block_size() as u64;
+        let length = (self.virtual_size() - chunk_offset).min(MB as u64) as usize;
+
+        let entry = self
+            .bat
+            .payload_entry(chunk_offset / block_size)?
+            .expect("chunk is within the virtual disk");
+        if !entry.state().is_allocated() {
+            self.chunk.fill(0);
+            self.chunk_offset = Some(chunk_offset);
+            return Ok(());
+        }
+
+        let file_offset = entry.file_offset() + chunk_offset % block_size;
+        if file_offset < self.stream.position {
+            if !self.spill.take(file_offset, &mut self.chunk[..length])? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "payload data was referenced more than once",
+                )
+                .into());
+            }
+            self.chunk_offset = Some(chunk_offset);
+            return Ok(());
+        }
+
+        // Hold on to any payload data before the chunk that will be needed
+        // later on
+        let mut skipped = vec![0; MB];
+        while self.stream.position < file_offset {
+            let position = self.stream.position;
+            let length = (MB as u64 - position % MB as u64).min(file_offset - position);
+            let skipped = &mut skipped[..length as usize];
+            self.stream.read_exact(skipped)?;
+            if length == MB as u64 && self.is_needed(position, chunk_offset + 1) {
+                self.spill.insert(position, skipped)?;
+            }
+        }
+
+        self.stream.read_exact(&mut self.chunk[..length])?;
+        self.chunk_offset = Some(chunk_offset);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let disk_size = self.virtual_size();
+        if self.offset >= disk_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let chunk_offset = self.offset - self.offset % MB as u64;
+        if self.chunk_offset != Some(chunk_offset) {
+            self.load_chunk(chunk_offset).map_err(into_io_error)?;
+        }
+
+        let start = (self.offset - chunk_offset) as usize;
+        let end = (disk_size - chunk_offset).min(MB as u64) as usize;
+        let length = buf.len().min(end - start);
+        buf[..length].copy_from_slice(&self.chunk[start..start + length]);
+        self.offset += length as u64;
+        Ok(length)
+    }
+}
+
+fn into_io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+/// A reader that tracks its position in the file.
+#[derive(Debug)]
+struct Stream<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> Stream<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Holds 1 MB chunks of the file, keyed by their offset in the file, up to a
+/// limit on the total size.
+#[derive(Debug)]
+struct SpillStore {
+    limit: u64,
+    used: u64,
+    memory: HashMap<u64, Box<[u8]>>,
+    file: Option<SpillFile>,
+}
+
+/// A temporary file that spilled chunks are stored in, in 1 MB slots.
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    slots: HashMap<u64, u64>,
+    free_slots: Vec<u64>,
+    slot_count: u64,
+}
+
+impl SpillStore {
+    fn new(limit: u64, dir: Option<&Path>) -> Result<Self, Error> {
+        let file = dir
+            .map(|dir| {
+                let path = dir.join(format!("vhdx-spill-{}", Guid::new_random()));
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?;
+                Ok::<_, Error>(SpillFile {
+                    path,
+                    file,
+                    slots: HashMap::new(),
+                    free_slots: Vec::new(),
+                    slot_count: 0,
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            limit,
+            used: 0,
+            memory: HashMap::new(),
+            file,
+        })
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if self.used + MB as u64 > self.limit {
+            return Err(Error::SpillLimitExceeded(self.limit));
+        }
+
+        match &mut self.file {
+            Some(spill_file) => {
+                let slot = spill_file.free_slots.pop().unwrap_or_else(|| {
+                    spill_file.slot_count += 1;
+                    spill_file.slot_count - 1
+                });
+                spill_file.file.seek(SeekFrom::Start(slot * MB as u64))?;
+                spill_file.file.write_all(data)?;
+                spill_file.slots.insert(offset, slot);
+            }
+            None => {
+                self.memory.insert(offset, data.into());
+            }
+        }
+        self.used += MB as u64;
+        Ok(())
+    }
+
+    /// Remove a chunk from the store, copying the start of it into `buf`.
+    ///
+    /// Returns false if the chunk is not in the store.
+    fn take(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, Error> {
+        match &mut self.file {
+            Some(spill_file) => {
+                let Some(&slot) = spill_file.slots.get(&offset) else {
+                    return Ok(false);
+                };
+                spill_file.file.seek(SeekFrom::Start(slot * MB as u64))?;
+                spill_file.file.read_exact(buf)?;
+            }
+            None => {
+                let Some(data) = self.memory.get(&offset) else {
+                    return Ok(false);
+                };
+                buf.copy_from_slice(&data[..buf.len()]);
+            }
+        }
+        self.remove(offset);
+        Ok(true)
+    }
+
+    /// Remove a chunk from the store without reading it.
+    fn remove(&mut self, offset: u64) -> bool {
+        let removed = match &mut self.file {
+            Some(spill_file) => spill_file
+                .slots
+                .remove(&offset)
+                .map(|slot| spill_file.free_slots.push(slot))
+                .is_some(),
+            None => self.memory.remove(&offset).is_some(),
+        };
+        if removed {
+            self.used -= MB as u64;
+        }
+        removed
+    }
+
+    /// The offsets of every chunk in the store.
+    fn offsets(&self) -> Vec<u64> {
+        match &self.file {
+            Some(spill_file) => spill_file.slots.keys().copied().collect(),
+            None => self.memory.keys().copied().collect(),
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata::{FileParameters, MetadataItem},
+        testing::{disk_with_data, pattern, TempPath},
+        Vhdx,
+    };
+
+    /// A reader that can't seek, as for a pipe or a socket.
+    struct Unseekable<R>(R);
+
+    impl<R: Read> Read for Unseekable<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn spill_store_limit() {
+        let dir = std::env::temp_dir();
+        for dir in [None, Some(dir.as_path())] {
+            let mut store = SpillStore::new(2 * MB as u64, dir).unwrap();
+            let chunk = vec![7; MB];
+            store.insert(0, &chunk).unwrap();
+            store.insert(MB as u64, &chunk).unwrap();
+            assert!(matches!(
+                store.insert(2 * MB as u64, &chunk),
+                Err(Error::SpillLimitExceeded(_))
+            ));
+
+            let mut buf = vec![0; 512];
+            assert!(store.take(MB as u64, &mut buf).unwrap());
+            assert_eq!(buf, vec![7; 512]);
+            assert!(!store.take(MB as u64, &mut buf).unwrap());
+            store.insert(2 * MB as u64, &chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn out_of_order_blocks() {
+        let path = TempPath::new("vhdx");
+        let mut data = pattern(3 * MB);
+        data[2 * MB..].fill(7);
+        let disk = disk_with_data(&path, 8 * MB as u64, &data);
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset;
+        let first = disk.bat.payload_entry(0).unwrap().unwrap();
+        let third = disk.bat.payload_entry(2).unwrap().unwrap();
+        drop(disk);
+
+        // Swap the first and third blocks in the file, so that the first
+        // block of the virtual disk is stored after the third
+        let mut file = File::options().read(true).write(true).open(&path).unwrap();
+        let mut first_block = vec![0; MB];
+        let mut third_block = vec![0; MB];
+        file.seek(SeekFrom::Start(first.file_offset())).unwrap();
+        file.read_exact(&mut first_block).unwrap();
+        file.seek(SeekFrom::Start(third.file_offset())).unwrap();
+        file.read_exact(&mut third_block).unwrap();
+        file.seek(SeekFrom::Start(first.file_offset())).unwrap();
+        file.write_all(&third_block).unwrap();
+        file.seek(SeekFrom::Start(third.file_offset())).unwrap();
+        file.write_all(&first_block).unwrap();
+        for (index, entry) in [(0, third), (2, first)] {
+            file.seek(SeekFrom::Start(bat_offset + index * 8)).unwrap();
+            file.write_all(&entry.to_bits().to_le_bytes()).unwrap();
+        }
+        drop(file);
+
+        let mut disk = Vhdx::options().read_only(true).open(&path).unwrap();
+        let mut expected = Vec::new();
+        disk.reader().read_to_end(&mut expected).unwrap();
+        assert_eq!(expected.len(), 8 * MB);
+        assert!(expected[..3 * MB] == data);
+
+        let dir = std::env::temp_dir();
+        for dir in [None, Some(dir.as_path())] {
+            let mut options = StreamOptions::new();
+            if let Some(dir) = dir {
+                options.spill_dir(dir);
+            }
+            let stream = Unseekable(File::open(&path).unwrap());
+            let mut reader = options.open(stream).unwrap();
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).unwrap();
+            assert!(contents == expected);
+        }
+
+        // The second and third blocks are held until the first has been read
+        let stream = Unseekable(File::open(&path).unwrap());
+        let mut reader = StreamOptions::new()
+            .spill_limit(MB as u64)
+            .open(stream)
+            .unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("spill limit"));
+    }
+
+    #[test]
+    fn corrupt_stream() {
+        let path = TempPath::new("vhdx");
+        let disk = disk_with_data(&path, 8 * MB as u64, &pattern(2 * MB));
+        let metadata_offset = disk.region(REGION_GUID_METADATA).unwrap().file_offset as usize;
+        let bat_offset = disk.region(REGION_GUID_BAT).unwrap().file_offset as usize;
+        let block_size_offset = disk.metadata_item_offset(FileParameters::GUID).unwrap() as usize;
+        drop(disk);
+        let file = std::fs::read(&path).unwrap();
+        let open = |bytes: &[u8]| StreamReader::new(Unseekable(bytes)).err();
+
+        let mut corrupt = file.clone();
+        corrupt[metadata_offset..][..8].copy_from_slice(b"corrupt!");
+        assert!(matches!(open(&corrupt), Some(Error::InvalidImage(_))));
+
+        let mut corrupt = file.clone();
+        corrupt[block_size_offset..][..4].fill(0);
+        assert!(matches!(
+            open(&corrupt),
+            Some(Error::InvalidMetadataItem(_))
+        ));
+
+        // The stream ends before the regions have been read
+        let truncated = &file[..metadata_offset.max(bat_offset)];
+        assert!(matches!(open(truncated), Some(Error::Io(_))));
+    }
+}
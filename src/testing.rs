@@ -0,0 +1,62 @@
+//! Fixtures for the tests of modules that work with files on disk.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{Builder, Vhdx, MB};
+
+/// A path in the temporary directory that is unique to the test, whose file
+/// is removed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub(crate) fn new(extension: &str) -> Self {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let name = format!(
+            "vhdx-test-{}-{}.{extension}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Bytes that differ from their neighbours, so that data read from the wrong
+/// place is noticed.
+pub(crate) fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+/// Create a dynamic disk with 1 MB blocks, with `data` at the start of the
+/// disk. Blocks of zeros are left unallocated.
+pub(crate) fn disk_with_data(path: &Path, virtual_size: u64, data: &[u8]) -> Vhdx {
+    let mut builder = Builder::new(path);
+    builder.virtual_size(virtual_size).block_size(MB as u32);
+    crate::convert::from_raw(&mut std::io::Cursor::new(data), &builder).unwrap()
+}
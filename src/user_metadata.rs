@@ -0,0 +1,462 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{
+    compact::find_free_space,
+    log::next_multiple_of,
+    metadata::{MetadataItem, LAYOUT_ITEMS, SYSTEM_ITEMS},
+    sparse, Error, Guid, Metadata, MetadataTable, MetadataTableEntry, Vhdx, KB, MB,
+    REGION_GUID_METADATA,
+};
+
+/// The maximum number of entries in the metadata table.
+const MAX_ENTRIES: usize = 2047;
+/// The maximum number of user metadata items.
+const MAX_USER_ENTRIES: usize = 1024;
+/// The maximum length of a single metadata item.
+const MAX_ITEM_LENGTH: usize = MB;
+/// Item data is stored after the 64 KB metadata table.
+const TABLE_LENGTH: u64 = 64 * KB as u64;
+
+impl Vhdx {
+    /// The entries in the metadata table, for both system and user items.
+    pub fn metadata_entries(&self) -> &[MetadataTableEntry] {
+        &self.metadata_table.entries
+    }
+
+    /// Read the raw data of a metadata item.
+    ///
+    /// Returns none if there is no item with the given GUID.
+    pub fn read_metadata_raw(&mut self, item_id: Guid) -> Result<Option<Vec<u8>>, Error> {
+        let Some(entry) = self
+            .metadata_table
+            .entries
+            .iter()
+            .find(|entry| entry.item_id == item_id)
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        let region_offset = self
+            .region(REGION_GUID_METADATA)
+            .ok_or(Error::MissingRequiredRegion("metadata"))?
+            .file_offset;
+
+        let mut data = vec![0; entry.length as usize];
+        self.file
+            .seek(SeekFrom::Start(region_offset + entry.offset as u64))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Read a metadata item and parse it as `T`.
+    ///
+    /// Returns none if the file does not contain the item.
+    pub fn get_metadata<T: MetadataItem>(&mut self) -> Result<Option<T>, Error> {
+        self.read_metadata_raw(T::GUID)?
+            .map(|data| T::read(&mut data.as_slice()))
+            .transpose()
+    }
+
+    /// Add a metadata item, or replace an existing one.
+    ///
+    /// User items are added with the flags from `T`. System items can only be
+    /// replaced if they are already present, and items that define the layout
+    /// of the file (the file parameters, virtual disk size and logical sector
+    /// size) cannot be replaced at all.
+    pub fn set_metadata<T: MetadataItem>(&mut self, item: &T) -> Result<(), Error> {
+        self.check_writable()?;
+        if LAYOUT_ITEMS.contains(&T::GUID) {
+            return Err(Error::Unsupported(
+                "replacing metadata that defines the layout",
+            ));
+        }
+
+        let mut data = Vec::new();
+        item.write(&mut data)?;
+
+        if !SYSTEM_ITEMS.contains(&T::GUID) {
+            return self.set_user_metadata_item(T::GUID, &data, T::IS_VIRTUAL_DISK, T::IS_REQUIRED);
+        }
+
+        let mut table = self.metadata_table.clone();
+        let entry = table
+            .entries
+            .iter_mut()
+            .find(|entry| entry.item_id == T::GUID)
+            .ok_or(Error::Unsupported("adding system metadata items"))?;
+        if data.len() > MAX_ITEM_LENGTH {
+            return Err(Error::InvalidMetadataItem("item is larger than 1 MB"));
+        }
+        entry.length = data.len() as u32;
+        entry.is_empty = data.is_empty();
+
+        self.write_metadata_table(table, Some((T::GUID, &data)))?;
+
+        // The parsed copies of the system items have to match the table
+        let metadata_offset = self
+            .region(REGION_GUID_METADATA)
+            .ok_or(Error::MissingRequiredRegion("metadata"))?
+            .file_offset;
+        self.metadata =
+            Metadata::from_table(&mut self.file, &self.metadata_table, metadata_offset)?;
+        Ok(())
+    }
+
+    /// Add a user metadata item, or replace the data of an existing one.
+    ///
+    /// Each item can be at most 1 MB, and a file can have at most 1024 user
+    /// items. System items cannot be modified through this function.
+    ///
+    /// An implementation that does not understand an item marked as required
+    /// will refuse to open the file, so `is_required` should only be set if
+    /// the file cannot be used correctly without the item. This crate also
+    /// refuses to open such files, unless the item is passed to
+    /// [`OpenOptions::known_metadata`](crate::OpenOptions::known_metadata).
+    pub fn set_user_metadata(
+        &mut self,
+        item_id: Guid,
+        data: &[u8],
+        is_required: bool,
+    ) -> Result<(), Error> {
+        self.set_user_metadata_item(item_id, data, false, is_required)
+    }
+
+    /// Add or replace a user item. `is_virtual_disk` is only used for new
+    /// items, since existing items keep their flag.
+    fn set_user_metadata_item(
+        &mut self,
+        item_id: Guid,
+        data: &[u8],
+        is_virtual_disk: bool,
+        is_required: bool,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        if data.len() > MAX_ITEM_LENGTH {
+            return Err(Error::InvalidMetadataItem("item is larger than 1 MB"));
+        }
+
+        let mut table = self.metadata_table.clone();
+        let existing = table
+            .entries
+            .iter()
+            .position(|entry| entry.item_id == item_id);
+        if SYSTEM_ITEMS.contains(&item_id)
+            || existing.is_some_and(|index| !table.entries[index].is_user)
+        {
+            return Err(Error::InvalidMetadataItem("item is a system item"));
+        }
+
+        let entry = MetadataTableEntry {
+            item_id,
+            offset: 0,
+            length: data.len() as u32,
+            is_user: true,
+            is_virtual_disk: existing.map_or(is_virtual_disk, |index| {
+                table.entries[index].is_virtual_disk
+            }),
+            is_required,
+            is_empty: data.is_empty(),
+        };
+        match existing {
+            Some(index) => table.entries[index] = entry,
+            None => {
+                let user_entries = table.entries.iter().filter(|entry| entry.is_user).count();
+                if user_entries >= MAX_USER_ENTRIES {
+                    return Err(Error::InvalidMetadataItem("too many user items"));
+                }
+                if table.entries.len() >= MAX_ENTRIES {
+                    return Err(Error::InvalidMetadataItem("metadata table is full"));
+                }
+                table.entries.push(entry);
+            }
+        }
+
+        self.write_metadata_table(table, Some((item_id, data)))
+    }
+
+    /// Remove a user metadata item.
+    ///
+    /// Returns whether there was an item with the given GUID.
+    pub fn remove_user_metadata(&mut self, item_id: Guid) -> Result<bool, Error> {
+        self.check_writable()?;
+        let mut table = self.metadata_table.clone();
+        let Some(index) = table
+            .entries
+            .iter()
+            .position(|entry| entry.item_id == item_id)
+        else {
+            return Ok(false);
+        };
+        if !table.entries[index].is_user {
+            return Err(Error::InvalidMetadataItem("item is a system item"));
+        }
+
+        table.entries.remove(index);
+        self.write_metadata_table(table, None)?;
+        Ok(true)
+    }
+
+    /// Replace the metadata table through the log, after writing the data of
+    /// a new or replaced item into free space in the metadata region.
+    ///
+    /// The item data is never written over data that is referenced by the
+    /// current table, so the table update is the only change that needs to be
+    /// atomic. If there is no room for the item, the region is moved.
+    pub(crate) fn write_metadata_table(
+        &mut self,
+        mut table: MetadataTable,
+        new_item: Option<(Guid, &[u8])>,
+    ) -> Result<(), Error> {
+        let region = self
+            .region(REGION_GUID_METADATA)
+            .ok_or(Error::MissingRequiredRegion("metadata"))?
+            .clone();
+
+        self.begin_modification(false)?;
+
+        if let Some((item_id, data)) = new_item.filter(|(_, data)| !data.is_empty()) {
+            // Items are never placed over the table
+            let mut used = vec![(0, TABLE_LENGTH)];
+            used.extend(
+                self.metadata_table
+                    .entries
+                    .iter()
+                    .filter(|entry| !entry.is_empty)
+                    .map(|entry| {
+                        (
+                            entry.offset as u64,
+                            entry.offset as u64 + entry.length as u64,
+                        )
+                    }),
+            );
+            used.sort_unstable();
+
+            let Some(offset) = find_free_space(&used, data.len() as u64, 1, region.length as u64)
+            else {
+                return self.relocate_metadata(table, item_id, data);
+            };
+
+            self.file
+                .seek(SeekFrom::Start(region.file_offset + offset))?;
+            self.file.write_all(data)?;
+            self.file.sync_data()?;
+
+            let entry = table
+                .entries
+                .iter_mut()
+                .find(|entry| entry.item_id == item_id)
+                .expect("new item is in the table");
+            entry.offset = offset as u32;
+        }
+
+        // Only the sectors containing entries need to be rewritten
+        let entries_count = table.entries.len().max(self.metadata_table.entries.len());
+        let length = next_multiple_of(32 + 32 * entries_count as u64, 4 * KB as u64);
+        let writes = self.range_writes(region.file_offset, &table.to_bytes()[..length as usize])?;
+        self.write_logged(&writes)?;
+
+        self.metadata_table = table;
+        Ok(())
+    }
+
+    /// Move the metadata region to the end of the file, with enough room for
+    /// every item, and with the data of one item replaced.
+    fn relocate_metadata(
+        &mut self,
+        mut table: MetadataTable,
+        item_id: Guid,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let mut items = Vec::with_capacity(table.entries.len());
+        for entry in table.entries.iter().filter(|entry| !entry.is_empty) {
+            let item = if entry.item_id == item_id {
+                data.to_vec()
+            } else {
+                self.read_metadata_raw(entry.item_id)?
+                    .expect("item is in the current table")
+            };
+            items.push(item);
+        }
+
+        let items_length = items.iter().map(|item| item.len() as u64).sum::<u64>();
+        let length = next_multiple_of(TABLE_LENGTH + items_length, MB as u64);
+        let mut region = vec![0; length as usize];
+        let mut offset = TABLE_LENGTH;
+        for (entry, item) in table
+            .entries
+            .iter_mut()
+            .filter(|entry| !entry.is_empty)
+            .zip(items)
+        {
+            entry.offset = offset as u32;
+            region[offset as usize..][..item.len()].copy_from_slice(&item);
+            offset += item.len() as u64;
+        }
+        region[..TABLE_LENGTH as usize].copy_from_slice(&table.to_bytes());
+
+        let old_region = self
+            .region(REGION_GUID_METADATA)
+            .ok_or(Error::MissingRequiredRegion("metadata"))?
+            .clone();
+
+        // The new region is not referenced until the region tables are
+        // updated, so it can be written directly
+        let file_offset = self.allocate(length)?;
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        self.file.write_all(&region)?;
+        self.file.sync_data()?;
+
+        let writes = self.region_table_writes(REGION_GUID_METADATA, file_offset, length as u32)?;
+        self.write_logged(&writes)?;
+        // The old region is no longer referenced, so its space can be released
+        sparse::punch_hole(&self.file, old_region.file_offset, old_region.length as u64)?;
+
+        for region_table in [
+            &mut self.header_section.region_table_1,
+            &mut self.header_section.region_table_2,
+        ] {
+            if let Some(entry) = region_table
+                .entries
+                .iter_mut()
+                .find(|entry| entry.guid == REGION_GUID_METADATA)
+            {
+                entry.file_offset = file_offset;
+                entry.length = length as u32;
+            }
+        }
+        self.metadata_table = table;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disk_with_data, TempPath};
+
+    #[test]
+    fn user_items_round_trip() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[]);
+        let first = Guid::new_random();
+        let second = Guid::new_random();
+        disk.set_user_metadata(first, b"first", false).unwrap();
+        disk.set_user_metadata(second, b"second", false).unwrap();
+        drop(disk);
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.read_metadata_raw(first).unwrap().unwrap(), b"first");
+        assert_eq!(disk.read_metadata_raw(second).unwrap().unwrap(), b"second");
+        disk.set_user_metadata(first, b"replaced", false).unwrap();
+        assert!(disk.remove_user_metadata(second).unwrap());
+        assert!(!disk.remove_user_metadata(second).unwrap());
+        drop(disk);
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(disk.read_metadata_raw(first).unwrap().unwrap(), b"replaced");
+        assert_eq!(disk.read_metadata_raw(second).unwrap(), None);
+        assert!(crate::check::check_file(&path).unwrap().is_ok());
+    }
+
+    #[test]
+    fn required_items_must_be_known() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[]);
+        let item_id = Guid::new_random();
+        disk.set_user_metadata(item_id, b"required", true).unwrap();
+        drop(disk);
+
+        let error = Vhdx::load(&path).unwrap_err();
+        assert!(matches!(error, Error::UnknownRequiredMetadata(ids) if ids == [item_id]));
+        assert!(Vhdx::options().best_effort(true).open(&path).is_ok());
+        let disk = Vhdx::options().known_metadata(item_id).open(&path).unwrap();
+        let entry = disk
+            .metadata_entries()
+            .iter()
+            .find(|entry| entry.item_id == item_id)
+            .unwrap();
+        assert!(entry.is_required());
+    }
+
+    #[test]
+    fn item_beyond_region() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[]);
+        let item_id = Guid::new_random();
+        disk.set_user_metadata(item_id, b"item", false).unwrap();
+        let index = disk
+            .metadata_entries()
+            .iter()
+            .position(|entry| entry.item_id == item_id)
+            .unwrap();
+        let region = disk.region(REGION_GUID_METADATA).unwrap().clone();
+        drop(disk);
+
+        for offset in [region.length - 2, u32::MAX - 2] {
+            let mut file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(region.file_offset + 48 + 32 * index as u64))
+                .unwrap();
+            file.write_all(&offset.to_le_bytes()).unwrap();
+            drop(file);
+
+            let error = Vhdx::load(&path).unwrap_err();
+            assert!(matches!(error, Error::InvalidMetadataItem(_)));
+        }
+    }
+
+    #[test]
+    fn item_length_limit() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[]);
+        let old_region = disk.region(REGION_GUID_METADATA).unwrap().clone();
+        let item_id = Guid::new_random();
+
+        let error = disk
+            .set_user_metadata(item_id, &vec![1; MB + 1], false)
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidMetadataItem(_)));
+
+        // An item of the largest size doesn't fit in the region after the
+        // table, so the region is moved and the old one released
+        disk.set_user_metadata(item_id, &vec![1; MB], false)
+            .unwrap();
+        let new_region = disk.region(REGION_GUID_METADATA).unwrap().clone();
+        assert_ne!(new_region.file_offset, old_region.file_offset);
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            sparse::next_hole(&disk.file, old_region.file_offset).unwrap(),
+            old_region.file_offset
+        );
+        drop(disk);
+
+        let mut disk = Vhdx::load(&path).unwrap();
+        assert_eq!(
+            disk.read_metadata_raw(item_id).unwrap().unwrap(),
+            vec![1; MB]
+        );
+        assert!(crate::check::check_file(&path).unwrap().is_ok());
+    }
+
+    #[test]
+    fn user_item_count_limit() {
+        let path = TempPath::new("vhdx");
+        let mut disk = disk_with_data(&path, 8 * MB as u64, &[]);
+        for _ in 0..MAX_USER_ENTRIES {
+            disk.set_user_metadata(Guid::new_random(), &[], false)
+                .unwrap();
+        }
+        let error = disk
+            .set_user_metadata(Guid::new_random(), &[], false)
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidMetadataItem(_)));
+        drop(disk);
+
+        let disk = Vhdx::load(&path).unwrap();
+        let user_entries = disk
+            .metadata_entries()
+            .iter()
+            .filter(|entry| entry.is_user)
+            .count();
+        assert_eq!(user_entries, MAX_USER_ENTRIES);
+    }
+}
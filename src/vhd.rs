@@ -0,0 +1,916 @@
+//! Reading and writing the legacy VHD format, which preceded VHDX.
+//!
+//! A VHD file is described by a 512 byte footer at the end of the file. Fixed
+//! disks store the virtual disk directly before the footer, while dynamic and
+//! differencing disks also have a dynamic header and a block allocation table
+//! (BAT). Each allocated block starts with a bitmap of the sectors that are
+//! present in it.
+//!
+//! All fields are big-endian.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{convert::RawSource, open::native_path, Error, Guid};
+
+pub(crate) static FOOTER_COOKIE: &[u8; 8] = b"conectix";
+pub(crate) static DYNAMIC_HEADER_COOKIE: &[u8; 8] = b"cxsparse";
+
+pub(crate) const SECTOR_SIZE: u64 = 512;
+pub(crate) const FOOTER_SIZE: usize = 512;
+pub(crate) const DYNAMIC_HEADER_SIZE: usize = 1024;
+/// The BAT entry of a block that is not allocated.
+pub(crate) const UNUSED_ENTRY: u32 = u32::MAX;
+/// The block size used when creating dynamic disks.
+pub(crate) const DEFAULT_BLOCK_SIZE: u32 = 2 * 1024 * 1024;
+
+/// The largest virtual disk that can be stored in a VHD, which is 2040 GB.
+pub const MAX_VIRTUAL_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+/// The largest parent locator that is read, which is enough for the longest
+/// Windows path in UTF-16.
+const MAX_LOCATOR_LENGTH: u32 = 64 * 1024;
+
+/// The offset of the footer timestamps, 2000-01-01 00:00:00 UTC, from the
+/// Unix epoch.
+const TIMESTAMP_EPOCH: u64 = 946_684_800;
+
+/// The type of a VHD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskType {
+    /// The whole virtual disk is stored in the file.
+    Fixed,
+    /// Blocks are allocated as they are written to.
+    Dynamic,
+    /// Blocks that have not been written to are read from a parent disk.
+    Differencing,
+}
+
+impl DiskType {
+    fn from_bits(value: u32) -> Result<Self, Error> {
+        match value {
+            2 => Ok(DiskType::Fixed),
+            3 => Ok(DiskType::Dynamic),
+            4 => Ok(DiskType::Differencing),
+            _ => Err(Error::Unsupported("VHD disk type")),
+        }
+    }
+
+    pub(crate) fn to_bits(self) -> u32 {
+        match self {
+            DiskType::Fixed => 2,
+            DiskType::Dynamic => 3,
+            DiskType::Differencing => 4,
+        }
+    }
+}
+
+/// The cylinder, head and sector geometry that is reported to BIOS based
+/// systems.
+///
+/// The geometry rounds the size of the disk down, so the size it describes
+/// may be smaller than [`Vhd::virtual_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+}
+
+impl Geometry {
+    /// Calculate the geometry for a disk of `size` bytes, using the algorithm
+    /// from the VHD specification. Disks that are too large for any geometry
+    /// use the largest one.
+    pub fn from_size(size: u64) -> Self {
+        let total_sectors = (size / SECTOR_SIZE).min(65535 * 16 * 255);
+
+        let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+            (255, 16, total_sectors / 255)
+        } else {
+            let mut sectors_per_track = 17;
+            let mut cylinder_times_heads = total_sectors / sectors_per_track;
+            let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+            if cylinder_times_heads >= heads * 1024 || heads > 16 {
+                sectors_per_track = 31;
+                heads = 16;
+                cylinder_times_heads = total_sectors / sectors_per_track;
+            }
+            if cylinder_times_heads >= heads * 1024 {
+                sectors_per_track = 63;
+                heads = 16;
+                cylinder_times_heads = total_sectors / sectors_per_track;
+            }
+            (sectors_per_track, heads, cylinder_times_heads)
+        };
+
+        Self {
+            cylinders: (cylinder_times_heads / heads) as u16,
+            heads: heads as u8,
+            sectors_per_track: sectors_per_track as u8,
+        }
+    }
+
+    /// The size of the disk described by the geometry in bytes.
+    pub fn size(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors_per_track as u64 * SECTOR_SIZE
+    }
+}
+
+/// The footer at the end of every VHD file. Dynamic and differencing disks
+/// also have a copy at the start of the file.
+#[derive(Debug, Clone)]
+pub(crate) struct Footer {
+    pub(crate) features: u32,
+    pub(crate) data_offset: u64,
+    pub(crate) timestamp: u32,
+    pub(crate) creator_application: [u8; 4],
+    pub(crate) creator_version: u32,
+    pub(crate) creator_host_os: [u8; 4],
+    pub(crate) original_size: u64,
+    pub(crate) current_size: u64,
+    pub(crate) geometry: Geometry,
+    pub(crate) disk_type: DiskType,
+    pub(crate) unique_id: [u8; 16],
+    pub(crate) saved_state: bool,
+}
+
+impl Footer {
+    /// A new footer for a disk created now.
+    pub(crate) fn new(disk_type: DiskType, size: u64, data_offset: u64) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs().saturating_sub(TIMESTAMP_EPOCH));
+
+        Self {
+            // The reserved bit is always set
+            features: 2,
+            data_offset,
+            timestamp: timestamp as u32,
+            creator_application: *b"vhdx",
+            creator_version: 0x0001_0000,
+            creator_host_os: *b"Wi2k",
+            original_size: size,
+            current_size: size,
+            geometry: Geometry::from_size(size),
+            disk_type,
+            unique_id: Guid::new_random().to_bytes(),
+            saved_state: false,
+        }
+    }
+
+    pub(crate) fn read(buffer: &[u8; FOOTER_SIZE]) -> Result<Self, Error> {
+        if &buffer[0..8] != FOOTER_COOKIE {
+            return Err(Error::InvalidSignature);
+        }
+        if be_u32(&buffer[64..68]) != checksum(buffer, 64) {
+            return Err(Error::InvalidChecksum);
+        }
+
+        Ok(Self {
+            features: be_u32(&buffer[8..12]),
+            data_offset: be_u64(&buffer[16..24]),
+            timestamp: be_u32(&buffer[24..28]),
+            creator_application: buffer[28..32].try_into().expect("infallible"),
+            creator_version: be_u32(&buffer[32..36]),
+            creator_host_os: buffer[36..40].try_into().expect("infallible"),
+            original_size: be_u64(&buffer[40..48]),
+            current_size: be_u64(&buffer[48..56]),
+            geometry: Geometry {
+                cylinders: u16::from_be_bytes(buffer[56..58].try_into().expect("infallible")),
+                heads: buffer[58],
+                sectors_per_track: buffer[59],
+            },
+            disk_type: DiskType::from_bits(be_u32(&buffer[60..64]))?,
+            unique_id: buffer[68..84].try_into().expect("infallible"),
+            saved_state: buffer[84] != 0,
+        })
+    }
+
+    /// Serialise the footer, calculating its checksum.
+    pub(crate) fn to_bytes(&self) -> [u8; FOOTER_SIZE] {
+        let mut buffer = [0; FOOTER_SIZE];
+        buffer[0..8].copy_from_slice(FOOTER_COOKIE);
+        buffer[8..12].copy_from_slice(&self.features.to_be_bytes());
+        buffer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        buffer[16..24].copy_from_slice(&self.data_offset.to_be_bytes());
+        buffer[24..28].copy_from_slice(&self.timestamp.to_be_bytes());
+        buffer[28..32].copy_from_slice(&self.creator_application);
+        buffer[32..36].copy_from_slice(&self.creator_version.to_be_bytes());
+        buffer[36..40].copy_from_slice(&self.creator_host_os);
+        buffer[40..48].copy_from_slice(&self.original_size.to_be_bytes());
+        buffer[48..56].copy_from_slice(&self.current_size.to_be_bytes());
+        buffer[56..58].copy_from_slice(&self.geometry.cylinders.to_be_bytes());
+        buffer[58] = self.geometry.heads;
+        buffer[59] = self.geometry.sectors_per_track;
+        buffer[60..64].copy_from_slice(&self.disk_type.to_bits().to_be_bytes());
+        buffer[68..84].copy_from_slice(&self.unique_id);
+        buffer[84] = self.saved_state as u8;
+
+        let checksum = checksum(&buffer, 64);
+        buffer[64..68].copy_from_slice(&checksum.to_be_bytes());
+        buffer
+    }
+}
+
+/// A parent locator entry in the dynamic header, which points to the data
+/// holding one form of the path to the parent.
+#[derive(Debug, Clone)]
+pub(crate) struct ParentLocatorEntry {
+    pub(crate) platform_code: [u8; 4],
+    pub(crate) platform_data_space: u32,
+    pub(crate) platform_data_length: u32,
+    pub(crate) platform_data_offset: u64,
+}
+
+/// The header of dynamic and differencing disks, describing the BAT.
+#[derive(Debug, Clone)]
+pub(crate) struct DynamicHeader {
+    pub(crate) table_offset: u64,
+    pub(crate) max_table_entries: u32,
+    pub(crate) block_size: u32,
+    pub(crate) parent_unique_id: [u8; 16],
+    pub(crate) parent_timestamp: u32,
+    pub(crate) parent_name: String,
+    pub(crate) parent_locators: Vec<ParentLocatorEntry>,
+}
+
+impl DynamicHeader {
+    fn read(buffer: &[u8; DYNAMIC_HEADER_SIZE]) -> Result<Self, Error> {
+        if &buffer[0..8] != DYNAMIC_HEADER_COOKIE {
+            return Err(Error::InvalidSignature);
+        }
+        if be_u32(&buffer[36..40]) != checksum(buffer, 36) {
+            return Err(Error::InvalidChecksum);
+        }
+
+        let block_size = be_u32(&buffer[32..36]);
+        if !block_size.is_power_of_two() || (block_size as u64) < SECTOR_SIZE {
+            return Err(Error::Unsupported("VHD block size"));
+        }
+
+        let parent_name = char::decode_utf16(
+            buffer[64..576]
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .take_while(|&ch| ch != 0),
+        )
+        .collect::<Result<String, _>>()?;
+
+        let parent_locators = buffer[576..768]
+            .chunks_exact(24)
+            .map(|entry| ParentLocatorEntry {
+                platform_code: entry[0..4].try_into().expect("infallible"),
+                platform_data_space: be_u32(&entry[4..8]),
+                platform_data_length: be_u32(&entry[8..12]),
+                platform_data_offset: be_u64(&entry[16..24]),
+            })
+            .filter(|entry| entry.platform_code != [0; 4])
+            .collect();
+
+        Ok(Self {
+            table_offset: be_u64(&buffer[16..24]),
+            max_table_entries: be_u32(&buffer[28..32]),
+            block_size,
+            parent_unique_id: buffer[40..56].try_into().expect("infallible"),
+            parent_timestamp: be_u32(&buffer[56..60]),
+            parent_name,
+            parent_locators,
+        })
+    }
+
+    /// A new header for a dynamic disk without a parent.
+    pub(crate) fn new(table_offset: u64, max_table_entries: u32, block_size: u32) -> Self {
+        Self {
+            table_offset,
+            max_table_entries,
+            block_size,
+            parent_unique_id: [0; 16],
+            parent_timestamp: 0,
+            parent_name: String::new(),
+            parent_locators: Vec::new(),
+        }
+    }
+
+    /// Serialise the header, calculating its checksum. Parent locators are
+    /// not written.
+    pub(crate) fn to_bytes(&self) -> [u8; DYNAMIC_HEADER_SIZE] {
+        let mut buffer = [0; DYNAMIC_HEADER_SIZE];
+        buffer[0..8].copy_from_slice(DYNAMIC_HEADER_COOKIE);
+        buffer[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        buffer[16..24].copy_from_slice(&self.table_offset.to_be_bytes());
+        buffer[24..28].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        buffer[28..32].copy_from_slice(&self.max_table_entries.to_be_bytes());
+        buffer[32..36].copy_from_slice(&self.block_size.to_be_bytes());
+        buffer[40..56].copy_from_slice(&self.parent_unique_id);
+        buffer[56..60].copy_from_slice(&self.parent_timestamp.to_be_bytes());
+        let name = self
+            .parent_name
+            .encode_utf16()
+            .take(256)
+            .flat_map(u16::to_be_bytes);
+        for (byte, value) in buffer[64..576].iter_mut().zip(name) {
+            *byte = value;
+        }
+
+        let checksum = checksum(&buffer, 36);
+        buffer[36..40].copy_from_slice(&checksum.to_be_bytes());
+        buffer
+    }
+
+    /// The size of the sector bitmap at the start of each block, which is
+    /// padded to a whole sector.
+    pub(crate) fn bitmap_size(&self) -> u64 {
+        let sectors = self.block_size as u64 / SECTOR_SIZE;
+        sectors.div_ceil(8).next_multiple_of(SECTOR_SIZE)
+    }
+}
+
+/// A VHD file, opened for reading.
+#[derive(Debug)]
+pub struct Vhd {
+    file: File,
+    footer: Footer,
+    dynamic_header: Option<DynamicHeader>,
+    bat: Vec<u32>,
+    parent: Option<Box<Vhd>>,
+    /// The sector bitmap of the most recently read block.
+    bitmap: Option<(u64, Vec<u8>)>,
+}
+
+impl Vhd {
+    /// Open the VHD file at the given path, along with the parent of a
+    /// differencing disk.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_in_chain(path.as_ref(), &mut Vec::new())
+    }
+
+    /// Open the file at `path`, which is a parent of each of the differencing
+    /// disks in `children`, given by their canonical paths.
+    fn open_in_chain(path: &Path, children: &mut Vec<PathBuf>) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        let footer = read_footer(&mut file)?;
+        let file_size = file.seek(SeekFrom::End(0))?;
+
+        if footer.disk_type == DiskType::Fixed {
+            if footer.current_size > file_size - FOOTER_SIZE as u64 {
+                return Err(Error::InvalidVirtualDiskSize(footer.current_size));
+            }
+            return Ok(Self {
+                file,
+                footer,
+                dynamic_header: None,
+                bat: Vec::new(),
+                parent: None,
+                bitmap: None,
+            });
+        }
+
+        let mut buffer = [0; DYNAMIC_HEADER_SIZE];
+        file.seek(SeekFrom::Start(footer.data_offset))?;
+        file.read_exact(&mut buffer)?;
+        let dynamic_header = DynamicHeader::read(&buffer)?;
+
+        let blocks_count = footer
+            .current_size
+            .div_ceil(dynamic_header.block_size as u64);
+        if blocks_count > dynamic_header.max_table_entries as u64
+            || footer.current_size > MAX_VIRTUAL_SIZE
+        {
+            return Err(Error::InvalidVirtualDiskSize(footer.current_size));
+        }
+        if dynamic_header.table_offset.saturating_add(blocks_count * 4) > file_size {
+            return Err(Error::InvalidImage(
+                "VHD block allocation table extends past the end of the file",
+            ));
+        }
+        let mut buffer = vec![0; blocks_count as usize * 4];
+        file.seek(SeekFrom::Start(dynamic_header.table_offset))?;
+        file.read_exact(&mut buffer)?;
+        let bat = buffer.chunks_exact(4).map(be_u32).collect();
+
+        let parent = if footer.disk_type == DiskType::Differencing {
+            Some(Box::new(Self::open_parent(
+                &mut file,
+                path,
+                &dynamic_header,
+                children,
+            )?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            file,
+            footer,
+            dynamic_header: Some(dynamic_header),
+            bat,
+            parent,
+            bitmap: None,
+        })
+    }
+
+    /// Find and open the parent of a differencing disk, checking that it is
+    /// the disk that the differencing disk was created from.
+    ///
+    /// Candidates that can't be read, or that are already part of the chain,
+    /// are skipped.
+    fn open_parent(
+        file: &mut File,
+        child_path: &Path,
+        dynamic_header: &DynamicHeader,
+        children: &mut Vec<PathBuf>,
+    ) -> Result<Vhd, Error> {
+        let child_dir = child_path.parent().unwrap_or(Path::new(""));
+
+        let mut candidates = Vec::new();
+        for locator in &dynamic_header.parent_locators {
+            if locator.platform_data_length > MAX_LOCATOR_LENGTH {
+                return Err(Error::InvalidImage("VHD parent locator is too long"));
+            }
+            let mut data = vec![0; locator.platform_data_length as usize];
+            file.seek(SeekFrom::Start(locator.platform_data_offset))?;
+            file.read_exact(&mut data)?;
+            if let Some(path) = locator_path(locator.platform_code, &data) {
+                candidates.push(child_dir.join(path));
+            }
+        }
+        // The name of the parent is only a last resort, as it is not always
+        // a full path
+        if !dynamic_header.parent_name.is_empty() {
+            candidates.push(child_dir.join(native_path(&dynamic_header.parent_name)));
+        }
+
+        children.push(child_path.canonicalize()?);
+        let mut modified = false;
+        let mut open_error = None;
+        for candidate in candidates {
+            let Ok(canonical_path) = candidate.canonicalize() else {
+                continue;
+            };
+            if !canonical_path.is_file() || children.contains(&canonical_path) {
+                continue;
+            }
+            // Only the footer is read to check the unique ID, so that a file
+            // that isn't the parent is never fully opened
+            let footer = File::open(&canonical_path)
+                .map_err(Error::from)
+                .and_then(|mut file| read_footer(&mut file));
+            let Ok(footer) = footer else {
+                continue;
+            };
+            if footer.unique_id != dynamic_header.parent_unique_id {
+                modified = true;
+                continue;
+            }
+            match Vhd::open_in_chain(&canonical_path, children) {
+                Ok(parent) => {
+                    children.pop();
+                    return Ok(parent);
+                }
+                Err(error) => open_error = open_error.or(Some(error)),
+            }
+        }
+        children.pop();
+
+        Err(match open_error {
+            Some(error) => error,
+            None if modified => Error::ParentModified,
+            None => Error::ParentNotFound,
+        })
+    }
+
+    /// The size of the virtual disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    /// The size of the virtual disk when it was created, in bytes.
+    pub fn original_size(&self) -> u64 {
+        self.footer.original_size
+    }
+
+    pub fn disk_type(&self) -> DiskType {
+        self.footer.disk_type
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.footer.geometry
+    }
+
+    /// The size of each block in bytes, for dynamic and differencing disks.
+    pub fn block_size(&self) -> Option<u32> {
+        self.dynamic_header.as_ref().map(|header| header.block_size)
+    }
+
+    /// The parent of a differencing disk.
+    pub fn parent(&self) -> Option<&Vhd> {
+        self.parent.as_deref()
+    }
+
+    /// Use the disk as a [`Reader`] that implements [`std::io::Read`] and
+    /// [`std::io::Seek`].
+    pub fn reader(&mut self) -> Reader<'_> {
+        Reader {
+            disk: self,
+            offset: 0,
+        }
+    }
+
+    /// Read from the virtual disk at `offset`, stopping at the end of the
+    /// block or of a run of sectors that are stored in the same place.
+    ///
+    /// Returns zero at the end of the disk.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let disk_size = self.footer.current_size;
+        let num_to_read = buf.len().min(disk_size.saturating_sub(offset) as usize);
+        let buf = &mut buf[..num_to_read];
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let Some(dynamic_header) = &self.dynamic_header else {
+            self.file.seek(SeekFrom::Start(offset))?;
+            return self.file.read(buf);
+        };
+
+        let block_size = dynamic_header.block_size as u64;
+        let bitmap_size = dynamic_header.bitmap_size();
+        let block_index = offset / block_size;
+        let offset_in_block = offset % block_size;
+        let buf = &mut buf[..(block_size - offset_in_block).min(num_to_read as u64) as usize];
+
+        let entry = self.bat[block_index as usize];
+        if entry == UNUSED_ENTRY {
+            return self.read_parent(offset, buf);
+        }
+
+        let block_offset = entry as u64 * SECTOR_SIZE;
+        let (present, length) = self.sector_run(block_index, block_offset, offset, buf.len())?;
+        let buf = &mut buf[..length];
+        if present {
+            self.file.seek(SeekFrom::Start(
+                block_offset + bitmap_size + offset_in_block,
+            ))?;
+            self.file.read(buf)
+        } else {
+            self.read_parent(offset, buf)
+        }
+    }
+
+    /// Read data that is not stored in this file, which is either from the
+    /// parent of a differencing disk or zero.
+    fn read_parent(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(parent) = &mut self.parent else {
+            buf.fill(0);
+            return Ok(buf.len());
+        };
+
+        // Beyond the end of a smaller parent is read as zeros
+        match parent.read_at(offset, buf)? {
+            0 => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            num_read => Ok(num_read),
+        }
+    }
+
+    /// Find whether the sector at `offset` in an allocated block is present,
+    /// and the length of the run of sectors from `offset` (up to
+    /// `max_length`) with the same state.
+    fn sector_run(
+        &mut self,
+        block_index: u64,
+        block_offset: u64,
+        offset: u64,
+        max_length: usize,
+    ) -> std::io::Result<(bool, usize)> {
+        let dynamic_header = self.dynamic_header.as_ref().expect("disk is dynamic");
+        let block_size = dynamic_header.block_size as u64;
+
+        if self.bitmap.as_ref().map(|(index, _)| *index) != Some(block_index) {
+            let mut bitmap = vec![0; dynamic_header.bitmap_size() as usize];
+            self.file.seek(SeekFrom::Start(block_offset))?;
+            self.file.read_exact(&mut bitmap)?;
+            self.bitmap = Some((block_index, bitmap));
+        }
+        let (_, bitmap) = self.bitmap.as_ref().expect("bitmap was just read");
+
+        // The most significant bit of each byte is the first sector
+        let is_present = |sector: u64| bitmap[(sector / 8) as usize] >> (7 - sector % 8) & 1 == 1;
+        let first_sector = offset % block_size / SECTOR_SIZE;
+        let last_sector = (offset % block_size + max_length as u64).div_ceil(SECTOR_SIZE);
+        let present = is_present(first_sector);
+        let run_end = (first_sector..last_sector)
+            .find(|&sector| is_present(sector) != present)
+            .map_or(u64::MAX, |sector| {
+                block_index * block_size + sector * SECTOR_SIZE
+            });
+
+        let length = (run_end - offset).min(max_length as u64) as usize;
+        Ok((present, length))
+    }
+
+    /// Whether any part of `start..end` contains data in this disk or any of
+    /// its parents.
+    fn has_data(&self, start: u64, end: u64) -> bool {
+        let end = end.min(self.footer.current_size);
+        if start >= end {
+            return false;
+        }
+        let Some(dynamic_header) = &self.dynamic_header else {
+            return true;
+        };
+
+        let block_size = dynamic_header.block_size as u64;
+        let first_block = start / block_size;
+        let last_block = (end - 1) / block_size;
+        self.bat[first_block as usize..=last_block as usize]
+            .iter()
+            .any(|&entry| entry != UNUSED_ENTRY)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.has_data(start, end))
+    }
+
+    /// The granularity at which data is allocated.
+    fn allocation_size(&self) -> u64 {
+        self.dynamic_header
+            .as_ref()
+            .map_or(self.footer.current_size.max(1), |header| {
+                header.block_size as u64
+            })
+    }
+}
+
+/// Read the footer of a VHD file.
+///
+/// The footer at the end of the file is authoritative, but dynamic disks have
+/// a copy at the start that can be used if it is damaged.
+fn read_footer(file: &mut File) -> Result<Footer, Error> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    if file_size < FOOTER_SIZE as u64 {
+        return Err(Error::InvalidSignature);
+    }
+    let mut buffer = [0; FOOTER_SIZE];
+    file.seek(SeekFrom::Start(file_size - FOOTER_SIZE as u64))?;
+    file.read_exact(&mut buffer)?;
+    match Footer::read(&buffer) {
+        Ok(footer) => Ok(footer),
+        Err(error) => {
+            file.seek(SeekFrom::Start(0))?;
+            file.read_exact(&mut buffer)?;
+            Footer::read(&buffer).map_err(|_| error)
+        }
+    }
+}
+
+/// Read the path to the parent from the data of a parent locator.
+///
+/// Only the locators that hold a path are understood. Relative paths are
+/// relative to the directory of the differencing disk.
+fn locator_path(platform_code: [u8; 4], data: &[u8]) -> Option<PathBuf> {
+    let path = match &platform_code {
+        // Windows paths as UTF-16
+        b"W2ru" | b"W2ku" => char::decode_utf16(
+            data.chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|&ch| ch != 0),
+        )
+        .collect::<Result<String, _>>()
+        .ok()?,
+        // Deprecated Windows paths as ANSI
+        b"Wi2r" | b"Wi2k" => std::str::from_utf8(data)
+            .ok()?
+            .trim_end_matches('\0')
+            .to_owned(),
+        // A file URL
+        b"MacX" => std::str::from_utf8(data)
+            .ok()?
+            .trim_end_matches('\0')
+            .strip_prefix("file://")?
+            .to_owned(),
+        _ => return None,
+    };
+    Some(native_path(&path))
+}
+
+/// A reader over the virtual disk of a [`Vhd`], created with [`Vhd::reader`].
+#[derive(Debug)]
+pub struct Reader<'a> {
+    disk: &'a mut Vhd,
+    offset: u64,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = self.disk.read_at(self.offset, buf)?;
+        self.offset += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+impl Seek for Reader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(from_end) => self.disk.footer.current_size.checked_add_signed(from_end),
+            SeekFrom::Current(from_current) => self.offset.checked_add_signed(from_current),
+        };
+        self.offset = offset.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative offset",
+            )
+        })?;
+        Ok(self.offset)
+    }
+}
+
+/// Blocks that are not allocated in the disk or any of its parents are
+/// skipped.
+impl RawSource for Reader<'_> {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let disk_size = self.disk.footer.current_size;
+        let allocation_size = self.disk.allocation_size();
+
+        let mut start = offset;
+        while start < disk_size
+            && !self
+                .disk
+                .has_data(start, next_boundary(start, allocation_size))
+        {
+            start = next_boundary(start, allocation_size);
+        }
+        if start >= disk_size {
+            return Ok(None);
+        }
+
+        let mut end = next_boundary(start, allocation_size);
+        while end < disk_size && self.disk.has_data(end, end + allocation_size) {
+            end += allocation_size;
+        }
+        Ok(Some((start, end.min(disk_size))))
+    }
+}
+
+/// The first multiple of `size` after `offset`.
+fn next_boundary(offset: u64, size: u64) -> u64 {
+    (offset / size + 1) * size
+}
+
+/// The checksum of a footer or dynamic header, which is the ones' complement
+/// of the sum of its bytes, excluding the checksum field at `checksum_offset`.
+pub(crate) fn checksum(buffer: &[u8], checksum_offset: usize) -> u32 {
+    let sum = buffer
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(checksum_offset..checksum_offset + 4).contains(i))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(byte as u32));
+    !sum
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().expect("infallible"))
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("infallible"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    /// An empty differencing disk of one block, with a parent locator that
+    /// holds `parent` as a relative path. The dynamic header can be edited
+    /// before its checksum is calculated.
+    fn differencing_disk(
+        unique_id: [u8; 16],
+        parent_unique_id: [u8; 16],
+        parent: &str,
+        edit: impl FnOnce(&mut [u8; DYNAMIC_HEADER_SIZE]),
+    ) -> Vec<u8> {
+        let block_size = DEFAULT_BLOCK_SIZE;
+        let table_offset = (FOOTER_SIZE + DYNAMIC_HEADER_SIZE) as u64;
+        let locator_offset = table_offset + SECTOR_SIZE;
+        let locator = parent
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+
+        let mut footer = Footer::new(
+            DiskType::Differencing,
+            block_size as u64,
+            FOOTER_SIZE as u64,
+        );
+        footer.unique_id = unique_id;
+        let mut dynamic_header = DynamicHeader::new(table_offset, 1, block_size);
+        dynamic_header.parent_unique_id = parent_unique_id;
+        let mut header = dynamic_header.to_bytes();
+        header[576..580].copy_from_slice(b"W2ru");
+        header[580..584].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        header[584..588].copy_from_slice(&(locator.len() as u32).to_be_bytes());
+        header[592..600].copy_from_slice(&locator_offset.to_be_bytes());
+        edit(&mut header);
+        let checksum = checksum(&header, 36);
+        header[36..40].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut file = footer.to_bytes().to_vec();
+        file.extend_from_slice(&header);
+        file.resize(table_offset as usize, 0);
+        file.extend_from_slice(&UNUSED_ENTRY.to_be_bytes());
+        file.resize(locator_offset as usize, 0);
+        file.extend_from_slice(&locator);
+        file.resize((locator_offset + SECTOR_SIZE) as usize, 0);
+        file.extend_from_slice(&footer.to_bytes());
+        file
+    }
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn geometry() {
+        let geometry = Geometry::from_size(10 * 1024 * 1024 * 1024);
+        assert_eq!(
+            geometry,
+            Geometry {
+                cylinders: 20805,
+                heads: 16,
+                sectors_per_track: 63
+            }
+        );
+
+        let geometry = Geometry::from_size(20 * 1024 * 1024);
+        assert_eq!(
+            geometry,
+            Geometry {
+                cylinders: 602,
+                heads: 4,
+                sectors_per_track: 17
+            }
+        );
+
+        let geometry = Geometry::from_size(MAX_VIRTUAL_SIZE);
+        assert_eq!(geometry.heads, 16);
+        assert_eq!(geometry.sectors_per_track, 255);
+        assert_eq!(geometry.cylinders, 65535);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let footer = Footer::new(DiskType::Dynamic, 20 * 1024 * 1024, 512);
+        let bytes = footer.to_bytes();
+        let read = Footer::read(&bytes).unwrap();
+        assert_eq!(read.current_size, footer.current_size);
+        assert_eq!(read.geometry, footer.geometry);
+        assert_eq!(read.disk_type, DiskType::Dynamic);
+        assert_eq!(read.unique_id, footer.unique_id);
+
+        let mut corrupted = bytes;
+        corrupted[100] ^= 1;
+        assert!(matches!(
+            Footer::read(&corrupted),
+            Err(Error::InvalidChecksum)
+        ));
+    }
+
+    #[test]
+    fn parent_locator_cycle() {
+        let first_path = TempPath::new("vhd");
+        let second_path = TempPath::new("vhd");
+        let (first_id, second_id) = ([1; 16], [2; 16]);
+        let first = differencing_disk(first_id, second_id, file_name(&second_path), |_| {});
+        let second = differencing_disk(second_id, first_id, file_name(&first_path), |_| {});
+        std::fs::write(&first_path, first).unwrap();
+        std::fs::write(&second_path, second).unwrap();
+
+        let error = Vhd::open(&first_path).unwrap_err();
+        assert!(matches!(error, Error::ParentNotFound));
+    }
+
+    #[test]
+    fn invalid_dynamic_header() {
+        let path = TempPath::new("vhd");
+        let parent_path = TempPath::new("vhd");
+        let disk = differencing_disk([1; 16], [2; 16], file_name(&parent_path), |header| {
+            header[584..588].copy_from_slice(&u32::MAX.to_be_bytes());
+        });
+        std::fs::write(&path, disk).unwrap();
+        let error = Vhd::open(&path).unwrap_err();
+        assert!(matches!(error, Error::InvalidImage(_)));
+
+        let disk = differencing_disk([1; 16], [2; 16], file_name(&parent_path), |header| {
+            header[16..24].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        });
+        std::fs::write(&path, disk).unwrap();
+        let error = Vhd::open(&path).unwrap_err();
+        assert!(matches!(error, Error::InvalidImage(_)));
+    }
+}
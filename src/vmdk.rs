@@ -0,0 +1,1032 @@
+//! Reading and writing the VMDK format used by VMware.
+//!
+//! A VMDK disk is described by a text descriptor, which lists the extents
+//! that make up the virtual disk. The descriptor is either a separate file,
+//! or embedded in a sparse extent. Flat extents store the data directly, and
+//! sparse extents map grains of the disk to the file through a grain
+//! directory of grain tables. In the stream optimised format, grains are
+//! compressed and the grain directory is at the end of the file.
+//!
+//! All fields are little-endian.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{convert::RawSource, Error, Guid};
+
+static SPARSE_MAGIC: &[u8; 4] = b"KDMV";
+
+const SECTOR_SIZE: u64 = 512;
+/// The grain directory offset of stream optimised files, where the real
+/// offset is in the footer.
+const GD_AT_END: u64 = u64::MAX;
+/// The grain table entry of a grain that reads as zero.
+const GRAIN_ZERO: u32 = 1;
+
+const FLAG_NEWLINE_TEST: u32 = 1 << 0;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+const COMPRESSION_DEFLATE: u16 = 1;
+
+const MARKER_EOS: u32 = 0;
+const MARKER_GRAIN_TABLE: u32 = 1;
+const MARKER_GRAIN_DIRECTORY: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+/// The size of each grain when writing, in sectors.
+const DEFAULT_GRAIN_SIZE: u64 = 128;
+/// The number of entries in each grain table when writing.
+const DEFAULT_GRAIN_TABLE_ENTRIES: u32 = 512;
+/// The space reserved for the embedded descriptor when writing, in sectors.
+const DESCRIPTOR_SECTORS: u64 = 20;
+/// The largest descriptor that is read, either embedded or in its own file.
+const MAX_DESCRIPTOR_SIZE: u64 = 1024 * 1024;
+
+/// The largest virtual disk that can be written as a sparse extent, which is
+/// 2040 GB. Sectors in the file are addressed with 32 bits, so the extent is
+/// limited to 2 TB, including its grain tables.
+pub const MAX_SPARSE_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+
+/// The layout of a VMDK disk to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subformat {
+    /// A single sparse extent with the descriptor embedded in it.
+    MonolithicSparse,
+    /// A descriptor file, with the data in a single flat extent next to it.
+    MonolithicFlat,
+    /// A single sparse extent with compressed grains, which can be written
+    /// and read sequentially.
+    StreamOptimized,
+}
+
+impl Subformat {
+    fn create_type(self) -> &'static str {
+        match self {
+            Subformat::MonolithicSparse => "monolithicSparse",
+            Subformat::MonolithicFlat => "monolithicFlat",
+            Subformat::StreamOptimized => "streamOptimized",
+        }
+    }
+}
+
+/// The header at the start of a sparse extent.
+#[derive(Debug, Clone)]
+struct SparseHeader {
+    version: u32,
+    flags: u32,
+    capacity: u64,
+    grain_size: u64,
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    grain_table_entries: u32,
+    redundant_gd_offset: u64,
+    gd_offset: u64,
+    overhead: u64,
+    compress_algorithm: u16,
+}
+
+impl SparseHeader {
+    fn read(buffer: &[u8; SECTOR_SIZE as usize]) -> Result<Self, Error> {
+        if &buffer[0..4] != SPARSE_MAGIC {
+            return Err(Error::InvalidSignature);
+        }
+        let header = Self {
+            version: le_u32(&buffer[4..8]),
+            flags: le_u32(&buffer[8..12]),
+            capacity: le_u64(&buffer[12..20]),
+            grain_size: le_u64(&buffer[20..28]),
+            descriptor_offset: le_u64(&buffer[28..36]),
+            descriptor_size: le_u64(&buffer[36..44]),
+            grain_table_entries: le_u32(&buffer[44..48]),
+            redundant_gd_offset: le_u64(&buffer[48..56]),
+            gd_offset: le_u64(&buffer[56..64]),
+            overhead: le_u64(&buffer[64..72]),
+            compress_algorithm: u16::from_le_bytes([buffer[77], buffer[78]]),
+        };
+
+        if !(1..=3).contains(&header.version) {
+            return Err(Error::Unsupported("VMDK sparse extent version"));
+        }
+        if !header.grain_size.is_power_of_two() || header.grain_size > 2048 {
+            return Err(Error::Unsupported("VMDK grain size"));
+        }
+        // Every grain table has the same number of entries in practice
+        if header.grain_table_entries != DEFAULT_GRAIN_TABLE_ENTRIES {
+            return Err(Error::Unsupported("VMDK grain table size"));
+        }
+        if header.capacity.checked_mul(SECTOR_SIZE).is_none() {
+            return Err(Error::InvalidImage("VMDK capacity is too large"));
+        }
+        if header.descriptor_size > MAX_DESCRIPTOR_SIZE / SECTOR_SIZE {
+            return Err(Error::InvalidImage("VMDK descriptor is too large"));
+        }
+        if header.flags & FLAG_COMPRESSED != 0 && header.compress_algorithm != COMPRESSION_DEFLATE {
+            return Err(Error::Unsupported("VMDK compression algorithm"));
+        }
+        Ok(header)
+    }
+
+    fn to_bytes(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut buffer = [0; SECTOR_SIZE as usize];
+        buffer[0..4].copy_from_slice(SPARSE_MAGIC);
+        buffer[4..8].copy_from_slice(&self.version.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        buffer[12..20].copy_from_slice(&self.capacity.to_le_bytes());
+        buffer[20..28].copy_from_slice(&self.grain_size.to_le_bytes());
+        buffer[28..36].copy_from_slice(&self.descriptor_offset.to_le_bytes());
+        buffer[36..44].copy_from_slice(&self.descriptor_size.to_le_bytes());
+        buffer[44..48].copy_from_slice(&self.grain_table_entries.to_le_bytes());
+        buffer[48..56].copy_from_slice(&self.redundant_gd_offset.to_le_bytes());
+        buffer[56..64].copy_from_slice(&self.gd_offset.to_le_bytes());
+        buffer[64..72].copy_from_slice(&self.overhead.to_le_bytes());
+        // Characters used to detect files that have been transferred in text
+        // mode
+        buffer[73..77].copy_from_slice(b"\n \r\n");
+        buffer[77..79].copy_from_slice(&self.compress_algorithm.to_le_bytes());
+        buffer
+    }
+
+    /// The number of bytes of the disk covered by each grain table.
+    fn grain_table_coverage(&self) -> u64 {
+        self.grain_size * SECTOR_SIZE * self.grain_table_entries as u64
+    }
+}
+
+/// Where the data of a grain is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Grain {
+    Unallocated,
+    Zero,
+    /// The sector of the grain, or of its marker if it is compressed.
+    Data(u64),
+}
+
+/// An extent made up of grains, which may be compressed.
+#[derive(Debug)]
+struct SparseExtent {
+    file: File,
+    header: SparseHeader,
+    grain_directory: Vec<u32>,
+    /// The most recently used grain table, along with its index in the grain
+    /// directory.
+    grain_table: Option<(u64, Vec<u32>)>,
+    /// The most recently decompressed grain, along with its sector.
+    decompressed: Option<(u64, Vec<u8>)>,
+}
+
+impl SparseExtent {
+    fn open(mut file: File) -> Result<Self, Error> {
+        let mut buffer = [0; SECTOR_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buffer)?;
+        let mut header = SparseHeader::read(&buffer)?;
+
+        // Stream optimised files are written before the location of the grain
+        // directory is known, so it is in the footer instead
+        if header.gd_offset == GD_AT_END {
+            let file_size = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(file_size.saturating_sub(2 * SECTOR_SIZE)))?;
+            file.read_exact(&mut buffer)?;
+            header = SparseHeader::read(&buffer)?;
+            if header.gd_offset == GD_AT_END {
+                return Err(Error::InvalidSignature);
+            }
+        }
+
+        // The grain directory has to be within the file
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let directory_entries = header
+            .capacity
+            .div_ceil(header.grain_size * header.grain_table_entries as u64);
+        let directory_offset = header
+            .gd_offset
+            .checked_mul(SECTOR_SIZE)
+            .filter(|offset| {
+                offset
+                    .checked_add(directory_entries * 4)
+                    .is_some_and(|end| end <= file_size)
+            })
+            .ok_or(Error::InvalidImage(
+                "VMDK grain directory extends past the end of the file",
+            ))?;
+        let mut buffer = vec![0; directory_entries as usize * 4];
+        file.seek(SeekFrom::Start(directory_offset))?;
+        file.read_exact(&mut buffer)?;
+        let grain_directory = buffer.chunks_exact(4).map(le_u32).collect();
+
+        Ok(Self {
+            file,
+            header,
+            grain_directory,
+            grain_table: None,
+            decompressed: None,
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.header.capacity * SECTOR_SIZE
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR_SIZE
+    }
+
+    /// Find where the data of a grain is stored.
+    fn grain(&mut self, grain_index: u64) -> std::io::Result<Grain> {
+        let entries = self.header.grain_table_entries as u64;
+        let table_index = grain_index / entries;
+        let table_sector = match self.grain_directory.get(table_index as usize) {
+            None | Some(0) => return Ok(Grain::Unallocated),
+            Some(&sector) => sector as u64,
+        };
+
+        if self.grain_table.as_ref().map(|(index, _)| *index) != Some(table_index) {
+            let mut buffer = vec![0; entries as usize * 4];
+            self.file
+                .seek(SeekFrom::Start(table_sector * SECTOR_SIZE))?;
+            self.file.read_exact(&mut buffer)?;
+            let table = buffer.chunks_exact(4).map(le_u32).collect();
+            self.grain_table = Some((table_index, table));
+        }
+        let (_, table) = self.grain_table.as_ref().expect("table was just read");
+
+        Ok(match table[(grain_index % entries) as usize] {
+            0 => Grain::Unallocated,
+            GRAIN_ZERO if self.header.version >= 2 => Grain::Zero,
+            sector => Grain::Data(sector as u64),
+        })
+    }
+
+    /// Read from the extent at `offset`, stopping at the end of the grain.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let grain_bytes = self.grain_bytes();
+        let offset_in_grain = offset % grain_bytes;
+        let num_to_read = (buf.len() as u64)
+            .min(self.size().saturating_sub(offset))
+            .min(grain_bytes - offset_in_grain) as usize;
+        let buf = &mut buf[..num_to_read];
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let sector = match self.grain(offset / grain_bytes)? {
+            Grain::Unallocated | Grain::Zero => {
+                buf.fill(0);
+                return Ok(buf.len());
+            }
+            Grain::Data(sector) => sector,
+        };
+
+        if self.header.flags & FLAG_COMPRESSED == 0 {
+            self.file
+                .seek(SeekFrom::Start(sector * SECTOR_SIZE + offset_in_grain))?;
+            return self.file.read(buf);
+        }
+
+        if self.decompressed.as_ref().map(|(sector, _)| *sector) != Some(sector) {
+            let grain = self.decompress(sector)?;
+            self.decompressed = Some((sector, grain));
+        }
+        let (_, grain) = self.decompressed.as_ref().expect("grain was just read");
+        buf.copy_from_slice(&grain[offset_in_grain as usize..][..buf.len()]);
+        Ok(buf.len())
+    }
+
+    /// Read and inflate a compressed grain, which starts with a marker
+    /// holding its length.
+    fn decompress(&mut self, sector: u64) -> std::io::Result<Vec<u8>> {
+        let invalid =
+            || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid compressed grain");
+
+        let mut marker = [0; 12];
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(&mut marker)?;
+        let length = le_u32(&marker[8..12]);
+        // Compressed data that is much larger than the grain isn't valid
+        if length as u64 > 2 * self.grain_bytes() {
+            return Err(invalid());
+        }
+        let mut compressed = vec![0; length as usize];
+        self.file.read_exact(&mut compressed)?;
+
+        let grain_bytes = self.grain_bytes() as usize;
+        let mut grain =
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&compressed, grain_bytes)
+                .map_err(|_| invalid())?;
+        grain.resize(grain_bytes, 0);
+        Ok(grain)
+    }
+
+    /// Whether any part of `start..end` has a grain allocated.
+    fn has_data(&mut self, start: u64, end: u64) -> std::io::Result<bool> {
+        let grain_bytes = self.grain_bytes();
+        for grain_index in start / grain_bytes..end.min(self.size()).div_ceil(grain_bytes) {
+            if let Grain::Data(_) = self.grain(grain_index)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Find the next range at or after `offset` that has grains allocated.
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let size = self.size();
+        let grain_bytes = self.grain_bytes();
+        let coverage = self.header.grain_table_coverage();
+
+        // Skip over whole grain tables at a time, then find the first grain
+        let mut start = offset;
+        while start < size && !self.has_data(start, next_boundary(start, coverage))? {
+            start = next_boundary(start, coverage);
+        }
+        while start < size && !self.has_data(start, next_boundary(start, grain_bytes))? {
+            start = next_boundary(start, grain_bytes);
+        }
+        if start >= size {
+            return Ok(None);
+        }
+
+        let mut end = next_boundary(start, grain_bytes);
+        while end < size && self.has_data(end, end + grain_bytes)? {
+            end += grain_bytes;
+        }
+        Ok(Some((start, end.min(size))))
+    }
+}
+
+/// How the data of an extent is stored.
+#[derive(Debug)]
+enum ExtentKind {
+    Flat { file: File, offset: u64 },
+    Sparse(Box<SparseExtent>),
+    Zero,
+}
+
+/// A part of the virtual disk, stored in its own file.
+#[derive(Debug)]
+struct Extent {
+    /// The offset of the extent in the virtual disk.
+    start: u64,
+    length: u64,
+    kind: ExtentKind,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// A VMDK disk, opened for reading.
+///
+/// Disks with a parent, such as snapshots, are not supported.
+#[derive(Debug)]
+pub struct Vmdk {
+    create_type: String,
+    extents: Vec<Extent>,
+}
+
+impl Vmdk {
+    /// Open the VMDK disk at the given path, which is either a descriptor file
+    /// or a sparse extent with an embedded descriptor.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mut magic = [0; 4];
+        let length = file.read(&mut magic)?;
+
+        if &magic[..length] == SPARSE_MAGIC {
+            let extent = SparseExtent::open(file)?;
+            let descriptor = if extent.header.descriptor_offset != 0 {
+                let mut buffer = vec![0; (extent.header.descriptor_size * SECTOR_SIZE) as usize];
+                let descriptor_offset = extent
+                    .header
+                    .descriptor_offset
+                    .checked_mul(SECTOR_SIZE)
+                    .ok_or(Error::InvalidImage("VMDK descriptor offset is too large"))?;
+                let mut file = &extent.file;
+                file.seek(SeekFrom::Start(descriptor_offset))?;
+                file.read_exact(&mut buffer)?;
+                Descriptor::parse(&buffer)?
+            } else {
+                Descriptor::default()
+            };
+            descriptor.check_no_parent()?;
+
+            return Ok(Self {
+                create_type: descriptor
+                    .create_type
+                    .unwrap_or_else(|| "monolithicSparse".to_owned()),
+                extents: vec![Extent {
+                    start: 0,
+                    length: extent.size(),
+                    kind: ExtentKind::Sparse(Box::new(extent)),
+                }],
+            });
+        }
+
+        // Otherwise this is a descriptor file, which is small
+        let mut buffer = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(MAX_DESCRIPTOR_SIZE).read_to_end(&mut buffer)?;
+        let descriptor = Descriptor::parse(&buffer)?;
+        descriptor.check_no_parent()?;
+        if descriptor.extents.is_empty() {
+            return Err(Error::InvalidSignature);
+        }
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut extents = Vec::with_capacity(descriptor.extents.len());
+        let mut start = 0;
+        for line in descriptor.extents {
+            let too_large = || Error::InvalidImage("VMDK extent is too large");
+            let length = line
+                .sectors
+                .checked_mul(SECTOR_SIZE)
+                .ok_or_else(too_large)?;
+            let kind = match line.kind.as_str() {
+                "FLAT" | "VMFS" => ExtentKind::Flat {
+                    file: File::open(dir.join(&line.file_name))?,
+                    offset: line.offset.checked_mul(SECTOR_SIZE).ok_or_else(too_large)?,
+                },
+                "SPARSE" => {
+                    let extent = SparseExtent::open(File::open(dir.join(&line.file_name))?)?;
+                    ExtentKind::Sparse(Box::new(extent))
+                }
+                "ZERO" => ExtentKind::Zero,
+                _ => return Err(Error::Unsupported("VMDK extent type")),
+            };
+            extents.push(Extent {
+                start,
+                length,
+                kind,
+            });
+            start = start.checked_add(length).ok_or_else(too_large)?;
+        }
+
+        Ok(Self {
+            create_type: descriptor.create_type.unwrap_or_default(),
+            extents,
+        })
+    }
+
+    /// The size of the virtual disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.extents.last().map_or(0, Extent::end)
+    }
+
+    /// The type of the disk from its descriptor, such as `monolithicSparse`.
+    pub fn create_type(&self) -> &str {
+        &self.create_type
+    }
+
+    /// Use the disk as a [`Reader`] that implements [`std::io::Read`] and
+    /// [`std::io::Seek`].
+    pub fn reader(&mut self) -> Reader<'_> {
+        Reader {
+            disk: self,
+            offset: 0,
+        }
+    }
+
+    /// The index of the extent that contains `offset`.
+    fn extent_index(&self, offset: u64) -> Option<usize> {
+        let index = self
+            .extents
+            .partition_point(|extent| extent.end() <= offset);
+        (index < self.extents.len()).then_some(index)
+    }
+
+    /// Read from the virtual disk at `offset`, stopping at the end of the
+    /// extent.
+    ///
+    /// Returns zero at the end of the disk.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(index) = self.extent_index(offset) else {
+            return Ok(0);
+        };
+        let extent = &mut self.extents[index];
+        let offset_in_extent = offset - extent.start;
+        let num_to_read = (buf.len() as u64).min(extent.length - offset_in_extent) as usize;
+        let buf = &mut buf[..num_to_read];
+
+        match &mut extent.kind {
+            ExtentKind::Flat { file, offset } => {
+                file.seek(SeekFrom::Start(*offset + offset_in_extent))?;
+                // A flat extent may be shorter than described
+                match file.read(buf)? {
+                    0 => {
+                        buf.fill(0);
+                        Ok(buf.len())
+                    }
+                    num_read => Ok(num_read),
+                }
+            }
+            ExtentKind::Sparse(sparse) => sparse.read_at(offset_in_extent, buf),
+            ExtentKind::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+        }
+    }
+}
+
+/// A reader over the virtual disk of a [`Vmdk`], created with
+/// [`Vmdk::reader`].
+#[derive(Debug)]
+pub struct Reader<'a> {
+    disk: &'a mut Vmdk,
+    offset: u64,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = self.disk.read_at(self.offset, buf)?;
+        self.offset += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+impl Seek for Reader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(from_end) => self.disk.virtual_size().checked_add_signed(from_end),
+            SeekFrom::Current(from_current) => self.offset.checked_add_signed(from_current),
+        };
+        self.offset = offset.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative offset",
+            )
+        })?;
+        Ok(self.offset)
+    }
+}
+
+/// Grains that are not allocated and zero extents are skipped.
+impl RawSource for Reader<'_> {
+    fn next_data_range(&mut self, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+        let Some(first) = self.disk.extent_index(offset) else {
+            return Ok(None);
+        };
+        for extent in &mut self.disk.extents[first..] {
+            let from = offset.max(extent.start) - extent.start;
+            match &mut extent.kind {
+                ExtentKind::Flat { .. } => return Ok(Some((extent.start + from, extent.end()))),
+                ExtentKind::Sparse(sparse) => {
+                    if let Some((start, end)) = sparse.next_data_range(from)? {
+                        return Ok(Some((extent.start + start, extent.start + end)));
+                    }
+                }
+                ExtentKind::Zero => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// An extent line from a descriptor.
+#[derive(Debug, Clone)]
+struct ExtentLine {
+    sectors: u64,
+    kind: String,
+    file_name: String,
+    offset: u64,
+}
+
+/// The parts of a descriptor that are needed to read the disk.
+#[derive(Debug, Clone, Default)]
+struct Descriptor {
+    create_type: Option<String>,
+    parent_cid: Option<String>,
+    extents: Vec<ExtentLine>,
+}
+
+impl Descriptor {
+    fn parse(buffer: &[u8]) -> Result<Self, Error> {
+        // The embedded descriptor is padded with zeros
+        let text = buffer.split(|&byte| byte == 0).next().unwrap_or_default();
+        let text = std::str::from_utf8(text)?;
+
+        let mut descriptor = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim().trim_matches('"').to_owned();
+                match key.trim() {
+                    "createType" => descriptor.create_type = Some(value),
+                    "parentCID" => descriptor.parent_cid = Some(value),
+                    _ => {}
+                }
+                continue;
+            }
+
+            // RW 2097152 SPARSE "disk.vmdk" [offset]
+            let (fields, file_name) = match line.split_once('"') {
+                Some((fields, rest)) => (fields, rest),
+                None => (line, ""),
+            };
+            let (file_name, offset) = file_name.split_once('"').unwrap_or((file_name, ""));
+            let mut fields = fields.split_whitespace();
+            let (Some(access), Some(sectors), Some(kind)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if !["RW", "RDONLY", "NOACCESS"].contains(&access) {
+                continue;
+            }
+            descriptor.extents.push(ExtentLine {
+                sectors: sectors
+                    .parse()
+                    .map_err(|_| Error::Unsupported("VMDK extent size"))?,
+                kind: kind.to_owned(),
+                file_name: file_name.to_owned(),
+                offset: offset.trim().parse().unwrap_or(0),
+            });
+        }
+        Ok(descriptor)
+    }
+
+    fn check_no_parent(&self) -> Result<(), Error> {
+        match self.parent_cid.as_deref() {
+            None => Ok(()),
+            Some(cid) if cid.eq_ignore_ascii_case("ffffffff") => Ok(()),
+            Some(_) => Err(Error::Unsupported("VMDK disks with a parent")),
+        }
+    }
+}
+
+/// Build the descriptor of a new disk with a single extent.
+pub(crate) fn descriptor(subformat: Subformat, size: u64, extent: &str) -> String {
+    let sectors = size / SECTOR_SIZE;
+    let kind = match subformat {
+        Subformat::MonolithicFlat => "FLAT",
+        Subformat::MonolithicSparse | Subformat::StreamOptimized => "SPARSE",
+    };
+    let offset = match subformat {
+        Subformat::MonolithicFlat => " 0",
+        _ => "",
+    };
+    // The geometry of an LSI Logic adapter, as used by VMware
+    let cylinders = (sectors / (255 * 63)).clamp(1, 65535);
+    let cid = Guid::new_random().to_bytes();
+
+    format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"{}\"\n\
+         \n\
+         # Extent description\n\
+         RW {sectors} {kind} \"{extent}\"{offset}\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{cylinders}\"\n\
+         ddb.geometry.heads = \"255\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"lsilogic\"\n",
+        u32::from_le_bytes(cid[..4].try_into().expect("infallible")),
+        subformat.create_type(),
+    )
+}
+
+/// Writes a sparse extent, where grains are added in order of the virtual
+/// disk, and the metadata is completed by [`SparseWriter::finish`].
+///
+/// In the monolithic sparse format the grain directory and tables are
+/// reserved at the start of the file, and filled in as grains are written. In
+/// the stream optimised format, everything is written sequentially, with each
+/// grain table following its grains and the grain directory at the end.
+pub(crate) struct SparseWriter<'a, W> {
+    writer: &'a mut W,
+    header: SparseHeader,
+    stream_optimized: bool,
+    grain_directory: Vec<u32>,
+    /// The grain table that is currently being filled, along with its index
+    /// in the grain directory.
+    grain_table: Option<(u64, Vec<u32>)>,
+    /// The sector after the end of the written data.
+    end: u64,
+}
+
+impl<'a, W: Write + Seek> SparseWriter<'a, W> {
+    pub(crate) fn new(
+        writer: &'a mut W,
+        size: u64,
+        subformat: Subformat,
+        descriptor: &str,
+    ) -> Result<Self, Error> {
+        let stream_optimized = match subformat {
+            Subformat::MonolithicSparse => false,
+            Subformat::StreamOptimized => true,
+            Subformat::MonolithicFlat => unreachable!("flat extents are written directly"),
+        };
+        if descriptor.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+            return Err(Error::Unsupported("VMDK descriptor larger than its space"));
+        }
+        if size > MAX_SPARSE_SIZE {
+            return Err(Error::VmdkTooLarge(size));
+        }
+
+        let capacity = size / SECTOR_SIZE;
+        let grain_table_entries = DEFAULT_GRAIN_TABLE_ENTRIES;
+        let directory_entries = capacity.div_ceil(DEFAULT_GRAIN_SIZE * grain_table_entries as u64);
+        let directory_sectors = (directory_entries * 4).div_ceil(SECTOR_SIZE);
+        let table_sectors = (grain_table_entries as u64 * 4).div_ceil(SECTOR_SIZE);
+
+        let descriptor_offset = 1;
+        let mut grain_directory = vec![0; directory_entries as usize];
+        let (gd_offset, metadata_end) = if stream_optimized {
+            (GD_AT_END, descriptor_offset + DESCRIPTOR_SECTORS)
+        } else {
+            // Every grain table is allocated up front, directly after the
+            // grain directory
+            let gd_offset = descriptor_offset + DESCRIPTOR_SECTORS;
+            let tables_offset = gd_offset + directory_sectors;
+            for (i, entry) in grain_directory.iter_mut().enumerate() {
+                *entry = u32::try_from(tables_offset + i as u64 * table_sectors)
+                    .map_err(|_| Error::VmdkTooLarge(size))?;
+            }
+            (gd_offset, tables_offset + directory_entries * table_sectors)
+        };
+        let overhead = metadata_end.next_multiple_of(DEFAULT_GRAIN_SIZE);
+
+        let header = SparseHeader {
+            version: if stream_optimized { 3 } else { 1 },
+            flags: if stream_optimized {
+                FLAG_NEWLINE_TEST | FLAG_COMPRESSED | FLAG_MARKERS
+            } else {
+                FLAG_NEWLINE_TEST
+            },
+            capacity,
+            grain_size: DEFAULT_GRAIN_SIZE,
+            descriptor_offset,
+            descriptor_size: DESCRIPTOR_SECTORS,
+            grain_table_entries,
+            redundant_gd_offset: 0,
+            gd_offset,
+            overhead,
+            compress_algorithm: if stream_optimized {
+                COMPRESSION_DEFLATE
+            } else {
+                0
+            },
+        };
+
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&header.to_bytes())?;
+        let mut descriptor_buffer = vec![0; (DESCRIPTOR_SECTORS * SECTOR_SIZE) as usize];
+        descriptor_buffer[..descriptor.len()].copy_from_slice(descriptor.as_bytes());
+        writer.write_all(&descriptor_buffer)?;
+
+        Ok(Self {
+            writer,
+            header,
+            stream_optimized,
+            grain_directory,
+            grain_table: None,
+            end: overhead,
+        })
+    }
+
+    /// The size of each grain in bytes.
+    pub(crate) fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR_SIZE
+    }
+
+    /// Write the data of a grain, which must come after any grain that has
+    /// already been written.
+    pub(crate) fn write_grain(&mut self, grain_index: u64, data: &[u8]) -> Result<(), Error> {
+        let sector = self.end;
+        self.writer.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        if self.stream_optimized {
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(data, 6);
+            let lba = grain_index * self.header.grain_size;
+            let mut buffer = Vec::with_capacity(12 + compressed.len());
+            buffer.extend_from_slice(&lba.to_le_bytes());
+            buffer.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&compressed);
+            buffer.resize(buffer.len().next_multiple_of(SECTOR_SIZE as usize), 0);
+            self.writer.write_all(&buffer)?;
+            self.end += buffer.len() as u64 / SECTOR_SIZE;
+        } else {
+            self.writer.write_all(data)?;
+            self.end += self.header.grain_size;
+        }
+
+        let entries = self.header.grain_table_entries as u64;
+        let table_index = grain_index / entries;
+        if self.grain_table.as_ref().map(|(index, _)| *index) != Some(table_index) {
+            self.flush_grain_table()?;
+            self.grain_table = Some((table_index, vec![0; entries as usize]));
+        }
+        let sector = self.sector_entry(sector)?;
+        let (_, table) = self.grain_table.as_mut().expect("table was just created");
+        table[(grain_index % entries) as usize] = sector;
+        Ok(())
+    }
+
+    /// Write the current grain table, either to its reserved location or to
+    /// the end of the stream.
+    fn flush_grain_table(&mut self) -> Result<(), Error> {
+        let Some((table_index, table)) = self.grain_table.take() else {
+            return Ok(());
+        };
+        let table = table
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        if self.stream_optimized {
+            let sector = self.write_metadata(MARKER_GRAIN_TABLE, &table)?;
+            self.grain_directory[table_index as usize] = self.sector_entry(sector)?;
+        } else {
+            let sector = self.grain_directory[table_index as usize] as u64;
+            self.writer.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            self.writer.write_all(&table)?;
+        }
+        Ok(())
+    }
+
+    /// A sector of the file as it is stored in a grain table or directory.
+    /// Compressed grains can take more space than the virtual disk, so the
+    /// end of the file can pass 2 TB even within [`MAX_SPARSE_SIZE`].
+    fn sector_entry(&self, sector: u64) -> Result<u32, Error> {
+        u32::try_from(sector).map_err(|_| Error::VmdkTooLarge(self.header.capacity * SECTOR_SIZE))
+    }
+
+    /// Write a metadata marker followed by its data at the end of the stream,
+    /// returning the sector of the data.
+    fn write_metadata(&mut self, marker_type: u32, data: &[u8]) -> Result<u64, Error> {
+        let sectors = (data.len() as u64).div_ceil(SECTOR_SIZE);
+        let mut marker = vec![0; SECTOR_SIZE as usize];
+        marker[0..8].copy_from_slice(&sectors.to_le_bytes());
+        marker[12..16].copy_from_slice(&marker_type.to_le_bytes());
+
+        let mut buffer = data.to_vec();
+        buffer.resize((sectors * SECTOR_SIZE) as usize, 0);
+        self.writer.seek(SeekFrom::Start(self.end * SECTOR_SIZE))?;
+        self.writer.write_all(&marker)?;
+        self.writer.write_all(&buffer)?;
+
+        let sector = self.end + 1;
+        self.end = sector + sectors;
+        Ok(sector)
+    }
+
+    /// Write the grain directory, and for stream optimised files the footer
+    /// and end of stream marker.
+    pub(crate) fn finish(mut self) -> Result<(), Error> {
+        self.flush_grain_table()?;
+        let directory = self
+            .grain_directory
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        if self.stream_optimized {
+            self.header.gd_offset = self.write_metadata(MARKER_GRAIN_DIRECTORY, &directory)?;
+            let footer = self.header.to_bytes();
+            self.write_metadata(MARKER_FOOTER, &footer)?;
+            self.write_metadata(MARKER_EOS, &[])?;
+        } else {
+            self.writer
+                .seek(SeekFrom::Start(self.header.gd_offset * SECTOR_SIZE))?;
+            self.writer.write_all(&directory)?;
+            // Extend the file to cover the reserved grain tables
+            if self.end == self.header.overhead {
+                self.writer
+                    .seek(SeekFrom::Start(self.end * SECTOR_SIZE - 1))?;
+                self.writer.write_all(&[0])?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The first multiple of `size` after `offset`.
+fn next_boundary(offset: u64, size: u64) -> u64 {
+    (offset / size + 1) * size
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().expect("infallible"))
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("infallible"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        convert::to_vmdk,
+        testing::{disk_with_data, pattern, TempPath},
+        MB,
+    };
+
+    #[test]
+    fn descriptor_round_trip() {
+        let text = descriptor(
+            Subformat::MonolithicFlat,
+            64 * 1024 * 1024,
+            "disk-flat.vmdk",
+        );
+        let parsed = Descriptor::parse(text.as_bytes()).unwrap();
+        assert_eq!(parsed.create_type.as_deref(), Some("monolithicFlat"));
+        assert!(parsed.check_no_parent().is_ok());
+        assert_eq!(parsed.extents.len(), 1);
+        assert_eq!(parsed.extents[0].sectors, 131072);
+        assert_eq!(parsed.extents[0].kind, "FLAT");
+        assert_eq!(parsed.extents[0].file_name, "disk-flat.vmdk");
+
+        let snapshot = b"parentCID=12345678\nRW 2048 SPARSE \"disk-000001.vmdk\"\n\0\0";
+        let parsed = Descriptor::parse(snapshot).unwrap();
+        assert!(matches!(
+            parsed.check_no_parent(),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_sparse_headers() {
+        let vhdx_path = TempPath::new("vhdx");
+        let path = TempPath::new("vmdk");
+        let mut disk = disk_with_data(&vhdx_path, 8 * MB as u64, &pattern(MB));
+        to_vmdk(&mut disk, &path, Subformat::MonolithicSparse).unwrap();
+        let mut header = [0; SECTOR_SIZE as usize];
+        File::open(&path).unwrap().read_exact(&mut header).unwrap();
+
+        let open_with = |offset: usize, bytes: &[u8]| {
+            let mut patched = header;
+            patched[offset..offset + bytes.len()].copy_from_slice(bytes);
+            let mut file = File::options().write(true).open(&path).unwrap();
+            file.write_all(&patched).unwrap();
+            let result = Vmdk::open(&path).map(|_| ());
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.write_all(&header).unwrap();
+            result
+        };
+        assert!(matches!(
+            open_with(44, &1024u32.to_le_bytes()),
+            Err(Error::Unsupported("VMDK grain table size"))
+        ));
+        assert!(matches!(
+            open_with(12, &u64::MAX.to_le_bytes()),
+            Err(Error::InvalidImage("VMDK capacity is too large"))
+        ));
+        assert!(matches!(
+            open_with(36, &u64::MAX.to_le_bytes()),
+            Err(Error::InvalidImage("VMDK descriptor is too large"))
+        ));
+        assert!(matches!(
+            open_with(56, &(u64::MAX / 2).to_le_bytes()),
+            Err(Error::InvalidImage(_))
+        ));
+        assert!(Vmdk::open(&path).is_ok());
+    }
+
+    #[test]
+    fn grain_beyond_2_tb() {
+        let size = 64 * MB as u64;
+        let subformat = Subformat::StreamOptimized;
+        let text = descriptor(subformat, size, "disk.vmdk");
+        // The data is discarded, so that nothing is written at 2 TB
+        struct Discard;
+        impl Write for Discard {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for Discard {
+            fn seek(&mut self, _: SeekFrom) -> std::io::Result<u64> {
+                Ok(0)
+            }
+        }
+        let mut file = Discard;
+        let mut writer = SparseWriter::new(&mut file, size, subformat, &text).unwrap();
+
+        // Compressed grains can push the end of the file past what a grain
+        // table can address
+        writer.end = 1 << 32;
+        let grain = vec![1; writer.grain_bytes() as usize];
+        let error = writer.write_grain(0, &grain).unwrap_err();
+        assert!(matches!(error, Error::VmdkTooLarge(_)));
+    }
+}
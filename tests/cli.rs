@@ -0,0 +1,72 @@
+//! Smoke tests of the command line tool.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// A path in the temporary directory that is removed when dropped.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("vhdx-cli-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn vhdx(command: &str, path: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_vhdx"))
+        .arg(command)
+        .arg(path)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "vhdx {command} {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn info(path: &Path) -> serde_json::Value {
+    let output = vhdx("info", path, &["--json"]);
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn check(path: &Path) {
+    let output = vhdx("check", path, &["--json"]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["ok"], true, "{report:#}");
+    assert_eq!(report["findings"], serde_json::json!([]));
+}
+
+#[test]
+fn create_info_check() {
+    let path = TempPath::new("create.vhdx");
+    vhdx("create", &path.0, &["--size", "64M", "--block-size", "1M"]);
+
+    let metadata = &info(&path.0)["metadata"];
+    assert_eq!(metadata["virtual_size"], 64 * 1024 * 1024);
+    assert_eq!(metadata["block_size"], 1024 * 1024);
+    assert_eq!(metadata["has_parent"], false);
+    check(&path.0);
+
+    // Resizing and compacting open the file for writing, which replays any
+    // log entries before the file is changed
+    vhdx("resize", &path.0, &["128M"]);
+    assert_eq!(info(&path.0)["metadata"]["virtual_size"], 128 * 1024 * 1024);
+    check(&path.0);
+
+    vhdx("compact", &path.0, &[]);
+    check(&path.0);
+}